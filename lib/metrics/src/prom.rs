@@ -173,6 +173,15 @@ impl<A: FmtMetrics, B: FmtMetrics> FmtMetrics for AndThen<A, B> {
     }
 }
 
+impl<A: FmtMetrics> FmtMetrics for Option<A> {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Some(a) => a.fmt_metrics(f),
+            None => Ok(()),
+        }
+    }
+}
+
 impl FmtMetrics for () {
     fn fmt_metrics(&self, _: &mut fmt::Formatter<'_>) -> fmt::Result {
         Ok(())
@@ -326,10 +326,12 @@ pub mod test_util {
     use tokio_timer;
 
     use self::tokio_timer as timer;
-    use futures::Future;
+    use futures::{future, Future};
     use tokio::runtime::current_thread;
+    use tokio_executor::park::{Park, Unpark};
 
-    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
 
     /// A trait that allows an executor to execute a future for up to a given
     /// time limit, and then panics if the future has not finished.
@@ -371,4 +373,87 @@ pub mod test_util {
             }
         }
     }
+
+    /// A clock and timer that only advance when a test advances them, so that
+    /// timeouts may be tested without waiting in real time.
+    pub struct MockTime {
+        now: MockNow,
+        timer: timer::Timer<MockPark>,
+    }
+
+    #[derive(Clone)]
+    struct MockNow(Arc<Mutex<Instant>>);
+
+    /// Never blocks: time only passes when `MockTime::advance` is called.
+    struct MockPark;
+
+    struct MockUnpark;
+
+    /// Runs `f` in a task with a `MockTime` as the default clock and timer.
+    ///
+    /// Futures must be polled directly by `f`, since runtimes may not be
+    /// started from within the task.
+    pub fn mock_time<F, R>(f: F) -> R
+    where
+        F: FnOnce(&mut MockTime) -> R,
+    {
+        let now = MockNow(Arc::new(Mutex::new(Instant::now())));
+        let clock = timer::clock::Clock::new_with_now(now.clone());
+        let mut time = MockTime {
+            timer: timer::Timer::new_with_now(MockPark, clock.clone()),
+            now,
+        };
+        let handle = time.timer.handle();
+
+        let mut enter = tokio_executor::enter().expect("must not be in an executor");
+        timer::clock::with_default(&clock, &mut enter, |enter| {
+            timer::with_default(&handle, enter, |_| {
+                future::lazy(|| Ok::<_, ()>(f(&mut time)))
+                    .wait()
+                    .expect("lazy future cannot fail")
+            })
+        })
+    }
+
+    impl MockTime {
+        pub fn now(&self) -> Instant {
+            timer::clock::Now::now(&self.now)
+        }
+
+        /// Advances the clock by `duration`, firing every timer that it
+        /// elapses.
+        pub fn advance(&mut self, duration: Duration) {
+            *self.now.0.lock().expect("mock clock lock poisoned") += duration;
+            self.timer
+                .turn(Some(Duration::from_secs(0)))
+                .expect("mock park cannot fail");
+        }
+    }
+
+    impl timer::clock::Now for MockNow {
+        fn now(&self) -> Instant {
+            *self.0.lock().expect("mock clock lock poisoned")
+        }
+    }
+
+    impl Park for MockPark {
+        type Unpark = MockUnpark;
+        type Error = ();
+
+        fn unpark(&self) -> Self::Unpark {
+            MockUnpark
+        }
+
+        fn park(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn park_timeout(&mut self, _: Duration) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl Unpark for MockUnpark {
+        fn unpark(&self) {}
+    }
 }
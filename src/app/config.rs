@@ -75,10 +75,51 @@ pub struct Config {
     // TCP Keepalive set on outbound connections to the remote peers.
     pub outbound_connect_keepalive: Option<Duration>,
 
+    // The mark (`SO_MARK`) set on sockets connected to remote peers, e.g. for
+    // policy-based routing of the proxy's egress. Only supported on Linux.
+    pub outbound_socket_mark: Option<u32>,
+
+    // The maximum number of connections that may be open to each remote
+    // peer. Connections beyond it wait, up to the connect timeout.
+    pub outbound_max_connections_per_endpoint: Option<usize>,
+
+    // The maximum amount of time that a balancer waits for one of its
+    // endpoints to become ready before failing the pending request.
+    pub outbound_balancer_max_ready_wait: Option<Duration>,
+
+    // When set, the removal of a balanced endpoint is deferred by up to this
+    // long, in case its pod has moved to a new address.
+    pub outbound_balancer_move_window: Option<Duration>,
+
+    // When set, each balanced endpoint's weight and its share of its
+    // balancer's requests over this window are exported as metrics.
+    pub outbound_balancer_weight_metrics_window: Option<Duration>,
+
+    // When set, each balancer holds a stable subset of at most this many of
+    // its endpoints, selected for this client identifier.
+    pub outbound_balancer_subset: Option<(String, usize)>,
+
+    // Whether `TCP_NODELAY` is set on proxied connections, both accepted and
+    // established, disabling Nagle's algorithm.
+    pub tcp_nodelay: bool,
+
     pub inbound_ports_disable_protocol_detection: IndexSet<u16>,
 
     pub outbound_ports_disable_protocol_detection: IndexSet<u16>,
 
+    /// Inbound ports on which clients must be authenticated with mTLS. HTTP
+    /// requests from unauthenticated (non-loopback) clients fail with a 403,
+    /// and other connections are closed.
+    pub inbound_ports_require_identity: IndexSet<u16>,
+
+    /// When set, the inbound listener staples the DER-encoded OCSP response
+    /// read from this path to its certificate, re-reading it at this interval.
+    pub inbound_ocsp_response: Option<(PathBuf, Duration)>,
+
+    /// Ports of original destinations to which forwarded connections begin
+    /// with a PROXY protocol (v2) header describing the client.
+    pub outbound_ports_proxy_protocol: IndexSet<u16>,
+
     pub inbound_router_capacity: usize,
 
     pub outbound_router_capacity: usize,
@@ -91,6 +132,10 @@ pub struct Config {
 
     pub outbound_max_requests_in_flight: usize,
 
+    /// The locality (e.g. zone) of this proxy. When set, outbound load
+    /// balancers prefer endpoints in the same locality.
+    pub outbound_locality: Option<String>,
+
     /// Age after which metrics may be dropped.
     pub metrics_retain_idle: Duration,
 
@@ -145,6 +190,28 @@ pub struct Config {
 
     pub dns_canonicalize_timeout: Duration,
 
+    /// Search domains applied to relative names when canonicalizing, in
+    /// place of the system resolver's search path. Empty when unset.
+    pub dns_canonicalize_search: Vec<dns::Name>,
+
+    /// Whether failed search domains are cached, so that each refresh retries
+    /// the search domains in priority order.
+    pub dns_canonicalize_search_cache: bool,
+
+    /// The number of dots at or above which a relative name is tried as-is
+    /// before the search domains, if set.
+    pub dns_canonicalize_ndots: Option<usize>,
+
+    /// Suffixes under which canonical names must fall, if set.
+    pub dns_canonicalize_allowed_suffixes: Option<Vec<dns::Suffix>>,
+
+    /// The records queried when canonicalizing names.
+    pub dns_canonicalize_query_type: dns::QueryType,
+
+    /// The amount of time that a canonical name may be used past its TTL
+    /// while it is revalidated, if bounded.
+    pub dns_canonicalize_max_stale: Option<Duration>,
+
     pub h2_settings: H2Settings,
 }
 
@@ -181,6 +248,9 @@ pub enum ParseError {
     NameError,
     InvalidTokenSource,
     InvalidTrustAnchors,
+    NotATlsVersion,
+    NotABool,
+    NotADnsQueryType,
 }
 
 /// The strings used to build a configuration.
@@ -216,6 +286,37 @@ const ENV_OUTBOUND_ACCEPT_KEEPALIVE: &str = "LINKERD2_PROXY_OUTBOUND_ACCEPT_KEEP
 const ENV_INBOUND_CONNECT_KEEPALIVE: &str = "LINKERD2_PROXY_INBOUND_CONNECT_KEEPALIVE";
 const ENV_OUTBOUND_CONNECT_KEEPALIVE: &str = "LINKERD2_PROXY_OUTBOUND_CONNECT_KEEPALIVE";
 
+const ENV_OUTBOUND_SOCKET_MARK: &str = "LINKERD2_PROXY_OUTBOUND_SOCKET_MARK";
+
+const ENV_OUTBOUND_MAX_CONNECTIONS_PER_ENDPOINT: &str =
+    "LINKERD2_PROXY_OUTBOUND_MAX_CONNECTIONS_PER_ENDPOINT";
+
+const ENV_OUTBOUND_BALANCER_MAX_READY_WAIT: &str =
+    "LINKERD2_PROXY_OUTBOUND_BALANCER_MAX_READY_WAIT";
+
+const ENV_OUTBOUND_BALANCER_MOVE_WINDOW: &str = "LINKERD2_PROXY_OUTBOUND_BALANCER_MOVE_WINDOW";
+
+const ENV_OUTBOUND_BALANCER_WEIGHT_METRICS_WINDOW: &str =
+    "LINKERD2_PROXY_OUTBOUND_BALANCER_WEIGHT_METRICS_WINDOW";
+
+/// Limits each balancer to a subset of this many endpoints. Must be set with
+/// `ENV_OUTBOUND_BALANCER_SUBSET_CLIENT_ID`, which should be unique to each
+/// proxy (e.g. its pod's name) so that subsets are spread across endpoints.
+const ENV_OUTBOUND_BALANCER_SUBSET_SIZE: &str = "LINKERD2_PROXY_OUTBOUND_BALANCER_SUBSET_SIZE";
+const ENV_OUTBOUND_BALANCER_SUBSET_CLIENT_ID: &str =
+    "LINKERD2_PROXY_OUTBOUND_BALANCER_SUBSET_CLIENT_ID";
+
+/// A file containing a DER-encoded OCSP response for the proxy's certificate,
+/// which the inbound listener staples to its certificate. The file is re-read
+/// every `ENV_INBOUND_OCSP_REFRESH_INTERVAL`, so that the response may be
+/// replaced before it expires.
+const ENV_INBOUND_OCSP_RESPONSE_PATH: &str = "LINKERD2_PROXY_INBOUND_OCSP_RESPONSE_PATH";
+const ENV_INBOUND_OCSP_REFRESH_INTERVAL: &str = "LINKERD2_PROXY_INBOUND_OCSP_REFRESH_INTERVAL";
+
+/// When `false`, `TCP_NODELAY` is not set on proxied connections. Defaults to
+/// `true`.
+const ENV_TCP_NODELAY: &str = "LINKERD2_PROXY_TCP_NODELAY";
+
 pub const DEPRECATED_ENV_PRIVATE_LISTEN_ADDR: &str = "LINKERD2_PROXY_PRIVATE_LISTEN_ADDR";
 pub const DEPRECATED_ENV_PRIVATE_FORWARD: &str = "LINKERD2_PROXY_PRIVATE_FORWARD";
 
@@ -231,6 +332,10 @@ pub const ENV_OUTBOUND_ROUTER_MAX_IDLE_AGE: &str = "LINKERD2_PROXY_OUTBOUND_ROUT
 pub const ENV_INBOUND_MAX_IN_FLIGHT: &str = "LINKERD2_PROXY_INBOUND_MAX_IN_FLIGHT";
 pub const ENV_OUTBOUND_MAX_IN_FLIGHT: &str = "LINKERD2_PROXY_OUTBOUND_MAX_IN_FLIGHT";
 
+/// The locality (e.g. zone) of this proxy, compared against the `zone` label
+/// of endpoints discovered from the destination service.
+pub const ENV_OUTBOUND_LOCALITY: &str = "LINKERD2_PROXY_OUTBOUND_LOCALITY";
+
 /// Constrains which destination names are resolved through the destination
 /// service.
 ///
@@ -260,6 +365,9 @@ pub const ENV_INBOUND_PORTS_DISABLE_PROTOCOL_DETECTION: &str =
     "LINKERD2_PROXY_INBOUND_PORTS_DISABLE_PROTOCOL_DETECTION";
 pub const ENV_OUTBOUND_PORTS_DISABLE_PROTOCOL_DETECTION: &str =
     "LINKERD2_PROXY_OUTBOUND_PORTS_DISABLE_PROTOCOL_DETECTION";
+pub const ENV_OUTBOUND_PORTS_PROXY_PROTOCOL: &str = "LINKERD2_PROXY_OUTBOUND_PORTS_PROXY_PROTOCOL";
+pub const ENV_INBOUND_PORTS_REQUIRE_IDENTITY: &str =
+    "LINKERD2_PROXY_INBOUND_PORTS_REQUIRE_IDENTITY";
 
 pub const ENV_IDENTITY_DISABLED: &str = "LINKERD2_PROXY_IDENTITY_DISABLED";
 pub const ENV_IDENTITY_DIR: &str = "LINKERD2_PROXY_IDENTITY_DIR";
@@ -269,6 +377,11 @@ pub const ENV_IDENTITY_TOKEN_FILE: &str = "LINKERD2_PROXY_IDENTITY_TOKEN_FILE";
 pub const ENV_IDENTITY_MIN_REFRESH: &str = "LINKERD2_PROXY_IDENTITY_MIN_REFRESH";
 pub const ENV_IDENTITY_MAX_REFRESH: &str = "LINKERD2_PROXY_IDENTITY_MAX_REFRESH";
 
+/// Constrains the TLS protocol versions (e.g. `1.2,1.3`) offered by outbound
+/// connections and accepted by inbound connections, respectively.
+pub const ENV_IDENTITY_CLIENT_TLS_VERSIONS: &str = "LINKERD2_PROXY_IDENTITY_CLIENT_TLS_VERSIONS";
+pub const ENV_IDENTITY_SERVER_TLS_VERSIONS: &str = "LINKERD2_PROXY_IDENTITY_SERVER_TLS_VERSIONS";
+
 pub const ENV_IDENTITY_SVC_BASE: &str = "LINKERD2_PROXY_IDENTITY_SVC";
 
 pub const ENV_DESTINATION_SVC_BASE: &str = "LINKERD2_PROXY_DESTINATION_SVC";
@@ -297,6 +410,38 @@ const ENV_DNS_MAX_TTL: &str = "LINKERD2_PROXY_DNS_MAX_TTL";
 /// an uncanonicalized address.
 const ENV_DNS_CANONICALIZE_TIMEOUT: &str = "LINKERD2_PROXY_DNS_CANONICALIZE_TIMEOUT";
 
+/// A comma-separated list of search domains to try, in order, when
+/// canonicalizing a relative name. When unset, the system resolver's search
+/// path is used.
+const ENV_DNS_CANONICALIZE_SEARCH: &str = "LINKERD2_PROXY_DNS_CANONICALIZE_SEARCH";
+
+/// When `true`, the failures of each canonicalization search domain are cached
+/// and every refresh retries the search domains in priority order, so that a
+/// preferred search domain is used as soon as it resolves. Defaults to `false`.
+const ENV_DNS_CANONICALIZE_SEARCH_CACHE: &str = "LINKERD2_PROXY_DNS_CANONICALIZE_SEARCH_CACHE";
+
+/// The number of dots at or above which a relative name is canonicalized
+/// as-is before the search domains are tried, as with resolv.conf(5)'s
+/// `ndots` option. Defaults to 1.
+const ENV_DNS_CANONICALIZE_NDOTS: &str = "LINKERD2_PROXY_DNS_CANONICALIZE_NDOTS";
+
+/// A comma-separated list of suffixes under which canonical names must fall.
+/// Names canonicalized to other domains are ignored in favor of the original
+/// name. When unset, canonical names may have any suffix.
+const ENV_DNS_CANONICALIZE_ALLOWED_SUFFIXES: &str =
+    "LINKERD2_PROXY_DNS_CANONICALIZE_ALLOWED_SUFFIXES";
+
+/// The records queried when canonicalizing a name: `A`, `AAAA`, or `IP` (the
+/// default), which queries A and/or AAAA records according to the resolver's
+/// IP lookup strategy. `A` avoids AAAA queries where they are slow or dropped.
+const ENV_DNS_CANONICALIZE_QUERY_TYPE: &str = "LINKERD2_PROXY_DNS_CANONICALIZE_QUERY_TYPE";
+
+/// Bounds the amount of time that a canonical name is used past its TTL while
+/// it is revalidated. Once it elapses, the original name is used until the name
+/// is revalidated. By default, expired names are used until they are
+/// revalidated.
+const ENV_DNS_CANONICALIZE_MAX_STALE: &str = "LINKERD2_PROXY_DNS_CANONICALIZE_MAX_STALE";
+
 /// Configure the stream or connection level flow control setting for HTTP2.
 ///
 /// If unspecified, the default value of 65,535 is used.
@@ -346,6 +491,8 @@ const DEFAULT_OUTBOUND_ROUTER_MAX_IDLE_AGE: Duration = Duration::from_secs(60);
 const DEFAULT_INBOUND_MAX_IN_FLIGHT: usize = 10_000;
 const DEFAULT_OUTBOUND_MAX_IN_FLIGHT: usize = 10_000;
 
+const DEFAULT_INBOUND_OCSP_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 const DEFAULT_DESTINATION_BUFFER_CAPACITY: usize = 100;
 
 const DEFAULT_DESTINATION_GET_SUFFIXES: &str = "svc.cluster.local.";
@@ -405,6 +552,22 @@ impl Config {
             parse(strings, ENV_INBOUND_CONNECT_KEEPALIVE, parse_duration);
         let outbound_connect_keepalive =
             parse(strings, ENV_OUTBOUND_CONNECT_KEEPALIVE, parse_duration);
+        let outbound_socket_mark = parse(strings, ENV_OUTBOUND_SOCKET_MARK, parse_number);
+        let outbound_max_connections_per_endpoint = parse_max_connections_per_endpoint(strings);
+        let outbound_balancer_max_ready_wait = parse(
+            strings,
+            ENV_OUTBOUND_BALANCER_MAX_READY_WAIT,
+            parse_duration,
+        );
+        let outbound_balancer_move_window =
+            parse(strings, ENV_OUTBOUND_BALANCER_MOVE_WINDOW, parse_duration);
+        let outbound_balancer_weight_metrics_window = parse(
+            strings,
+            ENV_OUTBOUND_BALANCER_WEIGHT_METRICS_WINDOW,
+            parse_duration,
+        );
+        let outbound_balancer_subset = parse_balancer_subset(strings);
+        let tcp_nodelay = parse(strings, ENV_TCP_NODELAY, parse_bool);
 
         let inbound_disable_ports = parse(
             strings,
@@ -416,6 +579,14 @@ impl Config {
             ENV_OUTBOUND_PORTS_DISABLE_PROTOCOL_DETECTION,
             parse_port_set,
         );
+        let inbound_require_identity_ports =
+            parse(strings, ENV_INBOUND_PORTS_REQUIRE_IDENTITY, parse_port_set);
+        let inbound_ocsp_response = parse_inbound_ocsp_response(strings);
+        let outbound_proxy_protocol_ports = parse(
+            strings,
+            ENV_OUTBOUND_PORTS_PROXY_PROTOCOL,
+            parse_port_set,
+        );
 
         let inbound_router_capacity = parse(strings, ENV_INBOUND_ROUTER_CAPACITY, parse_number);
         let outbound_router_capacity = parse(strings, ENV_OUTBOUND_ROUTER_CAPACITY, parse_number);
@@ -428,6 +599,8 @@ impl Config {
         let inbound_max_in_flight = parse(strings, ENV_INBOUND_MAX_IN_FLIGHT, parse_number);
         let outbound_max_in_flight = parse(strings, ENV_OUTBOUND_MAX_IN_FLIGHT, parse_number);
 
+        let outbound_locality = strings.get(ENV_OUTBOUND_LOCALITY);
+
         let metrics_retain_idle = parse(strings, ENV_METRICS_RETAIN_IDLE, parse_duration);
 
         // DNS
@@ -439,6 +612,25 @@ impl Config {
 
         let dns_canonicalize_timeout = parse(strings, ENV_DNS_CANONICALIZE_TIMEOUT, parse_duration);
 
+        let dns_canonicalize_search = parse(strings, ENV_DNS_CANONICALIZE_SEARCH, parse_dns_names);
+
+        let dns_canonicalize_search_cache =
+            parse(strings, ENV_DNS_CANONICALIZE_SEARCH_CACHE, parse_bool);
+
+        let dns_canonicalize_ndots = parse(strings, ENV_DNS_CANONICALIZE_NDOTS, parse_number);
+
+        let dns_canonicalize_allowed_suffixes = parse(
+            strings,
+            ENV_DNS_CANONICALIZE_ALLOWED_SUFFIXES,
+            parse_dns_suffixes,
+        );
+
+        let dns_canonicalize_query_type =
+            parse(strings, ENV_DNS_CANONICALIZE_QUERY_TYPE, parse_dns_query_type);
+
+        let dns_canonicalize_max_stale =
+            parse(strings, ENV_DNS_CANONICALIZE_MAX_STALE, parse_duration);
+
         let identity_config = parse_identity_config(strings);
 
         let id_disabled = identity_config
@@ -514,11 +706,21 @@ impl Config {
 
             inbound_connect_keepalive: inbound_connect_keepalive?,
             outbound_connect_keepalive: outbound_connect_keepalive?,
+            outbound_socket_mark: outbound_socket_mark?,
+            outbound_max_connections_per_endpoint: outbound_max_connections_per_endpoint?,
+            outbound_balancer_max_ready_wait: outbound_balancer_max_ready_wait?,
+            outbound_balancer_move_window: outbound_balancer_move_window?,
+            outbound_balancer_weight_metrics_window: outbound_balancer_weight_metrics_window?,
+            outbound_balancer_subset: outbound_balancer_subset?,
+            tcp_nodelay: tcp_nodelay?.unwrap_or(true),
 
             inbound_ports_disable_protocol_detection: inbound_disable_ports?
                 .unwrap_or_else(|| default_disable_ports_protocol_detection()),
             outbound_ports_disable_protocol_detection: outbound_disable_ports?
                 .unwrap_or_else(|| default_disable_ports_protocol_detection()),
+            outbound_ports_proxy_protocol: outbound_proxy_protocol_ports?.unwrap_or_default(),
+            inbound_ports_require_identity: inbound_require_identity_ports?.unwrap_or_default(),
+            inbound_ocsp_response: inbound_ocsp_response?,
 
             inbound_router_capacity: inbound_router_capacity?
                 .unwrap_or(DEFAULT_INBOUND_ROUTER_CAPACITY),
@@ -535,6 +737,8 @@ impl Config {
             outbound_max_requests_in_flight: outbound_max_in_flight?
                 .unwrap_or(DEFAULT_OUTBOUND_MAX_IN_FLIGHT),
 
+            outbound_locality: outbound_locality?.filter(|l| !l.is_empty()),
+
             destination_buffer_capacity: DEFAULT_DESTINATION_BUFFER_CAPACITY,
 
             destination_get_suffixes: dst_get_suffixes?
@@ -563,6 +767,14 @@ impl Config {
             dns_canonicalize_timeout: dns_canonicalize_timeout?
                 .unwrap_or(DEFAULT_DNS_CANONICALIZE_TIMEOUT),
 
+            dns_canonicalize_search: dns_canonicalize_search?.unwrap_or_default(),
+
+            dns_canonicalize_search_cache: dns_canonicalize_search_cache?.unwrap_or(false),
+            dns_canonicalize_ndots: dns_canonicalize_ndots?,
+            dns_canonicalize_allowed_suffixes: dns_canonicalize_allowed_suffixes?,
+            dns_canonicalize_query_type: dns_canonicalize_query_type?.unwrap_or_default(),
+            dns_canonicalize_max_stale: dns_canonicalize_max_stale?,
+
             h2_settings: H2Settings {
                 initial_stream_window_size: initial_stream_window_size?,
                 initial_connection_window_size: initial_connection_window_size?,
@@ -636,6 +848,10 @@ where
     s.parse().map_err(|_| ParseError::NotANumber)
 }
 
+fn parse_bool(s: &str) -> Result<bool, ParseError> {
+    s.trim().parse().map_err(|_| ParseError::NotABool)
+}
+
 fn parse_duration(s: &str) -> Result<Duration, ParseError> {
     use regex::Regex;
 
@@ -743,6 +959,47 @@ fn parse_dns_suffixes(list: &str) -> Result<Vec<dns::Suffix>, ParseError> {
     Ok(suffixes)
 }
 
+fn parse_dns_names(list: &str) -> Result<Vec<dns::Name>, ParseError> {
+    let mut names = Vec::new();
+    for item in list.split(',') {
+        let item = item.trim();
+        if !item.is_empty() {
+            let name = dns::Name::try_from(item.as_bytes()).map_err(|_| ParseError::NameError)?;
+            names.push(name);
+        }
+    }
+
+    Ok(names)
+}
+
+fn parse_tls_versions(list: &str) -> Result<Vec<identity::TlsVersion>, ParseError> {
+    let mut versions = Vec::new();
+    for item in list.split(',') {
+        let version = match item.trim() {
+            "" => continue,
+            "1.2" => identity::TlsVersion::TLSv1_2,
+            "1.3" => identity::TlsVersion::TLSv1_3,
+            _ => return Err(ParseError::NotATlsVersion),
+        };
+        versions.push(version);
+    }
+
+    if versions.is_empty() {
+        return Err(ParseError::NotATlsVersion);
+    }
+
+    Ok(versions)
+}
+
+fn parse_dns_query_type(s: &str) -> Result<dns::QueryType, ParseError> {
+    match s.trim().to_ascii_uppercase().as_str() {
+        "IP" => Ok(dns::QueryType::Ip),
+        "A" => Ok(dns::QueryType::A),
+        "AAAA" => Ok(dns::QueryType::Aaaa),
+        _ => Err(ParseError::NotADnsQueryType),
+    }
+}
+
 fn parse_dns_suffix(s: &str) -> Result<dns::Suffix, ParseError> {
     if s == "." {
         return Ok(dns::Suffix::Root);
@@ -792,6 +1049,75 @@ pub fn parse_backoff<S: Strings>(
     }
 }
 
+fn parse_max_connections_per_endpoint<S: Strings>(strings: &S) -> Result<Option<usize>, Error> {
+    let max = parse(
+        strings,
+        ENV_OUTBOUND_MAX_CONNECTIONS_PER_ENDPOINT,
+        parse_number::<usize>,
+    )?;
+    if max == Some(0) {
+        error!(
+            "{} must be positive",
+            ENV_OUTBOUND_MAX_CONNECTIONS_PER_ENDPOINT
+        );
+        return Err(Error::InvalidEnvVar);
+    }
+    Ok(max)
+}
+
+fn parse_balancer_subset<S: Strings>(strings: &S) -> Result<Option<(String, usize)>, Error> {
+    let size = parse(strings, ENV_OUTBOUND_BALANCER_SUBSET_SIZE, parse_number::<usize>);
+    let client = strings.get(ENV_OUTBOUND_BALANCER_SUBSET_CLIENT_ID);
+
+    match (size?, client?) {
+        (None, None) => Ok(None),
+        (Some(size), Some(client)) => {
+            if size == 0 {
+                error!("{} must be positive", ENV_OUTBOUND_BALANCER_SUBSET_SIZE);
+                Err(Error::InvalidEnvVar)
+            } else if client.is_empty() {
+                error!("{} must not be empty", ENV_OUTBOUND_BALANCER_SUBSET_CLIENT_ID);
+                Err(Error::InvalidEnvVar)
+            } else {
+                Ok(Some((client, size)))
+            }
+        }
+        _ => {
+            error!(
+                "{} and {} must be set together",
+                ENV_OUTBOUND_BALANCER_SUBSET_SIZE, ENV_OUTBOUND_BALANCER_SUBSET_CLIENT_ID
+            );
+            Err(Error::InvalidEnvVar)
+        }
+    }
+}
+
+fn parse_inbound_ocsp_response<S: Strings>(
+    strings: &S,
+) -> Result<Option<(PathBuf, Duration)>, Error> {
+    let path = parse(strings, ENV_INBOUND_OCSP_RESPONSE_PATH, |s| Ok(PathBuf::from(s)));
+    let interval = parse(strings, ENV_INBOUND_OCSP_REFRESH_INTERVAL, parse_duration);
+
+    match (path?, interval?) {
+        (None, None) => Ok(None),
+        (None, Some(_)) => {
+            error!(
+                "{} must be set with {}",
+                ENV_INBOUND_OCSP_REFRESH_INTERVAL, ENV_INBOUND_OCSP_RESPONSE_PATH
+            );
+            Err(Error::InvalidEnvVar)
+        }
+        (Some(_), Some(interval)) if interval == Duration::from_secs(0) => {
+            error!("{} must be positive", ENV_INBOUND_OCSP_REFRESH_INTERVAL);
+            Err(Error::InvalidEnvVar)
+        }
+        (Some(path), interval) => Ok(Some((
+            path,
+            interval.unwrap_or(DEFAULT_INBOUND_OCSP_REFRESH_INTERVAL),
+        ))),
+    }
+}
+
 pub fn parse_control_addr<S: Strings>(
     strings: &S,
     base: &str,
@@ -845,6 +1171,10 @@ pub fn parse_identity_config<S: Strings>(strings: &S) -> Result<Option<identity:
     let li = parse(strings, ENV_IDENTITY_IDENTITY_LOCAL_NAME, parse_identity);
     let min_refresh = parse(strings, ENV_IDENTITY_MIN_REFRESH, parse_duration);
     let max_refresh = parse(strings, ENV_IDENTITY_MAX_REFRESH, parse_duration);
+    let client_tls_versions =
+        parse(strings, ENV_IDENTITY_CLIENT_TLS_VERSIONS, parse_tls_versions)?;
+    let server_tls_versions =
+        parse(strings, ENV_IDENTITY_SERVER_TLS_VERSIONS, parse_tls_versions)?;
 
     let disabled = strings
         .get(ENV_IDENTITY_DISABLED)?
@@ -918,6 +1248,15 @@ pub fn parse_identity_config<S: Strings>(strings: &S) -> Result<Option<identity:
                     })
             };
 
+            let trust_anchors = match client_tls_versions {
+                Some(versions) => trust_anchors.with_client_tls_versions(versions),
+                None => trust_anchors,
+            };
+            let trust_anchors = match server_tls_versions {
+                Some(versions) => trust_anchors.with_server_tls_versions(versions),
+                None => trust_anchors,
+            };
+
             Ok(Some(identity::Config {
                 svc,
                 local_name,
@@ -1082,4 +1421,80 @@ mod tests {
             "names are coerced to lowercase"
         );
     }
+
+    #[test]
+    fn tls_versions() {
+        use super::identity::TlsVersion;
+
+        assert_eq!(parse_tls_versions("1.2"), Ok(vec![TlsVersion::TLSv1_2]));
+        assert_eq!(
+            parse_tls_versions(" 1.3 , 1.2 "),
+            Ok(vec![TlsVersion::TLSv1_3, TlsVersion::TLSv1_2]),
+            "whitespace is ignored"
+        );
+        assert_eq!(
+            parse_tls_versions(""),
+            Err(ParseError::NotATlsVersion),
+            "at least one version is required"
+        );
+        assert_eq!(parse_tls_versions("1.1"), Err(ParseError::NotATlsVersion));
+        assert_eq!(parse_tls_versions("tls1.3"), Err(ParseError::NotATlsVersion));
+    }
+
+    #[test]
+    fn dns_query_types() {
+        assert_eq!(parse_dns_query_type("IP"), Ok(dns::QueryType::Ip));
+        assert_eq!(parse_dns_query_type(" a "), Ok(dns::QueryType::A));
+        assert_eq!(parse_dns_query_type("AAAA"), Ok(dns::QueryType::Aaaa));
+        assert_eq!(parse_dns_query_type("AA"), Err(ParseError::NotADnsQueryType));
+        assert_eq!(parse_dns_query_type(""), Err(ParseError::NotADnsQueryType));
+    }
+
+    #[test]
+    fn max_connections_per_endpoint_must_be_positive() {
+        let max = |value: Option<&str>| {
+            let mut env = TestEnv::new();
+            if let Some(value) = value {
+                env.put(ENV_OUTBOUND_MAX_CONNECTIONS_PER_ENDPOINT, value.to_owned());
+            }
+            parse_max_connections_per_endpoint(&env)
+        };
+
+        assert_eq!(max(None).ok(), Some(None));
+        assert_eq!(max(Some("10")).ok(), Some(Some(10)));
+        assert!(max(Some("0")).is_err(), "zero must be rejected");
+        assert!(max(Some("-1")).is_err());
+    }
+
+    #[test]
+    fn inbound_ocsp_refresh_interval_requires_a_path() {
+        let ocsp = |path: Option<&str>, interval: Option<&str>| {
+            let mut env = TestEnv::new();
+            if let Some(path) = path {
+                env.put(ENV_INBOUND_OCSP_RESPONSE_PATH, path.to_owned());
+            }
+            if let Some(interval) = interval {
+                env.put(ENV_INBOUND_OCSP_REFRESH_INTERVAL, interval.to_owned());
+            }
+            parse_inbound_ocsp_response(&env)
+        };
+
+        assert_eq!(ocsp(None, None).ok(), Some(None));
+        assert_eq!(
+            ocsp(Some("/var/run/ocsp.der"), None).ok(),
+            Some(Some((
+                PathBuf::from("/var/run/ocsp.der"),
+                DEFAULT_INBOUND_OCSP_REFRESH_INTERVAL
+            ))),
+        );
+        assert_eq!(
+            ocsp(Some("/var/run/ocsp.der"), Some("10m")).ok(),
+            Some(Some((
+                PathBuf::from("/var/run/ocsp.der"),
+                Duration::from_secs(600)
+            ))),
+        );
+        assert!(ocsp(None, Some("10m")).is_err());
+        assert!(ocsp(Some("/var/run/ocsp.der"), Some("0s")).is_err());
+    }
 }
@@ -113,6 +113,7 @@ fn map_err_to_5xx(e: Error) -> StatusCode {
     use app::outbound;
     use proxy::buffer;
     use proxy::http::router::error as router;
+    use proxy::require_identity::IdentityRequired;
     use tower::load_shed::error as shed;
 
     if let Some(ref c) = e.downcast_ref::<router::NoCapacity>() {
@@ -132,6 +133,9 @@ fn map_err_to_5xx(e: Error) -> StatusCode {
     {
         error!("{}", err);
         http::StatusCode::FORBIDDEN
+    } else if let Some(err) = e.downcast_ref::<IdentityRequired>() {
+        warn!("{}", err);
+        http::StatusCode::FORBIDDEN
     } else {
         // we probably should have handled this before?
         error!("unexpected error: {}", e);
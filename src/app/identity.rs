@@ -1,16 +1,26 @@
 use futures::{Async, Future, Poll};
 use futures_watch::{Store, Watch};
+use std::fmt;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio_timer::{clock, Delay};
 use tower_grpc::{self as grpc, generic::client::GrpcService, BoxBody};
 
 use api::identity as api;
+use metrics::{FmtMetric, FmtMetrics, Gauge};
 use never::Never;
 
-pub use identity::{Crt, CrtKey, Csr, InvalidName, Key, Name, TokenSource, TrustAnchors};
+pub use identity::{
+    Crt, CrtKey, Csr, InvalidName, Key, Name, TlsVersion, TokenSource, TrustAnchors,
+};
 use transport::tls;
 
+metrics! {
+    identity_cert_remaining_validity_seconds: Gauge {
+        "Seconds until the proxy's identity certificate expires"
+    }
+}
+
 /// Configures the Identity service and local identity.
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -34,6 +44,13 @@ pub struct Local {
     crt_key: Watch<Option<CrtKey>>,
 }
 
+/// Reports how long the local identity's current certificate remains valid.
+///
+/// The certificate is read as metrics are scraped, so the report reflects
+/// each certificate that the `Daemon` rotates in.
+#[derive(Clone, Debug)]
+pub struct CrtExpiry(Watch<Option<CrtKey>>);
+
 /// Produces a `Local` identity once a certificate is available.
 #[derive(Debug)]
 pub struct AwaitCrt(Option<Local>);
@@ -111,6 +128,11 @@ impl Local {
     pub fn await_crt(self) -> AwaitCrt {
         AwaitCrt(Some(self))
     }
+
+    /// Returns a metrics report of the current certificate's expiry.
+    pub fn crt_expiry(&self) -> CrtExpiry {
+        CrtExpiry(self.crt_key.clone())
+    }
 }
 
 impl tls::client::HasConfig for Local {
@@ -251,6 +273,25 @@ where
     }
 }
 
+// === impl CrtExpiry ===
+
+impl FmtMetrics for CrtExpiry {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let expiry = match *self.0.borrow() {
+            Some(ref crt_key) => crt_key.expiry(),
+            None => return Ok(()),
+        };
+        // An expired certificate has no remaining validity.
+        let remaining = expiry
+            .duration_since(SystemTime::now())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        identity_cert_remaining_validity_seconds.fmt_help(f)?;
+        identity_cert_remaining_validity_seconds.fmt_metric(f, Gauge::from(remaining))
+    }
+}
+
 // === impl AwaitCrt ===
 
 impl Future for AwaitCrt {
@@ -277,3 +318,47 @@ impl Future for AwaitCrt {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use identity::test_util::*;
+
+    /// Returns the remaining validity that `expiry` reports, if any.
+    fn remaining(expiry: &CrtExpiry) -> Option<u64> {
+        let out = expiry.as_display().to_string();
+        out.lines()
+            .find(|l| l.starts_with("identity_cert_remaining_validity_seconds "))
+            .map(|l| {
+                let secs = l.rsplit(' ').next().expect("metric must have a value");
+                secs.parse().expect("value must be a number")
+            })
+    }
+
+    #[test]
+    fn reports_the_remaining_validity_of_the_rotated_certificate() {
+        const HOUR: u64 = 60 * 60;
+
+        let (watch, mut store) = Watch::new(None);
+        let expiry = CrtExpiry(watch);
+        assert_eq!(remaining(&expiry), None, "nothing is reported until certified");
+
+        let crt_key = FOO_NS1
+            .validate_until(SystemTime::now() + Duration::from_secs(HOUR))
+            .expect("foo.ns1 must be valid");
+        store.store(Some(crt_key)).expect("must store");
+        let secs = remaining(&expiry).expect("must report remaining validity");
+        assert!(secs > HOUR - 60 && secs <= HOUR, "unexpected validity: {}s", secs);
+
+        let crt_key = FOO_NS1
+            .validate_until(SystemTime::now() + Duration::from_secs(24 * HOUR))
+            .expect("foo.ns1 must be valid");
+        store.store(Some(crt_key)).expect("must store");
+        let secs = remaining(&expiry).expect("must report remaining validity");
+        assert!(
+            secs > 24 * HOUR - 60 && secs <= 24 * HOUR,
+            "rotated validity must be reported: {}s",
+            secs
+        );
+    }
+}
@@ -10,7 +10,7 @@ use super::identity;
 use proxy::http::{router, settings};
 use proxy::server::Source;
 use tap;
-use transport::{connect, tls};
+use transport::{connect, proxy_protocol::ProxyHeader, tls};
 use {Conditional, NameAddr};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -39,6 +39,13 @@ impl From<SocketAddr> for Endpoint {
     }
 }
 
+/// Forwarded connections are established to the original destination.
+impl From<ProxyHeader> for Endpoint {
+    fn from(forwarded: ProxyHeader) -> Self {
+        Self::from(forwarded.destination)
+    }
+}
+
 impl connect::HasPeerAddr for Endpoint {
     fn peer_addr(&self) -> SocketAddr {
         self.addr
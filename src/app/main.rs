@@ -4,7 +4,7 @@ use hyper;
 use std::net::SocketAddr;
 use std::thread;
 use std::time::{Duration, Instant, SystemTime};
-use std::{error, fmt, io};
+use std::{error, fmt, fs, io};
 use tokio::executor::{self, DefaultExecutor, Executor};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::runtime::current_thread;
@@ -25,14 +25,17 @@ use proxy::{
         client, insert, metrics as http_metrics, normalize_uri, profiles, router, settings,
         strip_header,
     },
-    reconnect,
+    reconnect, require_identity,
 };
 use svc::{self, LayerExt};
 use tap;
 use task;
 use telemetry;
 use trace;
-use transport::{self, connect, keepalive, tls, Connection, GetOriginalDst, Listen};
+use transport::{
+    self, connect, connections, keepalive, proxy_protocol, tls, Connection, GetOriginalDst,
+    Listen,
+};
 use {Addr, Conditional};
 
 use super::admin::{Admin, Readiness};
@@ -117,14 +120,16 @@ where
         )
         .expect("outbound listener bind")
         .with_original_dst(get_original_dst.clone())
-        .without_protocol_detection_for(config.outbound_ports_disable_protocol_detection.clone());
+        .without_protocol_detection_for(config.outbound_ports_disable_protocol_detection.clone())
+        .with_nodelay(config.tcp_nodelay);
 
         let inbound_listener = Listen::bind(config.inbound_listener.addr, local_identity)
             .expect("inbound listener bind")
             .with_original_dst(get_original_dst.clone())
             .without_protocol_detection_for(
                 config.inbound_ports_disable_protocol_detection.clone(),
-            );
+            )
+            .with_nodelay(config.tcp_nodelay);
 
         let runtime = runtime.into();
 
@@ -265,14 +270,41 @@ where
 
         let (transport_metrics, transport_report) = transport::metrics::new();
 
+        let balancer_in_flight = proxy::http::balance::InFlight::default();
+        let balancer_updates = proxy::resolve::UpdateMetrics::default();
+        let balancer_weights = config
+            .outbound_balancer_weight_metrics_window
+            .map(proxy::weight::Metrics::new);
+        let canonicalize_churn = proxy::http::canonicalize::Churn::default();
+        let canonicalize_ttls = proxy::http::canonicalize::Ttls::default();
+
+        let identity_report = identity.value().map(|&(ref local, _)| local.crt_expiry());
+
         let report = endpoint_http_report
             .and_then(route_http_report)
             .and_then(retry_http_report)
             .and_then(transport_report)
             //.and_then(tls_config_report)
             .and_then(ctl_http_report)
+            .and_then(balancer_in_flight.clone())
+            .and_then(balancer_updates.clone())
+            .and_then(balancer_weights.clone())
+            .and_then(canonicalize_churn.clone())
+            .and_then(canonicalize_ttls.clone())
+            .and_then(identity_report)
             .and_then(telemetry::process::Report::new(start_time));
 
+        // Sockets connected to the local application and to local control
+        // plane services.
+        let inbound_socket = connect::SocketOptions::default().with_nodelay(config.tcp_nodelay);
+
+        // Sockets connected to remote peers (including remote control plane
+        // services) are marked, if configured.
+        let outbound_socket = match config.outbound_socket_mark {
+            Some(mark) => inbound_socket.with_mark(mark),
+            None => inbound_socket,
+        };
+
         let mut identity_daemon = None;
         let (readiness, ready_latch) = Readiness::new();
         let local_identity = match identity {
@@ -290,10 +322,10 @@ where
 
                 // If the service is on localhost, use the inbound keepalive.
                 // If the service. is remote, use the outbound keepalive.
-                let keepalive = if id_config.svc.addr.is_loopback() {
-                    config.inbound_connect_keepalive
+                let (keepalive, socket) = if id_config.svc.addr.is_loopback() {
+                    (config.inbound_connect_keepalive, inbound_socket)
                 } else {
-                    config.outbound_connect_keepalive
+                    (config.outbound_connect_keepalive, outbound_socket)
                 };
 
                 let svc = svc::builder()
@@ -314,7 +346,7 @@ where
                     .layer(tls::client::layer(Conditional::Some(
                         id_config.trust_anchors.clone(),
                     )))
-                    .service(connect::svc())
+                    .service(connect::svc_with_options(socket))
                     .make(id_config.svc.clone());
 
                 identity_daemon = Some(identity::Daemon::new(id_config, crt_store, svc));
@@ -342,10 +374,10 @@ where
 
             // If the dst_svc is on localhost, use the inbound keepalive.
             // If the dst_svc is remote, use the outbound keepalive.
-            let keepalive = if addr.addr.is_loopback() {
-                config.inbound_connect_keepalive
+            let (keepalive, socket) = if addr.addr.is_loopback() {
+                (config.inbound_connect_keepalive, inbound_socket)
             } else {
-                config.outbound_connect_keepalive
+                (config.outbound_connect_keepalive, outbound_socket)
             };
 
             svc::builder()
@@ -364,7 +396,7 @@ where
                 .timeout(config.control_connect_timeout)
                 .layer(keepalive::connect::layer(keepalive))
                 .layer(tls::client::layer(local_identity.clone()))
-                .service(connect::svc())
+                .service(connect::svc_with_options(socket))
                 .make(addr.clone())
         });
 
@@ -440,7 +472,7 @@ where
             };
             use proxy::{
                 http::{balance, canonicalize, fallback, header_from_target, metrics, retry},
-                resolve,
+                locality, resolve, weight,
             };
 
             let profiles_client = profiles_client.clone();
@@ -451,16 +483,41 @@ where
             let route_http_metrics = route_http_metrics.clone();
             let profile_suffixes = config.destination_profile_suffixes.clone();
             let canonicalize_timeout = config.dns_canonicalize_timeout;
+            let canonicalize_search = config.dns_canonicalize_search.clone();
+            let canonicalize_search_cache = config.dns_canonicalize_search_cache;
+            let canonicalize_ndots = config.dns_canonicalize_ndots;
+            let canonicalize_allowed_suffixes = config.dns_canonicalize_allowed_suffixes.clone();
+            let canonicalize_query_type = config.dns_canonicalize_query_type;
+            let canonicalize_max_stale = config.dns_canonicalize_max_stale;
+            let canonicalize_churn = canonicalize_churn.clone();
+            let canonicalize_ttls = canonicalize_ttls.clone();
             let dispatch_timeout = config.outbound_dispatch_timeout;
+            let outbound_locality = config.outbound_locality.clone();
+            let balancer_in_flight = balancer_in_flight.clone();
+            let balancer_updates = balancer_updates.clone();
+            let balancer_weights = balancer_weights.clone();
+
+            // Limits the connections open to each remote peer, if
+            // configured.
+            let outbound_connections = match config.outbound_max_connections_per_endpoint {
+                Some(max) => connections::layer().with_max_per_endpoint(max),
+                None => connections::layer(),
+            };
 
             // Establishes connections to remote peers (for both TCP
-            // forwarding and HTTP proxying).
+            // forwarding and HTTP proxying). PROXY headers describing
+            // forwarded connections are written before any TLS handshake.
             let connect = svc::builder()
                 .layer(transport_metrics.connect("outbound"))
                 .timeout(config.outbound_connect_timeout)
+                .layer(outbound_connections)
                 .layer(keepalive::connect::layer(config.outbound_connect_keepalive))
                 .layer(tls::client::layer(local_identity.clone()))
-                .service(connect::svc());
+                .layer(
+                    proxy_protocol::layer()
+                        .with_ports(config.outbound_ports_proxy_protocol.clone()),
+                )
+                .service(connect::svc_with_options(outbound_socket));
 
             // Instantiates an HTTP client for for a `client::Config`
             let client_stack = svc::builder()
@@ -521,6 +578,7 @@ where
             // If the `l5d-require-id` header is present, then that identity is
             // used as the server name when connecting to the endpoint.
             let orig_dst_router = svc::builder()
+                .layer(balancer_in_flight.layer())
                 .layer(router::layer(
                     router::Config::new("out ep", capacity, max_idle_age),
                     |req: &http::Request<_>| {
@@ -531,11 +589,41 @@ where
                 ))
                 .buffer_pending(max_in_flight, DispatchDeadline::extract);
 
+            let balancer_layer = balance::layer(
+                EWMA_DEFAULT_RTT,
+                EWMA_DECAY,
+                balance::PendingUntilFirstData::default(),
+            )
+            .with_in_flight(balancer_in_flight);
+            // Bounds the time that requests wait for an endpoint to become
+            // ready, if configured.
+            let balancer_layer = match config.outbound_balancer_max_ready_wait {
+                Some(wait) => balancer_layer.with_max_ready_wait(wait),
+                None => balancer_layer,
+            };
+
+            // Limits each balancer to a stable subset of its endpoints, if
+            // configured.
+            let resolve_layer =
+                resolve::layer(Resolve::new(resolver)).with_recorder(balancer_updates);
+            let resolve_layer = match config.outbound_balancer_subset {
+                Some((ref client, size)) => resolve_layer.with_subset(client, size),
+                None => resolve_layer,
+            };
+            // Keeps endpoints whose pods have moved to a new address until
+            // they have been replaced, if configured.
+            let resolve_layer = match config.outbound_balancer_move_window {
+                Some(window) => resolve_layer.with_move_window(window),
+                None => resolve_layer,
+            };
+
             // Resolves the target via the control plane and balances requests
-            // over all endpoints returned from the destination service.
+            // over the endpoints returned from the destination service.
             let balancer = svc::builder()
-                .layer(balance::layer(EWMA_DEFAULT_RTT, EWMA_DECAY))
-                .layer(resolve::layer(Resolve::new(resolver)))
+                .layer(balancer_layer)
+                .layer(resolve_layer)
+                .layer(weight::layer(balancer_weights))
+                .layer(locality::layer(outbound_locality))
                 .spawn_ready();
 
             let distributor = svc::builder()
@@ -543,8 +631,13 @@ where
                     // Attempt to build a balancer. If the service is
                     // unresolvable, fall back to using a router that dispatches
                     // request to the application-selected original destination.
+                    // Requests are also routed to the original destination
+                    // while the balancer is unavailable, e.g. because all of
+                    // its endpoints have been removed. That router is only
+                    // built once the balancer first becomes unavailable.
                     fallback::layer(balancer, orig_dst_router)
-                        .on_error::<control::destination::Unresolvable>(),
+                        .on_error::<control::destination::Unresolvable>()
+                        .on_unavailable(),
                 )
                 .service(endpoint_stack);
 
@@ -588,8 +681,26 @@ where
             // Canonicalizes the request-specified `Addr` via DNS, and
             // annotates each request with a refined `Addr` so that it may be
             // routed by the dst_router.
+            let canonicalize = canonicalize::layer(dns_resolver, canonicalize_timeout)
+                .with_search(canonicalize_search)
+                .with_search_cache(canonicalize_search_cache)
+                .with_query_type(canonicalize_query_type)
+                .with_churn(canonicalize_churn)
+                .with_ttls(canonicalize_ttls);
+            let canonicalize = match canonicalize_allowed_suffixes {
+                Some(suffixes) => canonicalize.with_allowed_suffixes(suffixes),
+                None => canonicalize,
+            };
+            let canonicalize = match canonicalize_max_stale {
+                Some(max_stale) => canonicalize.with_max_stale(max_stale),
+                None => canonicalize,
+            };
+            let canonicalize = match canonicalize_ndots {
+                Some(ndots) => canonicalize.with_ndots(ndots),
+                None => canonicalize,
+            };
             let addr_stack = svc::builder()
-                .layer(canonicalize::layer(dns_resolver, canonicalize_timeout))
+                .layer(canonicalize)
                 .service(svc::shared(dst_router));
 
             // Routes requests to an `Addr`:
@@ -663,6 +774,7 @@ where
                 accept,
                 connect,
                 server_stack,
+                require_identity::Policy::default(),
                 config.h2_settings,
                 drain_rx.clone(),
             )
@@ -670,6 +782,22 @@ where
         };
         task::spawn(outbound);
 
+        // The OCSP response is re-read in the background, so that it may be
+        // replaced before it expires.
+        let inbound_listener = match config.inbound_ocsp_response.clone() {
+            Some((path, interval)) => {
+                info!("stapling the OCSP response in {}", path.display());
+                let staple = tls::OcspStaple::default();
+                task::spawn(
+                    staple
+                        .clone()
+                        .refresh_every(interval, move || future::result(fs::read(&path))),
+                );
+                inbound_listener.with_ocsp_staple(staple)
+            }
+            None => inbound_listener,
+        };
+
         let inbound = {
             use super::inbound::{
                 orig_proto_downgrade,
@@ -684,6 +812,8 @@ where
             let profile_suffixes = config.destination_profile_suffixes;
             let default_fwd_addr = config.inbound_forward.map(|a| a.into());
             let dispatch_timeout = config.inbound_dispatch_timeout;
+            let require_identity =
+                require_identity::Policy::new(config.inbound_ports_require_identity.clone());
 
             // Establishes connections to the local application (for both
             // TCP forwarding and HTTP proxying).
@@ -693,7 +823,7 @@ where
                 .timeout(config.inbound_connect_timeout)
                 .layer(keepalive::connect::layer(config.inbound_connect_keepalive))
                 .layer(tls::client::layer(local_identity))
-                .service(connect::svc());
+                .service(connect::svc_with_options(inbound_socket));
 
             // Instantiates an HTTP client for a `client::Config`
             let client_stack = svc::builder()
@@ -819,6 +949,7 @@ where
             // the router need not detect whether a request _will be_ downgraded.
             let source_stack = svc::builder()
                 .layer(super::errors::layer())
+                .layer(require_identity::layer(require_identity.clone()))
                 .layer(insert::layer(move || {
                     DispatchDeadline::after(dispatch_timeout)
                 }))
@@ -844,6 +975,7 @@ where
                 accept,
                 connect,
                 source_stack,
+                require_identity,
                 config.h2_settings,
                 drain_rx.clone(),
             )
@@ -861,6 +993,7 @@ fn serve<A, T, C, R, B, G>(
     accept: A,
     connect: C,
     router: R,
+    require_identity: require_identity::Policy,
     h2_settings: H2Settings,
     drain_rx: drain::Watch,
 ) -> impl Future<Item = (), Error = io::Error> + Send + 'static
@@ -868,7 +1001,7 @@ where
     A: proxy::Accept<Connection> + Send + 'static,
     A::Io: transport::Peek + fmt::Debug + Send + 'static,
 
-    T: From<SocketAddr> + Send + 'static,
+    T: From<proxy_protocol::ProxyHeader> + Send + 'static,
 
     C: svc::Service<T> + Send + Clone + 'static,
     C::Response: AsyncRead + AsyncWrite + fmt::Debug + Send + 'static,
@@ -899,20 +1032,23 @@ where
         connect,
         router,
         drain_rx.clone(),
-    );
+    )
+    .with_require_identity(require_identity);
     let log = server.log().clone();
 
     let future = log.future(
-        bound_port.listen_and_fold((), move |(), (connection, remote)| {
-            let s = server.serve(connection, remote, h2_settings);
-            // TODO: use trace spans for log contexts.
-            // .instrument(info_span!("conn", %remote));
-            // Logging context is configured by the server.
-            let r = DefaultExecutor::current()
-                .spawn(Box::new(s))
-                .map_err(task::Error::into_io);
-            future::result(r)
-        }),
+        bound_port
+            .with_drain(drain_rx.clone())
+            .listen_and_fold((), move |(), (connection, remote)| {
+                let s = server.serve(connection, remote, h2_settings);
+                // TODO: use trace spans for log contexts.
+                // .instrument(info_span!("conn", %remote));
+                // Logging context is configured by the server.
+                let r = DefaultExecutor::current()
+                    .spawn(Box::new(s))
+                    .map_err(task::Error::into_io);
+                future::result(r)
+            }),
     );
     // TODO: use trace spans for log contexts.
     // .instrument(info_span!("proxy", server = %proxy_name, local = %listen_addr));
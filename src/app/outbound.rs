@@ -8,11 +8,23 @@ use control::destination::{Metadata, ProtocolHint};
 use proxy::{
     self,
     http::{identity_from_header, settings},
+    locality, resolve, weight,
 };
 use tap;
-use transport::{connect, tls};
+use transport::{
+    connect,
+    proxy_protocol::{HasProxyHeader, ProxyHeader},
+    tls,
+};
 use {Conditional, NameAddr};
 
+/// The destination service label that describes an endpoint's locality.
+const LOCALITY_LABEL: &str = "zone";
+
+/// The destination service label that names an endpoint's pod, which it keeps
+/// if it moves to a new address.
+const POD_LABEL: &str = "pod";
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Endpoint {
     pub dst_name: Option<NameAddr>,
@@ -20,6 +32,8 @@ pub struct Endpoint {
     pub identity: tls::PeerIdentity,
     pub metadata: Metadata,
     pub http_settings: settings::Settings,
+    /// Describes the connection being forwarded to the endpoint, if any.
+    pub proxy_header: Option<ProxyHeader>,
 }
 
 // === impl Endpoint ===
@@ -66,6 +80,7 @@ impl Endpoint {
             identity,
             metadata: Metadata::empty(),
             http_settings,
+            proxy_header: None,
         })
     }
 }
@@ -78,6 +93,16 @@ impl From<SocketAddr> for Endpoint {
             identity: Conditional::None(tls::ReasonForNoPeerName::NotHttp.into()),
             metadata: Metadata::empty(),
             http_settings: settings::Settings::NotHttp,
+            proxy_header: None,
+        }
+    }
+}
+
+impl From<ProxyHeader> for Endpoint {
+    fn from(forwarded: ProxyHeader) -> Self {
+        Self {
+            proxy_header: Some(forwarded),
+            ..Self::from(forwarded.destination)
         }
     }
 }
@@ -94,7 +119,33 @@ impl hash::Hash for Endpoint {
         self.addr.hash(state);
         self.identity.hash(state);
         self.http_settings.hash(state);
-        // Ignore metadata.
+        // Ignore metadata and the PROXY header.
+    }
+}
+
+impl locality::HasLocality for Endpoint {
+    fn locality(&self) -> Option<&str> {
+        self.metadata.labels().get(LOCALITY_LABEL).map(String::as_str)
+    }
+}
+
+impl resolve::HasMoveKey for Endpoint {
+    fn move_key(&self) -> Option<&str> {
+        self.metadata.labels().get(POD_LABEL).map(String::as_str)
+    }
+}
+
+impl weight::HasWeight for Endpoint {
+    fn dst_name(&self) -> Option<&NameAddr> {
+        self.dst_name.as_ref()
+    }
+
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn weight(&self) -> u32 {
+        self.metadata.weight()
     }
 }
 
@@ -110,6 +161,12 @@ impl connect::HasPeerAddr for Endpoint {
     }
 }
 
+impl HasProxyHeader for Endpoint {
+    fn proxy_header(&self) -> Option<ProxyHeader> {
+        self.proxy_header
+    }
+}
+
 impl settings::HasSettings for Endpoint {
     fn http_settings(&self) -> &settings::Settings {
         &self.http_settings
@@ -270,6 +327,7 @@ pub mod discovery {
                             identity,
                             metadata,
                             http_settings: self.http_settings,
+                            proxy_header: None,
                         };
                         Ok(Async::Ready(resolve::Update::Add(addr, ep)))
                     }
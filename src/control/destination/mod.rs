@@ -156,6 +156,11 @@ impl Metadata {
         &self.labels
     }
 
+    /// Returns the endpoint's relative weight, where 10,000 corresponds to 1.0.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
     pub fn protocol_hint(&self) -> ProtocolHint {
         self.protocol_hint
     }
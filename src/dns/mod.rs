@@ -3,7 +3,9 @@ extern crate untrusted;
 extern crate webpki;
 
 use self::trust_dns_resolver::{
-    config::ResolverConfig, system_conf, AsyncResolver, BackgroundLookupIp,
+    config::ResolverConfig,
+    proto::{op::Query, rr::RecordType},
+    system_conf, AsyncResolver, BackgroundLookupIp,
 };
 use convert::TryFrom;
 use futures::prelude::*;
@@ -33,7 +35,11 @@ pub enum Error {
 
 pub struct IpAddrFuture(::logging::ContextualFuture<Ctx, BackgroundLookupIp>);
 
-pub struct RefineFuture(::logging::ContextualFuture<Ctx, BackgroundLookupIp>);
+pub struct RefineFuture(
+    ::logging::ContextualFuture<Ctx, Box<dyn Future<Item = Refine, Error = ResolveError> + Send>>,
+);
+
+pub struct IpAddrsFuture(::logging::ContextualFuture<Ctx, BackgroundLookupIp>);
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Suffix {
@@ -41,6 +47,18 @@ pub enum Suffix {
     Name(Name),
 }
 
+/// The records that are queried to refine a name.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueryType {
+    /// Queries A and/or AAAA records, according to the resolver's IP lookup
+    /// strategy.
+    Ip,
+    /// Queries only A records.
+    A,
+    /// Queries only AAAA records.
+    Aaaa,
+}
+
 struct Ctx(Name);
 
 pub struct Refine {
@@ -48,6 +66,12 @@ pub struct Refine {
     pub valid_until: Instant,
 }
 
+/// All of the addresses to which a name resolved.
+pub struct IpAddrs {
+    pub addrs: Vec<net::IpAddr>,
+    pub valid_until: Instant,
+}
+
 impl fmt::Display for Ctx {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         write!(f, "dns={}", self.0)
@@ -149,9 +173,44 @@ impl Resolver {
     /// For example, a name like `web` may be refined to `web.example.com.`,
     /// depending on the DNS search path.
     pub fn refine(&self, name: &Name) -> RefineFuture {
-        let f = self.resolver.lookup_ip(name.as_ref());
+        self.refine_with(name, QueryType::Ip)
+    }
+
+    /// Attempts to refine `name` to a fully-qualified name by querying only
+    /// `query_type` records.
+    ///
+    /// For example, A-only queries avoid the latency of AAAA queries in
+    /// environments where they are slow or dropped.
+    pub fn refine_with(&self, name: &Name, query_type: QueryType) -> RefineFuture {
+        let f: Box<dyn Future<Item = Refine, Error = ResolveError> + Send> = match query_type {
+            QueryType::Ip => Box::new(
+                self.resolver
+                    .lookup_ip(name.as_ref())
+                    .map(|lookup| Refine::new(lookup.query(), lookup.valid_until())),
+            ),
+            QueryType::A => Box::new(
+                self.resolver
+                    .lookup(name.as_ref(), RecordType::A)
+                    .map(|lookup| Refine::new(lookup.query(), lookup.valid_until())),
+            ),
+            QueryType::Aaaa => Box::new(
+                self.resolver
+                    .lookup(name.as_ref(), RecordType::AAAA)
+                    .map(|lookup| Refine::new(lookup.query(), lookup.valid_until())),
+            ),
+        };
         RefineFuture(::logging::context_future(Ctx(name.clone()), f))
     }
+
+    /// Resolves `name` to all of its addresses.
+    ///
+    /// Unlike `resolve_one_ip`, the time until which the addresses are valid
+    /// (i.e. as determined by the records' TTLs) is returned, so that callers
+    /// may re-resolve the name when it expires.
+    pub fn resolve_all_ips(&self, name: &Name) -> IpAddrsFuture {
+        let f = self.resolver.lookup_ip(name.as_ref());
+        IpAddrsFuture(::logging::context_future(Ctx(name.clone()), f))
+    }
 }
 
 /// Note: `AsyncResolver` does not implement `Debug`, so we must manually
@@ -182,15 +241,49 @@ impl Future for RefineFuture {
     type Error = ResolveError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let lookup = try_ready!(self.0.poll());
-        let valid_until = lookup.valid_until();
+        self.0.poll()
+    }
+}
+
+/// Returns the error with which a resolver fails a query that has no records,
+/// with the query's negative TTL, if it has one.
+#[cfg(test)]
+pub fn no_records_found(valid_until: Option<Instant>) -> ResolveError {
+    ResolveErrorKind::NoRecordsFound {
+        query: Query::new(),
+        valid_until,
+    }
+    .into()
+}
+
+// === impl QueryType ===
+
+impl Default for QueryType {
+    fn default() -> Self {
+        QueryType::Ip
+    }
+}
+
+// === impl Refine ===
 
-        let n = lookup.query().name();
-        let name = Name::try_from(n.to_ascii().as_bytes())
+impl Refine {
+    fn new(query: &Query, valid_until: Instant) -> Self {
+        let name = Name::try_from(query.name().to_ascii().as_bytes())
             .expect("Name returned from resolver must be valid");
+        Refine { name, valid_until }
+    }
+}
 
-        let refine = Refine { name, valid_until };
-        Ok(Async::Ready(refine))
+impl Future for IpAddrsFuture {
+    type Item = IpAddrs;
+    type Error = ResolveError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let lookup = try_ready!(self.0.poll());
+        Ok(Async::Ready(IpAddrs {
+            addrs: lookup.iter().collect(),
+            valid_until: lookup.valid_until(),
+        }))
     }
 }
 
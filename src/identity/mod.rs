@@ -11,6 +11,7 @@ use std::time::SystemTime;
 use std::{fmt, fs, io};
 
 pub use self::ring::error::KeyRejected;
+pub use self::rustls::ProtocolVersion as TlsVersion;
 
 use convert::TryFrom;
 use dns;
@@ -41,7 +42,10 @@ struct SigningKey(Arc<EcdsaKeyPair>);
 struct Signer(Arc<EcdsaKeyPair>);
 
 #[derive(Clone)]
-pub struct TrustAnchors(Arc<rustls::ClientConfig>);
+pub struct TrustAnchors {
+    client: Arc<rustls::ClientConfig>,
+    server_versions: Vec<TlsVersion>,
+}
 
 #[derive(Clone, Debug)]
 pub struct TokenSource(Arc<String>);
@@ -73,7 +77,8 @@ const SIGNATURE_ALG_RUSTLS_SCHEME: rustls::SignatureScheme =
     rustls::SignatureScheme::ECDSA_NISTP256_SHA256;
 const SIGNATURE_ALG_RUSTLS_ALGORITHM: rustls::internal::msgs::enums::SignatureAlgorithm =
     rustls::internal::msgs::enums::SignatureAlgorithm::ECDSA;
-const TLS_VERSIONS: &[rustls::ProtocolVersion] = &[rustls::ProtocolVersion::TLSv1_2];
+/// The TLS protocol versions accepted by servers unless configured otherwise.
+const DEFAULT_SERVER_TLS_VERSIONS: &[TlsVersion] = &[TlsVersion::TLSv1_2];
 
 // === impl Csr ===
 
@@ -195,7 +200,10 @@ impl TokenSource {
 impl TrustAnchors {
     #[cfg(test)]
     fn empty() -> Self {
-        TrustAnchors(Arc::new(rustls::ClientConfig::new()))
+        TrustAnchors {
+            client: Arc::new(rustls::ClientConfig::new()),
+            server_versions: DEFAULT_SERVER_TLS_VERSIONS.to_vec(),
+        }
     }
 
     pub fn from_pem(s: &str) -> Option<Self> {
@@ -223,11 +231,36 @@ impl TrustAnchors {
         // more tested.
         c.enable_tickets = false;
 
-        Some(TrustAnchors(Arc::new(c)))
+        Some(TrustAnchors {
+            client: Arc::new(c),
+            server_versions: DEFAULT_SERVER_TLS_VERSIONS.to_vec(),
+        })
+    }
+
+    /// Restricts the TLS protocol versions that clients will offer.
+    ///
+    /// By default, clients offer all versions supported by rustls.
+    pub fn with_client_tls_versions(self, versions: Vec<TlsVersion>) -> Self {
+        let mut client = self.client.as_ref().clone();
+        client.versions = versions;
+        Self {
+            client: Arc::new(client),
+            ..self
+        }
+    }
+
+    /// Restricts the TLS protocol versions that servers will accept.
+    ///
+    /// By default, servers only accept TLS 1.2.
+    pub fn with_server_tls_versions(self, server_versions: Vec<TlsVersion>) -> Self {
+        Self {
+            server_versions,
+            ..self
+        }
     }
 
     pub fn certify(&self, key: Key, crt: Crt) -> Result<CrtKey, InvalidCrt> {
-        let mut client = self.0.as_ref().clone();
+        let mut client = self.client.as_ref().clone();
 
         // Ensure the certificate is valid for the services we terminate for
         // TLS. This assumes that server cert validation does the same or
@@ -270,9 +303,9 @@ impl TrustAnchors {
         //
         // TODO: Change Rustls's API to Avoid needing to clone `root_cert_store`.
         let mut server = rustls::ServerConfig::new(
-            rustls::AllowAnyAnonymousOrAuthenticatedClient::new(self.0.root_store.clone()),
+            rustls::AllowAnyAnonymousOrAuthenticatedClient::new(self.client.root_store.clone()),
         );
-        server.versions = TLS_VERSIONS.to_vec();
+        server.versions = self.server_versions.clone();
         server.cert_resolver = resolver;
 
         Ok(CrtKey {
@@ -286,7 +319,7 @@ impl TrustAnchors {
 
 impl tls::client::HasConfig for TrustAnchors {
     fn tls_client_config(&self) -> Arc<rustls::ClientConfig> {
-        self.0.clone()
+        self.client.clone()
     }
 }
 
@@ -314,6 +347,14 @@ impl Crt {
 
 // === CrtKey ===
 
+impl CrtKey {
+    /// Returns the time at which the certificate expires (i.e. its
+    /// `not_after`).
+    pub fn expiry(&self) -> SystemTime {
+        self.expiry
+    }
+}
+
 impl tls::client::HasConfig for CrtKey {
     fn tls_client_config(&self) -> Arc<tls::client::Config> {
         self.client_config.clone()
@@ -422,7 +463,9 @@ impl Error for InvalidCrt {
 
 #[cfg(test)]
 mod tests {
+    use super::rustls::{self, Session};
     use super::test_util::*;
+    use super::{CrtKey, TlsVersion};
 
     #[test]
     fn can_construct_client_and_server_config_from_valid_settings() {
@@ -457,4 +500,64 @@ mod tests {
         };
         assert!(s.validate().is_err(), "identity should not be valid");
     }
+
+    #[test]
+    fn default_tls_versions_interoperate() {
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let client = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let version = handshake(&client, &server).expect("handshake must succeed");
+        assert_eq!(version, TlsVersion::TLSv1_2);
+    }
+
+    #[test]
+    fn tls13_only_server_accepts_tls13_client() {
+        let server = certify_with(&FOO_NS1, vec![TlsVersion::TLSv1_3]);
+        let client = certify_with(&BAR_NS1, vec![TlsVersion::TLSv1_3]);
+        let version = handshake(&client, &server).expect("handshake must succeed");
+        assert_eq!(version, TlsVersion::TLSv1_3);
+    }
+
+    #[test]
+    fn tls13_only_server_rejects_tls12_client() {
+        let server = certify_with(&FOO_NS1, vec![TlsVersion::TLSv1_3]);
+        let client = certify_with(&BAR_NS1, vec![TlsVersion::TLSv1_2]);
+        assert!(
+            handshake(&client, &server).is_err(),
+            "a TLS 1.2-only client must not handshake with a TLS 1.3-only server"
+        );
+    }
+
+    fn certify_with(s: &Strings, versions: Vec<TlsVersion>) -> CrtKey {
+        s.trust_anchors()
+            .with_client_tls_versions(versions.clone())
+            .with_server_tls_versions(versions)
+            .certify(s.key(), s.crt())
+            .expect("identity must be valid")
+    }
+
+    /// Drives a handshake between `client` and `server` in memory, returning
+    /// the negotiated protocol version.
+    fn handshake(client: &CrtKey, server: &CrtKey) -> Result<TlsVersion, rustls::TLSError> {
+        let server_name = server.name.as_dns_name_ref();
+        let mut c = rustls::ClientSession::new(&client.client_config, server_name);
+        let mut s = rustls::ServerSession::new(&server.server_config);
+
+        while c.is_handshaking() || s.is_handshaking() {
+            let mut buf = Vec::new();
+            c.write_tls(&mut buf).expect("write to vec");
+            let sent = !buf.is_empty();
+            s.read_tls(&mut &buf[..]).expect("read from slice");
+            s.process_new_packets()?;
+
+            let mut buf = Vec::new();
+            s.write_tls(&mut buf).expect("write to vec");
+            let received = !buf.is_empty();
+            c.read_tls(&mut &buf[..]).expect("read from slice");
+            c.process_new_packets()?;
+
+            assert!(sent || received, "handshake stalled");
+        }
+
+        Ok(c.get_protocol_version().expect("handshake must be complete"))
+    }
 }
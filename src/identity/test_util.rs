@@ -55,9 +55,14 @@ impl Strings {
     pub fn crt(&self) -> Crt {
         const HOUR: Duration = Duration::from_secs(60 * 60);
 
+        self.crt_until(SystemTime::now() + HOUR)
+    }
+
+    /// Returns the certificate, described as expiring at `expiry`.
+    pub fn crt_until(&self, expiry: SystemTime) -> Crt {
         let n = Name::from_hostname(self.name.as_bytes()).expect("name must be valid");
         let der = Self::read(&self.crt);
-        Crt::new(n, der, vec![], SystemTime::now() + HOUR)
+        Crt::new(n, der, vec![], expiry)
     }
 
     pub fn validate(&self) -> Result<CrtKey, InvalidCrt> {
@@ -65,4 +70,10 @@ impl Strings {
         let c = self.crt();
         self.trust_anchors().certify(k, c)
     }
+
+    pub fn validate_until(&self, expiry: SystemTime) -> Result<CrtKey, InvalidCrt> {
+        let k = self.key();
+        let c = self.crt_until(expiry);
+        self.trust_anchors().certify(k, c)
+    }
 }
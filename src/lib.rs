@@ -25,7 +25,11 @@ extern crate prost_types;
 extern crate quickcheck;
 extern crate rand;
 extern crate regex;
+extern crate serde;
+#[cfg(test)]
+extern crate serde_json;
 extern crate tokio;
+extern crate tokio_sync;
 extern crate tokio_timer;
 #[macro_use]
 extern crate tracing;
@@ -0,0 +1,755 @@
+//! Stops routing requests to endpoints that consistently fail.
+//!
+//! Endpoint stacks are wrapped so that, once an endpoint has failed a number
+//! of consecutive requests, its breaker opens and the endpoint reports that it
+//! is not ready until a cooldown elapses. Because the balancer only chooses
+//! between ready endpoints, no requests are routed to an endpoint while its
+//! breaker is open. Requests fail if they error or if their responses have a
+//! 5xx status or a gRPC status of `UNAVAILABLE`.
+//!
+//! When the cooldown elapses, the breaker half-opens: a single request is
+//! dispatched to the endpoint to probe whether it has recovered. If the probe
+//! succeeds, the breaker closes; otherwise, it opens for another cooldown.
+//!
+//! Breakers may also wrap a balancer's load-tracked endpoints, in which case
+//! an endpoint whose breaker has just closed may be ramped back into service:
+//! for a configured window after the breaker closes, the endpoint sometimes
+//! reports that it is maximally loaded, with a probability that decays from
+//! one to zero over the window, so that it receives a gradually increasing
+//! share of requests.
+
+extern crate tower_discover;
+
+use futures::{task::AtomicTask, Async, Future, Poll};
+use rand::{self, Rng};
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio_timer::{clock, Delay};
+
+use self::tower_discover::{Change, Discover};
+use http;
+use proxy::http::balance::Load;
+use svc;
+
+/// The gRPC status code that indicates that a service is unavailable.
+const GRPC_UNAVAILABLE: u32 = 14;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Layer {
+    config: Config,
+}
+
+#[derive(Clone, Debug)]
+pub struct MakeSvc<M> {
+    config: Config,
+    inner: M,
+}
+
+pub struct MakeFuture<F> {
+    config: Config,
+    inner: F,
+}
+
+/// Wraps the endpoints of a `D`-typed discovery stream with breakers, e.g. so
+/// that a balancer's load-tracked endpoints may be ramped back into service.
+///
+/// If no breaker is configured, endpoints are not wrapped with breakers.
+pub struct Breakers<D> {
+    config: Option<Config>,
+    inner: D,
+}
+
+/// An endpoint service that is not ready while its breaker is open.
+#[derive(Debug)]
+pub struct Service<S> {
+    breaker: Option<Breaker>,
+    inner: S,
+}
+
+pub struct ResponseFuture<F> {
+    /// Set until the response completes, so that its outcome is only recorded
+    /// once.
+    breaker: Option<Breaker>,
+    /// Whether this request is probing a half-open breaker.
+    probe: bool,
+    inner: F,
+}
+
+/// The load of an endpoint that may be ramping back into service.
+///
+/// A `Ramping` endpoint is more loaded than any other endpoint.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RampedLoad<M> {
+    Load(M),
+    Ramping,
+}
+
+/// Classifies an endpoint's responses, so that responses indicating that the
+/// endpoint is unhealthy count against its breaker as errors do.
+pub trait IsFailure {
+    fn is_failure(&self) -> bool;
+}
+
+/// Describes whether an endpoint's breaker permits requests to the endpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    /// The cooldown has elapsed, and a request may probe the endpoint.
+    HalfOpen,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Config {
+    failure_threshold: usize,
+    cooldown: Duration,
+    ramp: Option<Duration>,
+}
+
+#[derive(Clone, Debug)]
+struct Breaker {
+    config: Config,
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: State,
+    /// The time at which a half-open breaker last closed.
+    closed_at: Option<Instant>,
+    /// The task waiting for a half-open breaker's probe to complete.
+    parked: AtomicTask,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: usize },
+    Open(Delay),
+    HalfOpen { probing: bool },
+}
+
+// === impl Layer ===
+
+/// Opens an endpoint's breaker for `cooldown` after `failure_threshold`
+/// consecutive requests to the endpoint fail.
+///
+/// A `failure_threshold` of zero is treated as one.
+pub fn layer(failure_threshold: usize, cooldown: Duration) -> Layer {
+    Layer {
+        config: Config {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            ramp: None,
+        },
+    }
+}
+
+impl Layer {
+    /// Ramps each endpoint back into service over `ramp` once its breaker
+    /// closes.
+    ///
+    /// Ramping only applies where breakers wrap load-tracked endpoints (i.e.
+    /// in a balancer). By default, endpoints are not ramped.
+    pub fn with_recovery_ramp(self, ramp: Duration) -> Self {
+        Self {
+            config: Config {
+                ramp: Some(ramp),
+                ..self.config
+            },
+        }
+    }
+}
+
+impl<M> svc::Layer<M> for Layer {
+    type Service = MakeSvc<M>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        MakeSvc {
+            config: self.config,
+            inner,
+        }
+    }
+}
+
+// === impl MakeSvc ===
+
+impl<T, M> svc::Service<T> for MakeSvc<M>
+where
+    M: svc::Service<T>,
+{
+    type Response = Service<M::Response>;
+    type Error = M::Error;
+    type Future = MakeFuture<M::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        MakeFuture {
+            config: self.config,
+            inner: self.inner.call(target),
+        }
+    }
+}
+
+// === impl MakeFuture ===
+
+impl<F: Future> Future for MakeFuture<F> {
+    type Item = Service<F::Item>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(Service::new(Some(self.config), inner)))
+    }
+}
+
+// === impl Breakers ===
+
+impl<D> Breakers<D> {
+    pub fn new(inner: D, layer: Option<Layer>) -> Self {
+        Self {
+            config: layer.map(|l| l.config),
+            inner,
+        }
+    }
+}
+
+impl<D: Discover> Discover for Breakers<D> {
+    type Key = D::Key;
+    type Service = Service<D::Service>;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let change = match try_ready!(self.inner.poll()) {
+            Change::Insert(key, svc) => Change::Insert(key, Service::new(self.config, svc)),
+            Change::Remove(key) => Change::Remove(key),
+        };
+        Ok(Async::Ready(change))
+    }
+}
+
+// === impl Service ===
+
+impl<S> Service<S> {
+    fn new(config: Option<Config>, inner: S) -> Self {
+        let breaker = config.map(|config| Breaker {
+            config,
+            shared: Arc::new(Mutex::new(Shared {
+                state: State::Closed { failures: 0 },
+                closed_at: None,
+                parked: AtomicTask::new(),
+            })),
+        });
+        Self { breaker, inner }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the state of the endpoint's breaker, or `None` if the endpoint
+    /// has no breaker.
+    pub fn breaker_state(&self) -> Option<BreakerState> {
+        self.breaker.as_ref().map(|breaker| match breaker.lock().state {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open(_) => BreakerState::Open,
+            State::HalfOpen { .. } => BreakerState::HalfOpen,
+        })
+    }
+}
+
+impl<S, Req> svc::Service<Req> for Service<S>
+where
+    S: svc::Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        if let Some(ref breaker) = self.breaker {
+            if !breaker.lock().poll_available() {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let probe = match self.breaker {
+            Some(ref breaker) => breaker.lock().start_request(),
+            None => false,
+        };
+        ResponseFuture {
+            breaker: self.breaker.clone(),
+            probe,
+            inner: self.inner.call(req),
+        }
+    }
+}
+
+impl<S: Load> Load for Service<S> {
+    type Metric = RampedLoad<S::Metric>;
+
+    fn load(&self) -> Self::Metric {
+        let ramping = self.breaker.as_ref().map_or(0.0, |breaker| {
+            let closed_at = breaker.lock().closed_at;
+            match (closed_at, breaker.config.ramp) {
+                (Some(closed_at), Some(ramp)) => ramp_penalty(closed_at, ramp, clock::now()),
+                _ => 0.0,
+            }
+        });
+
+        if ramping > 0.0 && rand::thread_rng().gen_bool(ramping) {
+            return RampedLoad::Ramping;
+        }
+        RampedLoad::Load(self.inner.load())
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F> Future for ResponseFuture<F>
+where
+    F: Future,
+    F::Item: IsFailure,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = match self.inner.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            result => result,
+        };
+
+        if let Some(breaker) = self.breaker.take() {
+            let failed = match result {
+                Ok(Async::Ready(ref rsp)) => rsp.is_failure(),
+                _ => true,
+            };
+            let mut shared = breaker.lock();
+            if failed {
+                shared.record_failure(self.probe, &breaker.config);
+            } else {
+                shared.record_success(self.probe);
+            }
+        }
+
+        result
+    }
+}
+
+impl<F> Drop for ResponseFuture<F> {
+    fn drop(&mut self) {
+        // If a probe is canceled, another request must be permitted to probe
+        // the endpoint.
+        if let Some(breaker) = self.breaker.take() {
+            if self.probe {
+                breaker.lock().cancel_probe();
+            }
+        }
+    }
+}
+
+// === impl IsFailure ===
+
+/// Responses fail if they have a 5xx status, or if their headers have a gRPC
+/// status of `UNAVAILABLE` (i.e. the response is trailers-only).
+impl<B> IsFailure for http::Response<B> {
+    fn is_failure(&self) -> bool {
+        if self.status().is_server_error() {
+            return true;
+        }
+
+        self.headers()
+            .get("grpc-status")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.parse::<u32>().ok())
+            == Some(GRPC_UNAVAILABLE)
+    }
+}
+
+// === impl RampedLoad ===
+
+impl<M: PartialOrd> PartialOrd for RampedLoad<M> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (&RampedLoad::Load(ref a), &RampedLoad::Load(ref b)) => a.partial_cmp(b),
+            (&RampedLoad::Ramping, &RampedLoad::Ramping) => Some(Ordering::Equal),
+            (&RampedLoad::Ramping, &RampedLoad::Load(_)) => Some(Ordering::Greater),
+            (&RampedLoad::Load(_), &RampedLoad::Ramping) => Some(Ordering::Less),
+        }
+    }
+}
+
+/// Returns the probability that an endpoint whose breaker closed at
+/// `closed_at` reports that it is ramping, decaying linearly from one to zero
+/// over `ramp`.
+fn ramp_penalty(closed_at: Instant, ramp: Duration, now: Instant) -> f64 {
+    let ramp = duration_secs(ramp);
+    if now <= closed_at {
+        return if ramp > 0.0 { 1.0 } else { 0.0 };
+    }
+    let elapsed = duration_secs(now - closed_at);
+    if elapsed >= ramp {
+        return 0.0;
+    }
+    1.0 - elapsed / ramp
+}
+
+fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
+
+// === impl Breaker ===
+
+impl Breaker {
+    fn lock(&self) -> MutexGuard<Shared> {
+        self.shared.lock().expect("breaker lock poisoned")
+    }
+}
+
+// === impl Shared ===
+
+impl Shared {
+    /// Returns true if a request may be dispatched to the endpoint.
+    ///
+    /// If the breaker is open, the current task is notified when its cooldown
+    /// elapses.
+    fn poll_available(&mut self) -> bool {
+        let cooled_down = match self.state {
+            State::Closed { .. } | State::HalfOpen { probing: false } => false,
+            State::HalfOpen { probing: true } => {
+                self.parked.register();
+                return false;
+            }
+            State::Open(ref mut delay) => match delay.poll() {
+                Ok(Async::NotReady) => return false,
+                Ok(Async::Ready(())) => true,
+                Err(e) => {
+                    warn!("breaker timer failed; half-opening: {}", e);
+                    true
+                }
+            },
+        };
+
+        if cooled_down {
+            debug!("breaker cooldown elapsed; half-opening");
+            self.state = State::HalfOpen { probing: false };
+        }
+
+        true
+    }
+
+    /// Returns true if the request probes a half-open breaker.
+    fn start_request(&mut self) -> bool {
+        if let State::HalfOpen { probing: false } = self.state {
+            self.state = State::HalfOpen { probing: true };
+            return true;
+        }
+
+        false
+    }
+
+    fn record_success(&mut self, probe: bool) {
+        match self.state {
+            State::Closed { ref mut failures } => *failures = 0,
+            State::HalfOpen { .. } if probe => {
+                debug!("breaker probe succeeded; closing");
+                self.state = State::Closed { failures: 0 };
+                self.closed_at = Some(clock::now());
+                self.unpark();
+            }
+            // Responses to requests dispatched before the breaker opened do
+            // not indicate whether the endpoint has recovered.
+            _ => {}
+        }
+    }
+
+    fn record_failure(&mut self, probe: bool, config: &Config) {
+        let open = match self.state {
+            State::Closed { ref mut failures } => {
+                *failures += 1;
+                *failures >= config.failure_threshold
+            }
+            State::HalfOpen { .. } => probe,
+            State::Open(_) => false,
+        };
+
+        if open {
+            debug!("breaker opening for {:?}", config.cooldown);
+            self.state = State::Open(Delay::new(clock::now() + config.cooldown));
+            // Tasks waiting on the probe must instead wait for the cooldown.
+            self.unpark();
+        }
+    }
+
+    fn cancel_probe(&mut self) {
+        if let State::HalfOpen { ref mut probing } = self.state {
+            *probing = false;
+        }
+        self.unpark();
+    }
+
+    fn unpark(&mut self) {
+        self.parked.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use svc::{Layer as _Layer, Service as _Service};
+    use tokio::runtime::current_thread::Runtime;
+
+    /// An endpoint that fails requests while `failing` is set.
+    #[derive(Clone)]
+    struct Endpoint {
+        failing: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl svc::Service<()> for Endpoint {
+        type Response = http::Response<()>;
+        type Error = ();
+        type Future = future::FutureResult<http::Response<()>, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.failing.load(Ordering::SeqCst) {
+                future::err(())
+            } else {
+                future::ok(http::Response::new(()))
+            }
+        }
+    }
+
+    impl Load for Endpoint {
+        type Metric = usize;
+
+        fn load(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    fn endpoint(failing: bool) -> Endpoint {
+        Endpoint {
+            failing: Arc::new(AtomicBool::new(failing)),
+            calls: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn build(
+        endpoint: Endpoint,
+        failure_threshold: usize,
+        cooldown: Duration,
+    ) -> Service<Endpoint> {
+        layer(failure_threshold, cooldown)
+            .layer(svc::mk(move |_: ()| Ok::<_, ()>(endpoint.clone())))
+            .call(())
+            .wait()
+            .expect("make must succeed")
+    }
+
+    fn is_ready(svc: &mut Service<Endpoint>) -> bool {
+        svc::Service::<()>::poll_ready(svc)
+            .expect("poll_ready must not fail")
+            .is_ready()
+    }
+
+    #[test]
+    fn failing_endpoints_are_not_ready_after_threshold() {
+        let mut rt = Runtime::new().expect("runtime");
+        let endpoint = endpoint(true);
+        let calls = endpoint.calls.clone();
+        rt.block_on(future::lazy(move || {
+            let mut svc = build(endpoint, 3, Duration::from_secs(60));
+            for _ in 0..3 {
+                assert!(is_ready(&mut svc), "endpoint must be ready until threshold");
+                assert!(svc.call(()).wait().is_err());
+            }
+
+            assert!(
+                !is_ready(&mut svc),
+                "endpoint must not be ready once its breaker opens"
+            );
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn successes_reset_consecutive_failures() {
+        let mut rt = Runtime::new().expect("runtime");
+        let endpoint = endpoint(true);
+        let failing = endpoint.failing.clone();
+        rt.block_on(future::lazy(move || {
+            let mut svc = build(endpoint, 2, Duration::from_secs(60));
+            assert!(svc.call(()).wait().is_err());
+
+            failing.store(false, Ordering::SeqCst);
+            assert!(svc.call(()).wait().is_ok());
+
+            failing.store(true, Ordering::SeqCst);
+            assert!(svc.call(()).wait().is_err());
+            assert!(is_ready(&mut svc), "failures must be consecutive");
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    /// Fails a request to `svc` and waits for its breaker to half-open.
+    fn trip_and_half_open(rt: &mut Runtime, svc: &mut Service<Endpoint>) {
+        rt.block_on(future::lazy(|| svc.call(())))
+            .expect_err("request must fail");
+        rt.block_on(future::poll_fn(|| svc::Service::<()>::poll_ready(svc)))
+            .expect("breaker must half-open");
+    }
+
+    #[test]
+    fn half_open_breakers_probe_recovery() {
+        let mut rt = Runtime::new().expect("runtime");
+        let endpoint = endpoint(true);
+        let failing = endpoint.failing.clone();
+        let mut svc = build(endpoint, 1, Duration::from_secs(0));
+        trip_and_half_open(&mut rt, &mut svc);
+
+        rt.block_on(future::lazy(|| {
+            let probe = svc.call(());
+            assert!(
+                !is_ready(&mut svc),
+                "only one request may probe a half-open breaker"
+            );
+
+            failing.store(false, Ordering::SeqCst);
+            drop(probe);
+            assert!(is_ready(&mut svc), "canceled probes must be retried");
+            assert!(svc.call(()).wait().is_ok());
+
+            failing.store(true, Ordering::SeqCst);
+            assert!(is_ready(&mut svc), "successful probes must close the breaker");
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    fn response(status: u16, grpc_status: Option<&str>) -> http::Response<()> {
+        let mut rsp = http::Response::builder();
+        rsp.status(status);
+        if let Some(grpc_status) = grpc_status {
+            rsp.header("grpc-status", grpc_status);
+        }
+        rsp.body(()).expect("response must be valid")
+    }
+
+    #[test]
+    fn server_errors_and_unavailable_grpc_responses_are_failures() {
+        assert!(!response(200, None).is_failure());
+        assert!(!response(404, None).is_failure());
+        assert!(response(500, None).is_failure());
+        assert!(response(503, None).is_failure());
+        assert!(response(200, Some("14")).is_failure());
+        assert!(!response(200, Some("0")).is_failure());
+        assert!(!response(200, Some("5")).is_failure());
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(|| {
+            let config = layer(2, Duration::from_secs(60)).config;
+            let mut svc = Service::new(
+                Some(config),
+                svc::mk(|_: ()| Ok::<_, ()>(response(200, Some("14")))),
+            );
+            assert!(svc.call(()).wait().is_ok(), "responses must be returned");
+            assert!(svc.call(()).wait().is_ok(), "responses must be returned");
+            assert!(
+                svc::Service::<()>::poll_ready(&mut svc)
+                    .expect("poll_ready must not fail")
+                    .is_not_ready(),
+                "failed responses must open the breaker"
+            );
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn ramp_penalty_decays_over_the_window() {
+        let closed_at = clock::now();
+        let ramp = Duration::from_secs(10);
+        let at = |ms: u64| ramp_penalty(closed_at, ramp, closed_at + Duration::from_millis(ms));
+
+        assert_eq!(at(0), 1.0);
+        assert!((at(2_500) - 0.75).abs() < 1e-9);
+        assert!((at(5_000) - 0.5).abs() < 1e-9);
+        assert!((at(7_500) - 0.25).abs() < 1e-9);
+        assert_eq!(at(10_000), 0.0);
+        assert_eq!(at(20_000), 0.0);
+    }
+
+    #[test]
+    fn recovered_endpoints_ramp_into_service() {
+        const SAMPLES: usize = 1_000;
+        let ramp = Duration::from_secs(10);
+
+        let mut rt = Runtime::new().expect("runtime");
+        let endpoint = endpoint(true);
+        let failing = endpoint.failing.clone();
+        let config = layer(1, Duration::from_secs(0))
+            .with_recovery_ramp(ramp)
+            .config;
+        let mut svc = Service::new(Some(config), endpoint);
+        assert_eq!(svc.load(), RampedLoad::Load(0), "endpoints are not ramped initially");
+
+        trip_and_half_open(&mut rt, &mut svc);
+        failing.store(false, Ordering::SeqCst);
+        rt.block_on(future::lazy(|| svc.call(())))
+            .expect("probe must succeed");
+
+        // Sample the share of load estimates that are not penalized (i.e.
+        // that may win a P2C comparison) at each quarter of the ramp.
+        let closed_at = clock::now();
+        let mut shares = Vec::new();
+        for quarter in 0..5 {
+            let elapsed = ramp / 4 * quarter;
+            svc.breaker.as_ref().unwrap().lock().closed_at = Some(closed_at - elapsed);
+            let unpenalized = (0..SAMPLES)
+                .filter(|_| svc.load() != RampedLoad::Ramping)
+                .count();
+            shares.push(unpenalized as f64 / SAMPLES as f64);
+        }
+
+        for (quarter, share) in shares.iter().enumerate() {
+            let expected = quarter as f64 / 4.0;
+            assert!(
+                (share - expected).abs() < 0.1,
+                "share {} must be near {} after {}/4 of the ramp",
+                share,
+                expected,
+                quarter
+            );
+        }
+        assert!(
+            shares.windows(2).all(|w| w[0] < w[1]),
+            "share must increase through the ramp: {:?}",
+            shares
+        );
+    }
+
+    #[test]
+    fn ramping_endpoints_are_most_loaded() {
+        assert!(RampedLoad::Ramping > RampedLoad::Load(::std::usize::MAX));
+        assert!(RampedLoad::Load(1) < RampedLoad::Load(2));
+    }
+}
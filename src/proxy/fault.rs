@@ -0,0 +1,363 @@
+//! Injects faults into endpoint stacks, e.g. to exercise retries, hedging, and
+//! breakers against a live balancer.
+//!
+//! Endpoint stacks are wrapped so that a fraction of each endpoint's requests
+//! are delayed or failed. Faults are configured per endpoint address through a
+//! `Handle`, and changes take effect for subsequent requests, including those
+//! to endpoints that have already been built.
+
+use futures::{Async, Future, Poll};
+use rand::{self, Rng};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use std::{error, fmt};
+use tokio_timer::{clock, Delay};
+
+use proxy::Error;
+use svc;
+use transport::connect::HasPeerAddr;
+
+/// Describes the faults injected into an endpoint's requests.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Faults {
+    latency: Duration,
+    latency_rate: f64,
+    error_rate: f64,
+}
+
+/// Configures the faults injected by a `Layer`'s endpoints.
+///
+/// Clones share the same configuration.
+#[derive(Clone, Debug, Default)]
+pub struct Handle(Arc<RwLock<Config>>);
+
+#[derive(Debug, Default)]
+struct Config {
+    /// Faults injected into endpoints that are not configured individually.
+    default: Faults,
+    endpoints: HashMap<SocketAddr, Faults>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Layer {
+    handle: Handle,
+}
+
+#[derive(Clone, Debug)]
+pub struct MakeSvc<M> {
+    handle: Handle,
+    inner: M,
+}
+
+pub struct MakeFuture<F> {
+    addr: SocketAddr,
+    handle: Handle,
+    inner: F,
+}
+
+/// An endpoint service into whose requests faults are injected.
+#[derive(Debug)]
+pub struct Service<S> {
+    addr: SocketAddr,
+    handle: Handle,
+    inner: S,
+}
+
+pub enum ResponseFuture<F> {
+    /// The response is held until the delay, if any, elapses.
+    Inner(F, Option<Delay>),
+    Failed(Option<InjectedFault>),
+}
+
+enum Injected {
+    Error,
+    Latency(Duration),
+}
+
+/// Indicates that a request failed because a fault was injected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InjectedFault {
+    addr: SocketAddr,
+}
+
+// === impl Faults ===
+
+impl Faults {
+    /// Delays `rate` of requests by `latency`.
+    ///
+    /// `rate` is clamped to `[0.0, 1.0]`.
+    pub fn with_latency(self, rate: f64, latency: Duration) -> Self {
+        Self {
+            latency,
+            latency_rate: clamp(rate),
+            ..self
+        }
+    }
+
+    /// Fails `rate` of requests with `InjectedFault`.
+    ///
+    /// `rate` is clamped to `[0.0, 1.0]`.
+    pub fn with_errors(self, rate: f64) -> Self {
+        Self {
+            error_rate: clamp(rate),
+            ..self
+        }
+    }
+
+    /// Chooses the fault, if any, to inject into a request.
+    fn roll(&self) -> Option<Injected> {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(self.error_rate) {
+            return Some(Injected::Error);
+        }
+        if rng.gen_bool(self.latency_rate) {
+            return Some(Injected::Latency(self.latency));
+        }
+        None
+    }
+}
+
+fn clamp(rate: f64) -> f64 {
+    if rate.is_nan() {
+        0.0
+    } else {
+        rate.max(0.0).min(1.0)
+    }
+}
+
+// === impl Handle ===
+
+impl Handle {
+    pub fn layer(&self) -> Layer {
+        Layer {
+            handle: self.clone(),
+        }
+    }
+
+    /// Injects `faults` into requests to endpoints that have not been
+    /// configured individually.
+    pub fn set_default(&self, faults: Faults) {
+        self.0.write().expect("fault lock poisoned").default = faults;
+    }
+
+    /// Injects `faults` into requests to the endpoint at `addr`.
+    pub fn set(&self, addr: SocketAddr, faults: Faults) {
+        self.0
+            .write()
+            .expect("fault lock poisoned")
+            .endpoints
+            .insert(addr, faults);
+    }
+
+    /// Restores the default faults for the endpoint at `addr`.
+    pub fn clear(&self, addr: &SocketAddr) {
+        self.0
+            .write()
+            .expect("fault lock poisoned")
+            .endpoints
+            .remove(addr);
+    }
+
+    fn get(&self, addr: &SocketAddr) -> Faults {
+        let config = self.0.read().expect("fault lock poisoned");
+        config.endpoints.get(addr).cloned().unwrap_or(config.default)
+    }
+}
+
+// === impl Layer ===
+
+impl<M> svc::Layer<M> for Layer {
+    type Service = MakeSvc<M>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        MakeSvc {
+            handle: self.handle.clone(),
+            inner,
+        }
+    }
+}
+
+// === impl MakeSvc ===
+
+impl<T, M> svc::Service<T> for MakeSvc<M>
+where
+    T: HasPeerAddr,
+    M: svc::Service<T>,
+{
+    type Response = Service<M::Response>;
+    type Error = M::Error;
+    type Future = MakeFuture<M::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        MakeFuture {
+            addr: target.peer_addr(),
+            handle: self.handle.clone(),
+            inner: self.inner.call(target),
+        }
+    }
+}
+
+// === impl MakeFuture ===
+
+impl<F: Future> Future for MakeFuture<F> {
+    type Item = Service<F::Item>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(Service {
+            addr: self.addr,
+            handle: self.handle.clone(),
+            inner,
+        }))
+    }
+}
+
+// === impl Service ===
+
+impl<S, Req> svc::Service<Req> for Service<S>
+where
+    S: svc::Service<Req>,
+    S::Error: Into<Error>,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready().map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        match self.handle.get(&self.addr).roll() {
+            None => ResponseFuture::Inner(self.inner.call(req), None),
+            Some(Injected::Error) => {
+                debug!("injecting a failure into a request to {}", self.addr);
+                ResponseFuture::Failed(Some(InjectedFault { addr: self.addr }))
+            }
+            Some(Injected::Latency(latency)) => {
+                debug!("injecting {:?} of latency into a request to {}", latency, self.addr);
+                let delay = Delay::new(clock::now() + latency);
+                ResponseFuture::Inner(self.inner.call(req), Some(delay))
+            }
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F> Future for ResponseFuture<F>
+where
+    F: Future,
+    F::Error: Into<Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            ResponseFuture::Inner(ref mut inner, ref mut delay) => {
+                if let Some(ref mut d) = *delay {
+                    try_ready!(d.poll().map_err(Error::from));
+                }
+                *delay = None;
+                inner.poll().map_err(Into::into)
+            }
+            ResponseFuture::Failed(ref mut fault) => {
+                Err(fault.take().expect("polled after failure").into())
+            }
+        }
+    }
+}
+
+// === impl InjectedFault ===
+
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "injected a failure into a request to {}", self.addr)
+    }
+}
+
+impl error::Error for InjectedFault {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use svc::{Layer as _Layer, Service as _Service};
+
+    const REQUESTS: usize = 10_000;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn build(
+        handle: &Handle,
+        addr: SocketAddr,
+    ) -> Service<impl svc::Service<(), Response = (), Error = Error>> {
+        let endpoint = |_: SocketAddr| Ok::<_, Error>(svc::mk(|_: ()| Ok::<_, Error>(())));
+        let mut make = handle.layer().layer(svc::mk(endpoint));
+        make.call(addr).wait().expect("make must succeed")
+    }
+
+    /// Returns the fraction of `REQUESTS` for which `f` returns true.
+    fn rate<F: FnMut() -> bool>(mut f: F) -> f64 {
+        let n = (0..REQUESTS).filter(|_| f()).count();
+        n as f64 / REQUESTS as f64
+    }
+
+    #[test]
+    fn injected_error_rate_matches_configuration() {
+        let handle = Handle::default();
+        handle.set(addr(8080), Faults::default().with_errors(0.25));
+        let mut faulty = build(&handle, addr(8080));
+        let mut healthy = build(&handle, addr(8081));
+
+        let failed = rate(|| match faulty.call(()).wait() {
+            Ok(()) => false,
+            Err(e) => {
+                assert!(e.is::<InjectedFault>(), "unexpected error: {}", e);
+                true
+            }
+        });
+        assert!(failed > 0.22 && failed < 0.28, "failure rate {}", failed);
+
+        assert!(
+            (0..REQUESTS).all(|_| healthy.call(()).wait().is_ok()),
+            "unconfigured endpoints must not fail"
+        );
+    }
+
+    #[test]
+    fn injected_latency_rate_matches_configuration() {
+        let handle = Handle::default();
+        let faults = Faults::default().with_latency(0.1, Duration::from_millis(100));
+        handle.set_default(faults);
+        let mut svc = build(&handle, addr(8080));
+
+        let delayed = rate(|| match svc.call(()) {
+            ResponseFuture::Inner(_, delay) => delay.is_some(),
+            ResponseFuture::Failed(_) => panic!("requests must not fail"),
+        });
+        assert!(delayed > 0.08 && delayed < 0.12, "latency rate {}", delayed);
+    }
+
+    #[test]
+    fn handle_updates_existing_endpoints() {
+        let handle = Handle::default();
+        let mut svc = build(&handle, addr(8080));
+        assert!(svc.call(()).wait().is_ok());
+
+        handle.set(addr(8080), Faults::default().with_errors(1.0));
+        assert!(svc.call(()).wait().is_err(), "faults must apply to built endpoints");
+
+        handle.clear(&addr(8080));
+        assert!(svc.call(()).wait().is_ok(), "cleared faults must not apply");
+    }
+}
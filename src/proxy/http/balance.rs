@@ -3,145 +3,4314 @@ extern crate tower_balance;
 extern crate tower_discover;
 extern crate tower_load;
 
-use std::{marker::PhantomData, time::Duration};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    error, fmt,
+    hash::{Hash, Hasher},
+    io,
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
 
-use futures::{Async, Future, Poll};
+use futures::{future, Async, Future, Poll};
 use hyper::body::Payload;
-use rand::{rngs::SmallRng, FromEntropy};
+use indexmap::IndexMap;
+use rand::{rngs::SmallRng, FromEntropy, Rng};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use tokio_timer::{clock, Delay};
 
-use self::tower_discover::Discover;
+use self::tower_discover::{Change, Discover};
 
-pub use self::hyper_balance::{PendingUntilFirstData, PendingUntilFirstDataBody};
+pub use self::hyper_balance::{
+    PendingUntilEos, PendingUntilEosBody, PendingUntilFirstData, PendingUntilFirstDataBody,
+};
 pub use self::tower_balance::p2c::Balance;
 pub use self::tower_load::{Load, PeakEwmaDiscover};
 
 use http;
+use metrics::{FmtMetrics, Gauge};
+use proxy::breaker;
+use proxy::http::fallback;
+use proxy::Error;
 use svc;
+use svc::linkerd2_timeout::error::Timedout;
+
+metrics! {
+    balancer_requests_in_flight: Gauge {
+        "Number of requests in flight through balancers and their fallbacks"
+    }
+}
+
+/// The default amount of time to wait before rebuilding a failed balancer.
+const DEFAULT_REBUILD_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The default number of points that each endpoint has on a `Ring`.
+const DEFAULT_RING_REPLICAS: usize = 100;
 
 /// Configures a stack to resolve `T` typed targets to balance requests over
 /// `M`-typed endpoint stacks.
+///
+/// Endpoint load is tracked with the `I`-typed instrument (i.e.
+/// `PendingUntilFirstData` or `PendingUntilEos`).
 #[derive(Debug)]
-pub struct Layer<A, B> {
-    decay: Duration,
-    default_rtt: Duration,
-    rng: SmallRng,
+pub struct Layer<A, B, I> {
+    config: Config<A, I>,
     _marker: PhantomData<fn(A) -> B>,
 }
 
 /// Resolves `T` typed targets to balance requests over `M`-typed endpoint stacks.
 #[derive(Debug)]
-pub struct MakeSvc<M, A, B> {
+pub struct MakeSvc<M, A, B, I> {
+    config: Config<A, I>,
+    inner: M,
+    _marker: PhantomData<fn(A) -> B>,
+}
+
+/// The options configured on a `Layer`, which are shared by each balancer
+/// that it builds.
+#[derive(Debug)]
+struct Config<A, I> {
+    affinity: Option<AffinityKey<A>>,
+    affinity_hash: Arc<dyn AffinityHash>,
+    breaker: Option<breaker::Layer>,
+    deadline: Option<RequestDeadline<A>>,
     decay: Duration,
     default_rtt: Duration,
+    empty: EmptyPolicy,
+    instrument: I,
+    in_flight: InFlight,
+    load_reset: LoadReset,
+    max_ready_wait: Option<Duration>,
+    prewarm: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    rebuild_backoff: Duration,
+    recycle_ttl: Option<Duration>,
+    rng: SmallRng,
+}
+
+/// Completes with a `Service` once the initial balancer has been built.
+///
+/// Errors building the initial balancer (e.g. because the target could not be
+/// resolved) are returned so that outer layers may fall back.
+pub struct MakeFuture<F, M, T> {
+    inner: F,
+    /// Taken when the `Service` is built, so that polling the future again
+    /// fails with `PolledAfterReady`.
+    rebuild: Option<(M, T)>,
+}
+
+/// Indicates that a `MakeFuture` was polled after it completed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PolledAfterReady;
+
+/// Supervises an `S`-typed balancer, rebuilding it after a backoff if it
+/// fails.
+///
+/// A balancer fails when its discovery stream fails. While the balancer is
+/// being rebuilt, the service is not ready and is unavailable, so requests are
+/// served by an outer fallback layer, if one is configured. Otherwise, they
+/// wait for the new balancer rather than failing (unless a maximum ready wait
+/// elapses).
+pub struct Service<M, T, F, S> {
+    make: M,
+    target: T,
+    state: State<F, S>,
+    /// Set while the balancer is not ready, if a maximum wait is configured.
+    /// Once it elapses, requests are admitted in order to be rejected until
+    /// the balancer is ready again.
+    ready_wait: Option<Delay>,
+}
+
+enum State<F, S> {
+    Ready(S),
+    Backoff(Delay),
+    Rebuilding(F),
+}
+
+pub struct ResponseFuture<F> {
+    /// Unset if the request was not dispatched, i.e. because its deadline
+    /// passed or because the balancer has no endpoints.
+    inner: Option<F>,
+    /// Why the request was not dispatched.
+    rejected: Option<Error>,
+    in_flight: Option<InFlightGuard>,
+    deadline: Option<Delay>,
+}
+
+/// Extracts the time by which each request must complete, e.g. as set by an
+/// outer timeout layer.
+pub struct RequestDeadline<A>(Arc<dyn Fn(&http::Request<A>) -> Option<Instant> + Send + Sync>);
+
+/// Indicates that a request did not complete before its deadline.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DeadlineExceeded;
+
+/// Determines how requests are handled once all of a balancer's endpoints
+/// have been removed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EmptyPolicy {
+    /// The balancer is unavailable, so requests are routed by the fallback
+    /// layer (see `fallback::Layer::on_unavailable`). Without a fallback,
+    /// requests wait for endpoints to be discovered.
+    FailOpen,
+    /// Requests fail immediately with `NoEndpoints`.
+    FailClosed,
+}
+
+/// Indicates that a request was rejected because its balancer has no
+/// endpoints.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NoEndpoints;
+
+/// Indicates that a request was rejected because its balancer's endpoints
+/// did not become ready within the configured maximum wait.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ReadyTimeout;
+
+/// Counts requests that have been dispatched but have not yet completed.
+///
+/// Clones share the same count, so a single `InFlight` may be shared by many
+/// balancers (and their fallbacks) to measure their total load.
+#[derive(Clone, Debug, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+/// Decrements an `InFlight` count when dropped.
+#[derive(Debug)]
+struct InFlightGuard(InFlight);
+
+/// Counts the requests dispatched to services built by the inner
+/// `MakeService`, e.g. so that requests that bypass the balancer are also
+/// reflected in an `InFlight` count.
+#[derive(Clone, Debug)]
+pub struct InFlightLayer(InFlight);
+
+#[derive(Clone, Debug)]
+pub struct MakeInFlight<M> {
+    in_flight: InFlight,
     inner: M,
+}
+
+#[derive(Clone, Debug)]
+pub struct InFlightService<S> {
+    in_flight: InFlight,
+    inner: S,
+}
+
+/// Wraps a `D`-typed discovery stream so that endpoints that are removed and
+/// then quickly re-added retain their load estimates.
+///
+/// When an endpoint service is dropped by the balancer, it is held for the
+/// configured TTL. If the same key is inserted again for the same target
+/// before the TTL elapses, the held service (and, therefore, its load
+/// estimate) is restored in place of the newly-built service. If the target
+/// has changed (e.g. its metadata was updated), the newly-built service is
+/// used.
+pub struct Recycle<D: Discover, T> {
+    inner: D,
+    ttl: Option<Duration>,
+    targets: Targets<D::Key, T>,
+    removed: Arc<Mutex<Removed<D::Key, D::Service, T>>>,
+}
+
+/// Wraps a `D`-typed discovery stream so that, when enabled, endpoints are
+/// only inserted into the balancer once they are ready.
+///
+/// Newly-discovered endpoint services are polled to readiness (i.e. so that
+/// they connect) as the balancer polls its discovery stream, so that the
+/// first request dispatched to an endpoint does not wait for it to connect.
+/// Endpoints that are not ready within the configured timeout are inserted
+/// anyway, so that an endpoint that never connects is handled by the
+/// balancer rather than being held indefinitely.
+pub struct Prewarm<D: Discover, A> {
+    inner: D,
+    timeout: Option<Duration>,
+    /// Endpoints that are warming, each with the deadline by which it is
+    /// inserted whether or not it is ready.
+    warming: Vec<(D::Key, D::Service, Delay)>,
+    /// Endpoints that have been inserted into the balancer and not yet
+    /// removed.
+    inserted: HashSet<D::Key>,
+    _marker: PhantomData<fn(A)>,
+}
+
+/// Identifies the endpoint that a balancer selected for a request.
+///
+/// Inserted into the extensions of each request that a balancer dispatches to
+/// an endpoint, so that the endpoint's stack (e.g. access logging) may record
+/// which endpoint served the request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SelectedEndpoint(pub SocketAddr);
+
+/// Wraps a `D`-typed discovery stream so that requests dispatched to each
+/// endpoint are tagged with the endpoint's `SelectedEndpoint`.
+///
+/// The target that each inserted endpoint was built for is recorded, so that
+/// `Recycle` can tell whether a re-added endpoint's target has changed.
+pub struct Tag<D: HasTargets> {
+    inner: D,
+    targets: Targets<SocketAddr, D::Target>,
+}
+
+/// An endpoint service that tags each request with its address.
+pub struct Tagged<S> {
+    addr: SocketAddr,
+    inner: S,
+}
+
+/// Indicates that an endpoint rate limit was configured with a rate that is
+/// not a positive, finite number of requests per second.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InvalidRateLimit(f64);
+
+/// Limits the rate of requests dispatched to an endpoint.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct RateLimit {
+    /// Requests per second.
+    rate: f64,
+    burst: f64,
+}
+
+/// Wraps a `D`-typed discovery stream so that, when a limit is configured,
+/// requests to each endpoint are rate limited.
+pub struct RateLimits<D> {
+    inner: D,
+    limit: Option<RateLimit>,
+}
+
+/// An endpoint service that is not ready while its rate limit is saturated.
+pub struct RateLimited<S> {
+    inner: S,
+    bucket: Option<TokenBucket>,
+}
+
+/// Admits a request for each available token. Tokens accrue at the limit's
+/// rate, up to its burst.
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+    /// Set while the endpoint waits for a token to become available.
+    delay: Option<Delay>,
+}
+
+/// An endpoint service that is returned to its `Recycle` when dropped.
+pub struct Recyclable<K: Hash + Eq + Clone, S, T> {
+    key: K,
+    target: Option<T>,
+    inner: Option<S>,
+    recycle: Option<(Weak<Mutex<Removed<K, S, T>>>, Duration)>,
+}
+
+/// Removed endpoint services, with the time at which each expires and the
+/// target that each was built for.
+type Removed<K, S, T> = HashMap<K, (S, Instant, Option<T>)>;
+
+/// The targets of the endpoints that are currently inserted into a balancer.
+type Targets<K, T> = Arc<Mutex<HashMap<K, T>>>;
+
+/// Resets the load estimates of balancers' endpoints, e.g. after an upstream
+/// deploy, so that stale estimates are discarded and endpoints' latencies are
+/// re-learned.
+///
+/// Clones share the same state, so a single `LoadReset` may reset many
+/// balancers.
+#[derive(Clone, Debug, Default)]
+pub struct LoadReset(Arc<AtomicUsize>);
+
+/// Wraps a `D`-typed discovery stream so that its endpoints' load estimates
+/// may be reset by a `LoadReset`.
+pub struct ResetLoad<D> {
+    inner: D,
+    reset: LoadReset,
+    default_rtt: Duration,
+    decay: Duration,
+}
+
+/// An endpoint whose load is estimated by its `S`-typed service until the
+/// load is reset, after which the endpoint's latency is re-learned, starting
+/// from the default RTT.
+///
+/// Latencies are re-learned with the same peak-EWMA as the inner estimate,
+/// but each request's latency is measured until its response is received.
+pub struct Resettable<S> {
+    inner: S,
+    reset: LoadReset,
+    default_rtt: Duration,
+    decay: Duration,
+    pending: InFlight,
+    relearned: Arc<Mutex<Relearned>>,
+}
+
+struct Relearned {
+    /// The reset generation that was last observed.
+    generation: usize,
+    /// The RTT estimate learned since the last reset, if the endpoint's load
+    /// has been reset since it was created.
+    estimate: Option<RttEstimate>,
+}
+
+struct RttEstimate {
+    rtt_ns: f64,
+    updated_at: Instant,
+}
+
+pub struct ResettableFuture<F> {
+    inner: F,
+    relearned: Arc<Mutex<Relearned>>,
+    generation: usize,
+    sent_at: Instant,
+    decay: Duration,
+    _pending: InFlightGuard,
+}
+
+/// Extracts a session affinity key from requests.
+///
+/// Requests with the same key are dispatched to the same endpoint for as long
+/// as that endpoint remains in the balancer.
+pub struct AffinityKey<A>(Arc<dyn Fn(&http::Request<A>) -> Option<u64> + Send + Sync>);
+
+/// An endpoint held by a `Sticky` balancer.
+struct Slot<S> {
+    inner: S,
+    /// Counts the requests that the balancer has dispatched to the endpoint
+    /// since the counts were last drained.
+    selected: AtomicUsize,
+}
+
+/// Chooses the endpoint that serves each affinity key.
+///
+/// Implementations must be deterministic, so that each key is served by the
+/// same endpoint for as long as the balancer's endpoints do not change.
+pub trait AffinityHash: fmt::Debug + Send + Sync {
+    /// Builds a table that maps keys to `endpoints`.
+    ///
+    /// `endpoints` are sorted and non-empty. A balancer only builds a table
+    /// when its endpoints change, so the cost of building a table is not
+    /// incurred by each request.
+    fn table(&self, endpoints: &[SocketAddr]) -> Box<dyn AffinityTable>;
+}
+
+/// Maps affinity keys to the endpoints from which the table was built.
+pub trait AffinityTable: Send + Sync {
+    /// Returns the address that serves `key`.
+    fn choose(&self, key: u64) -> SocketAddr;
+}
+
+/// Chooses the endpoint with the highest score for each key, so that a key
+/// only moves to another endpoint when its endpoint is removed or when a
+/// higher-scoring endpoint is added.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Rendezvous;
+
+/// Places each endpoint at several points on a hash ring, choosing the
+/// endpoint at the first point at or after each key.
+///
+/// Like `Rendezvous`, only the keys of removed endpoints (or keys taken over
+/// by added endpoints) are remapped, but choosing an endpoint costs time
+/// logarithmic in the number of points rather than linear in the number of
+/// endpoints.
+#[derive(Copy, Clone, Debug)]
+pub struct Ring {
+    replicas: usize,
+}
+
+/// An `AffinityTable` that scores every endpoint for each key.
+struct RendezvousTable(Vec<SocketAddr>);
+
+/// An `AffinityTable` that holds the points of a `Ring`, sorted.
+struct RingTable(Vec<(u64, SocketAddr)>);
+
+struct ModuloTable(Vec<SocketAddr>);
+
+/// Chooses endpoints by the key modulo the number of endpoints.
+///
+/// This is cheap, but most keys are remapped whenever the number of endpoints
+/// changes.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Modulo;
+
+/// Balances requests over the endpoints of a `D`-typed discovery stream.
+///
+/// Each request is dispatched to the less loaded of two ready endpoints,
+/// chosen at random, unless it has an affinity key: keyed requests are
+/// dispatched to a consistent endpoint, chosen by an `AffinityHash` over the
+/// balancer's current endpoints, whenever that endpoint is ready.
+///
+/// The balancer owns its endpoints, so that requests may be pinned to an
+/// endpoint without sharing it.
+pub struct Sticky<D: Discover, A> {
+    discover: D,
+    /// Endpoints that have been polled to readiness and not yet called.
+    ready: IndexMap<D::Key, Slot<D::Service>>,
+    unready: IndexMap<D::Key, Slot<D::Service>>,
+    /// The index of the ready endpoint that was chosen by `poll_ready` for
+    /// the next request.
+    next: Option<usize>,
     rng: SmallRng,
-    _marker: PhantomData<fn(A) -> B>,
+    affinity: Option<AffinityKey<A>>,
+    affinity_hash: Arc<dyn AffinityHash>,
+    /// Built from the endpoints when a keyed request is dispatched, and
+    /// discarded whenever the endpoints change.
+    affinity_table: Option<Box<dyn AffinityTable>>,
+    /// Set once an endpoint has been inserted.
+    initialized: bool,
+}
+
+/// A `D`-typed discovery stream whose endpoints are load-tracked and, if
+/// configured, wrapped with breakers.
+type Loaded<D, A, I> = breaker::Breakers<
+    Recycle<ResetLoad<PeakEwmaDiscover<Prewarm<Limited<D>, A>, I>>, <D as HasTargets>::Target>,
+>;
+
+type Limited<D> = RateLimits<Tag<D>>;
+
+type Balancer<D, A> = Sticky<D, A>;
+
+pub struct StickyFuture<F>(F);
+
+/// Implemented by targets that may be known to have exactly one endpoint
+/// (e.g. a pinned address), so that they need not be balanced.
+pub trait HasSingleEndpoint {
+    type Endpoint;
+
+    /// Returns the target's endpoint, if it has exactly one.
+    fn single_endpoint(&self) -> Option<Self::Endpoint>;
+}
+
+/// Implemented by discovery streams that know the target that each of their
+/// endpoints was built for, so that a removed endpoint is only recycled if it
+/// is re-added for an equal target.
+pub trait HasTargets {
+    type Target: Clone + PartialEq;
+
+    /// Returns the target that the endpoint at `addr` was most recently built
+    /// for, if it is known.
+    fn target(&self, addr: &SocketAddr) -> Option<&Self::Target>;
+}
+
+/// Composes a balancer stack with the endpoint stack that it balances over,
+/// so that targets with a single endpoint are served by the endpoint stack
+/// directly, without discovery or balancing.
+///
+/// Failures to build either service are returned, so that an outer fallback
+/// layer may still handle them.
+#[derive(Clone, Debug)]
+pub struct DirectLayer<L> {
+    balance: svc::Builder<L>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MakeDirect<B, E> {
+    balance: B,
+    endpoint: E,
+}
+
+pub enum DirectFuture<B, E> {
+    Balanced(B),
+    Direct(E),
+}
+
+/// Lists the endpoints that a balancer currently holds, e.g. so that they may
+/// be rendered by an admin handler.
+pub trait HasEndpoints {
+    /// Returns the addresses of the endpoints that have been inserted into
+    /// (and not yet removed from) the balancer, in sorted order.
+    fn endpoints(&self) -> Vec<SocketAddr>;
+
+    /// Describes whether the balancer holds endpoints and, if it does not,
+    /// whether it ever has.
+    fn endpoint_state(&self) -> EndpointState;
+
+    /// Returns the number of requests that the balancer has dispatched to
+    /// each of its endpoints since the counts were last drained, resetting
+    /// the counts to zero.
+    ///
+    /// Endpoints that are removed are no longer reported, and endpoints that
+    /// are re-added start counting from zero.
+    fn drain_counts(&self) -> HashMap<SocketAddr, usize>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EndpointState {
+    /// No endpoints have been discovered yet (e.g. because the target is
+    /// still being resolved), so requests should wait.
+    Initializing,
+    /// The balancer holds this many endpoints.
+    Ready(usize),
+    /// Endpoints were discovered but all of them have since been removed.
+    Drained,
+}
+
+/// Describes why a request to a balanced endpoint failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A connection to the endpoint could not be established.
+    Connect,
+    /// The endpoint's response could not be read (e.g. because it timed out).
+    Read,
+    /// The endpoint reset the connection or the stream.
+    Reset,
+    Other,
+}
+
+/// Classifies the failures of balanced endpoints, so that callers (e.g.
+/// retries) can decide how to handle them.
+pub trait HasErrorKind {
+    fn kind(&self) -> ErrorKind;
+}
+
+/// Describes how far a failed request got before it failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Disposition {
+    /// The request was not sent to an endpoint, e.g. because the balancer
+    /// had no endpoints or because a connection to the endpoint could not be
+    /// established, so it may be retried.
+    NotSent,
+    /// The request was dispatched to an endpoint, which may have processed
+    /// it, so it should only be retried if it is idempotent.
+    MaybeProcessed,
+}
+
+/// Indicates that a balanced request failed, and how far it got before it
+/// failed.
+///
+/// The underlying error is the error's source, so it is still classified by
+/// `HasErrorKind`.
+#[derive(Debug)]
+pub struct BalanceError {
+    disposition: Disposition,
+    inner: Error,
+}
+
+/// Describes a balancer's endpoints at a point in time, e.g. for a debugging
+/// endpoint.
+pub trait HasSnapshot {
+    fn snapshot(&self) -> BalancerSnapshot;
+}
+
+/// Describes the load and breaker of a balanced endpoint.
+pub trait Inspect {
+    /// Returns the endpoint's load estimate.
+    fn load_estimate(&self) -> f64;
+
+    /// Returns the state of the endpoint's breaker, if it has one.
+    fn breaker_state(&self) -> Option<breaker::BreakerState>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BalancerSnapshot {
+    pub state: EndpointState,
+    /// The balancer's endpoints, in order of their addresses.
+    pub endpoints: Vec<EndpointSnapshot>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EndpointSnapshot {
+    pub addr: SocketAddr,
+    /// The endpoint's load estimate.
+    ///
+    /// Without an affinity key, the balancer does not share its endpoints, so
+    /// the load and breaker state are as of the last time that the balancer
+    /// compared the endpoint's load with another endpoint's.
+    pub load: f64,
+    /// The number of requests that the balancer has dispatched to the
+    /// endpoint since its counts were last drained.
+    pub requests: usize,
+    pub breaker: Option<breaker::BreakerState>,
 }
 
 // === impl Layer ===
 
-pub fn layer<A, B>(default_rtt: Duration, decay: Duration) -> Layer<A, B> {
+pub fn layer<A, B, I>(default_rtt: Duration, decay: Duration, instrument: I) -> Layer<A, B, I> {
     Layer {
-        decay,
-        default_rtt,
-        rng: SmallRng::from_entropy(),
+        config: Config {
+            affinity: None,
+            affinity_hash: Arc::new(Rendezvous),
+            breaker: None,
+            deadline: None,
+            decay,
+            default_rtt,
+            empty: EmptyPolicy::default(),
+            instrument,
+            in_flight: InFlight::default(),
+            load_reset: LoadReset::default(),
+            max_ready_wait: None,
+            prewarm: None,
+            rate_limit: None,
+            rebuild_backoff: DEFAULT_REBUILD_BACKOFF,
+            recycle_ttl: None,
+            rng: SmallRng::from_entropy(),
+        },
         _marker: PhantomData,
     }
 }
 
-impl<A, B> Clone for Layer<A, B> {
+impl<A, B, I> Layer<A, B, I> {
+    /// Configures the amount of time to wait before rebuilding a balancer
+    /// whose discovery stream has failed.
+    pub fn with_rebuild_backoff(mut self, rebuild_backoff: Duration) -> Self {
+        self.config.rebuild_backoff = rebuild_backoff;
+        self
+    }
+
+    /// Configures the amount of time that a removed endpoint's load estimate
+    /// is retained, so that an endpoint that flaps out of and back into
+    /// discovery does not restart with a cold estimate.
+    ///
+    /// By default, removed endpoints are discarded immediately.
+    pub fn with_recycle_ttl(mut self, ttl: Duration) -> Self {
+        self.config.recycle_ttl = Some(ttl);
+        self
+    }
+
+    /// Configures newly-discovered endpoints to be driven to readiness before
+    /// they are inserted into the balancer. Endpoints that are not ready
+    /// within `timeout` are inserted anyway.
+    ///
+    /// By default, endpoints are inserted immediately and connect when the
+    /// balancer first considers them for a request.
+    pub fn with_prewarm(mut self, timeout: Duration) -> Self {
+        self.config.prewarm = Some(timeout);
+        self
+    }
+
+    /// Limits the rate of requests dispatched to each endpoint to `rate`
+    /// requests per second, allowing bursts of up to `burst` requests.
+    ///
+    /// Once an endpoint's limit is saturated, the endpoint is not ready until
+    /// the limit admits another request, so that the balancer dispatches
+    /// requests to other endpoints rather than to one that would reject them.
+    /// By default, endpoints are not rate limited.
+    ///
+    /// Fails if `rate` is not a positive, finite number.
+    pub fn with_endpoint_rate_limit(
+        mut self,
+        rate: f64,
+        burst: u32,
+    ) -> Result<Self, InvalidRateLimit> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err(InvalidRateLimit(rate));
+        }
+        self.config.rate_limit = Some(RateLimit {
+            rate,
+            burst: f64::from(burst.max(1)),
+        });
+        Ok(self)
+    }
+
+    /// Configures a key that pins requests to endpoints, e.g. so that each
+    /// client's session is served by a single endpoint.
+    ///
+    /// Requests that have no key are balanced over all endpoints.
+    pub fn with_affinity_key(mut self, key: AffinityKey<A>) -> Self {
+        self.config.affinity = Some(key);
+        self
+    }
+
+    /// Configures how affinity keys are mapped to endpoints.
+    ///
+    /// By default, endpoints are chosen by `Rendezvous` hashing.
+    pub fn with_affinity_hash<H: AffinityHash + 'static>(mut self, hash: H) -> Self {
+        self.config.affinity_hash = Arc::new(hash);
+        self
+    }
+
+    /// Configures how each request's deadline is determined.
+    ///
+    /// Requests whose deadlines have passed by the time an endpoint is ready
+    /// are not dispatched, and requests that do not complete by their
+    /// deadlines fail with `DeadlineExceeded`. By default, requests have no
+    /// deadline.
+    ///
+    /// A request's deadline is only known once the balancer is ready, so the
+    /// time that it waits for an endpoint is bounded by the buffer that holds
+    /// it (see `svc::Builder::buffer_pending`) or by `with_max_ready_wait`.
+    pub fn with_deadline(mut self, deadline: RequestDeadline<A>) -> Self {
+        self.config.deadline = Some(deadline);
+        self
+    }
+
+    /// Configures how requests are handled when the balancer has no
+    /// endpoints.
+    ///
+    /// By default, balancers fail open.
+    pub fn with_empty_policy(mut self, empty: EmptyPolicy) -> Self {
+        self.config.empty = empty;
+        self
+    }
+
+    /// Wraps each of the balancer's endpoints with a circuit breaker.
+    ///
+    /// Because these breakers wrap the endpoints' load estimates, endpoints
+    /// may be ramped back into service when their breakers close (see
+    /// `breaker::Layer::with_recovery_ramp`). By default, endpoints have no
+    /// breakers.
+    pub fn with_breaker(mut self, breaker: breaker::Layer) -> Self {
+        self.config.breaker = Some(breaker);
+        self
+    }
+
+    /// Configures the count of in-flight requests that balancers update.
+    ///
+    /// By default, each `Layer` has its own count.
+    pub fn with_in_flight(mut self, in_flight: InFlight) -> Self {
+        self.config.in_flight = in_flight;
+        self
+    }
+
+    pub fn in_flight(&self) -> &InFlight {
+        &self.config.in_flight
+    }
+
+    /// Configures the handle that resets balancers' load estimates.
+    ///
+    /// By default, each `Layer` has its own handle.
+    pub fn with_load_reset(mut self, load_reset: LoadReset) -> Self {
+        self.config.load_reset = load_reset;
+        self
+    }
+
+    pub fn load_reset(&self) -> &LoadReset {
+        &self.config.load_reset
+    }
+
+    /// Configures the maximum amount of time that a balancer may wait for
+    /// one of its endpoints to become ready.
+    ///
+    /// Once it elapses, requests fail with `ReadyTimeout` rather than waiting
+    /// indefinitely, until one of the balancer's endpoints is ready. If the
+    /// balancer fails open, it is also unavailable, so that requests may be
+    /// served by a fallback instead. This includes time spent rebuilding a
+    /// failed balancer. By default, balancers wait indefinitely.
+    pub fn with_max_ready_wait(mut self, max_ready_wait: Duration) -> Self {
+        self.config.max_ready_wait = Some(max_ready_wait);
+        self
+    }
+}
+
+impl<A, B, I: Clone> Clone for Layer<A, B, I> {
     fn clone(&self) -> Self {
         Self {
-            decay: self.decay,
-            default_rtt: self.default_rtt,
-            rng: self.rng.clone(),
+            config: self.config.clone(),
             _marker: PhantomData,
         }
     }
 }
 
-impl<M, A, B> svc::Layer<M> for Layer<A, B>
+impl<M, A, B, I> svc::Layer<M> for Layer<A, B, I>
 where
     A: Payload,
     B: Payload,
+    I: Clone,
 {
-    type Service = MakeSvc<M, A, B>;
+    type Service = MakeSvc<M, A, B, I>;
 
     fn layer(&self, inner: M) -> Self::Service {
         MakeSvc {
+            config: self.config.clone(),
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+// === impl Config ===
+
+impl<A, I: Clone> Clone for Config<A, I> {
+    fn clone(&self) -> Self {
+        Self {
+            affinity: self.affinity.clone(),
+            affinity_hash: self.affinity_hash.clone(),
+            breaker: self.breaker,
+            deadline: self.deadline.clone(),
             decay: self.decay,
             default_rtt: self.default_rtt,
-            inner,
+            empty: self.empty,
+            instrument: self.instrument.clone(),
+            in_flight: self.in_flight.clone(),
+            load_reset: self.load_reset.clone(),
+            max_ready_wait: self.max_ready_wait,
+            prewarm: self.prewarm,
+            rate_limit: self.rate_limit,
+            rebuild_backoff: self.rebuild_backoff,
+            recycle_ttl: self.recycle_ttl,
             rng: self.rng.clone(),
-            _marker: PhantomData,
         }
     }
 }
 
 // === impl MakeSvc ===
 
-impl<M: Clone, A, B> Clone for MakeSvc<M, A, B> {
+impl<M: Clone, A, B, I: Clone> Clone for MakeSvc<M, A, B, I> {
     fn clone(&self) -> Self {
         MakeSvc {
-            decay: self.decay,
-            default_rtt: self.default_rtt,
+            config: self.config.clone(),
             inner: self.inner.clone(),
-            rng: self.rng.clone(),
             _marker: PhantomData,
         }
     }
 }
 
-impl<T, M, A, B> svc::Service<T> for MakeSvc<M, A, B>
+impl<M, A, B, I> MakeSvc<M, A, B, I> {
+    pub fn in_flight(&self) -> &InFlight {
+        &self.config.in_flight
+    }
+}
+
+impl<M, A, B, I: Clone> MakeSvc<M, A, B, I> {
+    fn make_balancer<T>(&mut self, target: T) -> MakeSvc<M::Future, A, B, I>
+    where
+        M: svc::Service<T>,
+    {
+        MakeSvc {
+            config: self.config.clone(),
+            inner: self.inner.call(target),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, M, A, B, I> svc::Service<T> for MakeSvc<M, A, B, I>
 where
-    M: svc::Service<T>,
-    M::Response: Discover,
+    T: Clone,
+    M: svc::Service<T> + Clone,
+    M::Response: Discover<Key = SocketAddr> + HasTargets,
     <M::Response as Discover>::Service:
         svc::Service<http::Request<A>, Response = http::Response<B>>,
+    M::Error: Into<Error>,
     A: Payload,
     B: Payload,
-    Balance<PeakEwmaDiscover<M::Response, PendingUntilFirstData>, http::Request<A>>:
-        svc::Service<http::Request<A>>,
+    I: Clone,
+    Balancer<Loaded<M::Response, A, I>, A>: svc::Service<http::Request<A>>,
 {
-    type Response = Balance<PeakEwmaDiscover<M::Response, PendingUntilFirstData>, http::Request<A>>;
-    type Error = M::Error;
-    type Future = MakeSvc<M::Future, A, B>;
+    type Response = Service<
+        MakeSvc<M, A, B, I>,
+        T,
+        MakeSvc<M::Future, A, B, I>,
+        Balancer<Loaded<M::Response, A, I>, A>,
+    >;
+    type Error = Error;
+    type Future = MakeFuture<MakeSvc<M::Future, A, B, I>, MakeSvc<M, A, B, I>, T>;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        self.inner.poll_ready()
+        self.inner.poll_ready().map_err(Into::into)
     }
 
     fn call(&mut self, target: T) -> Self::Future {
-        let inner = self.inner.call(target);
+        let inner = self.make_balancer(target.clone());
 
-        MakeSvc {
-            decay: self.decay,
-            default_rtt: self.default_rtt,
+        MakeFuture {
             inner,
-            rng: self.rng.clone(),
-            _marker: PhantomData,
+            rebuild: Some((self.clone(), target)),
         }
     }
 }
 
-impl<F, A, B> Future for MakeSvc<F, A, B>
+impl<F, A, B, I> Future for MakeSvc<F, A, B, I>
 where
     F: Future,
-    F::Item: Discover,
+    F::Item: Discover<Key = SocketAddr> + HasTargets,
     <F::Item as Discover>::Service: svc::Service<http::Request<A>, Response = http::Response<B>>,
     A: Payload,
     B: Payload,
-    Balance<PeakEwmaDiscover<F::Item, PendingUntilFirstData>, http::Request<A>>:
-        svc::Service<http::Request<A>>,
+    I: Clone,
+    Balancer<Loaded<F::Item, A, I>, A>: svc::Service<http::Request<A>>,
 {
-    type Item = Balance<PeakEwmaDiscover<F::Item, PendingUntilFirstData>, http::Request<A>>;
+    type Item = Balancer<Loaded<F::Item, A, I>, A>;
     type Error = F::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let discover = try_ready!(self.inner.poll());
-        let instrument = PendingUntilFirstData::default();
-        let loaded = PeakEwmaDiscover::new(discover, self.default_rtt, self.decay, instrument);
-        let balance = Balance::new(loaded, self.rng.clone());
-        Ok(Async::Ready(balance))
+        let config = &self.config;
+
+        let targets = Targets::default();
+        let discover = RateLimits {
+            inner: Tag {
+                inner: discover,
+                targets: targets.clone(),
+            },
+            limit: config.rate_limit,
+        };
+        let discover = Prewarm::new(discover, config.prewarm);
+        let instrument = config.instrument.clone();
+        let loaded = PeakEwmaDiscover::new(discover, config.default_rtt, config.decay, instrument);
+        let loaded = ResetLoad {
+            inner: loaded,
+            reset: config.load_reset.clone(),
+            default_rtt: config.default_rtt,
+            decay: config.decay,
+        };
+        let recycled = Recycle::new(loaded, config.recycle_ttl, targets);
+        let broken = breaker::Breakers::new(recycled, config.breaker);
+
+        Ok(Async::Ready(Sticky::new(
+            broken,
+            config.rng.clone(),
+            config.affinity.clone(),
+            config.affinity_hash.clone(),
+        )))
+    }
+}
+
+// === impl MakeFuture ===
+
+impl<F, M, T> Future for MakeFuture<F, M, T>
+where
+    F: Future,
+    F::Error: Into<Error>,
+{
+    type Item = Service<M, T, F, F::Item>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // The inner future has already completed, so it must not be polled.
+        if self.rebuild.is_none() {
+            warn!("balancer polled after it was built");
+            return Err(PolledAfterReady.into());
+        }
+
+        let balance = try_ready!(self.inner.poll().map_err(Into::into));
+        let (make, target) = self.rebuild.take().expect("checked above");
+        Ok(Async::Ready(Service {
+            make,
+            target,
+            state: State::Ready(balance),
+            ready_wait: None,
+        }))
+    }
+}
+
+// === impl Service ===
+
+impl<T, M, A, B, I, S> svc::Service<http::Request<A>>
+    for Service<MakeSvc<M, A, B, I>, T, MakeSvc<M::Future, A, B, I>, S>
+where
+    T: Clone,
+    M: svc::Service<T>,
+    M::Error: Into<Error>,
+    I: Clone,
+    MakeSvc<M::Future, A, B, I>: Future<Item = S>,
+    <MakeSvc<M::Future, A, B, I> as Future>::Error: Into<Error>,
+    S: svc::Service<http::Request<A>> + HasEndpoints,
+    S::Error: Into<Error>,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        loop {
+            self.state = match self.state {
+                // A drained balancer never becomes ready, so requests must
+                // be admitted in order to be rejected.
+                State::Ready(ref balance) if rejects(self.make.config.empty, balance) => {
+                    self.ready_wait = None;
+                    return Ok(Async::Ready(()));
+                }
+                State::Ready(ref mut balance) => match balance.poll_ready() {
+                    Ok(Async::Ready(())) => {
+                        self.ready_wait = None;
+                        return Ok(Async::Ready(()));
+                    }
+                    Ok(Async::NotReady) => {
+                        return poll_ready_wait(
+                            &mut self.ready_wait,
+                            self.make.config.max_ready_wait,
+                        );
+                    }
+                    Err(e) => {
+                        let e = e.into();
+                        warn!(
+                            "balancer failed: {}; rebuilding in {:?}",
+                            e, self.make.config.rebuild_backoff
+                        );
+                        State::Backoff(Delay::new(clock::now() + self.make.config.rebuild_backoff))
+                    }
+                },
+                State::Backoff(ref mut delay) => {
+                    let ready = delay.poll().map_err(Error::from)?.is_ready()
+                        && self.make.inner.poll_ready().map_err(Into::into)?.is_ready();
+                    if !ready {
+                        return poll_ready_wait(
+                            &mut self.ready_wait,
+                            self.make.config.max_ready_wait,
+                        );
+                    }
+                    debug!("rebuilding balancer");
+                    State::Rebuilding(self.make.make_balancer(self.target.clone()))
+                }
+                State::Rebuilding(ref mut future) => match future.poll() {
+                    Ok(Async::NotReady) => {
+                        return poll_ready_wait(
+                            &mut self.ready_wait,
+                            self.make.config.max_ready_wait,
+                        );
+                    }
+                    Ok(Async::Ready(balance)) => State::Ready(balance),
+                    Err(e) => {
+                        let e = e.into();
+                        warn!(
+                            "failed to rebuild balancer: {}; retrying in {:?}",
+                            e, self.make.config.rebuild_backoff
+                        );
+                        State::Backoff(Delay::new(clock::now() + self.make.config.rebuild_backoff))
+                    }
+                },
+            };
+        }
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let in_flight = self.make.config.in_flight.track();
+        // The request was only admitted because the maximum wait elapsed.
+        let timed_out = self.is_ready_timed_out();
+        let deadline = self
+            .make
+            .config
+            .deadline
+            .as_ref()
+            .and_then(|d| d.extract(&req));
+
+        match self.state {
+            State::Ready(ref mut balance) if !timed_out => {
+                dispatch_balanced(balance, self.make.config.empty, req, deadline, in_flight)
+            }
+            _ => {
+                debug!("balancer did not become ready in time; failing request");
+                ResponseFuture::rejected(ReadyTimeout.into(), in_flight)
+            }
+        }
+    }
+}
+
+/// Polls the time that a balancer has been waiting for its endpoints to
+/// become ready, starting it if necessary.
+///
+/// Once `max` elapses, the balancer is ready so that pending requests may be
+/// admitted in order to be rejected. The wait is only reset once the balancer
+/// is ready.
+fn poll_ready_wait(wait: &mut Option<Delay>, max: Option<Duration>) -> Poll<(), Error> {
+    let max = match max {
+        Some(max) => max,
+        None => return Ok(Async::NotReady),
+    };
+    let delay = wait.get_or_insert_with(|| Delay::new(clock::now() + max));
+    try_ready!(delay.poll().map_err(Error::from));
+    debug!("balancer was not ready within {:?}", max);
+    Ok(Async::Ready(()))
+}
+
+/// Returns true if requests to `balance` must fail because it has no
+/// endpoints.
+fn rejects<S: HasEndpoints>(empty: EmptyPolicy, balance: &S) -> bool {
+    empty == EmptyPolicy::FailClosed && balance.endpoint_state() == EndpointState::Drained
+}
+
+/// Dispatches `req` to `balance`, unless the balancer has no endpoints and
+/// `empty` is `FailClosed`.
+fn dispatch_balanced<S, A>(
+    balance: &mut S,
+    empty: EmptyPolicy,
+    req: http::Request<A>,
+    deadline: Option<Instant>,
+    in_flight: InFlightGuard,
+) -> ResponseFuture<S::Future>
+where
+    S: svc::Service<http::Request<A>> + HasEndpoints,
+{
+    if rejects(empty, balance) {
+        debug!("balancer has no endpoints; failing request");
+        return ResponseFuture::rejected(NoEndpoints.into(), in_flight);
+    }
+
+    dispatch(balance, req, deadline, in_flight)
+}
+
+/// Dispatches `req` to `svc`, unless `deadline` has already passed.
+fn dispatch<S, A>(
+    svc: &mut S,
+    req: http::Request<A>,
+    deadline: Option<Instant>,
+    in_flight: InFlightGuard,
+) -> ResponseFuture<S::Future>
+where
+    S: svc::Service<http::Request<A>>,
+{
+    match deadline {
+        None => ResponseFuture::new(svc.call(req), in_flight),
+        Some(deadline) if deadline <= clock::now() => {
+            debug!("request deadline passed before dispatch");
+            ResponseFuture::rejected(DeadlineExceeded.into(), in_flight)
+        }
+        Some(deadline) => ResponseFuture {
+            deadline: Some(Delay::new(deadline)),
+            ..ResponseFuture::new(svc.call(req), in_flight)
+        },
+    }
+}
+
+impl<M, T, F, S, A, B, I> Service<MakeSvc<M, A, B, I>, T, F, S> {
+    /// Returns the count of requests in flight through this balancer.
+    pub fn in_flight(&self) -> &InFlight {
+        &self.make.config.in_flight
+    }
+}
+
+impl<M, T, F, S> Service<M, T, F, S> {
+    /// Returns true if the balancer has not been ready for the maximum wait.
+    fn is_ready_timed_out(&self) -> bool {
+        self.ready_wait
+            .as_ref()
+            .map(|wait| wait.deadline() <= clock::now())
+            .unwrap_or(false)
+    }
+}
+
+/// While the balancer is being rebuilt, it holds no endpoints and is
+/// initializing.
+impl<M, T, F, S: HasEndpoints> HasEndpoints for Service<M, T, F, S> {
+    fn endpoints(&self) -> Vec<SocketAddr> {
+        match self.state {
+            State::Ready(ref balance) => balance.endpoints(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn endpoint_state(&self) -> EndpointState {
+        match self.state {
+            State::Ready(ref balance) => balance.endpoint_state(),
+            _ => EndpointState::Initializing,
+        }
+    }
+
+    fn drain_counts(&self) -> HashMap<SocketAddr, usize> {
+        match self.state {
+            State::Ready(ref balance) => balance.drain_counts(),
+            _ => HashMap::new(),
+        }
+    }
+}
+
+/// A balancer is unavailable while it is being rebuilt and, if it fails open,
+/// once all of its endpoints have been removed or none have been ready for the
+/// maximum wait, so that an outer fallback layer may serve its requests.
+impl<M, T, F, S, A, B, I> fallback::Availability for Service<MakeSvc<M, A, B, I>, T, F, S>
+where
+    S: HasEndpoints,
+{
+    fn is_available(&self) -> bool {
+        match self.state {
+            State::Ready(_) if self.make.config.empty == EmptyPolicy::FailClosed => true,
+            State::Ready(ref balance) => {
+                balance.endpoint_state() != EndpointState::Drained && !self.is_ready_timed_out()
+            }
+            State::Backoff(_) | State::Rebuilding(_) => false,
+        }
+    }
+}
+
+impl<M, T, F, S: HasSnapshot> HasSnapshot for Service<M, T, F, S> {
+    fn snapshot(&self) -> BalancerSnapshot {
+        match self.state {
+            State::Ready(ref balance) => balance.snapshot(),
+            _ => BalancerSnapshot {
+                state: EndpointState::Initializing,
+                endpoints: Vec::new(),
+            },
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F> Future for ResponseFuture<F>
+where
+    F: Future,
+    F::Error: Into<Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let ready = match self.inner {
+            Some(ref mut inner) => match inner.poll() {
+                Ok(Async::NotReady) => match self.deadline {
+                    Some(ref mut d) => match d.poll().map_err(Error::from)? {
+                        Async::NotReady => return Ok(Async::NotReady),
+                        Async::Ready(()) => {
+                            let e = BalanceError::new(
+                                Disposition::MaybeProcessed,
+                                DeadlineExceeded.into(),
+                            );
+                            Err(e.into())
+                        }
+                    },
+                    None => return Ok(Async::NotReady),
+                },
+                Ok(ready) => Ok(ready),
+                Err(e) => Err(BalanceError::dispatched(e.into()).into()),
+            },
+            None => {
+                let e = self.rejected.take().expect("polled after ready");
+                Err(BalanceError::new(Disposition::NotSent, e).into())
+            }
+        };
+        // The request has completed (successfully or not), so it is no
+        // longer in flight.
+        self.inner = None;
+        self.in_flight.take();
+        ready
+    }
+}
+
+impl<F> ResponseFuture<F> {
+    fn new(inner: F, in_flight: InFlightGuard) -> Self {
+        Self {
+            inner: Some(inner),
+            rejected: None,
+            in_flight: Some(in_flight),
+            deadline: None,
+        }
+    }
+
+    /// Fails the request with `error` without dispatching it.
+    fn rejected(error: Error, in_flight: InFlightGuard) -> Self {
+        Self {
+            inner: None,
+            rejected: Some(error),
+            in_flight: Some(in_flight),
+            deadline: None,
+        }
+    }
+}
+
+// === impl RequestDeadline ===
+
+impl<A> RequestDeadline<A> {
+    pub fn new<F>(extract: F) -> Self
+    where
+        F: Fn(&http::Request<A>) -> Option<Instant> + Send + Sync + 'static,
+    {
+        RequestDeadline(Arc::new(extract))
+    }
+
+    fn extract(&self, req: &http::Request<A>) -> Option<Instant> {
+        (self.0)(req)
+    }
+}
+
+impl<A> Clone for RequestDeadline<A> {
+    fn clone(&self) -> Self {
+        RequestDeadline(self.0.clone())
+    }
+}
+
+impl<A> fmt::Debug for RequestDeadline<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("RequestDeadline").finish()
+    }
+}
+
+// === impl DeadlineExceeded ===
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the request did not complete before its deadline")
+    }
+}
+
+impl error::Error for DeadlineExceeded {}
+
+// === impl PolledAfterReady ===
+
+impl fmt::Display for PolledAfterReady {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the balancer was polled after it was built")
+    }
+}
+
+impl error::Error for PolledAfterReady {}
+
+// === impl BalanceError ===
+
+impl BalanceError {
+    fn new(disposition: Disposition, inner: Error) -> Self {
+        Self { disposition, inner }
+    }
+
+    /// Wraps an error returned by an endpoint to which a request was
+    /// dispatched.
+    ///
+    /// The request was not sent if the endpoint could not connect.
+    fn dispatched(inner: Error) -> Self {
+        let disposition = match inner.kind() {
+            ErrorKind::Connect => Disposition::NotSent,
+            _ => Disposition::MaybeProcessed,
+        };
+        Self::new(disposition, inner)
+    }
+
+    pub fn disposition(&self) -> Disposition {
+        self.disposition
+    }
+
+    /// Returns true if a `method` request that failed with this error may be
+    /// retried.
+    pub fn is_retryable(&self, method: &http::Method) -> bool {
+        self.disposition.is_retryable(method)
+    }
+}
+
+impl fmt::Display for BalanceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl error::Error for BalanceError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&*self.inner)
+    }
+}
+
+// === impl Disposition ===
+
+impl Disposition {
+    /// Returns true if a `method` request with this disposition may be
+    /// retried.
+    pub fn is_retryable(self, method: &http::Method) -> bool {
+        match self {
+            Disposition::NotSent => true,
+            Disposition::MaybeProcessed => is_idempotent(method),
+        }
+    }
+}
+
+/// Returns true if `method` is idempotent, per RFC 7231 section 4.2.2.
+fn is_idempotent(method: &http::Method) -> bool {
+    match *method {
+        http::Method::GET
+        | http::Method::HEAD
+        | http::Method::OPTIONS
+        | http::Method::TRACE
+        | http::Method::PUT
+        | http::Method::DELETE => true,
+        _ => false,
+    }
+}
+
+// === impl EmptyPolicy ===
+
+impl Default for EmptyPolicy {
+    fn default() -> Self {
+        EmptyPolicy::FailOpen
+    }
+}
+
+// === impl NoEndpoints ===
+
+impl fmt::Display for NoEndpoints {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the balancer has no endpoints")
+    }
+}
+
+impl error::Error for NoEndpoints {}
+
+// === impl InvalidRateLimit ===
+
+impl fmt::Display for InvalidRateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid endpoint rate limit: {} requests per second",
+            self.0
+        )
+    }
+}
+
+impl error::Error for InvalidRateLimit {}
+
+// === impl ReadyTimeout ===
+
+impl fmt::Display for ReadyTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the balancer's endpoints did not become ready in time")
+    }
+}
+
+impl error::Error for ReadyTimeout {}
+
+// === impl InFlight ===
+
+impl InFlight {
+    /// Returns the number of requests currently in flight.
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Returns a `Layer` that counts requests to services built by the inner
+    /// `MakeService`.
+    pub fn layer(&self) -> InFlightLayer {
+        InFlightLayer(self.clone())
+    }
+
+    fn track(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::AcqRel);
+        InFlightGuard(self.clone())
+    }
+}
+
+impl FmtMetrics for InFlight {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        balancer_requests_in_flight.fmt_help(f)?;
+        balancer_requests_in_flight.fmt_metric(f, Gauge::from(self.get() as u64))
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        (self.0).0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<M> svc::Layer<M> for InFlightLayer {
+    type Service = MakeInFlight<M>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        MakeInFlight {
+            in_flight: self.0.clone(),
+            inner,
+        }
+    }
+}
+
+impl<T, M: svc::Service<T>> svc::Service<T> for MakeInFlight<M> {
+    type Response = InFlightService<M::Response>;
+    type Error = M::Error;
+    type Future = MakeInFlight<M::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        MakeInFlight {
+            in_flight: self.in_flight.clone(),
+            inner: self.inner.call(target),
+        }
+    }
+}
+
+impl<F: Future> Future for MakeInFlight<F> {
+    type Item = InFlightService<F::Item>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(InFlightService {
+            in_flight: self.in_flight.clone(),
+            inner,
+        }))
+    }
+}
+
+impl<S, Req> svc::Service<Req> for InFlightService<S>
+where
+    S: svc::Service<Req>,
+    S::Error: Into<Error>,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready().map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        ResponseFuture::new(self.inner.call(req), self.in_flight.track())
+    }
+}
+
+// === impl Recycle ===
+
+impl<D: Discover, T> Recycle<D, T> {
+    fn new(inner: D, ttl: Option<Duration>, targets: Targets<D::Key, T>) -> Self {
+        Self {
+            inner,
+            ttl,
+            targets,
+            removed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<D, T> Discover for Recycle<D, T>
+where
+    D: Discover,
+    D::Key: Clone,
+    T: Clone + PartialEq,
+{
+    type Key = D::Key;
+    type Service = Recyclable<D::Key, D::Service, T>;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => {
+                let change = match try_ready!(self.inner.poll()) {
+                    Change::Insert(key, svc) => {
+                        Change::Insert(key.clone(), Recyclable::new(key, None, svc, None))
+                    }
+                    Change::Remove(key) => Change::Remove(key),
+                };
+                return Ok(Async::Ready(change));
+            }
+        };
+
+        // Expired endpoints are purged whenever the balancer polls for
+        // changes, so that they are not retained until the next change.
+        let now = clock::now();
+        self.removed
+            .lock()
+            .expect("recycled endpoints lock poisoned")
+            .retain(|_, entry| entry.1 > now);
+
+        let change = match try_ready!(self.inner.poll()) {
+            Change::Insert(key, svc) => {
+                let target = self
+                    .targets
+                    .lock()
+                    .expect("endpoint targets lock poisoned")
+                    .get(&key)
+                    .cloned();
+                let recycled = self
+                    .removed
+                    .lock()
+                    .expect("recycled endpoints lock poisoned")
+                    .remove(&key);
+                // A removed endpoint is only restored if it was built for the
+                // same target; otherwise, the newly-built service reflects the
+                // target's changes and the removed service is dropped.
+                let svc = match recycled {
+                    Some((recycled, _, recycled_target)) => {
+                        if recycled_target == target {
+                            debug!("restoring recently-removed endpoint");
+                            recycled
+                        } else {
+                            debug!("endpoint target changed; not restoring removed endpoint");
+                            svc
+                        }
+                    }
+                    None => svc,
+                };
+                let recycle = Some((Arc::downgrade(&self.removed), ttl));
+                Change::Insert(key.clone(), Recyclable::new(key, target, svc, recycle))
+            }
+            Change::Remove(key) => Change::Remove(key),
+        };
+        Ok(Async::Ready(change))
+    }
+}
+
+// === impl Prewarm ===
+
+impl<D: Discover, A> Prewarm<D, A> {
+    fn new(inner: D, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            warming: Vec::new(),
+            inserted: HashSet::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<D, A> Discover for Prewarm<D, A>
+where
+    D: Discover,
+    D::Key: Clone,
+    D::Service: svc::Service<http::Request<A>>,
+{
+    type Key = D::Key;
+    type Service = D::Service;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return self.inner.poll(),
+        };
+
+        while let Async::Ready(change) = self.inner.poll()? {
+            match change {
+                Change::Insert(key, svc) => {
+                    // A newer service replaces one that is still warming.
+                    self.warming.retain(|&(ref k, _, _)| *k != key);
+                    let deadline = Delay::new(clock::now() + timeout);
+                    self.warming.push((key, svc, deadline));
+                }
+                Change::Remove(key) => {
+                    self.warming.retain(|&(ref k, _, _)| *k != key);
+                    // An endpoint that was removed while it was warming was
+                    // never inserted, so the balancer has nothing to remove.
+                    if self.inserted.remove(&key) {
+                        return Ok(Async::Ready(Change::Remove(key)));
+                    }
+                    trace!("endpoint removed before it warmed");
+                }
+            }
+        }
+
+        for i in 0..self.warming.len() {
+            // Endpoints that fail are inserted so that the balancer handles
+            // the failure.
+            let warmed = match self.warming[i].1.poll_ready() {
+                Ok(Async::NotReady) => false,
+                _ => true,
+            };
+            if warmed {
+                trace!("endpoint warmed");
+            } else {
+                match self.warming[i].2.poll() {
+                    Ok(Async::NotReady) => continue,
+                    _ => debug!("endpoint did not warm before its deadline; inserting it"),
+                }
+            }
+            let (key, svc, _) = self.warming.swap_remove(i);
+            self.inserted.insert(key.clone());
+            return Ok(Async::Ready(Change::Insert(key, svc)));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+// === impl Tag ===
+
+impl<D> Discover for Tag<D>
+where
+    D: Discover<Key = SocketAddr> + HasTargets,
+{
+    type Key = SocketAddr;
+    type Service = Tagged<D::Service>;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let change = try_ready!(self.inner.poll());
+        let mut targets = self.targets.lock().expect("endpoint targets lock poisoned");
+        let change = match change {
+            Change::Insert(addr, inner) => {
+                if let Some(target) = self.inner.target(&addr) {
+                    targets.insert(addr, target.clone());
+                } else {
+                    targets.remove(&addr);
+                }
+                Change::Insert(addr, Tagged { addr, inner })
+            }
+            Change::Remove(addr) => {
+                targets.remove(&addr);
+                Change::Remove(addr)
+            }
+        };
+        Ok(Async::Ready(change))
+    }
+}
+
+// === impl Tagged ===
+
+impl<S, A> svc::Service<http::Request<A>> for Tagged<S>
+where
+    S: svc::Service<http::Request<A>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut req: http::Request<A>) -> Self::Future {
+        req.extensions_mut().insert(SelectedEndpoint(self.addr));
+        self.inner.call(req)
+    }
+}
+
+// === impl RateLimits ===
+
+impl<D: Discover> Discover for RateLimits<D> {
+    type Key = D::Key;
+    type Service = RateLimited<D::Service>;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let change = match try_ready!(self.inner.poll()) {
+            Change::Insert(key, inner) => {
+                let bucket = self.limit.map(TokenBucket::new);
+                Change::Insert(key, RateLimited { inner, bucket })
+            }
+            Change::Remove(key) => Change::Remove(key),
+        };
+        Ok(Async::Ready(change))
+    }
+}
+
+// === impl RateLimited ===
+
+impl<S, Req> svc::Service<Req> for RateLimited<S>
+where
+    S: svc::Service<Req>,
+    S::Error: Into<Error>,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = future::MapErr<S::Future, fn(S::Error) -> Error>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        if let Some(ref mut bucket) = self.bucket {
+            try_ready!(bucket.poll_token());
+        }
+        self.inner.poll_ready().map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        if let Some(ref mut bucket) = self.bucket {
+            bucket.tokens -= 1.0;
+        }
+        self.inner.call(req).map_err(Into::into as fn(_) -> _)
+    }
+}
+
+// === impl TokenBucket ===
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            refilled: clock::now(),
+            delay: None,
+        }
+    }
+
+    /// Polls until a token is available, without taking it.
+    ///
+    /// While no token is available, the task is notified once the next token
+    /// accrues.
+    fn poll_token(&mut self) -> Poll<(), Error> {
+        let now = clock::now();
+        let elapsed = now - self.refilled;
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.refilled = now;
+
+        if self.tokens < 1.0 {
+            // Tokens are only taken once one is available, so a delay that is
+            // already registered still expires when the next token accrues.
+            let (tokens, rate) = (self.tokens, self.limit.rate);
+            let delay = self.delay.get_or_insert_with(|| {
+                let wait = (1.0 - tokens) / rate;
+                let at = now + Duration::from_nanos((wait * 1e9).ceil() as u64);
+                trace!("endpoint rate limited for {:?}", at - now);
+                Delay::new(at)
+            });
+            try_ready!(delay.poll().map_err(Error::from));
+            // The token has accrued, even if rounding lost part of it.
+            self.tokens = self.tokens.max(1.0);
+        }
+
+        self.delay = None;
+        Ok(Async::Ready(()))
+    }
+}
+
+// === impl Recyclable ===
+
+impl<K: Hash + Eq + Clone, S, T> Recyclable<K, S, T> {
+    fn new(
+        key: K,
+        target: Option<T>,
+        inner: S,
+        recycle: Option<(Weak<Mutex<Removed<K, S, T>>>, Duration)>,
+    ) -> Self {
+        Self {
+            key,
+            target,
+            inner: Some(inner),
+            recycle,
+        }
+    }
+
+    fn inner(&self) -> &S {
+        self.inner.as_ref().expect("endpoint must not be recycled")
+    }
+
+    fn inner_mut(&mut self) -> &mut S {
+        self.inner.as_mut().expect("endpoint must not be recycled")
+    }
+}
+
+impl<K, S, T, Req> svc::Service<Req> for Recyclable<K, S, T>
+where
+    K: Hash + Eq + Clone,
+    S: svc::Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner_mut().poll_ready()
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.inner_mut().call(req)
+    }
+}
+
+impl<K: Hash + Eq + Clone, S: Load, T> Load for Recyclable<K, S, T> {
+    type Metric = S::Metric;
+
+    fn load(&self) -> Self::Metric {
+        self.inner().load()
+    }
+}
+
+impl<K: Hash + Eq + Clone, S, T> Drop for Recyclable<K, S, T> {
+    fn drop(&mut self) {
+        let (svc, (removed, ttl)) = match (self.inner.take(), self.recycle.take()) {
+            (Some(svc), Some(recycle)) => (svc, recycle),
+            _ => return,
+        };
+
+        // If the balancer has been dropped, there's nothing to recycle into.
+        if let Some(removed) = removed.upgrade() {
+            if let Ok(mut removed) = removed.lock() {
+                let expiry = clock::now() + ttl;
+                removed.insert(self.key.clone(), (svc, expiry, self.target.take()));
+            }
+        }
+    }
+}
+
+// === impl LoadReset ===
+
+impl LoadReset {
+    /// Resets the load estimate of each live endpoint to the default RTT.
+    ///
+    /// Endpoints are not rebuilt, so their connections are retained. Each
+    /// endpoint's latency is re-learned from requests dispatched after it was
+    /// reset.
+    pub fn reset_load(&self) {
+        self.0.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn generation(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+// === impl ResetLoad ===
+
+impl<D: Discover> Discover for ResetLoad<D> {
+    type Key = D::Key;
+    type Service = Resettable<D::Service>;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let change = match try_ready!(self.inner.poll()) {
+            Change::Insert(key, inner) => {
+                let relearned = Relearned {
+                    generation: self.reset.generation(),
+                    estimate: None,
+                };
+                let svc = Resettable {
+                    inner,
+                    reset: self.reset.clone(),
+                    default_rtt: self.default_rtt,
+                    decay: self.decay,
+                    pending: InFlight::default(),
+                    relearned: Arc::new(Mutex::new(relearned)),
+                };
+                Change::Insert(key, svc)
+            }
+            Change::Remove(key) => Change::Remove(key),
+        };
+        Ok(Async::Ready(change))
+    }
+}
+
+// === impl Resettable ===
+
+impl<S> Resettable<S> {
+    /// Returns the generation of the last reset, discarding the endpoint's
+    /// estimate if it has been reset since the estimate was learned.
+    fn observe_reset(&self, relearned: &mut Relearned) -> usize {
+        let generation = self.reset.generation();
+        if relearned.generation != generation {
+            relearned.generation = generation;
+            relearned.estimate = Some(RttEstimate {
+                rtt_ns: nanos(self.default_rtt),
+                updated_at: clock::now(),
+            });
+        }
+        generation
+    }
+}
+
+impl<S, Req> svc::Service<Req> for Resettable<S>
+where
+    S: svc::Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResettableFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let generation = {
+            let mut relearned = self.relearned.lock().expect("load lock poisoned");
+            self.observe_reset(&mut relearned)
+        };
+        ResettableFuture {
+            inner: self.inner.call(req),
+            relearned: self.relearned.clone(),
+            generation,
+            sent_at: clock::now(),
+            decay: self.decay,
+            _pending: self.pending.track(),
+        }
+    }
+}
+
+impl<S> Load for Resettable<S>
+where
+    S: Load,
+    S::Metric: Into<f64>,
+{
+    type Metric = f64;
+
+    fn load(&self) -> f64 {
+        let mut relearned = self.relearned.lock().expect("load lock poisoned");
+        self.observe_reset(&mut relearned);
+        match relearned.estimate {
+            Some(ref estimate) => estimate.rtt_ns * (self.pending.get() + 1) as f64,
+            None => self.inner.load().into(),
+        }
+    }
+}
+
+// === impl ResettableFuture ===
+
+impl<F: Future> Future for ResettableFuture<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rsp = try_ready!(self.inner.poll());
+
+        // Only requests dispatched since the last reset inform the estimate.
+        let mut relearned = self.relearned.lock().expect("load lock poisoned");
+        if relearned.generation == self.generation {
+            if let Some(ref mut estimate) = relearned.estimate {
+                estimate.update(clock::now() - self.sent_at, self.decay);
+            }
+        }
+
+        Ok(Async::Ready(rsp))
+    }
+}
+
+// === impl RttEstimate ===
+
+impl RttEstimate {
+    /// Updates the estimate with an observed RTT.
+    ///
+    /// RTTs above the estimate replace it immediately; lower RTTs are
+    /// averaged in, weighted by the time since the estimate was last updated.
+    fn update(&mut self, rtt: Duration, decay: Duration) {
+        let now = clock::now();
+        let rtt_ns = nanos(rtt);
+        if rtt_ns > self.rtt_ns {
+            self.rtt_ns = rtt_ns;
+        } else {
+            let elapsed = nanos(now - self.updated_at);
+            let recency = (-elapsed / nanos(decay)).exp();
+            self.rtt_ns = recency * self.rtt_ns + (1.0 - recency) * rtt_ns;
+        }
+        self.updated_at = now;
+    }
+}
+
+fn nanos(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1_000_000_000.0 + f64::from(d.subsec_nanos())
+}
+
+// === impl AffinityKey ===
+
+impl<A> AffinityKey<A> {
+    pub fn new<F>(extract: F) -> Self
+    where
+        F: Fn(&http::Request<A>) -> Option<u64> + Send + Sync + 'static,
+    {
+        AffinityKey(Arc::new(extract))
+    }
+
+    /// Uses the value of the `name` header as the affinity key.
+    pub fn header(name: http::header::HeaderName) -> Self {
+        Self::new(move |req| req.headers().get(&name).map(|v| hash(v.as_bytes())))
+    }
+
+    /// Uses the request's `K`-typed extension as the affinity key.
+    pub fn extension<K: Hash + Send + Sync + 'static>() -> Self {
+        Self::new(|req| req.extensions().get::<K>().map(hash))
+    }
+
+    fn extract(&self, req: &http::Request<A>) -> Option<u64> {
+        (self.0)(req)
+    }
+}
+
+impl<A> Clone for AffinityKey<A> {
+    fn clone(&self) -> Self {
+        AffinityKey(self.0.clone())
+    }
+}
+
+impl<A> fmt::Debug for AffinityKey<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AffinityKey").finish()
+    }
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// === impl Rendezvous ===
+
+impl AffinityHash for Rendezvous {
+    fn table(&self, endpoints: &[SocketAddr]) -> Box<dyn AffinityTable> {
+        Box::new(RendezvousTable(endpoints.to_vec()))
+    }
+}
+
+impl AffinityTable for RendezvousTable {
+    fn choose(&self, key: u64) -> SocketAddr {
+        *self
+            .0
+            .iter()
+            .max_by_key(|addr| hash(&(key, addr)))
+            .expect("endpoints must not be empty")
+    }
+}
+
+// === impl Ring ===
+
+impl Ring {
+    /// Places each endpoint at `replicas` points on the ring.
+    ///
+    /// More points spread keys more evenly over endpoints, but make choosing
+    /// an endpoint more expensive.
+    pub fn new(replicas: usize) -> Self {
+        Ring {
+            replicas: replicas.max(1),
+        }
+    }
+}
+
+impl Default for Ring {
+    fn default() -> Self {
+        Self::new(DEFAULT_RING_REPLICAS)
+    }
+}
+
+impl AffinityHash for Ring {
+    fn table(&self, endpoints: &[SocketAddr]) -> Box<dyn AffinityTable> {
+        let mut points = endpoints
+            .iter()
+            .flat_map(|addr| (0..self.replicas).map(move |i| (hash(&(addr, i)), *addr)))
+            .collect::<Vec<_>>();
+        points.sort();
+        Box::new(RingTable(points))
+    }
+}
+
+impl AffinityTable for RingTable {
+    fn choose(&self, key: u64) -> SocketAddr {
+        let point = hash(&key);
+        // The first point at or after the key's point, wrapping around to the
+        // start of the ring.
+        let i = match self.0.binary_search_by_key(&point, |&(p, _)| p) {
+            Ok(i) | Err(i) => i,
+        };
+        self.0.get(i).unwrap_or(&self.0[0]).1
+    }
+}
+
+// === impl Modulo ===
+
+impl AffinityHash for Modulo {
+    fn table(&self, endpoints: &[SocketAddr]) -> Box<dyn AffinityTable> {
+        Box::new(ModuloTable(endpoints.to_vec()))
+    }
+}
+
+impl AffinityTable for ModuloTable {
+    fn choose(&self, key: u64) -> SocketAddr {
+        self.0[(key % self.0.len() as u64) as usize]
+    }
+}
+
+// === impl Sticky ===
+
+impl<D: Discover, A> Sticky<D, A> {
+    fn new(
+        discover: D,
+        rng: SmallRng,
+        affinity: Option<AffinityKey<A>>,
+        affinity_hash: Arc<dyn AffinityHash>,
+    ) -> Self {
+        Self {
+            discover,
+            ready: IndexMap::new(),
+            unready: IndexMap::new(),
+            next: None,
+            rng,
+            affinity,
+            affinity_hash,
+            affinity_table: None,
+            initialized: false,
+        }
+    }
+
+    /// Applies changes from the discovery stream until it is not ready.
+    fn poll_discover(&mut self) -> Result<(), D::Error> {
+        while let Async::Ready(change) = self.discover.poll()? {
+            // The indices of ready endpoints change, and the affinity table
+            // no longer reflects the endpoints.
+            self.next = None;
+            self.affinity_table = None;
+            match change {
+                Change::Insert(key, inner) => {
+                    self.initialized = true;
+                    self.ready.swap_remove(&key);
+                    let slot = Slot {
+                        inner,
+                        selected: AtomicUsize::new(0),
+                    };
+                    self.unready.insert(key, slot);
+                }
+                Change::Remove(key) => {
+                    self.ready.swap_remove(&key);
+                    self.unready.swap_remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.ready.len() + self.unready.len()
+    }
+
+    fn slots(&self) -> impl Iterator<Item = (&D::Key, &Slot<D::Service>)> {
+        self.ready.iter().chain(self.unready.iter())
+    }
+}
+
+impl<D, A> Sticky<D, A>
+where
+    D: Discover<Key = SocketAddr>,
+    D::Service: svc::Service<http::Request<A>> + Load,
+    <D::Service as svc::Service<http::Request<A>>>::Error: Into<Error>,
+    <D::Service as Load>::Metric: PartialOrd,
+{
+    /// Polls each endpoint that is not ready, so that endpoints that become
+    /// ready may be chosen. Endpoints that fail are dropped.
+    fn promote_unready(&mut self) {
+        let mut i = 0;
+        while i < self.unready.len() {
+            let ready = match self.unready.get_index_mut(i) {
+                Some((_, slot)) => slot.inner.poll_ready(),
+                None => unreachable!("index must be in bounds"),
+            };
+            match ready {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(())) => {
+                    let (addr, slot) = self.unready.swap_remove_index(i).expect("in bounds");
+                    self.ready.insert(addr, slot);
+                }
+                Err(e) => {
+                    let (addr, _) = self.unready.swap_remove_index(i).expect("in bounds");
+                    let e: Error = e.into();
+                    debug!("dropping failed endpoint {}: {}", addr, e);
+                    self.affinity_table = None;
+                }
+            }
+        }
+    }
+
+    /// Chooses the less loaded of two ready endpoints, chosen at random.
+    fn p2c(&mut self) -> Option<usize> {
+        let len = self.ready.len();
+        if len < 2 {
+            return if len == 1 { Some(0) } else { None };
+        }
+
+        let a = self.rng.gen_range(0, len);
+        let mut b = self.rng.gen_range(0, len - 1);
+        if b >= a {
+            b += 1;
+        }
+        let load = |i| self.ready.get_index(i).expect("in bounds").1.inner.load();
+        if load(a) <= load(b) {
+            Some(a)
+        } else {
+            Some(b)
+        }
+    }
+
+    /// Returns the index of the ready endpoint that serves `key`, if it is
+    /// ready.
+    ///
+    /// The affinity table is only rebuilt if the endpoints have changed since
+    /// it was last built.
+    fn pinned(&mut self, key: u64) -> Option<usize> {
+        if self.ready.is_empty() {
+            return None;
+        }
+
+        if self.affinity_table.is_none() {
+            trace!("rebuilding affinity table");
+            let mut addrs = self.slots().map(|(addr, _)| *addr).collect::<Vec<_>>();
+            addrs.sort();
+            self.affinity_table = Some(self.affinity_hash.table(&addrs));
+        }
+
+        let addr = match self.affinity_table {
+            Some(ref table) => table.choose(key),
+            None => unreachable!("affinity table must be built"),
+        };
+        self.ready.get_full(&addr).map(|(i, _, _)| i)
+    }
+}
+
+impl<D, A> svc::Service<http::Request<A>> for Sticky<D, A>
+where
+    D: Discover<Key = SocketAddr>,
+    D::Error: Into<Error>,
+    D::Service: svc::Service<http::Request<A>> + Load,
+    <D::Service as svc::Service<http::Request<A>>>::Error: Into<Error>,
+    <D::Service as Load>::Metric: PartialOrd,
+{
+    type Response = <D::Service as svc::Service<http::Request<A>>>::Response;
+    type Error = Error;
+    type Future = StickyFuture<<D::Service as svc::Service<http::Request<A>>>::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.poll_discover().map_err(Into::into)?;
+        self.promote_unready();
+
+        self.next = self.p2c();
+        if self.next.is_none() {
+            trace!("no endpoints are ready");
+            return Ok(Async::NotReady);
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let next = self.next.take().expect("called before ready");
+
+        // A keyed request is dispatched to its endpoint if `poll_ready` found
+        // that endpoint to be ready. Otherwise, it is balanced.
+        let key = self.affinity.as_ref().and_then(|a| a.extract(&req));
+        let pinned = key.and_then(|key| self.pinned(key));
+        if key.is_some() && pinned.is_none() {
+            trace!("pinned endpoint is not ready; balancing request");
+        }
+
+        // The endpoint's readiness is consumed, so it must be polled again
+        // before it is chosen for another request.
+        let (addr, mut slot) = self
+            .ready
+            .swap_remove_index(pinned.unwrap_or(next))
+            .expect("ready endpoint must exist");
+        slot.selected.fetch_add(1, Ordering::Relaxed);
+        let future = slot.inner.call(req);
+        self.unready.insert(addr, slot);
+        StickyFuture(future)
+    }
+}
+
+impl<D: Discover<Key = SocketAddr>, A> HasEndpoints for Sticky<D, A> {
+    fn endpoints(&self) -> Vec<SocketAddr> {
+        let mut addrs = self.slots().map(|(addr, _)| *addr).collect::<Vec<_>>();
+        addrs.sort();
+        addrs
+    }
+
+    fn endpoint_state(&self) -> EndpointState {
+        let len = self.len();
+        if len > 0 {
+            EndpointState::Ready(len)
+        } else if self.initialized {
+            EndpointState::Drained
+        } else {
+            EndpointState::Initializing
+        }
+    }
+
+    fn drain_counts(&self) -> HashMap<SocketAddr, usize> {
+        self.slots()
+            .map(|(addr, s)| (*addr, s.selected.swap(0, Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+impl<D, A> HasSnapshot for Sticky<D, A>
+where
+    D: Discover<Key = SocketAddr>,
+    D::Service: Inspect,
+{
+    fn snapshot(&self) -> BalancerSnapshot {
+        let mut endpoints = self
+            .slots()
+            .map(|(addr, s)| EndpointSnapshot {
+                addr: *addr,
+                load: s.inner.load_estimate(),
+                requests: s.selected.load(Ordering::Relaxed),
+                breaker: s.inner.breaker_state(),
+            })
+            .collect::<Vec<_>>();
+        endpoints.sort_by_key(|e| e.addr);
+        BalancerSnapshot {
+            state: self.endpoint_state(),
+            endpoints,
+        }
+    }
+}
+
+// === impl StickyFuture ===
+
+impl<F> Future for StickyFuture<F>
+where
+    F: Future,
+    F::Error: Into<Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll().map_err(Into::into)
+    }
+}
+
+// === impl DirectLayer ===
+
+/// Serves targets with a single endpoint directly, and all others with the
+/// balancer stack built by `balance`.
+pub fn direct<L>(balance: svc::Builder<L>) -> DirectLayer<L> {
+    DirectLayer { balance }
+}
+
+impl<L, M> svc::Layer<M> for DirectLayer<L>
+where
+    L: svc::Layer<M> + Clone,
+    M: Clone,
+{
+    type Service = MakeDirect<L::Service, M>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        MakeDirect {
+            balance: self.balance.clone().service(inner.clone()),
+            endpoint: inner,
+        }
+    }
+}
+
+// === impl MakeDirect ===
+
+impl<T, B, E> svc::Service<T> for MakeDirect<B, E>
+where
+    T: HasSingleEndpoint,
+    B: svc::Service<T>,
+    B::Error: Into<Error>,
+    E: svc::Service<T::Endpoint>,
+    E::Error: Into<Error>,
+{
+    type Response = svc::Either<B::Response, E::Response>;
+    type Error = Error;
+    type Future = DirectFuture<B::Future, E::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        let balance = self.balance.poll_ready().map_err(Into::into)?;
+        let endpoint = self.endpoint.poll_ready().map_err(Into::into)?;
+        if balance.is_ready() && endpoint.is_ready() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        match target.single_endpoint() {
+            Some(endpoint) => {
+                debug!("target has a single endpoint; not balancing");
+                DirectFuture::Direct(self.endpoint.call(endpoint))
+            }
+            None => DirectFuture::Balanced(self.balance.call(target)),
+        }
+    }
+}
+
+// === impl DirectFuture ===
+
+impl<B, E> Future for DirectFuture<B, E>
+where
+    B: Future,
+    B::Error: Into<Error>,
+    E: Future,
+    E::Error: Into<Error>,
+{
+    type Item = svc::Either<B::Item, E::Item>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            DirectFuture::Balanced(ref mut f) => {
+                let balance = try_ready!(f.poll().map_err(Into::into));
+                Ok(Async::Ready(svc::Either::A(balance)))
+            }
+            DirectFuture::Direct(ref mut f) => {
+                let endpoint = try_ready!(f.poll().map_err(Into::into));
+                Ok(Async::Ready(svc::Either::B(endpoint)))
+            }
+        }
+    }
+}
+
+// === impl Inspect ===
+
+/// Reports the endpoint's load without any penalty for ramping back into
+/// service.
+impl<S> Inspect for breaker::Service<S>
+where
+    S: Load,
+    S::Metric: Into<f64>,
+{
+    fn load_estimate(&self) -> f64 {
+        self.get_ref().load().into()
+    }
+
+    fn breaker_state(&self) -> Option<breaker::BreakerState> {
+        breaker::Service::breaker_state(self)
+    }
+}
+
+// === impl BalancerSnapshot ===
+
+/// Serializes the snapshot as a struct, e.g. as the JSON object:
+///
+/// ```json
+/// {"state":"ready","endpoints":[
+///   {"addr":"10.1.1.1:8080","load":0.5,"requests":3,"breaker":"closed"}
+/// ]}
+/// ```
+impl Serialize for BalancerSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let state = match self.state {
+            EndpointState::Initializing => "initializing",
+            EndpointState::Ready(_) => "ready",
+            EndpointState::Drained => "drained",
+        };
+
+        let mut snapshot = serializer.serialize_struct("BalancerSnapshot", 2)?;
+        snapshot.serialize_field("state", state)?;
+        snapshot.serialize_field("endpoints", &self.endpoints)?;
+        snapshot.end()
+    }
+}
+
+// === impl EndpointSnapshot ===
+
+/// Serializes the snapshot as a struct. Loads that are infinite or NaN, which
+/// JSON cannot represent, are serialized as empty options.
+impl Serialize for EndpointSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let load = if self.load.is_finite() {
+            Some(self.load)
+        } else {
+            None
+        };
+        let breaker = self.breaker.map(|b| match b {
+            breaker::BreakerState::Closed => "closed",
+            breaker::BreakerState::Open => "open",
+            breaker::BreakerState::HalfOpen => "half-open",
+        });
+
+        let mut snapshot = serializer.serialize_struct("EndpointSnapshot", 4)?;
+        snapshot.serialize_field("addr", &self.addr)?;
+        snapshot.serialize_field("load", &load)?;
+        snapshot.serialize_field("requests", &self.requests)?;
+        snapshot.serialize_field("breaker", &breaker)?;
+        snapshot.end()
+    }
+}
+
+// === impl ErrorKind ===
+
+impl<'a> HasErrorKind for &'a (dyn error::Error + 'static) {
+    fn kind(&self) -> ErrorKind {
+        let mut cause = Some(*self);
+
+        // The outermost error that can be classified determines the kind.
+        while let Some(err) = cause {
+            if let Some(err) = err.downcast_ref::<hyper::Error>() {
+                if err.is_connect() {
+                    return ErrorKind::Connect;
+                }
+            } else if let Some(err) = err.downcast_ref::<::h2::Error>() {
+                if err.reason().is_some() {
+                    return ErrorKind::Reset;
+                }
+            } else if err.is::<Timedout>() || err.is::<DeadlineExceeded>() {
+                return ErrorKind::Read;
+            } else if let Some(err) = err.downcast_ref::<io::Error>() {
+                match err.kind() {
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::AddrNotAvailable => {
+                        return ErrorKind::Connect;
+                    }
+                    io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe => {
+                        return ErrorKind::Reset;
+                    }
+                    io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof => {
+                        return ErrorKind::Read;
+                    }
+                    _ => {}
+                }
+            }
+
+            cause = err.source();
+        }
+
+        ErrorKind::Other
+    }
+}
+
+impl HasErrorKind for Error {
+    fn kind(&self) -> ErrorKind {
+        (&**self as &(dyn error::Error + 'static)).kind()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tower_load::Instrument;
+    use super::*;
+    use hyper::Body;
+    use serde_json;
+    use std::sync::atomic::AtomicBool;
+    use svc::{Layer as _Layer, Service as _Service};
+
+    /// Counts the responses that it instruments.
+    #[derive(Clone, Default)]
+    struct Counting<I> {
+        inner: I,
+        instrumented: Arc<AtomicUsize>,
+    }
+
+    impl<H, V, I: Instrument<H, V>> Instrument<H, V> for Counting<I> {
+        type Output = I::Output;
+
+        fn instrument(&self, handle: H, value: V) -> I::Output {
+            self.instrumented.fetch_add(1, Ordering::SeqCst);
+            self.inner.instrument(handle, value)
+        }
+    }
+
+    #[test]
+    fn responses_are_tracked_by_the_selected_instrument() {
+        use tokio::runtime::current_thread::Runtime;
+
+        let eos = Counting::<PendingUntilEos>::default();
+        let discovered = Arc::new(Mutex::new(vec![(
+            SocketAddr::from(([10, 0, 0, 1], 8080)),
+            Observed::default(),
+        )]));
+        let mut make = layer::<Body, Body, _>(
+            Duration::from_millis(30),
+            Duration::from_secs(10),
+            eos.clone(),
+        )
+        .layer(svc::mk(move |_: ()| Ok::<_, Error>(Fixed(discovered.clone()))));
+
+        let mut rt = Runtime::new().expect("runtime");
+        let mut balance = rt.block_on(make.call(())).expect("balancer must be built");
+        for _ in 0..3 {
+            rt.block_on(::futures::future::poll_fn(|| balance.poll_ready()))
+                .expect("balancer must not fail");
+            rt.block_on(balance.call(http::Request::new(Body::empty())))
+                .expect("request must succeed");
+        }
+        assert_eq!(
+            eos.instrumented.load(Ordering::SeqCst),
+            3,
+            "every response must be instrumented"
+        );
+    }
+
+    #[test]
+    fn make_future_fails_when_polled_after_ready() {
+        let mut make = MakeFuture {
+            inner: ::futures::future::ok::<_, Error>(()),
+            rebuild: Some(((), ())),
+        };
+        match make.poll() {
+            Ok(Async::Ready(Service {
+                state: State::Ready(()),
+                ..
+            })) => {}
+            _ => panic!("expected a service"),
+        }
+
+        let err = make.poll().err().expect("polling again must fail");
+        assert!(err.is::<PolledAfterReady>(), "unexpected error: {}", err);
+    }
+
+    struct Changes<K, S = Endpoint>(::std::collections::VecDeque<Change<K, S>>);
+
+    struct Endpoint(usize);
+
+    impl<K: Hash + Eq, S> Discover for Changes<K, S> {
+        type Key = K;
+        type Service = S;
+        type Error = Error;
+
+        fn poll(&mut self) -> Poll<Change<K, S>, Error> {
+            match self.0.pop_front() {
+                Some(change) => Ok(Async::Ready(change)),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl Load for Endpoint {
+        type Metric = usize;
+
+        fn load(&self) -> usize {
+            self.0
+        }
+    }
+
+    fn flap(ttl: Option<Duration>) -> usize {
+        flap_targets(ttl, None, None)
+    }
+
+    /// Removes and re-adds an endpoint, whose target is `added` when it is
+    /// first inserted and `readded` when it is inserted again.
+    fn flap_targets(
+        ttl: Option<Duration>,
+        added: Option<&'static str>,
+        readded: Option<&'static str>,
+    ) -> usize {
+        let changes = vec![
+            Change::Insert(1, Endpoint(7)),
+            Change::Remove(1),
+            Change::Insert(1, Endpoint(0)),
+        ];
+        let targets = Targets::default();
+        let mut recycle =
+            Recycle::new(Changes(changes.into_iter().collect()), ttl, targets.clone());
+        let set_target = |target: Option<&'static str>| {
+            let mut targets = targets.lock().unwrap();
+            match target {
+                Some(target) => targets.insert(1, target),
+                None => targets.remove(&1),
+            };
+        };
+
+        set_target(added);
+        let endpoint = match recycle.poll() {
+            Ok(Async::Ready(Change::Insert(1, endpoint))) => endpoint,
+            _ => panic!("expected an insert"),
+        };
+        assert_eq!(endpoint.load(), 7);
+
+        match recycle.poll() {
+            Ok(Async::Ready(Change::Remove(1))) => {}
+            _ => panic!("expected a remove"),
+        }
+        // The balancer drops the endpoint when it is removed.
+        drop(endpoint);
+
+        set_target(readded);
+        match recycle.poll() {
+            Ok(Async::Ready(Change::Insert(1, endpoint))) => endpoint.load(),
+            _ => panic!("expected an insert"),
+        }
+    }
+
+    #[test]
+    fn expired_endpoints_are_purged_without_changes() {
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let changes = vec![Change::Insert(1, Endpoint(7)), Change::Remove(1)];
+            let mut recycle = Recycle::<_, ()>::new(
+                Changes(changes.into_iter().collect()),
+                Some(Duration::from_secs(60)),
+                Targets::default(),
+            );
+            let mut changes = Vec::new();
+            while let Ok(Async::Ready(change)) = recycle.poll() {
+                changes.push(change);
+            }
+            // The balancer drops the endpoint once it is removed.
+            drop(changes);
+            assert_eq!(recycle.removed.lock().unwrap().len(), 1);
+
+            time.advance(Duration::from_secs(61));
+            assert!(recycle.poll().expect("must not fail").is_not_ready());
+            assert!(
+                recycle.removed.lock().unwrap().is_empty(),
+                "expired endpoints must be purged when polled"
+            );
+        })
+    }
+
+    #[test]
+    fn readded_endpoint_retains_load_within_ttl() {
+        let load = flap(Some(Duration::from_secs(60)));
+        assert_eq!(load, 7, "the prior load estimate must be restored");
+    }
+
+    #[test]
+    fn readded_endpoint_retains_load_for_the_same_target() {
+        let ttl = Some(Duration::from_secs(60));
+        let load = flap_targets(ttl, Some("zone=east"), Some("zone=east"));
+        assert_eq!(load, 7, "the prior load estimate must be restored");
+    }
+
+    #[test]
+    fn readded_endpoint_starts_cold_when_its_target_changes() {
+        let ttl = Some(Duration::from_secs(60));
+        let load = flap_targets(ttl, Some("zone=east"), Some("zone=west"));
+        assert_eq!(
+            load, 0,
+            "an endpoint built for a prior target must not be restored"
+        );
+
+        let load = flap_targets(ttl, None, Some("zone=west"));
+        assert_eq!(
+            load, 0,
+            "an endpoint built for an unknown target must not be restored"
+        );
+    }
+
+    #[test]
+    fn readded_endpoint_starts_cold_after_ttl() {
+        let load = flap(Some(Duration::from_secs(0)));
+        assert_eq!(load, 0, "an expired endpoint must not be restored");
+    }
+
+    #[test]
+    fn readded_endpoint_starts_cold_without_ttl() {
+        let load = flap(None);
+        assert_eq!(load, 0, "endpoints must not be recycled by default");
+    }
+
+    /// An endpoint whose inner load estimate is fixed, and whose responses
+    /// take at least `RELEARNED_RTT`.
+    struct Estimated(f64);
+
+    const RELEARNED_RTT: Duration = Duration::from_millis(2);
+
+    impl svc::Service<()> for Estimated {
+        type Response = ();
+        type Error = ();
+        type Future = Box<dyn Future<Item = (), Error = ()>>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            Box::new(::futures::future::lazy(|| {
+                ::std::thread::sleep(RELEARNED_RTT);
+                Ok(())
+            }))
+        }
+    }
+
+    impl Load for Estimated {
+        type Metric = f64;
+
+        fn load(&self) -> f64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn reset_load_discards_estimates_until_relearned() {
+        let default_rtt = Duration::from_micros(100);
+        let reset = LoadReset::default();
+        let changes = vec![
+            Change::Insert(1, Estimated(1_000_000.0)),
+            Change::Insert(2, Estimated(50_000_000.0)),
+        ];
+        let mut discover = ResetLoad {
+            inner: Changes(changes.into_iter().collect()),
+            reset: reset.clone(),
+            default_rtt,
+            decay: Duration::from_secs(10),
+        };
+        let mut endpoint = || match discover.poll() {
+            Ok(Async::Ready(Change::Insert(_, endpoint))) => endpoint,
+            _ => panic!("expected an insert"),
+        };
+        let mut fast = endpoint();
+        let mut slow = endpoint();
+        assert!(slow.load() > fast.load(), "inner estimates must be used");
+
+        reset.reset_load();
+        assert_eq!(slow.load(), nanos(default_rtt));
+        assert_eq!(fast.load(), slow.load(), "reset endpoints must be neutral");
+
+        slow.call(()).wait().expect("request must succeed");
+        assert!(slow.load() >= nanos(RELEARNED_RTT), "latency must be re-learned");
+        assert!(slow.load() > fast.load());
+
+        // Requests dispatched before a reset do not inform the new estimate.
+        let rsp = fast.call(());
+        reset.reset_load();
+        assert_eq!(fast.load(), 2.0 * nanos(default_rtt), "pending requests must count");
+        rsp.wait().expect("request must succeed");
+        assert_eq!(fast.load(), nanos(default_rtt));
+        assert_eq!(slow.load(), fast.load());
+    }
+
+    #[test]
+    fn in_flight_counts_requests_until_they_complete() {
+        use futures::sync::oneshot;
+
+        let in_flight = InFlight::default();
+        let mut svc = InFlightService {
+            in_flight: in_flight.clone(),
+            inner: svc::mk(|rx: oneshot::Receiver<()>| rx),
+        };
+        assert_eq!(in_flight.get(), 0);
+
+        let (tx0, rx0) = oneshot::channel();
+        let mut rsp0 = svc.call(rx0);
+        let (tx1, rx1) = oneshot::channel::<()>();
+        let mut rsp1 = svc.call(rx1);
+        let (_tx2, rx2) = oneshot::channel::<()>();
+        let rsp2 = svc.call(rx2);
+        assert_eq!(in_flight.get(), 3);
+
+        tx0.send(()).unwrap();
+        assert!(rsp0.poll().expect("response must succeed").is_ready());
+        assert_eq!(in_flight.get(), 2, "successful responses must be counted");
+
+        drop(tx1);
+        assert!(rsp1.poll().is_err());
+        assert_eq!(in_flight.get(), 1, "failed responses must be counted");
+
+        drop(rsp2);
+        assert_eq!(in_flight.get(), 0, "canceled responses must be counted");
+    }
+
+    fn balance_error(err: &Error) -> &BalanceError {
+        err.downcast_ref::<BalanceError>()
+            .unwrap_or_else(|| panic!("unexpected error: {}", err))
+    }
+
+    /// Dispatches a request to an endpoint that fails with `kind`.
+    fn dispatch_failing(kind: io::ErrorKind) -> Error {
+        let mut svc = svc::mk(move |_: http::Request<()>| Err::<(), _>(io::Error::from(kind)));
+        let in_flight = InFlight::default();
+        let mut rsp = dispatch(&mut svc, http::Request::new(()), None, in_flight.track());
+        rsp.poll().expect_err("request must fail")
+    }
+
+    #[test]
+    fn connect_failures_were_not_sent() {
+        let err = dispatch_failing(io::ErrorKind::ConnectionRefused);
+        assert_eq!(err.kind(), ErrorKind::Connect, "errors must still be classified");
+
+        let err = balance_error(&err);
+        assert_eq!(err.disposition(), Disposition::NotSent);
+        assert!(err.is_retryable(&http::Method::GET));
+        assert!(err.is_retryable(&http::Method::POST));
+    }
+
+    #[test]
+    fn reset_responses_may_have_been_processed() {
+        let err = dispatch_failing(io::ErrorKind::ConnectionReset);
+        assert_eq!(err.kind(), ErrorKind::Reset, "errors must still be classified");
+
+        let err = balance_error(&err);
+        assert_eq!(err.disposition(), Disposition::MaybeProcessed);
+        assert!(err.is_retryable(&http::Method::GET));
+        assert!(err.is_retryable(&http::Method::PUT));
+        assert!(!err.is_retryable(&http::Method::POST));
+        assert!(!err.is_retryable(&http::Method::PATCH));
+    }
+
+    #[test]
+    fn requests_past_their_deadline_are_not_dispatched() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut svc = {
+            let calls = calls.clone();
+            svc::mk(move |_: http::Request<()>| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, Error>(())
+            })
+        };
+        let in_flight = InFlight::default();
+
+        let deadline = clock::now() - Duration::from_millis(1);
+        let mut rsp = dispatch(
+            &mut svc,
+            http::Request::new(()),
+            Some(deadline),
+            in_flight.track(),
+        );
+        let err = rsp.poll().expect_err("request must fail");
+        let err = balance_error(&err);
+        assert!(err.inner.is::<DeadlineExceeded>(), "unexpected error: {}", err);
+        assert_eq!(err.disposition(), Disposition::NotSent);
+        assert_eq!(calls.load(Ordering::SeqCst), 0, "request must not be dispatched");
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    #[test]
+    fn requests_fail_when_their_deadline_passes() {
+        use futures::sync::oneshot;
+        use tokio::runtime::current_thread::Runtime;
+
+        let (_tx, rx) = oneshot::channel::<()>();
+        let mut rx = Some(rx);
+        let mut svc = svc::mk(move |_: http::Request<()>| rx.take().expect("called once"));
+        let in_flight = InFlight::default();
+
+        let mut rt = Runtime::new().expect("runtime");
+        let deadline = clock::now() + Duration::from_millis(10);
+        let rsp = dispatch(
+            &mut svc,
+            http::Request::new(()),
+            Some(deadline),
+            in_flight.track(),
+        );
+        let err = rt.block_on(rsp).expect_err("request must fail");
+        assert_eq!(err.kind(), ErrorKind::Read);
+        let err = balance_error(&err);
+        assert!(err.inner.is::<DeadlineExceeded>(), "unexpected error: {}", err);
+        assert_eq!(err.disposition(), Disposition::MaybeProcessed);
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    impl svc::Service<http::Request<()>> for Endpoint {
+        type Response = usize;
+        type Error = Error;
+        type Future = ::futures::future::FutureResult<usize, Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            ::futures::future::ok(self.0)
+        }
+    }
+
+    fn send<S>(svc: &mut S, session: Option<&'static str>) -> usize
+    where
+        S: svc::Service<http::Request<()>, Response = usize>,
+        S::Error: fmt::Debug,
+    {
+        let ready = svc.poll_ready().expect("service must not fail");
+        assert!(ready.is_ready(), "service must be ready");
+        let mut req = http::Request::builder();
+        if let Some(session) = session {
+            req.header("x-session", session);
+        }
+        svc.call(req.body(()).unwrap()).wait().expect("request must succeed")
+    }
+
+    /// Counts the affinity tables that are built.
+    #[derive(Debug)]
+    struct CountTables(Arc<AtomicUsize>);
+
+    impl AffinityHash for CountTables {
+        fn table(&self, endpoints: &[SocketAddr]) -> Box<dyn AffinityTable> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Rendezvous.table(endpoints)
+        }
+    }
+
+    /// Returns a balancer without an affinity key, whose endpoints are
+    /// discovered as it is polled.
+    fn sticky() -> Sticky<Changes<SocketAddr>, ()> {
+        let changes = Changes(Default::default());
+        Sticky::new(
+            changes,
+            SmallRng::from_entropy(),
+            None,
+            Arc::new(Rendezvous),
+        )
+    }
+
+    /// Applies `changes` to the balancer.
+    fn discover(
+        sticky: &mut Sticky<Changes<SocketAddr>, ()>,
+        changes: Vec<Change<SocketAddr, Endpoint>>,
+    ) {
+        sticky.discover.0.extend(changes);
+        sticky.poll_ready().expect("balancer must not fail");
+    }
+
+    #[test]
+    fn affinity_pins_requests_until_endpoint_is_removed() {
+        let addr = |id: usize| SocketAddr::from(([127, 0, 0, 1], 8080 + id as u16));
+
+        let tables = Arc::new(AtomicUsize::new(0));
+        let changes = (1..=3).map(|id| Change::Insert(addr(id), Endpoint(id)));
+        let mut sticky = Sticky::new(
+            Changes(changes.collect()),
+            SmallRng::from_entropy(),
+            Some(AffinityKey::header(http::header::HeaderName::from_static(
+                "x-session",
+            ))),
+            Arc::new(CountTables(tables.clone())),
+        );
+
+        let balanced = send(&mut sticky, None);
+        assert!(
+            1 <= balanced && balanced <= 3,
+            "unexpected endpoint {}",
+            balanced
+        );
+
+        let pinned = send(&mut sticky, Some("a"));
+        for _ in 0..10 {
+            assert_eq!(send(&mut sticky, Some("a")), pinned, "requests must stick");
+        }
+        assert_eq!(
+            tables.load(Ordering::SeqCst),
+            1,
+            "the affinity table must only be built when endpoints change"
+        );
+
+        sticky.discover.0.push_back(Change::Remove(addr(pinned)));
+        let repinned = send(&mut sticky, Some("a"));
+        assert_ne!(repinned, pinned, "removed endpoints must not be used");
+        for _ in 0..10 {
+            assert_eq!(
+                send(&mut sticky, Some("a")),
+                repinned,
+                "requests must stick"
+            );
+        }
+        assert_eq!(tables.load(Ordering::SeqCst), 2);
+
+        for id in (1..=3).filter(|id| *id != pinned) {
+            sticky.discover.0.push_back(Change::Remove(addr(id)));
+        }
+        assert!(
+            sticky
+                .poll_ready()
+                .expect("balancer must not fail")
+                .is_not_ready(),
+            "balancers without endpoints must not be ready"
+        );
+        assert_eq!(sticky.endpoint_state(), EndpointState::Drained);
+    }
+
+    const KEYS: u64 = 1000;
+
+    /// Returns the keys' endpoints before and after each key that moved when
+    /// the endpoints changed from `before` to `after`.
+    fn remapped(
+        hash: &dyn AffinityHash,
+        before: &[SocketAddr],
+        after: &[SocketAddr],
+    ) -> Vec<(SocketAddr, SocketAddr)> {
+        let (before, after) = (hash.table(before), hash.table(after));
+        (0..KEYS)
+            .map(|k| super::hash(&k))
+            .map(|key| (before.choose(key), after.choose(key)))
+            .filter(|&(from, to)| from != to)
+            .collect()
+    }
+
+    fn ten_endpoints() -> Vec<SocketAddr> {
+        (0..10).map(|i| SocketAddr::from(([10, 0, 0, i], 8080))).collect()
+    }
+
+    #[test]
+    fn consistent_affinity_hashes_only_remap_changed_endpoints() {
+        let addrs = ten_endpoints();
+        let removed = addrs[3];
+        let without = addrs.iter().cloned().filter(|a| *a != removed).collect::<Vec<_>>();
+
+        let hashes: [&dyn AffinityHash; 2] = [&Rendezvous, &Ring::default()];
+        for hash in &hashes {
+            assert!(remapped(*hash, &addrs, &addrs).is_empty(), "{:?}", hash);
+
+            let moved = remapped(*hash, &addrs, &without);
+            assert!(!moved.is_empty(), "{:?} must remap the removed endpoint's keys", hash);
+            for (from, _) in moved {
+                assert_eq!(from, removed, "{:?} must only remap removed keys", hash);
+            }
+
+            let moved = remapped(*hash, &without, &addrs);
+            assert!(!moved.is_empty(), "{:?} must remap keys to the added endpoint", hash);
+            for (_, to) in moved {
+                assert_eq!(to, removed, "{:?} must only remap keys to added endpoints", hash);
+            }
+        }
+    }
+
+    #[test]
+    fn modulo_affinity_hash_remaps_most_keys() {
+        let addrs = ten_endpoints();
+        let without = &addrs[..9];
+        assert!(remapped(&Modulo, &addrs, &addrs).is_empty());
+        assert!(remapped(&Modulo, &addrs, without).len() as u64 > KEYS / 2);
+        assert!(remapped(&Modulo, without, &addrs).len() as u64 > KEYS / 2);
+    }
+
+    #[test]
+    fn endpoints_reflect_balancer_membership() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut sticky = sticky();
+        assert!(sticky.endpoints().is_empty());
+
+        discover(
+            &mut sticky,
+            vec![
+                Change::Insert(addr(8082), Endpoint(2)),
+                Change::Insert(addr(8081), Endpoint(1)),
+            ],
+        );
+        assert_eq!(sticky.endpoints(), vec![addr(8081), addr(8082)]);
+        discover(&mut sticky, vec![Change::Remove(addr(8082))]);
+        assert_eq!(sticky.endpoints(), vec![addr(8081)]);
+        discover(&mut sticky, vec![Change::Insert(addr(8083), Endpoint(3))]);
+        assert_eq!(sticky.endpoints(), vec![addr(8081), addr(8083)]);
+    }
+
+    #[test]
+    fn endpoint_state_distinguishes_initializing_from_drained() {
+        let mut sticky = sticky();
+        assert_eq!(sticky.endpoint_state(), EndpointState::Initializing);
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        discover(&mut sticky, vec![Change::Insert(addr, Endpoint(0))]);
+        assert_eq!(sticky.endpoint_state(), EndpointState::Ready(1));
+
+        discover(&mut sticky, vec![Change::Remove(addr)]);
+        assert_eq!(
+            sticky.endpoint_state(),
+            EndpointState::Drained,
+            "a balancer whose endpoints were all removed must not be initializing"
+        );
+    }
+
+    #[test]
+    fn drain_counts_reports_balanced_requests_per_endpoint() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut sticky = sticky();
+        discover(
+            &mut sticky,
+            vec![
+                Change::Insert(addr(8081), Endpoint(1)),
+                Change::Insert(addr(8082), Endpoint(2)),
+            ],
+        );
+
+        // Each endpoint responds with its ID.
+        let mut served = HashMap::new();
+        for _ in 0..20 {
+            let id = send(&mut sticky, None);
+            *served.entry(addr(8080 + id as u16)).or_insert(0) += 1;
+        }
+        let counts = sticky.drain_counts();
+        assert_eq!(counts.len(), 2);
+        for (addr, n) in &counts {
+            assert_eq!(*n, served.get(addr).cloned().unwrap_or(0), "{}", addr);
+        }
+        assert_eq!(counts.values().sum::<usize>(), 20);
+        assert!(
+            sticky.drain_counts().values().all(|n| *n == 0),
+            "draining must reset the counts"
+        );
+
+        send(&mut sticky, None);
+        discover(&mut sticky, vec![Change::Remove(addr(8082))]);
+        assert_eq!(sticky.drain_counts().get(&addr(8082)), None);
+
+        discover(&mut sticky, vec![Change::Insert(addr(8082), Endpoint(2))]);
+        assert_eq!(
+            sticky.drain_counts()[&addr(8082)],
+            0,
+            "re-added endpoints must start counting from zero"
+        );
+    }
+
+    /// A balancer whose endpoints have all been removed, which counts the
+    /// requests that it is called with.
+    struct Drained(Arc<AtomicUsize>);
+
+    impl svc::Service<http::Request<()>> for Drained {
+        type Response = usize;
+        type Error = Error;
+        type Future = ::futures::future::FutureResult<usize, Error>;
+
+        /// A drained balancer never becomes ready.
+        fn poll_ready(&mut self) -> Poll<(), Error> {
+            Ok(Async::NotReady)
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            ::futures::future::ok(0)
+        }
+    }
+
+    impl HasEndpoints for Drained {
+        fn endpoints(&self) -> Vec<SocketAddr> {
+            Vec::new()
+        }
+
+        fn endpoint_state(&self) -> EndpointState {
+            EndpointState::Drained
+        }
+
+        fn drain_counts(&self) -> HashMap<SocketAddr, usize> {
+            HashMap::new()
+        }
+    }
+
+    /// Returns a balancer whose only endpoint has been removed, and the count
+    /// of requests that it has balanced.
+    fn drained() -> (Drained, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        (Drained(calls.clone()), calls)
+    }
+
+    #[test]
+    fn fail_closed_rejects_requests_without_endpoints() {
+        let (mut balance, calls) = drained();
+        let in_flight = InFlight::default();
+
+        let mut rsp = dispatch_balanced(
+            &mut balance,
+            EmptyPolicy::FailClosed,
+            http::Request::new(()),
+            None,
+            in_flight.track(),
+        );
+        let err = rsp.poll().expect_err("request must fail");
+        let err = balance_error(&err);
+        assert!(err.inner.is::<NoEndpoints>(), "unexpected error: {}", err);
+        assert_eq!(err.disposition(), Disposition::NotSent);
+        assert_eq!(calls.load(Ordering::SeqCst), 0, "request must not be balanced");
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    #[test]
+    fn fail_open_balances_requests_without_endpoints() {
+        let (mut balance, calls) = drained();
+        let in_flight = InFlight::default();
+        assert_eq!(EmptyPolicy::default(), EmptyPolicy::FailOpen);
+
+        let mut rsp = dispatch_balanced(
+            &mut balance,
+            EmptyPolicy::FailOpen,
+            http::Request::new(()),
+            None,
+            in_flight.track(),
+        );
+        assert!(rsp.poll().expect("request must succeed").is_ready());
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "request must be balanced");
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    #[test]
+    fn fail_open_balancers_are_unavailable_without_endpoints() {
+        use proxy::http::fallback::Availability;
+
+        let balancer = |empty| {
+            let make = layer::<Body, Body, _>(
+                Duration::from_millis(30),
+                Duration::from_secs(10),
+                PendingUntilFirstData::default(),
+            )
+            .with_empty_policy(empty)
+            .layer(());
+            Service::<_, (), (), _> {
+                make,
+                target: (),
+                state: State::Ready(drained().0),
+                ready_wait: None,
+            }
+        };
+
+        assert!(
+            !balancer(EmptyPolicy::FailOpen).is_available(),
+            "requests must fall back"
+        );
+        assert!(
+            balancer(EmptyPolicy::FailClosed).is_available(),
+            "requests must be rejected by the balancer"
+        );
+    }
+
+    #[test]
+    fn fail_closed_checks_track_endpoints() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let changes = vec![
+            Change::Insert(addr(8080), Endpoint(0)),
+            Change::Insert(addr(8081), Endpoint(0)),
+            Change::Remove(addr(8080)),
+            Change::Remove(addr(8081)),
+            Change::Insert(addr(8080), Endpoint(0)),
+        ];
+        let expected = vec![
+            (EndpointState::Ready(1), false),
+            (EndpointState::Ready(2), false),
+            (EndpointState::Ready(1), false),
+            (EndpointState::Drained, true),
+            (EndpointState::Ready(1), false),
+        ];
+
+        let mut sticky = sticky();
+        assert_eq!(sticky.endpoint_state(), EndpointState::Initializing);
+        assert!(!rejects(EmptyPolicy::FailClosed, &sticky));
+        for (change, (state, rejected)) in changes.into_iter().zip(expected) {
+            discover(&mut sticky, vec![change]);
+            assert_eq!(sticky.endpoint_state(), state);
+            assert_eq!(rejects(EmptyPolicy::FailClosed, &sticky), rejected);
+            assert!(!rejects(EmptyPolicy::FailOpen, &sticky));
+        }
+    }
+
+    /// Measures the per-request `FailClosed` check. Run with
+    /// `cargo test --release balance -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_fail_closed_checks() {
+        const CHECKS: u32 = 10_000_000;
+
+        let changes = (0..100)
+            .map(|port| Change::Insert(SocketAddr::from(([127, 0, 0, 1], port)), Endpoint(0)))
+            .collect();
+        let mut sticky = sticky();
+        discover(&mut sticky, changes);
+        assert_eq!(sticky.endpoint_state(), EndpointState::Ready(100));
+
+        let start = Instant::now();
+        let mut rejected = 0;
+        for _ in 0..CHECKS {
+            if rejects(EmptyPolicy::FailClosed, &sticky) {
+                rejected += 1;
+            }
+        }
+        let elapsed = start.elapsed();
+        assert_eq!(rejected, 0);
+        println!(
+            "{} checks in {:?} ({:?} per check)",
+            CHECKS,
+            elapsed,
+            elapsed / CHECKS
+        );
+    }
+
+    /// An endpoint that never becomes ready, e.g. because it is saturated.
+    struct NeverReady;
+
+    impl svc::Service<http::Request<Body>> for NeverReady {
+        type Response = http::Response<Body>;
+        type Error = Error;
+        type Future = ::futures::future::FutureResult<http::Response<Body>, Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Error> {
+            Ok(Async::NotReady)
+        }
+
+        fn call(&mut self, _: http::Request<Body>) -> Self::Future {
+            panic!("endpoint is never ready");
+        }
+    }
+
+    /// Discovers a fixed set of endpoints that never become ready.
+    struct Unready(Vec<SocketAddr>);
+
+    impl Discover for Unready {
+        type Key = SocketAddr;
+        type Service = NeverReady;
+        type Error = Error;
+
+        fn poll(&mut self) -> Poll<Change<SocketAddr, NeverReady>, Error> {
+            match self.0.pop() {
+                Some(addr) => Ok(Async::Ready(Change::Insert(addr, NeverReady))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl HasTargets for Unready {
+        type Target = ();
+
+        fn target(&self, _: &SocketAddr) -> Option<&()> {
+            None
+        }
+    }
+
+    /// An endpoint that records the `SelectedEndpoint` of each request that it
+    /// serves.
+    #[derive(Clone, Default)]
+    struct Observed(Arc<Mutex<Vec<SelectedEndpoint>>>);
+
+    impl svc::Service<http::Request<Body>> for Observed {
+        type Response = http::Response<Body>;
+        type Error = Error;
+        type Future = ::futures::future::FutureResult<http::Response<Body>, Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+            let selected = req.extensions().get::<SelectedEndpoint>().cloned();
+            self.0
+                .lock()
+                .unwrap()
+                .push(selected.expect("request must be tagged"));
+            ::futures::future::ok(http::Response::new(Body::empty()))
+        }
+    }
+
+    /// Discovers the endpoints that are added to a shared list.
+    struct Fixed(Arc<Mutex<Vec<(SocketAddr, Observed)>>>);
+
+    impl Discover for Fixed {
+        type Key = SocketAddr;
+        type Service = Observed;
+        type Error = Error;
+
+        fn poll(&mut self) -> Poll<Change<SocketAddr, Observed>, Error> {
+            match self.0.lock().unwrap().pop() {
+                Some((addr, svc)) => Ok(Async::Ready(Change::Insert(addr, svc))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl HasTargets for Fixed {
+        type Target = ();
+
+        fn target(&self, _: &SocketAddr) -> Option<&()> {
+            None
+        }
+    }
+
+    #[test]
+    fn balanced_requests_are_tagged_with_their_endpoint() {
+        use tokio::runtime::current_thread::Runtime;
+
+        let endpoints = vec![
+            (SocketAddr::from(([10, 0, 0, 1], 8080)), Observed::default()),
+            (SocketAddr::from(([10, 0, 0, 2], 8080)), Observed::default()),
+        ];
+        let discovered = Arc::new(Mutex::new(endpoints.clone()));
+        let mut make = layer::<Body, Body, _>(
+            Duration::from_millis(30),
+            Duration::from_secs(10),
+            PendingUntilFirstData::default(),
+        )
+        .layer(svc::mk(move |_: ()| Ok::<_, Error>(Fixed(discovered.clone()))));
+
+        let mut rt = Runtime::new().expect("runtime");
+        let mut balance = rt.block_on(make.call(())).expect("balancer must be built");
+        for _ in 0..10 {
+            rt.block_on(::futures::future::poll_fn(|| balance.poll_ready()))
+                .expect("balancer must not fail");
+            rt.block_on(balance.call(http::Request::new(Body::empty())))
+                .expect("request must succeed");
+        }
+
+        let mut served = 0;
+        for (addr, observed) in &endpoints {
+            let selected = observed.0.lock().unwrap();
+            assert!(selected.iter().all(|s| *s == SelectedEndpoint(*addr)));
+            served += selected.len();
+        }
+        assert_eq!(served, 10, "every request must be tagged");
+    }
+
+    #[test]
+    fn rebuilding_balancers_are_unavailable() {
+        use proxy::http::fallback::Availability;
+        use tokio::runtime::current_thread::Runtime;
+
+        let discovered = Arc::new(Mutex::new(vec![(
+            SocketAddr::from(([10, 0, 0, 1], 8080)),
+            Observed::default(),
+        )]));
+        let mut make = layer::<Body, Body, _>(
+            Duration::from_millis(30),
+            Duration::from_secs(10),
+            PendingUntilFirstData::default(),
+        )
+        .layer(svc::mk(move |_: ()| Ok::<_, Error>(Fixed(discovered.clone()))));
+
+        let mut rt = Runtime::new().expect("runtime");
+        let mut balance = rt.block_on(make.call(())).expect("balancer must be built");
+
+        // As if the balancer's discovery stream had failed.
+        balance.state = State::Backoff(Delay::new(clock::now() + Duration::from_secs(60)));
+        assert!(!balance.is_available(), "backing off must be unavailable");
+
+        balance.state = State::Rebuilding(balance.make.make_balancer(()));
+        assert!(!balance.is_available(), "rebuilding must be unavailable");
+
+        rt.block_on(::futures::future::poll_fn(|| balance.poll_ready()))
+            .expect("balancer must not fail");
+        assert!(
+            balance.is_available(),
+            "the rebuilt balancer must be available"
+        );
+    }
+
+    #[test]
+    fn requests_shift_away_from_rate_limited_endpoints() {
+        use tokio::runtime::current_thread::Runtime;
+
+        const BURST: usize = 3;
+        let (a, b) = (Observed::default(), Observed::default());
+        let discovered = Arc::new(Mutex::new(vec![(
+            SocketAddr::from(([10, 0, 0, 1], 8080)),
+            a.clone(),
+        )]));
+        let mut make = layer::<Body, Body, _>(
+            Duration::from_millis(30),
+            Duration::from_secs(10),
+            PendingUntilFirstData::default(),
+        )
+        // Effectively, no tokens accrue during the test.
+        .with_endpoint_rate_limit(0.001, BURST as u32)
+        .expect("rate limit must be valid")
+        .layer({
+            let discovered = discovered.clone();
+            svc::mk(move |_: ()| Ok::<_, Error>(Fixed(discovered.clone())))
+        });
+
+        let mut rt = Runtime::new().expect("runtime");
+        let mut balance = rt.block_on(make.call(())).expect("balancer must be built");
+        let mut send = |rt: &mut Runtime| {
+            rt.block_on(::futures::future::poll_fn(|| balance.poll_ready()))
+                .expect("balancer must not fail");
+            rt.block_on(balance.call(http::Request::new(Body::empty())))
+                .expect("request must succeed");
+        };
+
+        for _ in 0..BURST {
+            send(&mut rt);
+        }
+        assert_eq!(a.0.lock().unwrap().len(), BURST);
+
+        // Once the first endpoint's limit is exhausted, another endpoint is
+        // discovered, and all requests are dispatched to it.
+        discovered
+            .lock()
+            .unwrap()
+            .push((SocketAddr::from(([10, 0, 0, 2], 8080)), b.clone()));
+        for _ in 0..BURST {
+            send(&mut rt);
+        }
+        assert_eq!(a.0.lock().unwrap().len(), BURST, "limited endpoint must be avoided");
+        assert_eq!(b.0.lock().unwrap().len(), BURST);
+    }
+
+    #[test]
+    fn invalid_endpoint_rate_limits_are_rejected() {
+        for &rate in &[0.0, -1.0, ::std::f64::NAN, ::std::f64::INFINITY] {
+            let layer = layer::<Body, Body, _>(
+                Duration::from_millis(30),
+                Duration::from_secs(10),
+                PendingUntilFirstData::default(),
+            );
+            assert!(
+                layer.with_endpoint_rate_limit(rate, 1).is_err(),
+                "rate {} must be rejected",
+                rate
+            );
+        }
+    }
+
+    #[test]
+    fn rate_limited_endpoints_wait_for_the_next_token() {
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let mut bucket = TokenBucket::new(RateLimit {
+                rate: 10.0,
+                burst: 1.0,
+            });
+            assert!(bucket.poll_token().expect("must not fail").is_ready());
+            bucket.tokens -= 1.0;
+
+            assert!(bucket.poll_token().expect("must not fail").is_not_ready());
+            assert!(bucket.delay.is_some(), "must wait for the next token");
+            time.advance(Duration::from_millis(50));
+            assert!(bucket.poll_token().expect("must not fail").is_not_ready());
+
+            time.advance(Duration::from_millis(50));
+            assert!(bucket.poll_token().expect("must not fail").is_ready());
+            assert!(bucket.delay.is_none());
+        })
+    }
+
+    #[test]
+    fn requests_fail_when_no_endpoint_becomes_ready_in_time() {
+        use tokio::runtime::current_thread::Runtime;
+
+        let max_wait = Duration::from_millis(10);
+        let addrs = vec![
+            SocketAddr::from(([10, 0, 0, 1], 8080)),
+            SocketAddr::from(([10, 0, 0, 2], 8080)),
+        ];
+        let mut make = layer::<Body, Body, _>(
+            Duration::from_millis(30),
+            Duration::from_secs(10),
+            PendingUntilFirstData::default(),
+        )
+        .with_max_ready_wait(max_wait)
+        .layer(svc::mk(move |_: ()| Ok::<_, Error>(Unready(addrs.clone()))));
+
+        let mut rt = Runtime::new().expect("runtime");
+        let mut balance = rt.block_on(make.call(())).expect("balancer must be built");
+
+        let started = clock::now();
+        rt.block_on(::futures::future::poll_fn(|| balance.poll_ready()))
+            .expect("balancer must not fail");
+        assert!(clock::now() - started >= max_wait, "balancer must wait");
+
+        let mut rsp = balance.call(http::Request::new(Body::empty()));
+        let err = rsp.poll().expect_err("request must fail");
+        let err = balance_error(&err);
+        assert!(err.inner.is::<ReadyTimeout>(), "unexpected error: {}", err);
+        assert_eq!(err.disposition(), Disposition::NotSent);
+        assert_eq!(balance.in_flight().get(), 0);
+
+        // Requests fail immediately until an endpoint becomes ready.
+        let ready = rt
+            .block_on(::futures::future::lazy(|| balance.poll_ready()))
+            .expect("balancer must not fail");
+        assert!(ready.is_ready(), "the wait must not restart");
+        let err = balance
+            .call(http::Request::new(Body::empty()))
+            .poll()
+            .expect_err("request must fail");
+        assert!(balance_error(&err).inner.is::<ReadyTimeout>());
+    }
+
+    #[test]
+    fn balancers_that_fail_open_are_unavailable_once_the_ready_wait_elapses() {
+        use proxy::http::fallback::Availability;
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let max_wait = Duration::from_secs(1);
+            let gated = Gated::default();
+            let mut make = layer::<Body, Body, _>(
+                Duration::from_millis(30),
+                Duration::from_secs(10),
+                PendingUntilFirstData::default(),
+            )
+            .with_max_ready_wait(max_wait)
+            .layer({
+                let gated = gated.clone();
+                svc::mk(move |_: ()| Ok::<_, Error>(Gates(Some(gated.clone()))))
+            });
+
+            let mut balance = match make.call(()).poll().expect("balancer must be built") {
+                Async::Ready(balance) => balance,
+                Async::NotReady => panic!("balancer must be built immediately"),
+            };
+            let ready = balance.poll_ready().expect("balancer must not fail");
+            assert!(ready.is_not_ready(), "balancer must wait for an endpoint");
+            assert!(
+                balance.is_available(),
+                "waiting balancers must be available"
+            );
+
+            time.advance(max_wait);
+            let ready = balance.poll_ready().expect("balancer must not fail");
+            assert!(ready.is_ready(), "requests must be admitted to be rejected");
+            assert!(!balance.is_available(), "requests must fall back");
+
+            gated.0.store(true, Ordering::SeqCst);
+            let ready = balance.poll_ready().expect("balancer must not fail");
+            assert!(ready.is_ready(), "the endpoint must be ready");
+            assert!(balance.is_available(), "the wait must be reset");
+            let rsp = balance
+                .call(http::Request::new(Body::empty()))
+                .poll()
+                .expect("request must succeed");
+            assert!(rsp.is_ready());
+        })
+    }
+
+    /// An endpoint that is ready once its gate is opened.
+    #[derive(Clone, Default)]
+    struct Gated(Arc<AtomicBool>);
+
+    impl svc::Service<http::Request<Body>> for Gated {
+        type Response = http::Response<Body>;
+        type Error = Error;
+        type Future = ::futures::future::FutureResult<http::Response<Body>, Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Error> {
+            if self.0.load(Ordering::SeqCst) {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+
+        fn call(&mut self, _: http::Request<Body>) -> Self::Future {
+            ::futures::future::ok(http::Response::new(Body::empty()))
+        }
+    }
+
+    /// Discovers a single gated endpoint.
+    struct Gates(Option<Gated>);
+
+    impl Discover for Gates {
+        type Key = SocketAddr;
+        type Service = Gated;
+        type Error = Error;
+
+        fn poll(&mut self) -> Poll<Change<SocketAddr, Gated>, Error> {
+            match self.0.take() {
+                Some(gated) => {
+                    let addr = SocketAddr::from(([10, 0, 0, 1], 8080));
+                    Ok(Async::Ready(Change::Insert(addr, gated)))
+                }
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl HasTargets for Gates {
+        type Target = ();
+
+        fn target(&self, _: &SocketAddr) -> Option<&()> {
+            None
+        }
+    }
+
+    #[test]
+    fn requests_past_their_deadline_once_an_endpoint_is_ready_are_rejected() {
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let gated = Gated::default();
+            let mut make = layer::<Body, Body, _>(
+                Duration::from_millis(30),
+                Duration::from_secs(10),
+                PendingUntilFirstData::default(),
+            )
+            .with_deadline(RequestDeadline::new(|req| {
+                req.extensions().get::<Instant>().cloned()
+            }))
+            .layer({
+                let gated = gated.clone();
+                svc::mk(move |_: ()| Ok::<_, Error>(Gates(Some(gated.clone()))))
+            });
+
+            let mut balance = match make.call(()).poll().expect("balancer must be built") {
+                Async::Ready(balance) => balance,
+                Async::NotReady => panic!("balancer must be built immediately"),
+            };
+
+            let ready = balance.poll_ready().expect("balancer must not fail");
+            assert!(
+                ready.is_not_ready(),
+                "requests must not be admitted until an endpoint is ready"
+            );
+
+            // The request's deadline passes while it waits for an endpoint.
+            let deadline = time.now() + Duration::from_millis(10);
+            time.advance(Duration::from_secs(1));
+            gated.0.store(true, Ordering::SeqCst);
+
+            let ready = balance.poll_ready().expect("balancer must not fail");
+            assert!(ready.is_ready(), "the endpoint must be ready");
+            let mut req = http::Request::new(Body::empty());
+            req.extensions_mut().insert(deadline);
+            let err = balance.call(req).poll().expect_err("request must fail");
+            let err = balance_error(&err);
+            assert!(
+                err.inner.is::<DeadlineExceeded>(),
+                "unexpected error: {}",
+                err
+            );
+            assert_eq!(err.disposition(), Disposition::NotSent);
+            assert_eq!(balance.in_flight().get(), 0);
+
+            let ready = balance.poll_ready().expect("balancer must not fail");
+            assert!(ready.is_ready(), "the balancer must remain ready");
+            let mut req = http::Request::new(Body::empty());
+            req.extensions_mut()
+                .insert(time.now() + Duration::from_secs(1));
+            let rsp = balance.call(req).poll().expect("request must succeed");
+            assert!(rsp.is_ready());
+        })
+    }
+
+    #[test]
+    fn requests_fail_when_a_balancer_is_not_rebuilt_in_time() {
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let max_wait = Duration::from_secs(1);
+            let backoff = Duration::from_secs(60);
+            let mut make = layer::<Body, Body, _>(
+                Duration::from_millis(30),
+                Duration::from_secs(10),
+                PendingUntilFirstData::default(),
+            )
+            .with_max_ready_wait(max_wait)
+            .with_rebuild_backoff(backoff)
+            .layer(svc::mk(|_: ()| Ok::<_, Error>(Unready(Vec::new()))));
+
+            let mut balance = match make.call(()).poll().expect("balancer must be built") {
+                Async::Ready(balance) => balance,
+                Async::NotReady => panic!("balancer must be built immediately"),
+            };
+            // As if the balancer's discovery stream had failed.
+            balance.state = State::Backoff(Delay::new(time.now() + backoff));
+
+            let ready = balance.poll_ready().expect("balancer must not fail");
+            assert!(ready.is_not_ready(), "balancer must wait to be rebuilt");
+
+            time.advance(max_wait);
+            let ready = balance.poll_ready().expect("balancer must not fail");
+            assert!(ready.is_ready(), "request must be admitted to be rejected");
+
+            let mut rsp = balance.call(http::Request::new(Body::empty()));
+            let err = rsp.poll().expect_err("request must fail");
+            let err = balance_error(&err);
+            assert!(err.inner.is::<ReadyTimeout>(), "unexpected error: {}", err);
+            assert_eq!(err.disposition(), Disposition::NotSent);
+        })
+    }
+
+    /// An endpoint that is ready once it has connected.
+    struct Connecting {
+        connected: Arc<::std::sync::atomic::AtomicBool>,
+        polls: Arc<AtomicUsize>,
+    }
+
+    impl svc::Service<http::Request<()>> for Connecting {
+        type Response = usize;
+        type Error = Error;
+        type Future = ::futures::future::FutureResult<usize, Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Error> {
+            self.polls.fetch_add(1, Ordering::SeqCst);
+            if self.connected.load(Ordering::SeqCst) {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            ::futures::future::ok(0)
+        }
+    }
+
+    const PREWARM_TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn connecting(connected: bool) -> Connecting {
+        Connecting {
+            connected: Arc::new(::std::sync::atomic::AtomicBool::new(connected)),
+            polls: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    #[test]
+    fn prewarmed_endpoints_are_inserted_once_ready() {
+        use task::test_util::mock_time;
+
+        mock_time(|_| {
+            let connected = Arc::new(::std::sync::atomic::AtomicBool::new(false));
+            let polls = Arc::new(AtomicUsize::new(0));
+            let changes = vec![Change::Insert(
+                1,
+                Connecting {
+                    connected: connected.clone(),
+                    polls: polls.clone(),
+                },
+            )];
+            let mut discover = Prewarm::<_, ()>::new(
+                Changes(changes.into_iter().collect()),
+                Some(PREWARM_TIMEOUT),
+            );
+
+            assert!(
+                discover.poll().unwrap().is_not_ready(),
+                "endpoints must not be inserted until they are ready"
+            );
+            assert_eq!(
+                polls.load(Ordering::SeqCst),
+                1,
+                "endpoints must start connecting when they are discovered"
+            );
+
+            connected.store(true, Ordering::SeqCst);
+            let mut endpoint = match discover.poll() {
+                Ok(Async::Ready(Change::Insert(1, endpoint))) => endpoint,
+                _ => panic!("expected an insert"),
+            };
+            // The first request does not wait for the endpoint to connect.
+            assert!(endpoint.poll_ready().unwrap().is_ready());
+            assert_eq!(polls.load(Ordering::SeqCst), 3);
+        })
+    }
+
+    #[test]
+    fn prewarming_endpoints_are_inserted_once_the_timeout_elapses() {
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let changes = vec![Change::Insert(1, connecting(false))];
+            let mut discover = Prewarm::<_, ()>::new(
+                Changes(changes.into_iter().collect()),
+                Some(PREWARM_TIMEOUT),
+            );
+            assert!(discover.poll().unwrap().is_not_ready());
+
+            time.advance(PREWARM_TIMEOUT - Duration::from_millis(1));
+            assert!(
+                discover.poll().unwrap().is_not_ready(),
+                "endpoints must not be inserted before the timeout elapses"
+            );
+
+            time.advance(Duration::from_millis(1));
+            match discover.poll() {
+                Ok(Async::Ready(Change::Insert(1, _))) => {}
+                _ => panic!("endpoints must be inserted once the timeout elapses"),
+            }
+        })
+    }
+
+    #[test]
+    fn endpoints_removed_while_prewarming_are_not_removed_from_the_balancer() {
+        use task::test_util::mock_time;
+
+        mock_time(|_| {
+            let changes = vec![
+                Change::Insert(1, connecting(false)),
+                Change::Remove(1),
+                Change::Insert(2, connecting(true)),
+                Change::Remove(2),
+            ];
+            let mut discover = Prewarm::<_, ()>::new(
+                Changes(changes.into_iter().collect()),
+                Some(PREWARM_TIMEOUT),
+            );
+
+            let mut changes = Vec::new();
+            while let Ok(Async::Ready(change)) = discover.poll() {
+                changes.push(match change {
+                    Change::Insert(key, _) => Change::Insert(key, ()),
+                    Change::Remove(key) => Change::Remove(key),
+                });
+            }
+            assert!(
+                changes.is_empty(),
+                "endpoints that never warmed must not be inserted or removed"
+            );
+            assert!(discover.warming.is_empty());
+        })
+    }
+
+    #[test]
+    fn endpoints_removed_while_a_replacement_prewarms_are_removed() {
+        use task::test_util::mock_time;
+
+        mock_time(|_| {
+            let changes = vec![Change::Insert(1, connecting(true))];
+            let mut discover = Prewarm::<_, ()>::new(
+                Changes(changes.into_iter().collect()),
+                Some(PREWARM_TIMEOUT),
+            );
+            match discover.poll() {
+                Ok(Async::Ready(Change::Insert(1, _))) => {}
+                _ => panic!("expected an insert"),
+            }
+
+            // The endpoint is replaced by a service that is still warming
+            // when the endpoint is removed.
+            discover
+                .inner
+                .0
+                .push_back(Change::Insert(1, connecting(false)));
+            discover.inner.0.push_back(Change::Remove(1));
+            match discover.poll() {
+                Ok(Async::Ready(Change::Remove(1))) => {}
+                _ => panic!("the inserted endpoint must be removed"),
+            }
+            assert!(discover.warming.is_empty());
+        })
+    }
+
+    #[test]
+    fn endpoints_are_inserted_immediately_without_prewarm() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let changes = vec![Change::Insert(
+            1,
+            Connecting {
+                connected: Arc::new(::std::sync::atomic::AtomicBool::new(false)),
+                polls: polls.clone(),
+            },
+        )];
+        let mut discover = Prewarm::<_, ()>::new(Changes(changes.into_iter().collect()), None);
+        match discover.poll() {
+            Ok(Async::Ready(Change::Insert(1, _))) => {}
+            _ => panic!("expected an insert"),
+        }
+        assert_eq!(polls.load(Ordering::SeqCst), 0);
+    }
+
+    /// A target that has a single endpoint if `pinned` is set.
+    #[derive(Clone, Debug)]
+    struct MaybePinned {
+        pinned: Option<SocketAddr>,
+    }
+
+    impl HasSingleEndpoint for MaybePinned {
+        type Endpoint = SocketAddr;
+
+        fn single_endpoint(&self) -> Option<SocketAddr> {
+            self.pinned
+        }
+    }
+
+    #[test]
+    fn single_endpoint_targets_are_not_balanced() {
+        let balancers = Arc::new(AtomicUsize::new(0));
+        let endpoints = Arc::new(Mutex::new(Vec::new()));
+        let mut make = MakeDirect {
+            balance: {
+                let balancers = balancers.clone();
+                svc::mk(move |_: MaybePinned| {
+                    balancers.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, Error>("balanced")
+                })
+            },
+            endpoint: {
+                let endpoints = endpoints.clone();
+                svc::mk(move |addr: SocketAddr| {
+                    endpoints.lock().unwrap().push(addr);
+                    Ok::<_, Error>("direct")
+                })
+            },
+        };
+
+        let addr = SocketAddr::from(([10, 0, 0, 1], 8080));
+        assert!(make.poll_ready().unwrap().is_ready());
+        match make.call(MaybePinned { pinned: Some(addr) }).wait() {
+            Ok(svc::Either::B("direct")) => {}
+            _ => panic!("single-endpoint targets must be served directly"),
+        }
+        assert_eq!(balancers.load(Ordering::SeqCst), 0, "no balancer must be built");
+        assert_eq!(*endpoints.lock().unwrap(), vec![addr]);
+
+        match make.call(MaybePinned { pinned: None }).wait() {
+            Ok(svc::Either::A("balanced")) => {}
+            _ => panic!("other targets must be balanced"),
+        }
+        assert_eq!(balancers.load(Ordering::SeqCst), 1);
+        assert_eq!(endpoints.lock().unwrap().len(), 1);
+    }
+
+    /// Wraps an error so that it is only reachable as a source.
+    #[derive(Debug)]
+    struct Wrapped(Error);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "wrapped: {}", self.0)
+        }
+    }
+
+    impl error::Error for Wrapped {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            Some(&*self.0)
+        }
+    }
+
+    #[test]
+    fn error_kinds() {
+        let io_err = |kind| Error::from(io::Error::from(kind));
+        assert_eq!(io_err(io::ErrorKind::ConnectionRefused).kind(), ErrorKind::Connect);
+        assert_eq!(io_err(io::ErrorKind::ConnectionReset).kind(), ErrorKind::Reset);
+        assert_eq!(io_err(io::ErrorKind::BrokenPipe).kind(), ErrorKind::Reset);
+        assert_eq!(io_err(io::ErrorKind::TimedOut).kind(), ErrorKind::Read);
+        assert_eq!(io_err(io::ErrorKind::Other).kind(), ErrorKind::Other);
+
+        let reset = Error::from(::h2::Error::from(::h2::Reason::REFUSED_STREAM));
+        assert_eq!(reset.kind(), ErrorKind::Reset);
+
+        let wrapped = Error::from(Wrapped(io_err(io::ErrorKind::ConnectionReset)));
+        assert_eq!(wrapped.kind(), ErrorKind::Reset, "sources must be classified");
+
+        assert_eq!(Error::from("unknown").kind(), ErrorKind::Other);
+    }
+
+    /// An endpoint with a fixed load that fails every request while `failing`.
+    struct Weighted {
+        load: f64,
+        failing: bool,
+    }
+
+    impl Load for Weighted {
+        type Metric = f64;
+
+        fn load(&self) -> f64 {
+            self.load
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Weighted {
+        type Response = http::Response<()>;
+        type Error = Error;
+        type Future = ::futures::future::FutureResult<http::Response<()>, Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            if self.failing {
+                ::futures::future::err("failed".into())
+            } else {
+                ::futures::future::ok(http::Response::new(()))
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_describes_each_endpoint() {
+        let addr = |port| SocketAddr::from(([10, 1, 1, 1], port));
+        let changes = vec![
+            Change::Insert(
+                addr(8081),
+                Weighted {
+                    load: 0.5,
+                    failing: false,
+                },
+            ),
+            Change::Insert(
+                addr(8082),
+                Weighted {
+                    load: 2.0,
+                    failing: true,
+                },
+            ),
+        ];
+        let breakers = breaker::Breakers::new(
+            Changes(changes.into_iter().collect()),
+            Some(breaker::layer(1, Duration::from_secs(60))),
+        );
+        let mut sticky = Sticky::new(
+            breakers,
+            SmallRng::from_entropy(),
+            None,
+            Arc::new(Rendezvous),
+        );
+
+        for port in &[8081, 8081, 8082] {
+            assert!(sticky
+                .poll_ready()
+                .expect("balancer must not fail")
+                .is_ready());
+            // Overrides the balancer's choice of endpoint.
+            sticky.next = sticky.ready.get_full(&addr(*port)).map(|(i, _, _)| i);
+            let _ = sticky.call(http::Request::new(())).wait();
+        }
+
+        let expected = BalancerSnapshot {
+            state: EndpointState::Ready(2),
+            endpoints: vec![
+                EndpointSnapshot {
+                    addr: addr(8081),
+                    load: 0.5,
+                    requests: 2,
+                    breaker: Some(breaker::BreakerState::Closed),
+                },
+                EndpointSnapshot {
+                    addr: addr(8082),
+                    load: 2.0,
+                    requests: 1,
+                    breaker: Some(breaker::BreakerState::Open),
+                },
+            ],
+        };
+        assert_eq!(sticky.snapshot(), expected);
+
+        let json = serde_json::to_string(&expected).expect("snapshot must serialize");
+        let json: serde_json::Value = serde_json::from_str(&json).expect("JSON must parse");
+        let expected: serde_json::Value = serde_json::from_str(
+            "{\"state\":\"ready\",\"endpoints\":[\
+             {\"addr\":\"10.1.1.1:8081\",\"load\":0.5,\"requests\":2,\"breaker\":\"closed\"},\
+             {\"addr\":\"10.1.1.1:8082\",\"load\":2.0,\"requests\":1,\"breaker\":\"open\"}]}",
+        )
+        .expect("JSON must parse");
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn snapshots_serialize_loads_that_json_cannot_represent_as_null() {
+        let snapshot = BalancerSnapshot {
+            state: EndpointState::Drained,
+            endpoints: vec![EndpointSnapshot {
+                addr: SocketAddr::from(([10, 1, 1, 1], 8080)),
+                load: ::std::f64::INFINITY,
+                requests: 0,
+                breaker: Some(breaker::BreakerState::HalfOpen),
+            }],
+        };
+        assert_eq!(
+            serde_json::to_string(&snapshot).expect("snapshot must serialize"),
+            "{\"state\":\"drained\",\"endpoints\":[\
+             {\"addr\":\"10.1.1.1:8080\",\"load\":null,\"requests\":0,\"breaker\":\"half-open\"}]}",
+        );
     }
 }
@@ -7,58 +7,263 @@
 //! `web.example.net.:8080`, or `web:8080`, depending on the state of DNS.
 //!
 //! DNS TTLs are honored and the most recent value is added to each request's
-//! extensions.
+//! extensions. Refined names that are invalid (or outside of the allowed
+//! suffixes) are ignored in favor of the last canonical name, and names that
+//! can't be refined are routed with the original name or a static fallback
+//! address. The layer's other options are described on its `with_*` methods.
 
 use futures::{Async, Future, Poll, Stream};
 use http;
 use log::trace;
 use never::Never;
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio;
 use tokio::sync::{mpsc, oneshot};
 use tokio_timer::{clock, Delay, Timeout};
 
+use convert::TryFrom;
 use dns;
+use metrics::{FmtLabels, FmtMetric, FmtMetrics, Gauge};
 use svc;
-use {Addr, NameAddr};
+use {Addr, Conditional, NameAddr};
 
 /// Duration to wait before polling DNS again after an error (or a NXDOMAIN
 /// response with no TTL).
 const DNS_ERROR_TTL: Duration = Duration::from_secs(3);
 
+/// The maximum length of a DNS name, excluding its trailing dot.
+const DEFAULT_MAX_NAME_LEN: usize = 253;
+
+/// The maximum length of each label in a DNS name.
+const MAX_LABEL_LEN: usize = 63;
+
+/// The number of dots at or above which a relative name is tried as-is before
+/// it is qualified with search domains, as in resolv.conf(5).
+const DEFAULT_NDOTS: usize = 1;
+
+/// The default window over which canonical name changes are counted.
+const DEFAULT_CHURN_WINDOW: Duration = Duration::from_secs(60);
+
+/// The amount of time that a name's last TTL is reported after it expires,
+/// i.e. once the name is no longer being refined.
+const TTL_RETENTION: Duration = Duration::from_secs(60);
+
+/// The amount of time that a seeded canonical name is used before the name is
+/// refined.
+const SEED_TTL: Duration = Duration::from_secs(1);
+
+/// The number of times that a name's initial refinement is retried before the
+/// original name is used.
+const INITIAL_RETRIES: usize = 2;
+
+/// The amount of time to wait before retrying a failed initial refinement.
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+metrics! {
+    canonicalize_name_changes: Gauge {
+        "Number of times each name's canonical name changed within the churn window"
+    },
+    canonicalize_ttl_ms: Gauge {
+        "The TTL, in milliseconds, that each name was last refined with"
+    }
+}
+
+/// Refines a name to its canonical, fully-qualified form.
+///
+/// This is abstracted so that the canonicalization task may be tested without
+/// a real DNS service.
+pub trait Refine: Clone + Send + 'static {
+    type Future: Future<Item = dns::Refine, Error = dns::ResolveError> + Send + 'static;
+
+    /// Refines `name` by querying `query_type` records.
+    fn refine(&self, name: &dns::Name, query_type: dns::QueryType) -> Self::Future;
+}
+
 #[derive(Debug, Clone)]
-pub struct Layer {
-    resolver: dns::Resolver,
+pub struct Layer<R> {
+    resolver: R,
     timeout: Duration,
+    search: Vec<dns::Name>,
+    search_cache: bool,
+    ndots: usize,
+    query_type: dns::QueryType,
+    max_name_len: usize,
+    rewrite: Option<Rewrite>,
+    allowed_suffixes: Option<Arc<Vec<dns::Suffix>>>,
+    churn: Option<Churn>,
+    ttls: Option<Ttls>,
+    events: Option<Events>,
+    fallbacks: Arc<HashMap<NameAddr, SocketAddr>>,
+    seeds: Arc<HashMap<NameAddr, NameAddr>>,
+    max_stale: Option<Duration>,
 }
 
 #[derive(Clone, Debug)]
-pub struct Stack<M> {
-    resolver: dns::Resolver,
+pub struct Stack<M, R> {
+    config: Layer<R>,
     inner: M,
-    timeout: Duration,
 }
 
-pub struct MakeFuture<F> {
+pub struct MakeFuture<F, R> {
     inner: F,
-    task: Option<(NameAddr, dns::Resolver, Duration)>,
+    task: Option<(NameAddr, Layer<R>)>,
 }
 
 pub struct Service<S> {
-    canonicalized: Option<Addr>,
+    original: NameAddr,
+    resolved: Cache,
     inner: S,
-    rx: mpsc::Receiver<NameAddr>,
+    rx: mpsc::Receiver<Cache>,
+    tx_refresh: mpsc::Sender<()>,
     /// Notifies the daemon `Task` on drop.
     _tx_stop: oneshot::Sender<Never>,
 }
 
-struct Task {
+/// Why a `Service` is not using a canonical name.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReasonUnresolved {
+    /// The name has not yet been refined.
+    AwaitingInitial,
+
+    /// The name could not be refined (or was refined to an invalid or
+    /// disallowed name), so the original name is used.
+    UsingOriginalAfterError,
+
+    /// The name could not be refined, so its static fallback address is
+    /// used.
+    UsingFallbackAfterError,
+}
+
+/// Forces a `Service`'s daemon `Task` to refine its name immediately, rather
+/// than waiting for the last resolution's TTL to expire.
+#[derive(Clone, Debug)]
+pub struct Refresh(mpsc::Sender<()>);
+
+/// Rewrites refined names before they are published.
+///
+/// The rewrite function must be pure: it may be called on each refinement of
+/// each name. Names that it does not rewrite are returned as `None`.
+#[derive(Clone)]
+pub struct Rewrite(Arc<dyn Fn(&dns::Name) -> Option<dns::Name> + Send + Sync>);
+
+/// Counts the changes to each name's canonical name over a sliding window.
+///
+/// Clones share the same counts, so a single `Churn` may be shared by all
+/// canonicalized names.
+#[derive(Clone, Debug)]
+pub struct Churn(Arc<Mutex<ChurnInner>>);
+
+#[derive(Debug)]
+struct ChurnInner {
+    window: Duration,
+    /// The times at which each original name's canonical name changed,
+    /// oldest first.
+    changes: HashMap<NameAddr, VecDeque<Instant>>,
+}
+
+/// Records the TTL that each name was last refined with.
+///
+/// Clones share the same records, so a single `Ttls` may be shared by all
+/// canonicalized names.
+#[derive(Clone, Debug, Default)]
+pub struct Ttls(Arc<Mutex<HashMap<NameAddr, Ttl>>>);
+
+/// Describes the TTL that a name was last refined with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Ttl {
+    /// The time at which the name is refined again.
+    pub valid_until: Instant,
+    /// The amount of time from when the TTL was chosen until it expires.
+    pub ttl: Duration,
+    pub source: TtlSource,
+}
+
+/// Describes where a name's TTL came from.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum TtlSource {
+    /// The TTL of a successful refinement.
+    Resolver,
+
+    /// The negative TTL of a response with no records (e.g. NXDOMAIN).
+    NoRecords,
+
+    /// The resolver failed without a TTL (e.g. it timed out), so
+    /// `DNS_ERROR_TTL` is used.
+    ErrorFallback,
+
+    /// The initial refinement failed and is retried after a short backoff.
+    InitialRetry,
+
+    /// A preferred search candidate's cached failure expires before the
+    /// name's TTL, so the name is refined again once it does.
+    NegativeCache,
+}
+
+/// Publishes the resolution events of canonicalized names.
+///
+/// The channel is bounded, so if the subscriber falls behind, events are
+/// dropped (with a warning) rather than delaying resolution.
+#[derive(Clone, Debug)]
+pub struct Events(mpsc::Sender<Event>);
+
+/// Describes a change in the resolution of a canonicalized name.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    /// The name that is canonicalized.
+    pub original: NameAddr,
+    /// The canonical name that requests are routed with, if the name has
+    /// been canonicalized.
+    pub canonical: Option<NameAddr>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    /// The name was resolved for the first time.
+    Initial,
+
+    /// The name was refined to a different canonical name.
+    Changed,
+
+    /// The name could not initially be refined, so the original name (or its
+    /// static fallback address) is used.
+    ErrorFallback,
+
+    /// The name was refined again, and its canonical name is unchanged.
+    Refreshed,
+
+    /// The name's canonical name was not revalidated within the maximum
+    /// staleness after its TTL expired, so the original name (or its static
+    /// fallback address) is used.
+    Expired,
+}
+
+struct Authority<'a>(&'a NameAddr);
+
+struct Task<R: Refine> {
     original: NameAddr,
     resolved: Cache,
-    resolver: dns::Resolver,
-    state: State,
+    resolver: R,
+    search: SearchList,
+    query_type: dns::QueryType,
+    validate: Validate,
+    canonical: Canonical,
+    expiry: Expiry,
+    stale: Stale,
+    events: Option<Events>,
+    /// Published instead of the original name if the name can't be refined.
+    fallback: Option<SocketAddr>,
+    state: State<R>,
     timeout: Duration,
-    tx: mpsc::Sender<NameAddr>,
+    tx: mpsc::Sender<Cache>,
+    rx_refresh: mpsc::Receiver<()>,
+    /// Set when a refresh is requested while a resolution is pending, so that
+    /// the name is refined once more after the resolution completes.
+    refresh_pending: bool,
     rx_stop: oneshot::Receiver<Never>,
 }
 
@@ -72,48 +277,272 @@ enum Cache {
     /// error), and we do not yet have a resolved name.
     Unresolved,
 
+    /// The service has been notified with a static fallback address (i.e.
+    /// due to an error), and we do not yet have a resolved name.
+    Fallback(SocketAddr),
+
     /// The service was last-notified with this name.
     Resolved(NameAddr),
 }
 
-enum State {
+enum State<R: Refine> {
     Init,
-    Pending(Timeout<dns::RefineFuture>),
+    Pending(Timeout<Search<R>>),
     ValidUntil(Delay),
 }
 
+/// Builds the names that a name is refined as, in priority order.
+struct SearchList {
+    domains: Vec<dns::Name>,
+    ndots: usize,
+    /// When set, failed candidates are not retried until their failures
+    /// expire.
+    negative: Option<NegativeCache>,
+}
+
+/// Caches the failures of each of a name's search candidates.
+struct NegativeCache {
+    /// All candidates, in priority order.
+    candidates: VecDeque<dns::Name>,
+    /// The time until which each failed candidate should not be retried.
+    failed: HashMap<dns::Name, Instant>,
+}
+
+/// Refines a name by trying each of a list of candidate names in order,
+/// completing with the first successful refinement.
+struct Search<R: Refine> {
+    resolver: R,
+    query_type: dns::QueryType,
+    current: dns::Name,
+    pending: R::Future,
+    candidates: VecDeque<dns::Name>,
+    /// Candidates that failed to refine, with the time until which each
+    /// failure may be cached.
+    failed: Vec<(dns::Name, Instant)>,
+}
+
+/// Checks and rewrites refined names before they are published.
+struct Validate {
+    max_name_len: usize,
+    rewrite: Option<Rewrite>,
+    /// When set, only names under one of these suffixes may be published.
+    allowed_suffixes: Option<Arc<Vec<dns::Suffix>>>,
+}
+
+/// Tracks a name's canonical names, so that changes between them are counted.
+struct Canonical {
+    churn: Option<Churn>,
+    /// The last canonical name that was published. It is kept while the
+    /// original name (or fallback address) is used, so that churn is only
+    /// counted between canonical names.
+    last: Option<NameAddr>,
+    /// Set while the last canonical name is a seed that has not yet been
+    /// refined. A seed is only the expected canonical name, so correcting it
+    /// is not churn.
+    seeded: bool,
+}
+
+/// Chooses when a name is refined again, recording each TTL if configured.
+struct Expiry {
+    ttls: Option<Ttls>,
+    /// The number of times that the initial refinement may still be retried.
+    initial_retries: usize,
+}
+
+/// Bounds the amount of time that a canonical name may be used past its TTL.
+struct Stale {
+    max: Option<Duration>,
+    /// Set while an expired canonical name is being revalidated, if a
+    /// maximum staleness is configured. Once it elapses, the original name (or
+    /// the fallback address) is published.
+    until: Option<Delay>,
+}
+
+// === impl Refine ===
+
+impl Refine for dns::Resolver {
+    type Future = dns::RefineFuture;
+
+    fn refine(&self, name: &dns::Name, query_type: dns::QueryType) -> Self::Future {
+        dns::Resolver::refine_with(self, name, query_type)
+    }
+}
+
 // === Layer ===
 
-// FIXME the resolver should be abstracted to a trait so that this can be tested
-// without a real DNS service.
-pub fn layer(resolver: dns::Resolver, timeout: Duration) -> Layer {
-    Layer { resolver, timeout }
+pub fn layer<R: Refine>(resolver: R, timeout: Duration) -> Layer<R> {
+    Layer {
+        resolver,
+        timeout,
+        search: Vec::new(),
+        search_cache: false,
+        ndots: DEFAULT_NDOTS,
+        query_type: dns::QueryType::default(),
+        max_name_len: DEFAULT_MAX_NAME_LEN,
+        rewrite: None,
+        allowed_suffixes: None,
+        churn: None,
+        ttls: None,
+        events: None,
+        fallbacks: Arc::new(HashMap::new()),
+        seeds: Arc::new(HashMap::new()),
+        max_stale: None,
+    }
+}
+
+impl<R> Layer<R> {
+    /// Refines relative names by trying each of `search` in order, rather than
+    /// relying on the system resolver's search path.
+    pub fn with_search(self, search: Vec<dns::Name>) -> Self {
+        Self { search, ..self }
+    }
+
+    /// Caches the outcome of each search domain attempt.
+    ///
+    /// When enabled, each refresh retries the search domains in priority
+    /// order, skipping domains whose failures have not yet expired, and the
+    /// name is refreshed when the failure of a preferred domain expires.
+    pub fn with_search_cache(self, search_cache: bool) -> Self {
+        Self {
+            search_cache,
+            ..self
+        }
+    }
+
+    /// Tries relative names with at least `ndots` dots as-is before the
+    /// search domains, and those with fewer after them, as in resolv.conf(5).
+    ///
+    /// By default, `ndots` is 1.
+    pub fn with_ndots(self, ndots: usize) -> Self {
+        Self { ndots, ..self }
+    }
+
+    /// Refines names by querying only `query_type` records, e.g. to avoid
+    /// AAAA queries where they are slow or black-holed.
+    ///
+    /// By default, the resolver's IP lookup strategy is used.
+    pub fn with_query_type(self, query_type: dns::QueryType) -> Self {
+        Self { query_type, ..self }
+    }
+
+    /// Ignores refined names that are longer than `max_name_len` octets,
+    /// excluding the trailing dot.
+    ///
+    /// By default, names may be up to 253 octets long.
+    pub fn with_max_name_len(self, max_name_len: usize) -> Self {
+        Self {
+            max_name_len,
+            ..self
+        }
+    }
+
+    /// Rewrites each refined name with `rewrite` before it is published.
+    ///
+    /// Rewritten names are subject to the same validation as refined names;
+    /// invalid rewrites are ignored.
+    pub fn with_rewrite(self, rewrite: Rewrite) -> Self {
+        Self {
+            rewrite: Some(rewrite),
+            ..self
+        }
+    }
+
+    /// Ignores refined names that are not under one of `suffixes`, in favor
+    /// of the original name.
+    ///
+    /// Names are checked after they are rewritten. By default, refined names
+    /// may have any suffix.
+    pub fn with_allowed_suffixes(self, suffixes: Vec<dns::Suffix>) -> Self {
+        Self {
+            allowed_suffixes: Some(Arc::new(suffixes)),
+            ..self
+        }
+    }
+
+    /// Uses `addr` for requests to `name` if `name` can't be refined (e.g.
+    /// because DNS is down), rather than the original name.
+    ///
+    /// A fallback address is used until the name is first refined, and again
+    /// if its canonical name expires (see `with_max_stale`).
+    pub fn with_fallback_addr(mut self, name: NameAddr, addr: SocketAddr) -> Self {
+        Arc::make_mut(&mut self.fallbacks).insert(name, addr);
+        self
+    }
+
+    /// Uses `canonical` for requests to `name` until `name` is refined, so
+    /// that requests need not wait for DNS.
+    ///
+    /// Seeded names are refined shortly after their services are built, and
+    /// are then refreshed as usual.
+    pub fn with_seed(mut self, name: NameAddr, canonical: NameAddr) -> Self {
+        Arc::make_mut(&mut self.seeds).insert(name, canonical);
+        self
+    }
+
+    /// Bounds the amount of time that a canonical name may be used after its
+    /// TTL expires while it is being revalidated.
+    ///
+    /// If the name is not refined again (e.g. because DNS is slow or failing)
+    /// within `max_stale` of its TTL, requests are routed with the original
+    /// name (or its fallback address) until it is. By default, an expired
+    /// canonical name is used until it is successfully refined again.
+    pub fn with_max_stale(self, max_stale: Duration) -> Self {
+        Self {
+            max_stale: Some(max_stale),
+            ..self
+        }
+    }
+
+    /// Counts the changes to each name's canonical name with `churn`.
+    pub fn with_churn(self, churn: Churn) -> Self {
+        Self {
+            churn: Some(churn),
+            ..self
+        }
+    }
+
+    /// Records the TTL that each name is refined with in `ttls`.
+    pub fn with_ttls(self, ttls: Ttls) -> Self {
+        Self {
+            ttls: Some(ttls),
+            ..self
+        }
+    }
+
+    /// Publishes each name's resolution events to `events`.
+    pub fn with_events(self, events: Events) -> Self {
+        Self {
+            events: Some(events),
+            ..self
+        }
+    }
 }
 
-impl<M> svc::Layer<M> for Layer
+impl<M, R> svc::Layer<M> for Layer<R>
 where
     M: svc::Service<Addr> + Clone,
+    R: Refine,
 {
-    type Service = Stack<M>;
+    type Service = Stack<M, R>;
 
     fn layer(&self, inner: M) -> Self::Service {
         Stack {
+            config: self.clone(),
             inner,
-            resolver: self.resolver.clone(),
-            timeout: self.timeout,
         }
     }
 }
 
 // === impl Stack ===
 
-impl<M> svc::Service<Addr> for Stack<M>
+impl<M, R> svc::Service<Addr> for Stack<M, R>
 where
     M: svc::Service<Addr>,
+    R: Refine,
 {
     type Response = svc::Either<Service<M::Response>, M::Response>;
     type Error = M::Error;
-    type Future = MakeFuture<M::Future>;
+    type Future = MakeFuture<M::Future, R>;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
         self.inner.poll_ready()
@@ -121,7 +550,7 @@ where
 
     fn call(&mut self, addr: Addr) -> Self::Future {
         let task = match addr {
-            Addr::Name(ref na) => Some((na.clone(), self.resolver.clone(), self.timeout)),
+            Addr::Name(ref na) => Some((na.clone(), self.config.clone())),
             Addr::Socket(_) => None,
         };
 
@@ -132,25 +561,61 @@ where
 
 // === impl MakeFuture ===
 
-impl<F> Future for MakeFuture<F>
+impl<F, R> Future for MakeFuture<F, R>
 where
     F: Future,
+    R: Refine,
 {
     type Item = svc::Either<Service<F::Item>, F::Item>;
     type Error = F::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let inner = try_ready!(self.inner.poll());
-        let svc = if let Some((na, resolver, timeout)) = self.task.take() {
+        let svc = if let Some((na, config)) = self.task.take() {
             let (tx, rx) = mpsc::channel(1);
+            // At most one refresh may be pending, so that many refresh
+            // requests are coalesced into a single resolution.
+            let (tx_refresh, rx_refresh) = mpsc::channel(1);
             let (_tx_stop, rx_stop) = oneshot::channel();
+            let fallback = config.fallbacks.get(&na).cloned();
+            let seed = config.seeds.get(&na).cloned();
+            let resolved = match seed {
+                Some(ref canonical) => Cache::Resolved(canonical.clone()),
+                None => Cache::AwaitingInitial,
+            };
 
-            tokio::spawn(Task::new(na, resolver, timeout, tx, rx_stop));
+            let task = Task::new(
+                na.clone(),
+                config.resolver,
+                config.timeout,
+                config.search,
+                tx,
+                rx_refresh,
+                rx_stop,
+            )
+            .with_ndots(config.ndots)
+            .with_query_type(config.query_type)
+            .with_max_name_len(config.max_name_len)
+            .with_rewrite(config.rewrite)
+            .with_allowed_suffixes(config.allowed_suffixes)
+            .with_churn(config.churn)
+            .with_ttls(config.ttls)
+            .with_events(config.events)
+            .with_fallback(fallback)
+            .with_max_stale(config.max_stale)
+            .with_seed(seed, SEED_TTL);
+            if config.search_cache {
+                tokio::spawn(task.with_search_cache());
+            } else {
+                tokio::spawn(task);
+            }
 
             svc::Either::A(Service {
-                canonicalized: None,
+                original: na,
+                resolved,
                 inner,
                 rx,
+                tx_refresh,
                 _tx_stop,
             })
         } else {
@@ -163,27 +628,132 @@ where
 
 // === impl Task ===
 
-impl Task {
+impl<R: Refine> Task<R> {
     fn new(
         original: NameAddr,
-        resolver: dns::Resolver,
+        resolver: R,
         timeout: Duration,
-        tx: mpsc::Sender<NameAddr>,
+        search: Vec<dns::Name>,
+        tx: mpsc::Sender<Cache>,
+        rx_refresh: mpsc::Receiver<()>,
         rx_stop: oneshot::Receiver<Never>,
     ) -> Self {
         Self {
             original,
             resolved: Cache::AwaitingInitial,
             resolver,
+            search: SearchList {
+                domains: search,
+                ndots: DEFAULT_NDOTS,
+                negative: None,
+            },
+            query_type: dns::QueryType::default(),
+            validate: Validate {
+                max_name_len: DEFAULT_MAX_NAME_LEN,
+                rewrite: None,
+                allowed_suffixes: None,
+            },
+            canonical: Canonical {
+                churn: None,
+                last: None,
+                seeded: false,
+            },
+            expiry: Expiry {
+                ttls: None,
+                initial_retries: INITIAL_RETRIES,
+            },
+            stale: Stale {
+                max: None,
+                until: None,
+            },
+            events: None,
+            fallback: None,
             state: State::Init,
             timeout,
             tx,
+            rx_refresh,
+            refresh_pending: false,
             rx_stop,
         }
     }
+
+    fn with_ndots(mut self, ndots: usize) -> Self {
+        self.search.ndots = ndots;
+        self
+    }
+
+    fn with_search_cache(mut self) -> Self {
+        self.search.cache_failures(self.original.name());
+        self
+    }
+
+    fn with_query_type(self, query_type: dns::QueryType) -> Self {
+        Self { query_type, ..self }
+    }
+
+    fn with_max_name_len(mut self, max_name_len: usize) -> Self {
+        self.validate.max_name_len = max_name_len;
+        self
+    }
+
+    fn with_rewrite(mut self, rewrite: Option<Rewrite>) -> Self {
+        self.validate.rewrite = rewrite;
+        self
+    }
+
+    fn with_allowed_suffixes(mut self, allowed_suffixes: Option<Arc<Vec<dns::Suffix>>>) -> Self {
+        self.validate.allowed_suffixes = allowed_suffixes;
+        self
+    }
+
+    fn with_churn(mut self, churn: Option<Churn>) -> Self {
+        self.canonical.churn = churn;
+        self
+    }
+
+    fn with_ttls(mut self, ttls: Option<Ttls>) -> Self {
+        self.expiry.ttls = ttls;
+        self
+    }
+
+    fn with_events(self, events: Option<Events>) -> Self {
+        Self { events, ..self }
+    }
+
+    fn with_fallback(self, fallback: Option<SocketAddr>) -> Self {
+        Self { fallback, ..self }
+    }
+
+    fn with_max_stale(mut self, max_stale: Option<Duration>) -> Self {
+        self.stale.max = max_stale;
+        self
+    }
+
+    /// Starts with `seed` as the canonical name, which the service already
+    /// uses, and refines the name once `ttl` elapses.
+    ///
+    /// The seed is the name's initial value, so it is published to the
+    /// events subscriber, which must already be configured.
+    fn with_seed(mut self, seed: Option<NameAddr>, ttl: Duration) -> Self {
+        match seed {
+            None => self,
+            Some(canonical) => {
+                let resolved = Cache::Resolved(canonical.clone());
+                if let Some(ref mut events) = self.events {
+                    events.publish(EventKind::Initial, &self.original, &resolved);
+                }
+                self.canonical.seed(canonical);
+                Self {
+                    resolved,
+                    state: State::ValidUntil(Delay::new(clock::now() + ttl)),
+                    ..self
+                }
+            }
+        }
+    }
 }
 
-impl Future for Task {
+impl<R: Refine> Future for Task<R> {
     type Item = ();
     type Error = ();
 
@@ -198,13 +768,76 @@ impl Future for Task {
                 }
             }
 
+            if self.stale.poll_expired() {
+                match self.tx.poll_ready() {
+                    Ok(Async::Ready(())) => {}
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_) => {
+                        trace!("task complete; name={:?}", self.original);
+                        return Ok(Async::Ready(()));
+                    }
+                }
+                let resolved = match self.fallback {
+                    Some(addr) => {
+                        warn!(
+                            "{} was not revalidated within {:?} of its TTL; using fallback {}",
+                            self.original.name(),
+                            self.stale.max.unwrap_or_default(),
+                            addr,
+                        );
+                        Cache::Fallback(addr)
+                    }
+                    None => {
+                        warn!(
+                            "{} was not revalidated within {:?} of its TTL; using original name",
+                            self.original.name(),
+                            self.stale.max.unwrap_or_default(),
+                        );
+                        Cache::Unresolved
+                    }
+                };
+                self.stale.end();
+                self.resolved = resolved.clone();
+                self.tx
+                    .try_send(resolved)
+                    .expect("tx failed despite being ready");
+                if let Some(ref mut events) = self.events {
+                    events.publish(EventKind::Expired, &self.original, &self.resolved);
+                }
+            }
+
             self.state = match self.state {
                 State::Init => {
                     trace!("task init; name={:?}", self.original);
-                    let f = self.resolver.refine(self.original.name());
-                    State::Pending(Timeout::new(f, self.timeout))
+
+                    // This resolution satisfies any refreshes that were
+                    // requested before it started. Refreshes requested while
+                    // it is pending coalesce into a single follow-up.
+                    while let Ok(Async::Ready(Some(()))) = self.rx_refresh.poll() {}
+                    self.refresh_pending = false;
+
+                    let candidates = self.search.uncached(self.original.name());
+                    if candidates.is_empty() {
+                        // Every candidate's failure is still cached, so wait
+                        // for the first of them to expire.
+                        trace!("all candidates cached; name={:?}", self.original);
+                        let retry_at = self.search.retry_at(None).unwrap_or_else(clock::now);
+                        let delay =
+                            self.expiry
+                                .until(&self.original, retry_at, TtlSource::NegativeCache);
+                        State::ValidUntil(delay)
+                    } else {
+                        let f = Search::new(self.resolver.clone(), self.query_type, candidates);
+                        State::Pending(Timeout::new(f, self.timeout))
+                    }
                 }
                 State::Pending(ref mut fut) => {
+                    // Refreshes can't restart a pending resolution, so they're
+                    // recorded to be satisfied by a single follow-up.
+                    while let Ok(Async::Ready(Some(()))) = self.rx_refresh.poll() {
+                        self.refresh_pending = true;
+                    }
+
                     // Only poll the resolution for updates when the receiver is
                     // ready to receive an update.
                     match self.tx.poll_ready() {
@@ -219,7 +852,10 @@ impl Future for Task {
                         }
                     };
 
-                    match fut.poll() {
+                    let poll = fut.poll();
+                    self.search.record_failures(fut.get_mut().take_failed());
+
+                    match poll {
                         Ok(Async::NotReady) => {
                             return Ok(Async::NotReady);
                         }
@@ -229,37 +865,130 @@ impl Future for Task {
                                 self.original,
                                 refine.name
                             );
+                            let port = self.original.port();
+                            let refined = self
+                                .validate
+                                .refined(self.original.name(), refine.name)
+                                .map(|name| NameAddr::new(name, port));
+                            let valid = refined.is_some();
+                            let resolved = match refined {
+                                Some(na) => {
+                                    // The name has been revalidated.
+                                    self.stale.end();
+                                    Cache::Resolved(na)
+                                }
+                                // The last good value is used until the name
+                                // is refined again, as if the resolver had
+                                // failed.
+                                None if self.resolved != Cache::AwaitingInitial => {
+                                    self.resolved.clone()
+                                }
+                                None => match self.fallback {
+                                    Some(addr) => Cache::Fallback(addr),
+                                    None => Cache::Unresolved,
+                                },
+                            };
+
                             // If the resolved name is a new name, bind a
                             // service with it and set a delay that will notify
                             // when the resolver should be consulted again.
-                            let resolved = NameAddr::new(refine.name, self.original.port());
-                            if self.resolved.get() != Some(&resolved) {
+                            let kind = if self.resolved == resolved {
+                                // An ignored refinement does not refresh the
+                                // last good value.
+                                if valid {
+                                    Some(EventKind::Refreshed)
+                                } else {
+                                    None
+                                }
+                            } else {
+                                let kind = if !valid {
+                                    EventKind::ErrorFallback
+                                } else if self.resolved == Cache::AwaitingInitial {
+                                    // The initial name is not a change.
+                                    EventKind::Initial
+                                } else {
+                                    EventKind::Changed
+                                };
+                                if let Cache::Resolved(ref na) = resolved {
+                                    self.canonical.publish(&self.original, na);
+                                }
                                 self.tx
                                     .try_send(resolved.clone())
                                     .expect("tx failed despite being ready");
-                                self.resolved = Cache::Resolved(resolved);
+                                self.resolved = resolved;
+                                Some(kind)
+                            };
+                            if let (Some(kind), Some(events)) = (kind, self.events.as_mut()) {
+                                events.publish(kind, &self.original, &self.resolved);
+                            }
+                            if !valid {
+                                let delay = self.expiry.failed(&self.original, None);
+                                State::ValidUntil(delay)
+                            } else {
+                                self.canonical.refined();
+                                // If a preferred candidate failed, refine the
+                                // name again once that failure expires.
+                                let current = fut.get_ref().current();
+                                let retry_at = self.search.retry_at(Some(current));
+                                let delay = self.expiry.refined(
+                                    &self.original,
+                                    refine.valid_until,
+                                    retry_at,
+                                );
+                                State::ValidUntil(delay)
                             }
-
-                            State::ValidUntil(Delay::new(refine.valid_until))
                         }
                         Err(e) => {
                             trace!("task error; name={:?} err={:?}", self.original, e);
 
-                            if self.resolved == Cache::AwaitingInitial {
-                                // The service needs a value, so we need to
-                                // publish the original name so it can proceed.
-                                warn!(
-                                    "failed to refine {}: {}; using original name",
+                            // The initial failure may be transient, so it is
+                            // retried shortly rather than using the original
+                            // name until the error expires.
+                            let retry = self.resolved == Cache::AwaitingInitial
+                                && self.expiry.retry_initial();
+                            if retry {
+                                debug!(
+                                    "failed to refine {}: {}; retrying in {:?}",
                                     self.original.name(),
                                     e,
+                                    INITIAL_RETRY_BACKOFF,
                                 );
-                                self.tx
-                                    .try_send(self.original.clone())
-                                    .expect("tx failed despite being ready");
-
+                            } else if self.resolved == Cache::AwaitingInitial {
+                                // The service needs a value, so we need to
+                                // publish the original name (or its fallback
+                                // address) so it can proceed.
+                                let resolved = match self.fallback {
+                                    Some(addr) => {
+                                        warn!(
+                                            "failed to refine {}: {}; using fallback {}",
+                                            self.original.name(),
+                                            e,
+                                            addr,
+                                        );
+                                        Cache::Fallback(addr)
+                                    }
+                                    None => {
+                                        warn!(
+                                            "failed to refine {}: {}; using original name",
+                                            self.original.name(),
+                                            e,
+                                        );
+                                        Cache::Unresolved
+                                    }
+                                };
                                 // There's now no need to re-publish the
                                 // original name on subsequent failures.
-                                self.resolved = Cache::Unresolved;
+                                self.resolved = resolved.clone();
+                                self.tx
+                                    .try_send(resolved)
+                                    .expect("tx failed despite being ready");
+                                if let Some(ref mut events) = self.events {
+                                    events.publish(
+                                        EventKind::ErrorFallback,
+                                        &self.original,
+                                        &self.resolved,
+                                    );
+                                }
                             } else {
                                 debug!(
                                     "failed to refresh {}: {}; cache={:?}",
@@ -269,30 +998,44 @@ impl Future for Task {
                                 );
                             }
 
-                            let valid_until = e
-                                .into_inner()
-                                .and_then(|e| match e.kind() {
-                                    dns::ResolveErrorKind::NoRecordsFound {
-                                        valid_until, ..
-                                    } => *valid_until,
-                                    _ => None,
-                                })
-                                .unwrap_or_else(|| clock::now() + DNS_ERROR_TTL);
-
-                            State::ValidUntil(Delay::new(valid_until))
+                            let delay = if retry {
+                                let retry_at = clock::now() + INITIAL_RETRY_BACKOFF;
+                                self.expiry
+                                    .until(&self.original, retry_at, TtlSource::InitialRetry)
+                            } else {
+                                self.expiry.failed(&self.original, e.into_inner().as_ref())
+                            };
+                            State::ValidUntil(delay)
                         }
                     }
                 }
 
+                State::ValidUntil(_) if self.refresh_pending => {
+                    // A refresh was requested while the last resolution was
+                    // pending, so it may not reflect the refresh.
+                    debug!("task deferred refresh; name={:?}", self.original);
+                    State::Init
+                }
+
                 State::ValidUntil(ref mut f) => {
                     trace!("task idle; name={:?}", self.original);
 
-                    match f.poll().expect("timer must not fail") {
-                        Async::NotReady => return Ok(Async::NotReady),
-                        Async::Ready(()) => {
-                            // The last resolution's TTL expired, so issue a new DNS query.
+                    match self.rx_refresh.poll() {
+                        Ok(Async::Ready(Some(()))) => {
+                            // A refresh was requested, so issue a new DNS
+                            // query without waiting for the TTL to expire.
+                            debug!("task refresh; name={:?}", self.original);
                             State::Init
                         }
+                        _ => match f.poll().expect("timer must not fail") {
+                            Async::NotReady => return Ok(Async::NotReady),
+                            Async::Ready(()) => {
+                                // The last resolution's TTL expired, so issue a new DNS query.
+                                // The expired name is used until it is revalidated.
+                                self.stale.start(&self.resolved);
+                                State::Init
+                            }
+                        },
                     }
                 }
             };
@@ -300,51 +1043,1916 @@ impl Future for Task {
     }
 }
 
-impl Cache {
-    fn get(&self) -> Option<&NameAddr> {
-        match self {
-            Cache::Resolved(ref r) => Some(&r),
-            _ => None,
+// === impl SearchList ===
+
+impl SearchList {
+    /// Builds the list of names to refine `name` as, in order.
+    ///
+    /// Absolute names are refined as-is. Relative names are qualified with
+    /// each search domain and are also tried as-is: first if they have at
+    /// least `ndots` dots, and last otherwise.
+    fn candidates(&self, name: &dns::Name) -> VecDeque<dns::Name> {
+        let mut candidates = VecDeque::new();
+        if name.as_ref().ends_with('.') {
+            candidates.push_back(name.clone());
+            return candidates;
         }
+
+        for sfx in &self.domains {
+            let fqdn = format!(
+                "{}.{}.",
+                name.without_trailing_dot(),
+                sfx.without_trailing_dot()
+            );
+            if let Ok(n) = dns::Name::try_from(fqdn.as_bytes()) {
+                candidates.push_back(n);
+            }
+        }
+        if name.as_ref().matches('.').count() >= self.ndots {
+            candidates.push_front(name.clone());
+        } else {
+            candidates.push_back(name.clone());
+        }
+        candidates
+    }
+
+    /// Caches the failures of `name`'s candidates, so that they are skipped
+    /// until their failures expire.
+    fn cache_failures(&mut self, name: &dns::Name) {
+        self.negative = Some(NegativeCache {
+            candidates: self.candidates(name),
+            failed: HashMap::new(),
+        });
+    }
+
+    /// Returns the candidates to refine `name` as, in order, skipping those
+    /// whose failures are cached.
+    fn uncached(&mut self, name: &dns::Name) -> VecDeque<dns::Name> {
+        match self.negative {
+            Some(ref mut negative) => negative.uncached(),
+            None => self.candidates(name),
+        }
+    }
+
+    fn record_failures(&mut self, failed: Vec<(dns::Name, Instant)>) {
+        if let Some(ref mut negative) = self.negative {
+            negative.failed.extend(failed);
+        }
+    }
+
+    /// Returns the earliest time at which a cached failure expires.
+    ///
+    /// If `preferred_to` is set, only candidates with a higher priority than
+    /// it are considered.
+    fn retry_at(&self, preferred_to: Option<&dns::Name>) -> Option<Instant> {
+        self.negative
+            .as_ref()
+            .and_then(|n| n.retry_at(preferred_to))
     }
 }
 
-// === impl Service ===
+// === impl NegativeCache ===
 
-impl<S, B> svc::Service<http::Request<B>> for Service<S>
-where
-    S: svc::Service<http::Request<B>>,
-{
-    type Response = S::Response;
-    type Error = S::Error;
-    type Future = S::Future;
+impl NegativeCache {
+    /// Returns the candidates whose failures are not cached, in order.
+    fn uncached(&mut self) -> VecDeque<dns::Name> {
+        let now = clock::now();
+        self.failed.retain(|_, until| *until > now);
 
-    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        try_ready!(self.inner.poll_ready());
+        let failed = &self.failed;
+        self.candidates
+            .iter()
+            .filter(|c| !failed.contains_key(c))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the earliest time at which a failed candidate may be retried.
+    ///
+    /// If `preferred_to` is set, only candidates with a higher priority than
+    /// it are considered.
+    fn retry_at(&self, preferred_to: Option<&dns::Name>) -> Option<Instant> {
+        self.candidates
+            .iter()
+            .take_while(|c| Some(*c) != preferred_to)
+            .filter_map(|c| self.failed.get(c).cloned())
+            .min()
+    }
+}
+
+// === impl Validate ===
 
-        while let Ok(Async::Ready(Some(addr))) = self.rx.poll() {
-            debug!("refined: {}", addr);
-            self.canonicalized = Some(addr.into());
+impl Validate {
+    /// Returns the name to publish for a refinement of `original`, or `None`
+    /// if the refined name must be ignored.
+    fn refined(&self, original: &dns::Name, refined: dns::Name) -> Option<dns::Name> {
+        // A misbehaving resolver must not be able to publish a malformed name.
+        if !is_valid_name(&refined, self.max_name_len) {
+            warn!("ignoring invalid refinement of {}: {}", original, refined);
+            return None;
         }
-        if self.canonicalized.is_none() {
-            return Ok(Async::NotReady);
+
+        let name = self.rewrite(refined);
+        if !self.is_allowed(&name) {
+            // Names outside of the allowed suffixes may have been injected by
+            // a malicious DNS server.
+            warn!("ignoring disallowed refinement of {}: {}", original, name);
+            return None;
         }
 
-        Ok(Async::Ready(()))
+        Some(name)
     }
 
-    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
-        let addr = self
-            .canonicalized
-            .clone()
-            .expect("called before canonicalized address");
-        req.extensions_mut().insert(addr);
-        self.inner.call(req)
+    /// Applies the configured rewrite to a refined name, unless the rewritten
+    /// name is invalid.
+    fn rewrite(&self, refined: dns::Name) -> dns::Name {
+        let rewritten = match self.rewrite {
+            Some(ref rewrite) => rewrite.apply(&refined),
+            None => None,
+        };
+        match rewritten {
+            None => refined,
+            Some(name) => {
+                if is_valid_name(&name, self.max_name_len) {
+                    debug!("rewrote {} to {}", refined, name);
+                    name
+                } else {
+                    warn!("ignoring invalid rewrite of {}: {}", refined, name);
+                    refined
+                }
+            }
+        }
+    }
+
+    /// Returns true if `name` may be published, i.e. if it is under one of
+    /// the allowed suffixes or no suffixes are configured.
+    fn is_allowed(&self, name: &dns::Name) -> bool {
+        match self.allowed_suffixes {
+            Some(ref suffixes) => suffixes.iter().any(|sfx| sfx.contains(name)),
+            None => true,
+        }
     }
 }
 
-impl<S> Drop for Service<S> {
-    fn drop(&mut self) {
-        trace!("dropping service; name={:?}", self.canonicalized);
+// === impl Canonical ===
+
+impl Canonical {
+    fn seed(&mut self, seed: NameAddr) {
+        self.last = Some(seed);
+        self.seeded = true;
+    }
+
+    /// Records that `canonical` was published for `original`, counting a
+    /// change if it differs from the last canonical name.
+    fn publish(&mut self, original: &NameAddr, canonical: &NameAddr) {
+        let changed = match self.last {
+            Some(ref last) => last != canonical,
+            None => false,
+        };
+        match self.churn {
+            Some(ref churn) if changed && !self.seeded => churn.record(original),
+            _ => {}
+        }
+        self.last = Some(canonical.clone());
+    }
+
+    /// Records that the name was refined, so its canonical name is no longer
+    /// a seed.
+    fn refined(&mut self) {
+        self.seeded = false;
+    }
+}
+
+// === impl Expiry ===
+
+impl Expiry {
+    /// Consumes one of the initial refinement's retries, if any remain.
+    fn retry_initial(&mut self) -> bool {
+        if self.initial_retries == 0 {
+            return false;
+        }
+        self.initial_retries -= 1;
+        true
+    }
+
+    /// Refines `original` again once its TTL expires or, if it is sooner,
+    /// once a preferred candidate may be retried (at `retry_at`).
+    fn refined(
+        &self,
+        original: &NameAddr,
+        valid_until: Instant,
+        retry_at: Option<Instant>,
+    ) -> Delay {
+        match retry_at {
+            Some(at) if at < valid_until => self.until(original, at, TtlSource::NegativeCache),
+            _ => self.until(original, valid_until, TtlSource::Resolver),
+        }
+    }
+
+    /// Refines `original` again after it failed to refine, once the error's
+    /// negative TTL (if any) expires.
+    fn failed(&self, original: &NameAddr, error: Option<&dns::ResolveError>) -> Delay {
+        let (valid_until, source) = error
+            .map(error_ttl)
+            .unwrap_or_else(|| (clock::now() + DNS_ERROR_TTL, TtlSource::ErrorFallback));
+        self.until(original, valid_until, source)
+    }
+
+    fn until(&self, original: &NameAddr, valid_until: Instant, source: TtlSource) -> Delay {
+        if let Some(ref ttls) = self.ttls {
+            ttls.record(original, valid_until, source);
+        }
+        Delay::new(valid_until)
+    }
+}
+
+// === impl Stale ===
+
+impl Stale {
+    /// Starts bounding the time that `resolved` is used past its TTL, unless
+    /// it is already bounded or is not a canonical name.
+    fn start(&mut self, resolved: &Cache) {
+        if self.until.is_some() {
+            return;
+        }
+        if let (Some(max), &Cache::Resolved(_)) = (self.max, resolved) {
+            self.until = Some(Delay::new(clock::now() + max));
+        }
+    }
+
+    /// Returns true once the bound elapses, until it is ended.
+    fn poll_expired(&mut self) -> bool {
+        match self.until {
+            Some(ref mut delay) => delay.poll().expect("timer must not fail").is_ready(),
+            None => false,
+        }
+    }
+
+    fn end(&mut self) {
+        self.until = None;
+    }
+}
+
+// === impl Search ===
+
+impl<R: Refine> Search<R> {
+    fn new(
+        resolver: R,
+        query_type: dns::QueryType,
+        mut candidates: VecDeque<dns::Name>,
+    ) -> Self {
+        let first = candidates
+            .pop_front()
+            .expect("search must have at least one candidate");
+        Search {
+            pending: resolver.refine(&first, query_type),
+            current: first,
+            resolver,
+            query_type,
+            candidates,
+            failed: Vec::new(),
+        }
+    }
+
+    /// The candidate currently being refined.
+    fn current(&self) -> &dns::Name {
+        &self.current
+    }
+
+    fn take_failed(&mut self) -> Vec<(dns::Name, Instant)> {
+        self.failed.drain(..).collect()
+    }
+}
+
+impl<R: Refine> Future for Search<R> {
+    type Item = dns::Refine;
+    type Error = dns::ResolveError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.pending.poll() {
+                Ok(ready) => return Ok(ready),
+                Err(e) => {
+                    self.failed.push((self.current.clone(), error_ttl(&e).0));
+                    match self.candidates.pop_front() {
+                        None => return Err(e),
+                        Some(name) => {
+                            trace!("search failed: {}; trying {}", e, name);
+                            self.pending = self.resolver.refine(&name, self.query_type);
+                            self.current = name;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns true if `name` is no longer than `max_len` octets (excluding a
+/// trailing dot) and each of its labels is a valid hostname label.
+fn is_valid_name(name: &dns::Name, max_len: usize) -> bool {
+    let name = name.without_trailing_dot();
+    if name.is_empty() || name.len() > max_len {
+        return false;
+    }
+
+    name.split('.').all(|label| {
+        let bytes = label.as_bytes();
+        !bytes.is_empty()
+            && bytes.len() <= MAX_LABEL_LEN
+            && bytes[0] != b'-'
+            && bytes[bytes.len() - 1] != b'-'
+            && bytes
+                .iter()
+                .all(|b| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_')
+    })
+}
+
+/// Returns the time until which a failed refinement may be cached, and
+/// whether it was chosen by the resolver.
+fn error_ttl(e: &dns::ResolveError) -> (Instant, TtlSource) {
+    match e.kind() {
+        dns::ResolveErrorKind::NoRecordsFound {
+            valid_until: Some(valid_until),
+            ..
+        } => (*valid_until, TtlSource::NoRecords),
+        _ => (clock::now() + DNS_ERROR_TTL, TtlSource::ErrorFallback),
+    }
+}
+
+// === impl Refresh ===
+
+impl Refresh {
+    /// Requests that the name be refined immediately.
+    ///
+    /// If a refresh is already pending, this request is coalesced with it.
+    pub fn refresh(&mut self) {
+        if let Err(e) = self.0.try_send(()) {
+            trace!("refresh not sent: {:?}", e);
+        }
+    }
+}
+
+// === impl Rewrite ===
+
+impl Rewrite {
+    pub fn new<F>(rewrite: F) -> Self
+    where
+        F: Fn(&dns::Name) -> Option<dns::Name> + Send + Sync + 'static,
+    {
+        Rewrite(Arc::new(rewrite))
+    }
+
+    fn apply(&self, name: &dns::Name) -> Option<dns::Name> {
+        (self.0)(name)
+    }
+}
+
+impl fmt::Debug for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Rewrite").finish()
+    }
+}
+
+// === impl Churn ===
+
+impl Churn {
+    /// Counts changes that occurred within the last `window`.
+    pub fn new(window: Duration) -> Self {
+        Churn(Arc::new(Mutex::new(ChurnInner {
+            window,
+            changes: HashMap::new(),
+        })))
+    }
+
+    /// Returns the number of times that `name`'s canonical name changed
+    /// within the window.
+    pub fn changes(&self, name: &NameAddr) -> usize {
+        let mut inner = self.0.lock().expect("churn lock poisoned");
+        inner.expire(clock::now());
+        inner.changes.get(name).map(VecDeque::len).unwrap_or(0)
+    }
+
+    fn record(&self, name: &NameAddr) {
+        if let Ok(mut inner) = self.0.lock() {
+            let now = clock::now();
+            inner
+                .changes
+                .entry(name.clone())
+                .or_insert_with(VecDeque::new)
+                .push_back(now);
+            inner.expire(now);
+        }
+    }
+}
+
+impl Default for Churn {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHURN_WINDOW)
+    }
+}
+
+impl FmtMetrics for Churn {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut inner = match self.0.lock() {
+            Ok(inner) => inner,
+            Err(_) => return Ok(()),
+        };
+        inner.expire(clock::now());
+        if inner.changes.is_empty() {
+            return Ok(());
+        }
+
+        canonicalize_name_changes.fmt_help(f)?;
+        for (name, changes) in &inner.changes {
+            Gauge::from(changes.len() as u64).fmt_metric_labeled(
+                f,
+                canonicalize_name_changes.name,
+                Authority(name),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl ChurnInner {
+    /// Forgets changes that are older than the window, and names that have
+    /// no changes within it.
+    fn expire(&mut self, now: Instant) {
+        let window = self.window;
+        self.changes.retain(|_, changes| {
+            while changes.front().map_or(false, |at| *at + window <= now) {
+                changes.pop_front();
+            }
+            !changes.is_empty()
+        });
+    }
+}
+
+// === impl Ttls ===
+
+impl Ttls {
+    /// Returns the TTL that `name` was last refined with, unless it expired
+    /// long enough ago to have been forgotten.
+    pub fn get(&self, name: &NameAddr) -> Option<Ttl> {
+        let mut inner = self.0.lock().expect("ttls lock poisoned");
+        expire_ttls(&mut inner, clock::now());
+        inner.get(name).cloned()
+    }
+
+    fn record(&self, name: &NameAddr, valid_until: Instant, source: TtlSource) {
+        if let Ok(mut inner) = self.0.lock() {
+            let now = clock::now();
+            let ttl = if valid_until > now {
+                valid_until - now
+            } else {
+                Duration::from_secs(0)
+            };
+            trace!("ttl; name={:?} ttl={:?} source={:?}", name, ttl, source);
+            inner.insert(
+                name.clone(),
+                Ttl {
+                    valid_until,
+                    ttl,
+                    source,
+                },
+            );
+            expire_ttls(&mut inner, now);
+        }
+    }
+}
+
+impl FmtMetrics for Ttls {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut inner = match self.0.lock() {
+            Ok(inner) => inner,
+            Err(_) => return Ok(()),
+        };
+        expire_ttls(&mut inner, clock::now());
+        if inner.is_empty() {
+            return Ok(());
+        }
+
+        canonicalize_ttl_ms.fmt_help(f)?;
+        for (name, ttl) in inner.iter() {
+            let ms = ttl.ttl.as_secs() * 1_000 + u64::from(ttl.ttl.subsec_millis());
+            Gauge::from(ms).fmt_metric_labeled(
+                f,
+                canonicalize_ttl_ms.name,
+                (Authority(name), ttl.source),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Forgets the TTLs of names that have not been refined since well after
+/// their TTLs expired.
+fn expire_ttls(ttls: &mut HashMap<NameAddr, Ttl>, now: Instant) {
+    ttls.retain(|_, ttl| ttl.valid_until + TTL_RETENTION > now);
+}
+
+impl FmtLabels for TtlSource {
+    fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source = match *self {
+            TtlSource::Resolver => "resolver",
+            TtlSource::NoRecords => "no_records",
+            TtlSource::ErrorFallback => "error_fallback",
+            TtlSource::InitialRetry => "initial_retry",
+            TtlSource::NegativeCache => "negative_cache",
+        };
+        write!(f, "source=\"{}\"", source)
+    }
+}
+
+// === impl Events ===
+
+/// Returns a publisher and a subscriber for resolution events.
+///
+/// At most `capacity` events are buffered for the subscriber.
+pub fn events(capacity: usize) -> (Events, mpsc::Receiver<Event>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    (Events(tx), rx)
+}
+
+impl Events {
+    fn publish(&mut self, kind: EventKind, original: &NameAddr, resolved: &Cache) {
+        let canonical = match *resolved {
+            Cache::Resolved(ref na) => Some(na.clone()),
+            _ => None,
+        };
+        let event = Event {
+            kind,
+            original: original.clone(),
+            canonical,
+        };
+        if let Err(e) = self.0.try_send(event) {
+            if e.is_full() {
+                warn!(
+                    "dropping canonicalize event; subscriber is lagging: {:?}",
+                    e.into_inner()
+                );
+            } else {
+                trace!("canonicalize event subscriber dropped");
+            }
+        }
+    }
+}
+
+impl<'a> FmtLabels for Authority<'a> {
+    fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "authority=\"{}\"", self.0)
+    }
+}
+
+// === impl Service ===
+
+impl<S, B> svc::Service<http::Request<B>> for Service<S>
+where
+    S: svc::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        try_ready!(self.inner.poll_ready());
+
+        while let Ok(Async::Ready(Some(resolved))) = self.rx.poll() {
+            debug!("refined: {:?}", resolved);
+            self.resolved = resolved;
+        }
+        if self.resolved == Cache::AwaitingInitial {
+            return Ok(Async::NotReady);
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        let addr = match self.resolved {
+            Cache::AwaitingInitial => panic!("called before canonicalized address"),
+            Cache::Unresolved => Addr::from(self.original.clone()),
+            Cache::Fallback(addr) => Addr::Socket(addr),
+            Cache::Resolved(ref na) => Addr::from(na.clone()),
+        };
+        req.extensions_mut().insert(addr);
+        self.inner.call(req)
+    }
+}
+
+impl<S> Service<S> {
+    /// Returns a handle that forces the name to be refined immediately.
+    pub fn refresh_handle(&self) -> Refresh {
+        Refresh(self.tx_refresh.clone())
+    }
+
+    /// Returns the canonical name that requests are routed with, or why the
+    /// name has not been canonicalized.
+    ///
+    /// This reflects the names received as of the last call to `poll_ready`.
+    pub fn canonical_name(&self) -> Conditional<dns::Name, ReasonUnresolved> {
+        match self.resolved {
+            Cache::AwaitingInitial => Conditional::None(ReasonUnresolved::AwaitingInitial),
+            Cache::Unresolved => Conditional::None(ReasonUnresolved::UsingOriginalAfterError),
+            Cache::Fallback(_) => Conditional::None(ReasonUnresolved::UsingFallbackAfterError),
+            Cache::Resolved(ref na) => Conditional::Some(na.name().clone()),
+        }
+    }
+
+    /// Returns true if requests are routed without a canonical name because
+    /// the name could not be refined, i.e. with the original name or its
+    /// fallback address.
+    ///
+    /// This becomes false once the name is refined. Like `canonical_name`, it
+    /// reflects the names received as of the last call to `poll_ready`.
+    pub fn is_degraded(&self) -> bool {
+        match self.resolved {
+            Cache::Unresolved | Cache::Fallback(_) => true,
+            Cache::AwaitingInitial | Cache::Resolved(_) => false,
+        }
+    }
+}
+
+impl<S> Drop for Service<S> {
+    fn drop(&mut self) {
+        trace!(
+            "dropping service; name={:?} resolved={:?}",
+            self.original,
+            self.resolved
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use task::test_util::mock_time;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Refines only the names it knows about, each with a fixed TTL.
+    ///
+    /// Clones share the same names, so that names may be added while a task
+    /// is running.
+    #[derive(Clone, Default)]
+    struct MockRefine {
+        names: Arc<Mutex<HashMap<String, Duration>>>,
+        /// Names that are refined with a specific `valid_until`, rather than
+        /// a TTL from the time of the call.
+        deadlines: Arc<Mutex<HashMap<String, Instant>>>,
+        /// Names that have no records, each with its negative TTL.
+        missing: Arc<Mutex<HashMap<String, Option<Instant>>>>,
+        calls: Arc<AtomicUsize>,
+        /// The number of calls that fail before names are refined.
+        failures: Arc<AtomicUsize>,
+        /// The query type of each call, in order.
+        query_types: Arc<Mutex<Vec<dns::QueryType>>>,
+    }
+
+    impl MockRefine {
+        fn with(self, name: &str, ttl: Duration) -> Self {
+            self.names.lock().unwrap().insert(name.to_owned(), ttl);
+            self
+        }
+
+        fn failing(self, calls: usize) -> Self {
+            self.failures.store(calls, Ordering::SeqCst);
+            self
+        }
+
+        fn with_deadline(self, name: &str, valid_until: Instant) -> Self {
+            self.deadlines
+                .lock()
+                .unwrap()
+                .insert(name.to_owned(), valid_until);
+            self
+        }
+
+        fn without_records(self, name: &str, valid_until: Option<Instant>) -> Self {
+            self.missing
+                .lock()
+                .unwrap()
+                .insert(name.to_owned(), valid_until);
+            self
+        }
+    }
+
+    impl Refine for MockRefine {
+        type Future = future::FutureResult<dns::Refine, dns::ResolveError>;
+
+        fn refine(&self, name: &dns::Name, query_type: dns::QueryType) -> Self::Future {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.query_types.lock().unwrap().push(query_type);
+            let failures = self.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::SeqCst);
+                return future::err("temporary failure".into());
+            }
+            if let Some(valid_until) = self.deadlines.lock().unwrap().get(name.as_ref()) {
+                return future::ok(dns::Refine {
+                    name: name.clone(),
+                    valid_until: *valid_until,
+                });
+            }
+            if let Some(valid_until) = self.missing.lock().unwrap().get(name.as_ref()) {
+                return future::err(dns::no_records_found(*valid_until));
+            }
+            match self.names.lock().unwrap().get(name.as_ref()) {
+                Some(ttl) => future::ok(dns::Refine {
+                    name: name.clone(),
+                    valid_until: clock::now() + *ttl,
+                }),
+                None => future::err("no records found".into()),
+            }
+        }
+    }
+
+    /// Holds each refinement pending until it is released, so that the task
+    /// may be observed while a resolution is in flight.
+    #[derive(Clone, Default)]
+    struct GatedRefine {
+        inner: MockRefine,
+        released: Arc<AtomicBool>,
+    }
+
+    struct Gated<F> {
+        inner: F,
+        released: Arc<AtomicBool>,
+    }
+
+    impl Refine for GatedRefine {
+        type Future = Gated<<MockRefine as Refine>::Future>;
+
+        fn refine(&self, name: &dns::Name, query_type: dns::QueryType) -> Self::Future {
+            Gated {
+                inner: self.inner.refine(name, query_type),
+                released: self.released.clone(),
+            }
+        }
+    }
+
+    impl<F: Future> Future for Gated<F> {
+        type Item = F::Item;
+        type Error = F::Error;
+
+        fn poll(&mut self) -> Poll<F::Item, F::Error> {
+            if !self.released.load(Ordering::SeqCst) {
+                return Ok(Async::NotReady);
+            }
+            self.inner.poll()
+        }
+    }
+
+    fn name(s: &str) -> dns::Name {
+        dns::Name::try_from(s.as_bytes()).expect("name must be valid")
+    }
+
+    /// Runs a `Task` for `original` until it publishes its first name.
+    fn first_published(resolver: MockRefine, search: &[&str], original: &str) -> NameAddr {
+        first_published_with_max_len(resolver, search, original, DEFAULT_MAX_NAME_LEN)
+    }
+
+    fn first_published_with_max_len(
+        resolver: MockRefine,
+        search: &[&str],
+        original: &str,
+        max_name_len: usize,
+    ) -> NameAddr {
+        first_published_with(resolver, search, original, |task| {
+            task.with_max_name_len(max_name_len)
+        })
+    }
+
+    fn first_published_with<F>(
+        resolver: MockRefine,
+        search: &[&str],
+        original: &str,
+        configure: F,
+    ) -> NameAddr
+    where
+        F: FnOnce(Task<MockRefine>) -> Task<MockRefine>,
+    {
+        match first_cached_with(resolver, search, original, configure) {
+            Cache::Resolved(na) => na,
+            Cache::Unresolved => NameAddr::new(name(original), 8080),
+            cache => panic!("unexpected cache: {:?}", cache),
+        }
+    }
+
+    /// Runs a `Task` for `original` until it publishes its first update.
+    fn first_cached_with<F>(
+        resolver: MockRefine,
+        search: &[&str],
+        original: &str,
+        configure: F,
+    ) -> Cache
+    where
+        F: FnOnce(Task<MockRefine>) -> Task<MockRefine>,
+    {
+        let mut rt = Runtime::new().expect("runtime");
+        let (tx, rx) = mpsc::channel(1);
+        let (_tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, rx_stop) = oneshot::channel();
+        let original = NameAddr::new(name(original), 8080);
+        let search = search.iter().map(|s| name(s)).collect();
+        rt.spawn(configure(Task::new(
+            original,
+            resolver,
+            Duration::from_secs(1),
+            search,
+            tx,
+            rx_refresh,
+            rx_stop,
+        )));
+
+        let (published, _rx) = rt
+            .block_on(rx.into_future())
+            .ok()
+            .expect("task must publish a name");
+        published.expect("task must not complete")
+    }
+
+    #[test]
+    fn search_tries_each_domain_in_order() {
+        let resolver = MockRefine::default().with("web.b.example.com.", Duration::from_secs(10));
+        let published = first_published(resolver, &["a.example.com", "b.example.com"], "web");
+        assert_eq!(published.name(), &name("web.b.example.com."));
+        assert_eq!(published.port(), 8080);
+    }
+
+    #[test]
+    fn search_uses_first_success() {
+        let resolver = MockRefine::default()
+            .with("web.a.example.com.", Duration::from_secs(10))
+            .with("web.b.example.com.", Duration::from_secs(10));
+        let published = first_published(resolver, &["a.example.com", "b.example.com"], "web");
+        assert_eq!(published.name(), &name("web.a.example.com."));
+    }
+
+    #[test]
+    fn search_skipped_for_absolute_names() {
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(10));
+        let published = first_published(resolver, &["a.example.com"], "web.example.com.");
+        assert_eq!(published.name(), &name("web.example.com."));
+    }
+
+    #[test]
+    fn search_honors_ndots() {
+        let resolver = MockRefine::default()
+            .with("web.ns", Duration::from_secs(10))
+            .with("web.ns.svc.cluster.local.", Duration::from_secs(10));
+        let search = &["svc.cluster.local"];
+
+        // Names with at least `ndots` dots are tried as-is first.
+        let published = first_published(resolver.clone(), search, "web.ns");
+        assert_eq!(published.name(), &name("web.ns"));
+
+        // Names with fewer dots are qualified with each search domain first.
+        let published = first_published_with(resolver.clone(), search, "web.ns", |task| {
+            task.with_ndots(5)
+        });
+        assert_eq!(published.name(), &name("web.ns.svc.cluster.local."));
+
+        // Either way, the name is tried as-is if no search domain succeeds.
+        let resolver = MockRefine::default().with("web.ns", Duration::from_secs(10));
+        let calls = resolver.calls.clone();
+        let published = first_published_with(resolver, search, "web.ns", |task| task.with_ndots(5));
+        assert_eq!(published.name(), &name("web.ns"));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn search_falls_back_to_original_name_on_failure() {
+        let published = first_published(MockRefine::default(), &["a.example.com"], "web");
+        assert_eq!(published.name(), &name("web"));
+    }
+
+    #[test]
+    fn initial_refinement_is_retried() {
+        let resolver = MockRefine::default()
+            .with("web.example.com.", Duration::from_secs(10))
+            .failing(1);
+        let calls = resolver.calls.clone();
+        let cache = first_cached_with(resolver, &[], "web.example.com.", |task| task);
+        assert_eq!(
+            cache,
+            Cache::Resolved(NameAddr::new(name("web.example.com."), 8080))
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Once the retries are exhausted, the original name is used.
+        let resolver = MockRefine::default()
+            .with("web.example.com.", Duration::from_secs(10))
+            .failing(INITIAL_RETRIES + 1);
+        let calls = resolver.calls.clone();
+        let cache = first_cached_with(resolver, &[], "web.example.com.", |task| task);
+        assert_eq!(cache, Cache::Unresolved);
+        assert_eq!(calls.load(Ordering::SeqCst), INITIAL_RETRIES + 1);
+    }
+
+    #[test]
+    fn query_type_is_passed_to_resolver() {
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(10));
+        let query_types = resolver.query_types.clone();
+        let search = &["a.example.com", "example.com"];
+        first_published(resolver.clone(), search, "web");
+        assert_eq!(
+            *query_types.lock().unwrap(),
+            vec![dns::QueryType::Ip, dns::QueryType::Ip],
+            "the resolver's strategy must be used by default"
+        );
+
+        query_types.lock().unwrap().clear();
+        let published = first_published_with(resolver, search, "web", |task| {
+            task.with_query_type(dns::QueryType::A)
+        });
+        assert_eq!(published.name(), &name("web.example.com."));
+        assert_eq!(
+            *query_types.lock().unwrap(),
+            vec![dns::QueryType::A, dns::QueryType::A],
+            "each search candidate must be queried with the configured type"
+        );
+    }
+
+    #[test]
+    fn overlong_names_fall_back_to_original_name() {
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(10));
+        let published =
+            first_published_with_max_len(resolver.clone(), &["example.com"], "web", 15);
+        assert_eq!(published.name(), &name("web.example.com."));
+
+        let published = first_published_with_max_len(resolver, &["example.com"], "web", 14);
+        assert_eq!(published.name(), &name("web"));
+        assert_eq!(published.port(), 8080);
+    }
+
+    #[test]
+    fn fallback_addr_is_used_when_refinement_fails() {
+        let fallback = SocketAddr::from(([10, 1, 2, 3], 8080));
+        let cache = first_cached_with(MockRefine::default(), &["example.com"], "web", |task| {
+            task.with_fallback(Some(fallback))
+        });
+        assert_eq!(cache, Cache::Fallback(fallback));
+
+        // The fallback is not used when the name is refined.
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(10));
+        let cache = first_cached_with(resolver, &["example.com"], "web", |task| {
+            task.with_fallback(Some(fallback))
+        });
+        assert_eq!(
+            cache,
+            Cache::Resolved(NameAddr::new(name("web.example.com."), 8080))
+        );
+    }
+
+    #[test]
+    fn service_routes_to_fallback_addr() {
+        let fallback = SocketAddr::from(([10, 1, 2, 3], 8080));
+        let (mut tx, rx) = mpsc::channel(1);
+        let mut service = service(rx);
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            use svc::Service as _Service;
+
+            tx.try_send(Cache::Fallback(fallback)).expect("fallback must be queued");
+            assert!(service.poll_ready().unwrap().is_ready());
+            assert_eq!(
+                service.canonical_name(),
+                Conditional::None(ReasonUnresolved::UsingFallbackAfterError)
+            );
+            let addr = service.call(http::Request::new(())).wait().unwrap();
+            assert_eq!(addr, Some(Addr::Socket(fallback)));
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    fn legacy_rewrite() -> Option<Rewrite> {
+        Some(Rewrite::new(|n| {
+            if n.as_ref() == "legacy.example.com." {
+                Some(name("new.example.com."))
+            } else {
+                None
+            }
+        }))
+    }
+
+    #[test]
+    fn rewrite_applies_to_matched_names() {
+        let resolver = MockRefine::default().with("legacy.example.com.", Duration::from_secs(10));
+        let published = first_published_with(resolver, &["example.com"], "legacy", |task| {
+            task.with_rewrite(legacy_rewrite())
+        });
+        assert_eq!(published.name(), &name("new.example.com."));
+        assert_eq!(published.port(), 8080);
+    }
+
+    #[test]
+    fn rewrite_ignores_unmatched_names() {
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(10));
+        let published = first_published_with(resolver, &["example.com"], "web", |task| {
+            task.with_rewrite(legacy_rewrite())
+        });
+        assert_eq!(published.name(), &name("web.example.com."));
+    }
+
+    #[test]
+    fn invalid_rewrites_are_ignored() {
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(10));
+        let rewrite = Rewrite::new(|_| Some(name("overlong-name.example.com.")));
+        let published = first_published_with(resolver, &["example.com"], "web", |task| {
+            task.with_max_name_len(20).with_rewrite(Some(rewrite))
+        });
+        assert_eq!(published.name(), &name("web.example.com."));
+    }
+
+    #[test]
+    fn refined_names_must_have_an_allowed_suffix() {
+        let allowed = || Some(Arc::new(vec![dns::Suffix::Name(name("example.com"))]));
+        let resolver = MockRefine::default()
+            .with("web.example.com.", Duration::from_secs(10))
+            .with("web.example.net.", Duration::from_secs(10));
+
+        let cache = first_cached_with(resolver.clone(), &["example.com"], "web", |task| {
+            task.with_allowed_suffixes(allowed())
+        });
+        assert_eq!(
+            cache,
+            Cache::Resolved(NameAddr::new(name("web.example.com."), 8080))
+        );
+
+        let cache = first_cached_with(resolver.clone(), &["example.net"], "web", |task| {
+            task.with_allowed_suffixes(allowed())
+        });
+        assert_eq!(cache, Cache::Unresolved);
+
+        // Rewritten names are also checked.
+        let rewrite = Rewrite::new(|_| Some(name("web.example.net.")));
+        let cache = first_cached_with(resolver, &["example.com"], "web", |task| {
+            task.with_rewrite(Some(rewrite)).with_allowed_suffixes(allowed())
+        });
+        assert_eq!(cache, Cache::Unresolved);
+    }
+
+    #[test]
+    fn name_validation() {
+        assert!(is_valid_name(&name("web.example.com."), 253));
+        assert!(!is_valid_name(&name("web.example.com."), 14));
+
+        let label = "a".repeat(MAX_LABEL_LEN);
+        assert!(is_valid_name(&name(&format!("{}.example.com", label)), 253));
+
+        let long = vec![label.as_str(); 4].join(".");
+        assert_eq!(long.len(), 255);
+        if let Ok(long) = dns::Name::try_from(long.as_bytes()) {
+            assert!(!is_valid_name(&long, DEFAULT_MAX_NAME_LEN));
+        }
+    }
+
+    #[test]
+    fn refresh_coalesces_requests() {
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(3600));
+        let calls = resolver.calls.clone();
+        let (tx, mut rx) = mpsc::channel(1);
+        let (tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, rx_stop) = oneshot::channel();
+        let mut refresh = Refresh(tx_refresh);
+        let mut task = Task::new(
+            NameAddr::new(name("web.example.com."), 8080),
+            resolver,
+            Duration::from_secs(1),
+            vec![],
+            tx,
+            rx_refresh,
+            rx_stop,
+        );
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+            assert!(rx.poll().unwrap().is_ready(), "name must be published");
+
+            // The TTL is long, so the task stays idle until refreshed.
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+            refresh.refresh();
+            refresh.refresh();
+            refresh.refresh();
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 2, "refreshes must coalesce");
+
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn refreshes_during_resolution_coalesce() {
+        let inner = MockRefine::default().with("web.example.com.", Duration::from_secs(3600));
+        let calls = inner.calls.clone();
+        let resolver = GatedRefine {
+            inner,
+            ..GatedRefine::default()
+        };
+        let released = resolver.released.clone();
+        let (tx, mut rx) = mpsc::channel(1);
+        let (tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, rx_stop) = oneshot::channel();
+        let refresh = Refresh(tx_refresh);
+        let mut task = Task::new(
+            NameAddr::new(name("web.example.com."), 8080),
+            resolver,
+            Duration::from_secs(1),
+            vec![],
+            tx,
+            rx_refresh,
+            rx_stop,
+        );
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+            // Refreshes from several handles arrive while the query is pending.
+            for _ in 0..3 {
+                refresh.clone().refresh();
+                assert!(task.poll().unwrap().is_not_ready());
+            }
+            assert_eq!(calls.load(Ordering::SeqCst), 1, "pending query must not restart");
+
+            // Once the query completes, exactly one more query is issued.
+            released.store(true, Ordering::SeqCst);
+            assert!(task.poll().unwrap().is_not_ready());
+            assert!(rx.poll().unwrap().is_ready(), "name must be published");
+            assert_eq!(calls.load(Ordering::SeqCst), 2, "refreshes must coalesce");
+
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn seeded_names_are_used_until_refined() {
+        let seed = NameAddr::new(name("web.seed.example.com."), 8080);
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(3600));
+        let calls = resolver.calls.clone();
+        let churn = Churn::new(Duration::from_secs(3600));
+        let (events, mut rx_events) = events(8);
+        let original = NameAddr::new(name("web"), 8080);
+
+        let (_tx_cache, rx_cache) = mpsc::channel(1);
+        let mut service = service(rx_cache);
+        service.resolved = Cache::Resolved(seed.clone());
+
+        let event = |kind, canonical: &str| Event {
+            kind,
+            original: original.clone(),
+            canonical: Some(NameAddr::new(name(canonical), 8080)),
+        };
+
+        mock_time(|time| {
+            use svc::Service as _Service;
+
+            let (tx, mut rx) = mpsc::channel(1);
+            let (_tx_refresh, rx_refresh) = mpsc::channel(1);
+            let (_tx_stop, rx_stop) = oneshot::channel();
+            let mut task = Task::new(
+                original.clone(),
+                resolver,
+                Duration::from_secs(1),
+                vec![name("example.com")],
+                tx,
+                rx_refresh,
+                rx_stop,
+            )
+            .with_churn(Some(churn.clone()))
+            .with_events(Some(events))
+            .with_seed(Some(seed.clone()), Duration::from_secs(1));
+
+            // The seed is served without waiting for the task.
+            assert!(service.poll_ready().unwrap().is_ready());
+            assert_eq!(service.canonical_name(), Conditional::Some(seed.name().clone()));
+            let addr = service.call(http::Request::new(())).wait().unwrap();
+            assert_eq!(addr, Some(seed.clone().into()));
+
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 0, "seeds must not be refined at first");
+            assert!(rx.poll().unwrap().is_not_ready(), "seeds must not be published");
+            assert_eq!(
+                rx_events.poll().unwrap(),
+                Async::Ready(Some(event(EventKind::Initial, "web.seed.example.com."))),
+                "the seed must be the initial name"
+            );
+
+            time.advance(Duration::from_secs(2));
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 1, "seeds must be refined");
+            match rx.poll().unwrap() {
+                Async::Ready(Some(Cache::Resolved(na))) => {
+                    assert_eq!(na.name(), &name("web.example.com."));
+                }
+                cache => panic!("unexpected cache: {:?}", cache),
+            }
+            assert_eq!(
+                rx_events.poll().unwrap(),
+                Async::Ready(Some(event(EventKind::Changed, "web.example.com."))),
+            );
+            assert_eq!(churn.changes(&original), 0, "correcting a seed is not a change");
+        })
+    }
+
+    /// Returns a task for `web.example.com.` whose refinements expire after
+    /// 10ms and may be used for `max_stale` past their TTLs, with its
+    /// published names, the gate that releases its refinements, and the
+    /// sender that keeps it running.
+    fn stale_task(
+        max_stale: Duration,
+    ) -> (
+        Task<GatedRefine>,
+        mpsc::Receiver<Cache>,
+        Arc<AtomicBool>,
+        oneshot::Sender<Never>,
+    ) {
+        let inner = MockRefine::default().with("web.example.com.", Duration::from_millis(10));
+        let resolver = GatedRefine {
+            inner,
+            ..GatedRefine::default()
+        };
+        let released = resolver.released.clone();
+        let (tx, rx) = mpsc::channel(1);
+        let (_tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (tx_stop, rx_stop) = oneshot::channel();
+        let task = Task::new(
+            NameAddr::new(name("web.example.com."), 8080),
+            resolver,
+            Duration::from_secs(1),
+            vec![],
+            tx,
+            rx_refresh,
+            rx_stop,
+        )
+        .with_max_stale(Some(max_stale));
+        (task, rx, released, tx_stop)
+    }
+
+    #[test]
+    fn stale_names_are_used_while_revalidated_within_max_stale() {
+        mock_time(|time| {
+            let (mut task, mut rx, released, _tx_stop) = stale_task(Duration::from_secs(60));
+            let resolved = Cache::Resolved(NameAddr::new(name("web.example.com."), 8080));
+
+            released.store(true, Ordering::SeqCst);
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(rx.poll().unwrap(), Async::Ready(Some(resolved.clone())));
+            // Revalidation stalls once the TTL expires.
+            released.store(false, Ordering::SeqCst);
+
+            time.advance(Duration::from_millis(20));
+            assert!(task.poll().unwrap().is_not_ready());
+            assert!(task.stale.until.is_some(), "the stale window must start");
+            assert!(
+                rx.poll().unwrap().is_not_ready(),
+                "the stale name must be used"
+            );
+
+            released.store(true, Ordering::SeqCst);
+            assert!(task.poll().unwrap().is_not_ready());
+            assert!(task.stale.until.is_none(), "the name must be revalidated");
+            assert!(
+                rx.poll().unwrap().is_not_ready(),
+                "unchanged names are not published"
+            );
+            assert_eq!(task.resolved, resolved);
+        })
+    }
+
+    #[test]
+    fn original_name_is_used_once_max_stale_is_exceeded() {
+        mock_time(|time| {
+            let (mut task, mut rx, released, _tx_stop) = stale_task(Duration::from_millis(30));
+            let resolved = Cache::Resolved(NameAddr::new(name("web.example.com."), 8080));
+
+            released.store(true, Ordering::SeqCst);
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(rx.poll().unwrap(), Async::Ready(Some(resolved.clone())));
+            released.store(false, Ordering::SeqCst);
+
+            time.advance(Duration::from_millis(20));
+            assert!(task.poll().unwrap().is_not_ready());
+            assert!(rx.poll().unwrap().is_not_ready(), "the stale name must be used");
+
+            time.advance(Duration::from_millis(50));
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(
+                rx.poll().unwrap(),
+                Async::Ready(Some(Cache::Unresolved)),
+                "the original name must be used once the stale window elapses"
+            );
+
+            // The canonical name is used again once it is revalidated.
+            released.store(true, Ordering::SeqCst);
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(rx.poll().unwrap(), Async::Ready(Some(resolved.clone())));
+        })
+    }
+
+    #[test]
+    fn expired_names_are_not_churn() {
+        mock_time(|time| {
+            let churn = Churn::new(Duration::from_secs(3600));
+            let original = NameAddr::new(name("web.example.com."), 8080);
+            let (task, mut rx, released, _tx_stop) = stale_task(Duration::from_millis(30));
+            let mut task = task.with_churn(Some(churn.clone()));
+
+            released.store(true, Ordering::SeqCst);
+            assert!(task.poll().unwrap().is_not_ready());
+            assert!(rx.poll().unwrap().is_ready(), "name must be published");
+            released.store(false, Ordering::SeqCst);
+
+            time.advance(Duration::from_millis(20));
+            assert!(task.poll().unwrap().is_not_ready());
+            time.advance(Duration::from_millis(50));
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(rx.poll().unwrap(), Async::Ready(Some(Cache::Unresolved)));
+            assert_eq!(
+                churn.changes(&original),
+                0,
+                "expiring a name is not a change"
+            );
+
+            released.store(true, Ordering::SeqCst);
+            assert!(task.poll().unwrap().is_not_ready());
+            assert!(rx.poll().unwrap().is_ready(), "name must be revalidated");
+            assert_eq!(
+                churn.changes(&original),
+                0,
+                "revalidating an unchanged name is not a change"
+            );
+        })
+    }
+
+    #[test]
+    fn fallback_addr_is_used_once_max_stale_is_exceeded() {
+        mock_time(|time| {
+            let fallback = SocketAddr::from(([10, 1, 2, 3], 8080));
+            let (task, mut rx, released, _tx_stop) = stale_task(Duration::from_millis(30));
+            let mut task = task.with_fallback(Some(fallback));
+
+            released.store(true, Ordering::SeqCst);
+            assert!(task.poll().unwrap().is_not_ready());
+            assert!(rx.poll().unwrap().is_ready(), "name must be published");
+            released.store(false, Ordering::SeqCst);
+
+            time.advance(Duration::from_millis(20));
+            assert!(task.poll().unwrap().is_not_ready());
+            time.advance(Duration::from_millis(50));
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(
+                rx.poll().unwrap(),
+                Async::Ready(Some(Cache::Fallback(fallback))),
+                "the fallback must be used once the stale window elapses"
+            );
+        })
+    }
+
+    #[test]
+    fn ignored_refinements_keep_the_last_canonical_name() {
+        let disallowed = Arc::new(AtomicBool::new(false));
+        let rewrite = {
+            let disallowed = disallowed.clone();
+            Rewrite::new(move |_| {
+                if disallowed.load(Ordering::SeqCst) {
+                    Some(name("web.example.net."))
+                } else {
+                    None
+                }
+            })
+        };
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(3600));
+        let ttls = Ttls::default();
+        let (events, mut rx_events) = events(8);
+        let (tx, mut rx) = mpsc::channel(1);
+        let (tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, rx_stop) = oneshot::channel();
+        let mut refresh = Refresh(tx_refresh);
+        let original = NameAddr::new(name("web.example.com."), 8080);
+        let mut task = Task::new(
+            original.clone(),
+            resolver,
+            Duration::from_secs(1),
+            vec![],
+            tx,
+            rx_refresh,
+            rx_stop,
+        )
+        .with_rewrite(Some(rewrite))
+        .with_allowed_suffixes(Some(Arc::new(vec![dns::Suffix::Name(name("example.com"))])))
+        .with_ttls(Some(ttls.clone()))
+        .with_events(Some(events));
+        let resolved = Cache::Resolved(original.clone());
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(rx.poll().unwrap(), Async::Ready(Some(resolved.clone())));
+
+            // The name is now refined to a disallowed name, which is ignored.
+            disallowed.store(true, Ordering::SeqCst);
+            refresh.refresh();
+            assert!(task.poll().unwrap().is_not_ready());
+            assert!(rx.poll().unwrap().is_not_ready(), "the last name must be kept");
+            assert_eq!(task.resolved, resolved);
+
+            // The name is refined again as if the resolver had failed.
+            let ttl = ttls.get(&original).expect("ttl must be recorded");
+            assert_eq!(ttl.source, TtlSource::ErrorFallback);
+            assert!(ttl.ttl <= DNS_ERROR_TTL);
+
+            let mut received = Vec::new();
+            while let Ok(Async::Ready(Some(ev))) = rx_events.poll() {
+                received.push(ev.kind);
+            }
+            assert_eq!(received, vec![EventKind::Initial]);
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    fn service(
+        rx: mpsc::Receiver<Cache>,
+    ) -> Service<impl svc::Service<http::Request<()>, Response = Option<Addr>, Error = ()>> {
+        let (tx_refresh, _rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, _rx_stop) = oneshot::channel();
+        Service {
+            original: NameAddr::new(name("web"), 8080),
+            resolved: Cache::AwaitingInitial,
+            inner: svc::mk(|req: http::Request<()>| {
+                Ok::<_, ()>(req.extensions().get::<Addr>().cloned())
+            }),
+            rx,
+            tx_refresh,
+            _tx_stop,
+        }
+    }
+
+    #[test]
+    fn service_uses_latest_queued_name() {
+        let (mut tx, rx) = mpsc::channel(3);
+        let mut service = service(rx);
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            use svc::Service as _Service;
+
+            assert!(service.poll_ready().unwrap().is_not_ready());
+
+            for n in &["web.a.example.com.", "web.b.example.com.", "web.c.example.com."] {
+                let na = NameAddr::new(name(n), 8080);
+                tx.try_send(Cache::Resolved(na)).expect("name must be queued");
+            }
+            assert!(service.poll_ready().unwrap().is_ready());
+            let addr = service.call(http::Request::new(())).wait().unwrap();
+            let expected = NameAddr::new(name("web.c.example.com."), 8080);
+            assert_eq!(addr, Some(expected.into()), "only the latest name must be used");
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn service_is_degraded_until_refined() {
+        let (mut tx, rx) = mpsc::channel(1);
+        let mut service = service(rx);
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            use svc::Service as _Service;
+
+            assert!(service.poll_ready().unwrap().is_not_ready());
+            assert!(!service.is_degraded(), "must not be degraded before a name");
+
+            tx.try_send(Cache::Unresolved).expect("name must be queued");
+            assert!(service.poll_ready().unwrap().is_ready());
+            assert!(service.is_degraded(), "original name must be degraded");
+
+            let na = NameAddr::new(name("web.example.com."), 8080);
+            tx.try_send(Cache::Resolved(na)).expect("name must be queued");
+            assert!(service.poll_ready().unwrap().is_ready());
+            assert!(!service.is_degraded(), "refined name must recover");
+
+            let fallback = SocketAddr::from(([10, 1, 2, 3], 8080));
+            tx.try_send(Cache::Fallback(fallback)).expect("fallback must be queued");
+            assert!(service.poll_ready().unwrap().is_ready());
+            assert!(service.is_degraded(), "fallback address must be degraded");
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn canonical_name_awaits_initial_name() {
+        let (tx, rx) = mpsc::channel::<Cache>(1);
+        let mut service = service(rx);
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            use svc::Service as _Service;
+
+            assert!(_Service::<http::Request<()>>::poll_ready(&mut service)
+                .unwrap()
+                .is_not_ready());
+            assert_eq!(
+                service.canonical_name(),
+                Conditional::None(ReasonUnresolved::AwaitingInitial)
+            );
+
+            // The task may complete without publishing a name.
+            drop(tx);
+            assert!(_Service::<http::Request<()>>::poll_ready(&mut service)
+                .unwrap()
+                .is_not_ready());
+            assert_eq!(
+                service.canonical_name(),
+                Conditional::None(ReasonUnresolved::AwaitingInitial)
+            );
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    /// Runs a `Task` that refines `web` with `resolver` until its `Service` is
+    /// ready, returning the service's canonical name.
+    fn canonical_name_after_refining(
+        resolver: MockRefine,
+    ) -> (Conditional<dns::Name, ReasonUnresolved>, Option<Addr>) {
+        let mut rt = Runtime::new().expect("runtime");
+        let (tx, rx) = mpsc::channel(1);
+        let (_tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, rx_stop) = oneshot::channel();
+        rt.spawn(Task::new(
+            NameAddr::new(name("web"), 8080),
+            resolver,
+            Duration::from_secs(1),
+            vec![name("example.com")],
+            tx,
+            rx_refresh,
+            rx_stop,
+        ));
+
+        let mut service = service(rx);
+        rt.block_on(future::poll_fn(|| {
+            svc::Service::<http::Request<()>>::poll_ready(&mut service)
+        }))
+        .expect("service must become ready");
+        let addr = svc::Service::call(&mut service, http::Request::new(()))
+            .wait()
+            .unwrap();
+        (service.canonical_name(), addr)
+    }
+
+    #[test]
+    fn canonical_name_reports_resolved_name() {
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(10));
+        let (canonical, addr) = canonical_name_after_refining(resolver);
+        assert_eq!(canonical, Conditional::Some(name("web.example.com.")));
+        assert_eq!(
+            addr,
+            Some(NameAddr::new(name("web.example.com."), 8080).into())
+        );
+    }
+
+    #[test]
+    fn canonical_name_reports_use_of_original_name_after_error() {
+        let (canonical, addr) = canonical_name_after_refining(MockRefine::default());
+        assert_eq!(
+            canonical,
+            Conditional::None(ReasonUnresolved::UsingOriginalAfterError)
+        );
+        assert_eq!(addr, Some(NameAddr::new(name("web"), 8080).into()));
+    }
+
+    #[test]
+    fn churn_counts_canonical_name_changes() {
+        let resolver = MockRefine::default().with("web.a.example.com.", Duration::from_secs(3600));
+        let churn = Churn::new(Duration::from_secs(3600));
+        let (tx, mut rx) = mpsc::channel(1);
+        let (tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, rx_stop) = oneshot::channel();
+        let mut refresh = Refresh(tx_refresh);
+        let original = NameAddr::new(name("web"), 8080);
+        let mut task = Task::new(
+            original.clone(),
+            resolver.clone(),
+            Duration::from_secs(1),
+            vec![name("a.example.com"), name("b.example.com")],
+            tx,
+            rx_refresh,
+            rx_stop,
+        )
+        .with_churn(Some(churn.clone()));
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            let mut published = || {
+                assert!(task.poll().unwrap().is_not_ready());
+                match rx.poll().unwrap() {
+                    Async::Ready(Some(Cache::Resolved(na))) => Some(na.name().clone()),
+                    _ => None,
+                }
+            };
+
+            assert_eq!(published(), Some(name("web.a.example.com.")));
+            assert_eq!(churn.changes(&original), 0, "the initial name is not a change");
+
+            // The name alternates between search domains.
+            for (i, n) in ["web.b.example.com.", "web.a.example.com."].iter().enumerate() {
+                {
+                    let mut names = resolver.names.lock().unwrap();
+                    names.clear();
+                    names.insert(n.to_string(), Duration::from_secs(3600));
+                }
+                refresh.refresh();
+                assert_eq!(published(), Some(name(n)));
+                assert_eq!(churn.changes(&original), i + 1);
+            }
+
+            // Refreshing an unchanged name is not a change.
+            refresh.refresh();
+            assert_eq!(published(), None);
+            assert_eq!(churn.changes(&original), 2);
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn events_describe_each_resolution() {
+        let resolver = MockRefine::default().with("web.a.example.com.", Duration::from_secs(3600));
+        let (events, mut rx_events) = events(8);
+        let (tx, mut rx) = mpsc::channel(1);
+        let (tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, rx_stop) = oneshot::channel();
+        let mut refresh = Refresh(tx_refresh);
+        let original = NameAddr::new(name("web"), 8080);
+        let mut task = Task::new(
+            original.clone(),
+            resolver.clone(),
+            Duration::from_secs(1),
+            vec![name("a.example.com"), name("b.example.com")],
+            tx,
+            rx_refresh,
+            rx_stop,
+        )
+        .with_events(Some(events));
+
+        let event = |kind, canonical: Option<&str>| Event {
+            kind,
+            original: original.clone(),
+            canonical: canonical.map(|n| NameAddr::new(name(n), 8080)),
+        };
+
+        let mut rt = Runtime::new().expect("runtime");
+        let received = rt
+            .block_on(future::lazy(move || {
+                assert!(task.poll().unwrap().is_not_ready());
+                let _ = rx.poll();
+
+                refresh.refresh();
+                assert!(task.poll().unwrap().is_not_ready());
+
+                {
+                    let mut names = resolver.names.lock().unwrap();
+                    names.clear();
+                    names.insert("web.b.example.com.".into(), Duration::from_secs(3600));
+                }
+                refresh.refresh();
+                assert!(task.poll().unwrap().is_not_ready());
+                let _ = rx.poll();
+
+                let mut received = Vec::new();
+                while let Ok(Async::Ready(Some(ev))) = rx_events.poll() {
+                    received.push(ev);
+                }
+                Ok::<_, ()>(received)
+            }))
+            .unwrap();
+
+        assert_eq!(
+            received,
+            vec![
+                event(EventKind::Initial, Some("web.a.example.com.")),
+                event(EventKind::Refreshed, Some("web.a.example.com.")),
+                event(EventKind::Changed, Some("web.b.example.com.")),
+            ]
+        );
+    }
+
+    #[test]
+    fn events_describe_fallback_after_error() {
+        let (events, rx_events) = events(8);
+        let fallback = SocketAddr::from(([10, 1, 2, 3], 8080));
+        let cache = first_cached_with(MockRefine::default(), &["example.com"], "web", |task| {
+            task.with_fallback(Some(fallback)).with_events(Some(events))
+        });
+        assert_eq!(cache, Cache::Fallback(fallback));
+
+        let (event, _) = rx_events.into_future().wait().ok().unwrap();
+        assert_eq!(
+            event,
+            Some(Event {
+                kind: EventKind::ErrorFallback,
+                original: NameAddr::new(name("web"), 8080),
+                canonical: None,
+            })
+        );
+    }
+
+    #[test]
+    fn events_are_dropped_when_the_subscriber_lags() {
+        let resolver = MockRefine::default().with("web.example.com.", Duration::from_secs(3600));
+        let (events, rx_events) = events(1);
+        let (tx, mut rx) = mpsc::channel(1);
+        let (tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, rx_stop) = oneshot::channel();
+        let mut refresh = Refresh(tx_refresh);
+        let calls = resolver.calls.clone();
+        let mut task = Task::new(
+            NameAddr::new(name("web.example.com."), 8080),
+            resolver,
+            Duration::from_secs(1),
+            vec![],
+            tx,
+            rx_refresh,
+            rx_stop,
+        )
+        .with_events(Some(events));
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            assert!(task.poll().unwrap().is_not_ready());
+            let _ = rx.poll();
+
+            // The subscriber never receives, but resolution continues.
+            for i in 0..3 {
+                refresh.refresh();
+                assert!(task.poll().unwrap().is_not_ready());
+                assert_eq!(calls.load(Ordering::SeqCst), i + 2);
+            }
+
+            drop(rx_events);
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn ttls_record_each_chosen_ttl_and_its_source() {
+        let now = Instant::now();
+        let valid_until = now + Duration::from_secs(30);
+        let nxdomain_until = now + Duration::from_secs(5);
+        let resolver = MockRefine::default()
+            .with_deadline("web.a.example.com.", valid_until)
+            .without_records("api.a.example.com.", Some(nxdomain_until))
+            .without_records("api", Some(nxdomain_until))
+            .without_records("db.a.example.com.", None)
+            .without_records("db", None);
+
+        let ttl = |original: &str| {
+            let ttls = Ttls::default();
+            first_cached_with(resolver.clone(), &["a.example.com"], original, |task| {
+                // Failures are published without retrying.
+                let mut task = task.with_ttls(Some(ttls.clone()));
+                task.expiry.initial_retries = 0;
+                task
+            });
+            ttls.get(&NameAddr::new(name(original), 8080))
+                .expect("ttl must be recorded")
+        };
+
+        let web = ttl("web");
+        assert_eq!(web.source, TtlSource::Resolver);
+        assert_eq!(web.valid_until, valid_until);
+        assert!(web.ttl <= Duration::from_secs(30));
+
+        let api = ttl("api");
+        assert_eq!(api.source, TtlSource::NoRecords);
+        assert_eq!(api.valid_until, nxdomain_until);
+
+        // Without a negative TTL, the error fallback is used.
+        let db = ttl("db");
+        assert_eq!(db.source, TtlSource::ErrorFallback);
+        assert!(db.ttl <= DNS_ERROR_TTL);
+    }
+
+    #[test]
+    fn ttls_are_exported_as_metrics() {
+        let ttls = Ttls::default();
+        let original = NameAddr::new(name("web"), 8080);
+        ttls.record(
+            &original,
+            Instant::now() + Duration::from_secs(3600),
+            TtlSource::Resolver,
+        );
+        let out = ttls.as_display().to_string();
+        let labels = "canonicalize_ttl_ms{authority=\"web:8080\",source=\"resolver\"} ";
+        let ms = out
+            .lines()
+            .find(|l| l.starts_with(labels))
+            .and_then(|l| l[labels.len()..].parse::<u64>().ok())
+            .unwrap_or_else(|| panic!("unexpected metrics: {}", out));
+        assert!(ms > 3_590_000 && ms <= 3_600_000, "unexpected ttl: {}ms", ms);
+    }
+
+    #[test]
+    fn churn_forgets_changes_outside_the_window() {
+        let original = NameAddr::new(name("web"), 8080);
+        let churn = Churn::new(Duration::from_secs(0));
+        churn.record(&original);
+        assert_eq!(churn.changes(&original), 0);
+        assert!(churn.0.lock().unwrap().changes.is_empty());
+    }
+
+    #[test]
+    fn search_cache_fails_over_to_preferred_domain() {
+        let resolver = MockRefine::default().with("web.b.example.com.", Duration::from_secs(3600));
+        let calls = resolver.calls.clone();
+        let (tx, mut rx) = mpsc::channel(1);
+        let (tx_refresh, rx_refresh) = mpsc::channel(1);
+        let (_tx_stop, rx_stop) = oneshot::channel();
+        let mut refresh = Refresh(tx_refresh);
+        let mut task = Task::new(
+            NameAddr::new(name("web"), 8080),
+            resolver.clone(),
+            Duration::from_secs(1),
+            vec![name("a.example.com"), name("b.example.com")],
+            tx,
+            rx_refresh,
+            rx_stop,
+        )
+        .with_search_cache();
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(move || {
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+            match rx.poll().unwrap() {
+                Async::Ready(Some(Cache::Resolved(na))) => {
+                    assert_eq!(na.name(), &name("web.b.example.com."))
+                }
+                _ => panic!("name must be published"),
+            }
+            match task.state {
+                State::ValidUntil(ref delay) => assert!(
+                    delay.deadline() <= Instant::now() + DNS_ERROR_TTL,
+                    "the preferred domain must be retried when its failure expires"
+                ),
+                _ => panic!("task must be idle"),
+            }
+
+            // The preferred domain starts resolving, but its failure is still
+            // cached, so it is not retried.
+            let _ = resolver.with("web.a.example.com.", Duration::from_secs(3600));
+            refresh.refresh();
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 3);
+            assert!(rx.poll().unwrap().is_not_ready(), "name must not change");
+
+            // Once the failure expires, the preferred domain is used.
+            for until in task.search.negative.as_mut().unwrap().failed.values_mut() {
+                *until = Instant::now();
+            }
+            refresh.refresh();
+            assert!(task.poll().unwrap().is_not_ready());
+            assert_eq!(calls.load(Ordering::SeqCst), 4);
+            match rx.poll().unwrap() {
+                Async::Ready(Some(Cache::Resolved(na))) => {
+                    assert_eq!(na.name(), &name("web.a.example.com."))
+                }
+                _ => panic!("preferred name must be published"),
+            }
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
     }
 }
@@ -270,6 +270,7 @@ impl Future for ClientServiceFuture {
                 let mut res = try_ready!(future.poll()).map(|b| HttpBody {
                     body: Some(b),
                     upgrade: upgrade.take(),
+                    polled: false,
                 });
                 if *is_http_connect {
                     res.extensions_mut().insert(HttpConnect);
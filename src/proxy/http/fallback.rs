@@ -1,20 +1,32 @@
 use bytes::Buf;
-use futures::{Future, Poll};
+use futures::{Async, Future, Poll};
 use http;
 use hyper::body::Payload;
-use proxy;
-use svc;
+use std::{fmt, sync::Arc};
+
+use proxy::{self, pending};
+use svc::{self, ServiceExt};
 
 /// A fallback layer composing two service builders.
 ///
 /// If the future returned by the primary builder's `MakeService` fails with
 /// an error matching a given predicate, the fallback future will attempt
 /// to call the secondary `MakeService`.
+///
+/// If a `Bypass` is configured, or if requests fall back while the primary
+/// service is unavailable, the fallback service is also built once it is first
+/// needed: when the service is first polled if a bypass is configured, and
+/// otherwise once the primary service first becomes unavailable. Requests
+/// whose paths match the bypass, and requests received while the primary
+/// service is unavailable, are dispatched to the fallback service even though
+/// the primary service was built.
 #[derive(Clone, Debug)]
 pub struct Layer<A, B, P = fn(&proxy::Error) -> bool> {
     primary: svc::Builder<A>,
     fallback: svc::Builder<B>,
     predicate: P,
+    bypass: Option<Bypass>,
+    unavailable: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -22,8 +34,23 @@ pub struct MakeSvc<A, B, P> {
     primary: A,
     fallback: B,
     predicate: P,
+    bypass: Option<Bypass>,
+    unavailable: bool,
+}
+
+/// Implemented by primary services that may be unable to serve requests once
+/// they have been built, e.g. balancers whose endpoints have all been removed.
+pub trait Availability {
+    /// Returns false if requests should be dispatched to the fallback service
+    /// instead.
+    fn is_available(&self) -> bool;
 }
 
+/// Matches the paths of requests that should always be dispatched to the
+/// fallback service, e.g. health checks.
+#[derive(Clone)]
+pub struct Bypass(Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
 pub struct MakeFuture<A, B, P, T>
 where
     A: Future,
@@ -33,9 +60,37 @@ where
     fallback: B,
     target: Option<T>,
     predicate: P,
+    bypass: Option<Bypass>,
+    unavailable: bool,
     state: FallbackState<A, B::Future, T>,
 }
 
+/// Dispatches requests to the service that was built.
+pub enum Service<A, B> {
+    Primary(A),
+    Fallback(B),
+    /// The primary service was built, and the fallback service is built once
+    /// it is needed, so that requests that match the bypass, or that are
+    /// received while the primary service is unavailable, are dispatched to
+    /// the fallback service.
+    Both {
+        primary: A,
+        fallback: B,
+        bypass: Option<Bypass>,
+        /// Whether requests fall back while the primary is unavailable.
+        unavailable: bool,
+        /// Set by `poll_ready` if the next request must fall back because the
+        /// primary service is unavailable.
+        use_fallback: bool,
+    },
+}
+
+/// Marks requests that are dispatched to the fallback service, so that the
+/// fallback's stack (e.g. access logging) may record that the primary service
+/// did not serve the request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UsedFallback;
+
 #[derive(Clone)]
 pub enum Either<A, B> {
     A(A),
@@ -57,6 +112,8 @@ pub fn layer<A, B>(primary: svc::Builder<A>, fallback: svc::Builder<B>) -> Layer
         primary,
         fallback,
         predicate,
+        bypass: None,
+        unavailable: false,
     }
 }
 
@@ -73,6 +130,8 @@ impl<A, B> Layer<A, B> {
             primary: self.primary,
             fallback: self.fallback,
             predicate,
+            bypass: self.bypass,
+            unavailable: self.unavailable,
         }
     }
 
@@ -86,6 +145,26 @@ impl<A, B> Layer<A, B> {
     }
 }
 
+impl<A, B, P> Layer<A, B, P> {
+    /// Dispatches requests that match `bypass` to the fallback service, even
+    /// when the primary service is built.
+    pub fn with_bypass(self, bypass: Bypass) -> Self {
+        Self {
+            bypass: Some(bypass),
+            ..self
+        }
+    }
+
+    /// Dispatches requests to the fallback service while the primary service
+    /// is built but unavailable (see `Availability`).
+    pub fn on_unavailable(self) -> Self {
+        Self {
+            unavailable: true,
+            ..self
+        }
+    }
+}
+
 impl<A, B, P, M> svc::Layer<M> for Layer<A, B, P>
 where
     A: svc::Layer<M> + Clone,
@@ -100,6 +179,8 @@ where
             primary: self.primary.clone().service(inner.clone()),
             fallback: self.fallback.clone().service(inner),
             predicate: self.predicate.clone(),
+            bypass: self.bypass.clone(),
+            unavailable: self.unavailable,
         }
     }
 }
@@ -115,7 +196,7 @@ where
     P: Fn(&proxy::Error) -> bool + Clone,
     T: Clone,
 {
-    type Response = Either<A::Response, B::Response>;
+    type Response = Service<A::Response, pending::Svc<B, T>>;
     type Error = proxy::Error;
     type Future = MakeFuture<A::Future, B, P, T>;
 
@@ -127,6 +208,8 @@ where
         MakeFuture {
             fallback: self.fallback.clone(),
             predicate: self.predicate.clone(),
+            bypass: self.bypass.clone(),
+            unavailable: self.unavailable,
             target: Some(target.clone()),
             state: FallbackState::Primary(self.primary.call(target)),
         }
@@ -137,11 +220,11 @@ impl<A, B, P, T> Future for MakeFuture<A, B, P, T>
 where
     A: Future,
     A::Error: Into<proxy::Error>,
-    B: svc::Service<T>,
+    B: svc::Service<T> + Clone,
     B::Error: Into<proxy::Error>,
     P: Fn(&proxy::Error) -> bool,
 {
-    type Item = Either<A::Item, B::Response>;
+    type Item = Service<A::Item, pending::Svc<B, T>>;
     type Error = proxy::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -150,7 +233,23 @@ where
                 // We've called the primary service and are waiting for its
                 // future to complete.
                 FallbackState::Primary(ref mut f) => match f.poll() {
-                    Ok(r) => return Ok(r.map(Either::A)),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(primary)) => {
+                        if self.bypass.is_none() && !self.unavailable {
+                            return Ok(Async::Ready(Service::Primary(primary)));
+                        }
+                        // The fallback service is only built once it is
+                        // polled, i.e. once a request may be dispatched to it.
+                        let target = self.target.take().expect("polled after ready");
+                        let fallback = self.fallback.clone().oneshot(target);
+                        return Ok(Async::Ready(Service::Both {
+                            primary,
+                            fallback: pending::Pending::Making(fallback),
+                            bypass: self.bypass.clone(),
+                            unavailable: self.unavailable,
+                            use_fallback: false,
+                        }));
+                    }
                     Err(error) => {
                         let error = error.into();
                         if (self.predicate)(&error) {
@@ -172,20 +271,50 @@ where
                 // We've called the fallback service and are waiting for its
                 // future to complete.
                 FallbackState::Fallback(ref mut f) => {
-                    return f.poll().map(|a| a.map(Either::B)).map_err(Into::into)
+                    let fallback = try_ready!(f.poll().map_err(Into::into));
+                    return Ok(Async::Ready(Service::Fallback(pending::Pending::Made(
+                        fallback,
+                    ))));
                 }
             }
         }
     }
 }
 
-// === impl Either ===
+// === impl Bypass ===
+
+impl Bypass {
+    pub fn new<F>(matches: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        Bypass(Arc::new(matches))
+    }
+
+    /// Matches requests whose paths are exactly one of `paths`.
+    pub fn paths<I: IntoIterator<Item = String>>(paths: I) -> Self {
+        let paths = paths.into_iter().collect::<Vec<_>>();
+        Self::new(move |path| paths.iter().any(|p| p == path))
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        (self.0)(path)
+    }
+}
+
+impl fmt::Debug for Bypass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Bypass").finish()
+    }
+}
+
+// === impl Service ===
 
-impl<A, B, B1, B2, R> svc::Service<R> for Either<A, B>
+impl<A, B, B1, B2, Q> svc::Service<http::Request<Q>> for Service<A, B>
 where
-    A: svc::Service<R, Response = http::Response<B1>>,
+    A: svc::Service<http::Request<Q>, Response = http::Response<B1>> + Availability,
     A::Error: Into<proxy::Error>,
-    B: svc::Service<R, Response = http::Response<B2>>,
+    B: svc::Service<http::Request<Q>, Response = http::Response<B2>>,
     B::Error: Into<proxy::Error>,
 {
     type Response = http::Response<Either<B1, B2>>;
@@ -193,20 +322,73 @@ where
     type Error = proxy::Error;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        match self {
-            Either::A(ref mut inner) => inner.poll_ready().map_err(Into::into),
-            Either::B(ref mut inner) => inner.poll_ready().map_err(Into::into),
+        match *self {
+            Service::Primary(ref mut inner) => inner.poll_ready().map_err(Into::into),
+            Service::Fallback(ref mut inner) => inner.poll_ready().map_err(Into::into),
+            Service::Both {
+                ref mut primary,
+                ref mut fallback,
+                ref bypass,
+                unavailable,
+                ref mut use_fallback,
+            } => {
+                // The primary is always polled, so that it may recover.
+                let primary_ready = primary.poll_ready().map_err(Into::into)?;
+                *use_fallback = unavailable && !primary.is_available();
+                if *use_fallback {
+                    trace!("primary service is unavailable; falling back");
+                    return fallback.poll_ready().map_err(Into::into);
+                }
+                if bypass.is_none() {
+                    return Ok(primary_ready);
+                }
+
+                // Either service may be called, so both must be ready.
+                let fallback_ready = fallback.poll_ready().map_err(Into::into)?;
+                if primary_ready.is_ready() && fallback_ready.is_ready() {
+                    Ok(Async::Ready(()))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            }
         }
     }
 
-    fn call(&mut self, req: R) -> Self::Future {
-        match self {
-            Either::A(ref mut inner) => Either::A(inner.call(req)),
-            Either::B(ref mut inner) => Either::B(inner.call(req)),
+    fn call(&mut self, mut req: http::Request<Q>) -> Self::Future {
+        match *self {
+            Service::Primary(ref mut inner) => Either::A(inner.call(req)),
+            Service::Fallback(ref mut inner) => {
+                req.extensions_mut().insert(UsedFallback);
+                Either::B(inner.call(req))
+            }
+            Service::Both {
+                ref mut primary,
+                ref mut fallback,
+                ref bypass,
+                ref mut use_fallback,
+                ..
+            } => {
+                let bypassed = bypass
+                    .as_ref()
+                    .map(|b| b.matches(req.uri().path()))
+                    .unwrap_or(false);
+                if bypassed {
+                    trace!("bypassing primary service for {}", req.uri().path());
+                }
+                if bypassed || *use_fallback {
+                    *use_fallback = false;
+                    req.extensions_mut().insert(UsedFallback);
+                    Either::B(fallback.call(req))
+                } else {
+                    Either::A(primary.call(req))
+                }
+            }
         }
     }
 }
 
+// === impl Either ===
+
 impl<A, B, B1, B2> Future for Either<A, B>
 where
     A: Future<Item = http::Response<B1>>,
@@ -301,3 +483,225 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use svc::Service as _Service;
+
+    /// Both makes and is an endpoint that responds with a fixed body.
+    #[derive(Clone)]
+    struct Respond(&'static str);
+
+    impl svc::Service<()> for Respond {
+        type Response = Respond;
+        type Error = proxy::Error;
+        type Future = future::FutureResult<Respond, proxy::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, (): ()) -> Self::Future {
+            future::ok(self.clone())
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Respond {
+        type Response = http::Response<&'static str>;
+        type Error = proxy::Error;
+        type Future = future::FutureResult<Self::Response, proxy::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            future::ok(http::Response::new(self.0))
+        }
+    }
+
+    impl Availability for Respond {
+        fn is_available(&self) -> bool {
+            true
+        }
+    }
+
+    /// A primary service that has been built but cannot serve requests, e.g.
+    /// a balancer whose endpoints have all been removed.
+    #[derive(Clone)]
+    struct Drained;
+
+    impl svc::Service<()> for Drained {
+        type Response = Drained;
+        type Error = proxy::Error;
+        type Future = future::FutureResult<Drained, proxy::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, (): ()) -> Self::Future {
+            future::ok(Drained)
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Drained {
+        type Response = http::Response<&'static str>;
+        type Error = proxy::Error;
+        type Future = future::FutureResult<Self::Response, proxy::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::NotReady)
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            panic!("drained service must not be called");
+        }
+    }
+
+    impl Availability for Drained {
+        fn is_available(&self) -> bool {
+            false
+        }
+    }
+
+    type Fallback<M = Respond> = pending::Svc<M, ()>;
+
+    fn make(bypass: Option<Bypass>) -> Service<Respond, Fallback> {
+        let mut make = MakeSvc {
+            primary: Respond("balanced"),
+            fallback: Respond("fallback"),
+            predicate: |_: &proxy::Error| true,
+            bypass,
+            unavailable: false,
+        };
+        make.call(()).wait().expect("make must succeed")
+    }
+
+    type Body = Either<&'static str, &'static str>;
+
+    fn dispatch<A, B>(svc: &mut Service<A, B>, path: &str) -> Body
+    where
+        Service<A, B>:
+            svc::Service<http::Request<()>, Response = http::Response<Body>, Error = proxy::Error>,
+    {
+        assert!(svc.poll_ready().expect("service must not fail").is_ready());
+        let req = http::Request::get(path).body(()).unwrap();
+        svc.call(req).wait().expect("request must succeed").into_body()
+    }
+
+    #[test]
+    fn bypassed_paths_are_dispatched_to_fallback() {
+        let mut svc = make(Some(Bypass::paths(vec!["/healthz".to_owned()])));
+        match svc {
+            Service::Both { .. } => {}
+            _ => panic!("both services must be built"),
+        }
+
+        match dispatch(&mut svc, "/healthz") {
+            Either::B("fallback") => {}
+            _ => panic!("matching paths must be dispatched to the fallback service"),
+        }
+        match dispatch(&mut svc, "/api/users") {
+            Either::A("balanced") => {}
+            _ => panic!("other paths must be dispatched to the primary service"),
+        }
+    }
+
+    #[test]
+    fn without_bypass_only_primary_is_built() {
+        let mut svc = make(None);
+        match dispatch(&mut svc, "/healthz") {
+            Either::A("balanced") => {}
+            _ => panic!("requests must be dispatched to the primary service"),
+        }
+    }
+
+    #[test]
+    fn unavailable_primary_requests_are_dispatched_to_fallback() {
+        let mut make = MakeSvc {
+            primary: Drained,
+            fallback: Respond("fallback"),
+            predicate: |_: &proxy::Error| true,
+            bypass: None,
+            unavailable: true,
+        };
+        let mut svc = make.call(()).wait().expect("make must succeed");
+
+        for _ in 0..3 {
+            let ready = svc.poll_ready().expect("service must not fail");
+            assert!(ready.is_ready(), "the fallback must serve requests");
+            let req = http::Request::get("/api/users").body(()).unwrap();
+            match svc.call(req).wait().expect("request must succeed").into_body() {
+                Either::B("fallback") => {}
+                _ => panic!("requests must be dispatched to the fallback service"),
+            }
+        }
+    }
+
+    /// Builds fallback services, counting how many it has built.
+    #[derive(Clone, Default)]
+    struct CountBuilds(Arc<AtomicUsize>);
+
+    impl svc::Service<()> for CountBuilds {
+        type Response = Respond;
+        type Error = proxy::Error;
+        type Future = future::FutureResult<Respond, proxy::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, (): ()) -> Self::Future {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            future::ok(Respond("fallback"))
+        }
+    }
+
+    #[test]
+    fn fallback_is_only_built_once_the_primary_is_unavailable() {
+        let builds = CountBuilds::default();
+        let mut make = MakeSvc {
+            primary: Respond("balanced"),
+            fallback: builds.clone(),
+            predicate: |_: &proxy::Error| true,
+            bypass: None,
+            unavailable: true,
+        };
+        let mut svc = make.call(()).wait().expect("make must succeed");
+        for _ in 0..3 {
+            match dispatch(&mut svc, "/api/users") {
+                Either::A("balanced") => {}
+                _ => panic!("requests must be dispatched to the primary service"),
+            }
+        }
+        assert_eq!(
+            builds.0.load(Ordering::SeqCst),
+            0,
+            "the fallback must not be built while the primary is available"
+        );
+
+        let mut make = MakeSvc {
+            primary: Drained,
+            fallback: builds.clone(),
+            predicate: |_: &proxy::Error| true,
+            bypass: None,
+            unavailable: true,
+        };
+        let mut svc = make.call(()).wait().expect("make must succeed");
+        for _ in 0..3 {
+            match dispatch(&mut svc, "/api/users") {
+                Either::B("fallback") => {}
+                _ => panic!("requests must be dispatched to the fallback service"),
+            }
+        }
+        assert_eq!(
+            builds.0.load(Ordering::SeqCst),
+            1,
+            "the fallback must be built once"
+        );
+    }
+}
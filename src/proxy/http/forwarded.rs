@@ -0,0 +1,96 @@
+//! Describes the original client of a request, e.g. so that it may be
+//! propagated to servers with the `Forwarded` (RFC 7239) or
+//! `X-Forwarded-For` headers.
+//!
+//! Connections accepted from a load balancer that speaks the PROXY protocol
+//! are described by the load balancer's PROXY header, so the client is the
+//! header's source address rather than the connection's peer.
+
+use http::header::HeaderValue;
+use std::net::{IpAddr, SocketAddr};
+
+use proxy::server::Source;
+
+/// The addresses of a connection that a PROXY protocol header describes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The address of the client that connected to the load balancer.
+    pub source: SocketAddr,
+    /// The address to which the client connected.
+    pub destination: SocketAddr,
+}
+
+/// Returns the address of the client that originated an accepted connection.
+///
+/// If the connection carried a PROXY header, the header's source is the
+/// client; otherwise, the connection's peer is.
+pub fn client_addr(source: &Source, proxy: Option<&ProxyHeader>) -> SocketAddr {
+    match proxy {
+        Some(proxy) => proxy.source,
+        None => source.remote,
+    }
+}
+
+/// Renders a `Forwarded` header value identifying `client`, i.e.
+/// `for="192.0.2.60:47011"` or `for="[2001:db8::17]:4711"`.
+///
+/// Addresses are always quoted, since a node with a port contains a colon.
+pub fn forwarded_for(client: SocketAddr) -> HeaderValue {
+    let value = match client.ip() {
+        IpAddr::V4(ip) => format!("for=\"{}:{}\"", ip, client.port()),
+        IpAddr::V6(ip) => format!("for=\"[{}]:{}\"", ip, client.port()),
+    };
+    HeaderValue::from_str(&value).expect("forwarded header value must be valid")
+}
+
+/// Renders an `X-Forwarded-For` header value identifying `client`.
+///
+/// By convention, this is only the client's IP address, without brackets.
+pub fn x_forwarded_for(client: SocketAddr) -> HeaderValue {
+    HeaderValue::from_str(&client.ip().to_string())
+        .expect("x-forwarded-for header value must be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::tls;
+    use Conditional;
+
+    const TLS_DISABLED: tls::PeerIdentity = Conditional::None(tls::ReasonForNoIdentity::Disabled);
+
+    fn source(remote: SocketAddr) -> Source {
+        let local = SocketAddr::from(([127, 0, 0, 1], 4143));
+        Source::for_test(remote, local, None, TLS_DISABLED)
+    }
+
+    #[test]
+    fn ipv4_clients() {
+        let remote = SocketAddr::from(([10, 0, 0, 1], 40000));
+        let client = SocketAddr::from(([192, 0, 2, 60], 47011));
+        let proxy = ProxyHeader {
+            source: client,
+            destination: SocketAddr::from(([10, 0, 0, 2], 80)),
+        };
+
+        assert_eq!(client_addr(&source(remote), None), remote);
+        assert_eq!(client_addr(&source(remote), Some(&proxy)), client);
+        assert_eq!(forwarded_for(client), "for=\"192.0.2.60:47011\"");
+        assert_eq!(x_forwarded_for(client), "192.0.2.60");
+    }
+
+    #[test]
+    fn ipv6_clients() {
+        let remote = SocketAddr::from(([0xfd00, 0, 0, 0, 0, 0, 0, 1], 40000));
+        let client = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x17], 4711));
+        let proxy = ProxyHeader {
+            source: client,
+            destination: SocketAddr::from(([0xfd00, 0, 0, 0, 0, 0, 0, 2], 80)),
+        };
+
+        assert_eq!(client_addr(&source(remote), None), remote);
+        assert_eq!(client_addr(&source(remote), Some(&proxy)), client);
+        assert_eq!(forwarded_for(client), "for=\"[2001:db8::17]:4711\"");
+        assert_eq!(x_forwarded_for(client), "2001:db8::17");
+    }
+}
@@ -16,6 +16,9 @@ pub struct HttpBody {
     /// to be inserted into the Http11Upgrade half.
     pub(super) body: Option<hyper::Body>,
     pub(super) upgrade: Option<Http11Upgrade>,
+    /// Set once the body has been polled, after which it may no longer be
+    /// cloned for retries.
+    pub(super) polled: bool,
 }
 
 /// Glue for a `tower::Service` to used as a `hyper::server::Service`.
@@ -56,6 +59,7 @@ impl Payload for HttpBody {
     }
 
     fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
+        self.polled = true;
         self.body
             .as_mut()
             .expect("only taken in drop")
@@ -67,6 +71,7 @@ impl Payload for HttpBody {
     }
 
     fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, Self::Error> {
+        self.polled = true;
         self.body
             .as_mut()
             .expect("only taken in drop")
@@ -100,13 +105,15 @@ impl Default for HttpBody {
         HttpBody {
             body: Some(hyper::Body::empty()),
             upgrade: None,
+            polled: false,
         }
     }
 }
 
 impl super::retry::TryClone for HttpBody {
+    /// Only empty bodies that have not yet been polled may be cloned.
     fn try_clone(&self) -> Option<Self> {
-        if self.is_end_stream() {
+        if !self.polled && self.is_end_stream() {
             Some(HttpBody::default())
         } else {
             None
@@ -151,6 +158,7 @@ where
         self.service.call(req.map(|b| HttpBody {
             body: Some(b),
             upgrade: None,
+            polled: false,
         }))
     }
 }
@@ -215,3 +223,33 @@ impl HasH2Reason for hyper::Error {
         (self as &(dyn std::error::Error + 'static)).h2_reason()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proxy::http::retry::TryClone;
+
+    #[test]
+    fn untouched_request_is_cloneable() {
+        let req = http::Request::new(HttpBody::default());
+        assert!(req.try_clone().is_some());
+    }
+
+    #[test]
+    fn polled_request_is_not_cloneable() {
+        let mut req = http::Request::new(HttpBody::default());
+        let data = Payload::poll_data(req.body_mut()).expect("empty body must not fail");
+        assert!(data.is_ready());
+        assert!(
+            req.try_clone().is_none(),
+            "a request must not be cloned once its body has been polled"
+        );
+    }
+
+    #[test]
+    fn request_with_polled_trailers_is_not_cloneable() {
+        let mut req = http::Request::new(HttpBody::default());
+        let _ = Payload::poll_trailers(req.body_mut()).expect("empty body must not fail");
+        assert!(req.try_clone().is_none());
+    }
+}
@@ -3,6 +3,7 @@ pub mod balance;
 pub mod canonicalize;
 pub mod client;
 pub mod fallback;
+pub mod forwarded;
 pub(super) mod glue;
 pub mod h1;
 pub mod h2;
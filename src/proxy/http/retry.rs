@@ -23,7 +23,12 @@ pub enum NoRetry {
     Budget,
 }
 
+/// Clones a request (or its body) so that it may be sent again.
 pub trait TryClone: Sized {
+    /// Returns a clone of `self`, if possible.
+    ///
+    /// Bodies must return `None` once they have been polled, since any data
+    /// that has already been read cannot be replayed.
     fn try_clone(&self) -> Option<Self>;
 }
 
@@ -0,0 +1,379 @@
+//! Biases load balancing toward endpoints in the client's locality.
+//!
+//! Endpoint stacks are wrapped so that, while any endpoint in the client's
+//! locality is ready, endpoints in other localities report that they are not
+//! ready. Because the balancer only chooses between ready endpoints, requests
+//! stay local and only spill to remote endpoints when no local endpoint is
+//! available (i.e. because all local endpoints have been removed or are
+//! saturated).
+
+use futures::{task::AtomicTask, Async, Future, Poll};
+use std::sync::{Arc, Mutex, Weak};
+
+use svc;
+
+/// Describes the locality (e.g. the zone) of an endpoint.
+pub trait HasLocality {
+    fn locality(&self) -> Option<&str>;
+}
+
+#[derive(Clone, Debug)]
+pub struct Layer {
+    local: Option<Arc<str>>,
+}
+
+/// Builds endpoint services that prefer the client's locality.
+///
+/// Every `MakeSvc` tracks its own set of local endpoints, and cloning a
+/// `MakeSvc` starts a new set. Because a new endpoint stack is cloned for each
+/// balancer, each balancer prefers its own local endpoints.
+#[derive(Debug)]
+pub struct MakeSvc<M> {
+    local: Option<Arc<str>>,
+    shared: Arc<Mutex<Shared>>,
+    inner: M,
+}
+
+pub struct MakeFuture<F> {
+    role: Option<Role>,
+    inner: F,
+}
+
+/// An endpoint service that is only ready when it is preferred.
+#[derive(Debug)]
+pub struct Service<S> {
+    role: Role,
+    inner: S,
+}
+
+#[derive(Debug)]
+enum Role {
+    /// No locality is configured, so all endpoints are preferred equally.
+    Any,
+    Local {
+        shared: Arc<Mutex<Shared>>,
+        is_ready: bool,
+    },
+    Remote {
+        shared: Arc<Mutex<Shared>>,
+        /// Notified once no local endpoints are ready.
+        parked: Arc<AtomicTask>,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    ready_locals: usize,
+    /// Remote endpoints that wait for all local endpoints to become
+    /// unavailable. Each remote endpoint is registered once, when it is
+    /// built, however often it is polled.
+    remotes: Vec<Weak<AtomicTask>>,
+}
+
+// === impl Layer ===
+
+/// Prefers endpoints whose locality matches `local`.
+///
+/// If `local` is `None`, endpoints are not biased by locality.
+pub fn layer(local: Option<String>) -> Layer {
+    Layer {
+        local: local.map(Into::into),
+    }
+}
+
+impl<M> svc::Layer<M> for Layer {
+    type Service = MakeSvc<M>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        MakeSvc {
+            local: self.local.clone(),
+            shared: Arc::new(Mutex::new(Shared::default())),
+            inner,
+        }
+    }
+}
+
+// === impl MakeSvc ===
+
+impl<M: Clone> Clone for MakeSvc<M> {
+    fn clone(&self) -> Self {
+        Self {
+            local: self.local.clone(),
+            shared: Arc::new(Mutex::new(Shared::default())),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, M> svc::Service<T> for MakeSvc<M>
+where
+    T: HasLocality,
+    M: svc::Service<T>,
+{
+    type Response = Service<M::Response>;
+    type Error = M::Error;
+    type Future = MakeFuture<M::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let role = match self.local {
+            None => Role::Any,
+            Some(ref local) if target.locality() == Some(&**local) => Role::Local {
+                shared: self.shared.clone(),
+                is_ready: false,
+            },
+            Some(_) => {
+                let parked = Arc::new(AtomicTask::new());
+                self.shared
+                    .lock()
+                    .expect("locality lock poisoned")
+                    .add_remote(&parked);
+                Role::Remote {
+                    shared: self.shared.clone(),
+                    parked,
+                }
+            }
+        };
+
+        MakeFuture {
+            role: Some(role),
+            inner: self.inner.call(target),
+        }
+    }
+}
+
+// === impl MakeFuture ===
+
+impl<F: Future> Future for MakeFuture<F> {
+    type Item = Service<F::Item>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        let role = self.role.take().expect("polled after ready");
+        Ok(Async::Ready(Service { role, inner }))
+    }
+}
+
+// === impl Service ===
+
+impl<S> Service<S> {
+    fn set_local_ready(&mut self, ready: bool) {
+        if let Role::Local {
+            ref shared,
+            ref mut is_ready,
+        } = self.role
+        {
+            if *is_ready != ready {
+                *is_ready = ready;
+                shared.lock().expect("locality lock poisoned").set_local_ready(ready);
+            }
+        }
+    }
+}
+
+impl<S, Req> svc::Service<Req> for Service<S>
+where
+    S: svc::Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        if let Role::Remote {
+            ref shared,
+            ref parked,
+        } = self.role
+        {
+            // The task is registered while the lock is held, so that it is
+            // notified if the last local endpoint becomes unavailable.
+            let shared = shared.lock().expect("locality lock poisoned");
+            if shared.ready_locals > 0 {
+                parked.register();
+                return Ok(Async::NotReady);
+            }
+        }
+
+        let ready = self.inner.poll_ready();
+        let is_ready = match ready {
+            Ok(Async::Ready(())) => true,
+            _ => false,
+        };
+        self.set_local_ready(is_ready);
+        ready
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.inner.call(req)
+    }
+}
+
+impl<S> Drop for Service<S> {
+    fn drop(&mut self) {
+        self.set_local_ready(false);
+    }
+}
+
+// === impl Shared ===
+
+impl Shared {
+    fn add_remote(&mut self, parked: &Arc<AtomicTask>) {
+        // Remote endpoints that have been dropped are forgotten.
+        self.remotes.retain(|r| r.upgrade().is_some());
+        self.remotes.push(Arc::downgrade(parked));
+    }
+
+    fn set_local_ready(&mut self, ready: bool) {
+        if ready {
+            self.ready_locals += 1;
+            return;
+        }
+
+        self.ready_locals -= 1;
+        if self.ready_locals == 0 {
+            trace!("no local endpoints are ready; unparking remote endpoints");
+            for parked in self.remotes.iter().filter_map(Weak::upgrade) {
+                parked.notify();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use svc::{Layer as _Layer, Service as _Service};
+
+    struct Endpoint(&'static str);
+
+    impl HasLocality for Endpoint {
+        fn locality(&self) -> Option<&str> {
+            Some(self.0)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Ready;
+
+    impl svc::Service<()> for Ready {
+        type Response = ();
+        type Error = ();
+        type Future = future::FutureResult<(), ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            future::ok(())
+        }
+    }
+
+    fn make(
+        local: Option<&str>,
+    ) -> impl svc::Service<Endpoint, Response = Service<Ready>> + Clone {
+        layer(local.map(String::from)).layer(svc::mk(|_: Endpoint| Ok::<_, ()>(Ready)))
+    }
+
+    fn build<M>(make: &mut M, locality: &'static str) -> Service<Ready>
+    where
+        M: svc::Service<Endpoint, Response = Service<Ready>>,
+        M::Error: ::std::fmt::Debug,
+    {
+        make.call(Endpoint(locality)).wait().expect("make must succeed")
+    }
+
+    fn with_task<F: FnOnce() -> U, U>(f: F) -> U {
+        future::lazy(|| Ok::<_, ()>(f())).wait().unwrap()
+    }
+
+    #[test]
+    fn traffic_stays_local_until_local_endpoints_are_removed() {
+        with_task(|| {
+            let mut make = make(Some("east"));
+            let mut local0 = build(&mut make, "east");
+            let mut local1 = build(&mut make, "east");
+            let mut remote = build(&mut make, "west");
+
+            assert!(local0.poll_ready().unwrap().is_ready());
+            assert!(local1.poll_ready().unwrap().is_ready());
+            assert!(
+                remote.poll_ready().unwrap().is_not_ready(),
+                "remote endpoints must not be ready while local endpoints are"
+            );
+
+            drop(local0);
+            assert!(
+                remote.poll_ready().unwrap().is_not_ready(),
+                "remote endpoints must not be ready while any local endpoint is"
+            );
+
+            drop(local1);
+            assert!(
+                remote.poll_ready().unwrap().is_ready(),
+                "remote endpoints must be ready once local endpoints are removed"
+            );
+        })
+    }
+
+    #[test]
+    fn remote_endpoints_are_parked_once_however_often_they_are_polled() {
+        with_task(|| {
+            let mut make =
+                layer(Some("east".to_owned())).layer(svc::mk(|_: Endpoint| Ok::<_, ()>(Ready)));
+            let mut local = build(&mut make, "east");
+            let mut remote = build(&mut make, "west");
+
+            assert!(local.poll_ready().unwrap().is_ready());
+            for _ in 0..1000 {
+                assert!(remote.poll_ready().unwrap().is_not_ready());
+            }
+            assert_eq!(make.shared.lock().unwrap().remotes.len(), 1);
+
+            drop(remote);
+            let _remote = build(&mut make, "west");
+            assert_eq!(
+                make.shared.lock().unwrap().remotes.len(),
+                1,
+                "dropped remote endpoints must be forgotten"
+            );
+        })
+    }
+
+    #[test]
+    fn remote_endpoints_are_ready_without_local_endpoints() {
+        with_task(|| {
+            let mut make = make(Some("east"));
+            let mut remote = build(&mut make, "west");
+            assert!(remote.poll_ready().unwrap().is_ready());
+        })
+    }
+
+    #[test]
+    fn endpoints_are_not_biased_without_a_locality() {
+        with_task(|| {
+            let mut make = make(None);
+            let mut east = build(&mut make, "east");
+            let mut west = build(&mut make, "west");
+            assert!(east.poll_ready().unwrap().is_ready());
+            assert!(west.poll_ready().unwrap().is_ready());
+        })
+    }
+
+    #[test]
+    fn clones_track_local_endpoints_independently() {
+        with_task(|| {
+            let mut make0 = make(Some("east"));
+            let mut make1 = make0.clone();
+            let mut local = build(&mut make0, "east");
+            let mut remote = build(&mut make1, "west");
+            assert!(local.poll_ready().unwrap().is_ready());
+            assert!(remote.poll_ready().unwrap().is_ready());
+        })
+    }
+}
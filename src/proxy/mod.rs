@@ -1,15 +1,20 @@
 //! Tools for building a transparent TCP/HTTP proxy.
 
 pub mod accept;
+pub mod breaker;
 pub mod buffer;
+pub mod fault;
 pub mod grpc;
 pub mod http;
+pub mod locality;
 pub mod pending;
 mod protocol;
 pub mod reconnect;
+pub mod require_identity;
 pub mod resolve;
 pub mod server;
 mod tcp;
+pub mod weight;
 
 pub use self::accept::Accept;
 pub use self::resolve::{Resolution, Resolve};
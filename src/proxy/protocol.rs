@@ -1,12 +1,21 @@
 use httparse;
+use std::{error, fmt};
 
 /// Transport protocols that can be transparently detected by `Server`.
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Protocol {
     Http1,
     Http2,
 }
 
+/// Indicates that a peer did not speak the protocol that it negotiated via
+/// ALPN.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolMismatch {
+    negotiated: Protocol,
+    detected: Option<Protocol>,
+}
+
 const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
 
 impl Protocol {
@@ -42,4 +51,92 @@ impl Protocol {
 
         None
     }
+
+    /// Returns the protocol identified by an ALPN protocol ID, if it is one
+    /// that can be detected.
+    pub fn from_alpn(id: &str) -> Option<Protocol> {
+        match id {
+            "h2" => Some(Protocol::Http2),
+            "http/1.1" | "http/1.0" => Some(Protocol::Http1),
+            _ => None,
+        }
+    }
+
+    /// Tries to detect a known protocol in the peeked bytes, failing if the
+    /// bytes do not match the protocol negotiated via ALPN.
+    ///
+    /// If no protocol was negotiated, or the negotiated protocol is not one
+    /// that can be detected, this is the same as `detect`.
+    pub fn detect_negotiated(
+        bytes: &[u8],
+        alpn: Option<&str>,
+    ) -> Result<Option<Protocol>, ProtocolMismatch> {
+        let detected = Self::detect(bytes);
+        match alpn.and_then(Self::from_alpn) {
+            Some(negotiated) if detected != Some(negotiated) => Err(ProtocolMismatch {
+                negotiated,
+                detected,
+            }),
+            _ => Ok(detected),
+        }
+    }
+}
+
+// === impl ProtocolMismatch ===
+
+impl fmt::Display for ProtocolMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.detected {
+            Some(detected) => write!(
+                f,
+                "peer negotiated {:?} via ALPN but spoke {:?}",
+                self.negotiated, detected
+            ),
+            None => write!(
+                f,
+                "peer negotiated {:?} via ALPN but spoke an unknown protocol",
+                self.negotiated
+            ),
+        }
+    }
+}
+
+impl error::Error for ProtocolMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTTP1: &[u8] = b"GET / HTTP/1.1\r\nhost: example.com\r\n\r\n";
+
+    #[test]
+    fn h2_negotiated_but_http1_spoken() {
+        assert_eq!(
+            Protocol::detect_negotiated(HTTP1, Some("h2")),
+            Err(ProtocolMismatch {
+                negotiated: Protocol::Http2,
+                detected: Some(Protocol::Http1),
+            })
+        );
+        assert_eq!(
+            Protocol::detect_negotiated(b"\x16\x03\x01\x00\x00\x00\x00\x00", Some("h2")),
+            Err(ProtocolMismatch {
+                negotiated: Protocol::Http2,
+                detected: None,
+            })
+        );
+    }
+
+    #[test]
+    fn negotiated_protocols_are_detected() {
+        assert_eq!(Protocol::detect_negotiated(H2_PREFACE, Some("h2")), Ok(Some(Protocol::Http2)));
+        assert_eq!(Protocol::detect_negotiated(HTTP1, Some("http/1.1")), Ok(Some(Protocol::Http1)));
+        assert!(Protocol::detect_negotiated(H2_PREFACE, Some("http/1.1")).is_err());
+    }
+
+    #[test]
+    fn unknown_or_missing_alpn_is_not_checked() {
+        assert_eq!(Protocol::detect_negotiated(HTTP1, None), Ok(Some(Protocol::Http1)));
+        assert_eq!(Protocol::detect_negotiated(HTTP1, Some("spdy/3")), Ok(Some(Protocol::Http1)));
+    }
 }
@@ -0,0 +1,263 @@
+//! Rejects connections whose clients are not authenticated with mTLS, on the
+//! ports where policy requires it.
+//!
+//! On such ports, the HTTP requests of unauthenticated clients fail with an
+//! `IdentityRequired` error (which is served as a 403), and non-HTTP
+//! connections are closed rather than forwarded. Loopback connections are
+//! always allowed, since they do not leave the host and do not support TLS.
+//!
+//! Every other reason for a connection not having a client identity--i.e.
+//! including identity being disabled--fails closed.
+
+use futures::{future, Async, Future, Poll};
+use indexmap::IndexSet;
+use std::sync::Arc;
+use std::{error, fmt};
+
+use proxy::server::Source;
+use proxy::Error;
+use svc;
+use transport::tls::{ReasonForNoIdentity, ReasonForNoPeerName};
+use Conditional;
+
+/// Describes the original destination ports on which clients must be
+/// authenticated.
+///
+/// By default, identity is not required on any port.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    ports: Arc<IndexSet<u16>>,
+}
+
+/// Indicates that a client was not authenticated on a port that requires it.
+#[derive(Clone, Debug)]
+pub struct IdentityRequired {
+    port: u16,
+    reason: ReasonForNoIdentity,
+}
+
+#[derive(Clone, Debug)]
+pub struct Layer {
+    policy: Policy,
+}
+
+#[derive(Clone, Debug)]
+pub struct MakeSvc<M> {
+    policy: Policy,
+    inner: M,
+}
+
+pub struct MakeFuture<F> {
+    rejected: Option<IdentityRequired>,
+    inner: F,
+}
+
+/// Serves the requests of an authorized connection, or fails every request
+/// of an unauthorized one.
+#[derive(Debug)]
+pub enum Service<S> {
+    Authorized(S),
+    Unauthorized(IdentityRequired),
+}
+
+// === impl Policy ===
+
+impl Policy {
+    /// Requires that clients are authenticated on each of `ports`.
+    pub fn new(ports: IndexSet<u16>) -> Self {
+        Self {
+            ports: Arc::new(ports),
+        }
+    }
+
+    /// Fails if `source`'s client must be, but is not, authenticated.
+    pub fn check(&self, source: &Source) -> Result<(), IdentityRequired> {
+        let port = source.orig_dst.unwrap_or(source.local).port();
+        if !self.ports.contains(&port) {
+            return Ok(());
+        }
+
+        match source.tls_peer {
+            Conditional::Some(_) => Ok(()),
+            Conditional::None(ReasonForNoIdentity::NoPeerName(ReasonForNoPeerName::Loopback)) => {
+                Ok(())
+            }
+            Conditional::None(reason) => Err(IdentityRequired { port, reason }),
+        }
+    }
+}
+
+// === impl Layer ===
+
+/// Fails the HTTP requests of connections that do not satisfy `policy`.
+pub fn layer(policy: Policy) -> Layer {
+    Layer { policy }
+}
+
+impl<M> svc::Layer<M> for Layer {
+    type Service = MakeSvc<M>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        MakeSvc {
+            policy: self.policy.clone(),
+            inner,
+        }
+    }
+}
+
+// === impl MakeSvc ===
+
+impl<M> svc::Service<Source> for MakeSvc<M>
+where
+    M: svc::Service<Source>,
+{
+    type Response = Service<M::Response>;
+    type Error = M::Error;
+    type Future = MakeFuture<M::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, source: Source) -> Self::Future {
+        let rejected = self.policy.check(&source).err();
+        if let Some(ref e) = rejected {
+            debug!("rejecting requests from {}: {}", source, e);
+        }
+
+        MakeFuture {
+            rejected,
+            inner: self.inner.call(source),
+        }
+    }
+}
+
+// === impl MakeFuture ===
+
+impl<F: Future> Future for MakeFuture<F> {
+    type Item = Service<F::Item>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        let svc = match self.rejected.take() {
+            None => Service::Authorized(inner),
+            Some(e) => Service::Unauthorized(e),
+        };
+        Ok(Async::Ready(svc))
+    }
+}
+
+// === impl Service ===
+
+impl<S, Req> svc::Service<Req> for Service<S>
+where
+    S: svc::Service<Req>,
+    S::Error: Into<Error>,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = future::Either<
+        future::FutureResult<S::Response, Error>,
+        future::MapErr<S::Future, fn(S::Error) -> Error>,
+    >;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        match *self {
+            Service::Authorized(ref mut inner) => inner.poll_ready().map_err(Into::into),
+            Service::Unauthorized(_) => Ok(Async::Ready(())),
+        }
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        match *self {
+            Service::Authorized(ref mut inner) => {
+                future::Either::B(inner.call(req).map_err(Into::into))
+            }
+            Service::Unauthorized(ref e) => future::Either::A(future::err(e.clone().into())),
+        }
+    }
+}
+
+// === impl IdentityRequired ===
+
+impl fmt::Display for IdentityRequired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "port {} requires an authenticated client; no_tls_reason={}",
+            self.port, self.reason
+        )
+    }
+}
+
+impl error::Error for IdentityRequired {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use identity;
+    use std::net::SocketAddr;
+    use svc::{Layer as _Layer, Service as _Service};
+    use transport::tls;
+
+    const PORT: u16 = 8080;
+
+    fn source(tls_peer: tls::PeerIdentity) -> Source {
+        Source::for_test(
+            SocketAddr::from(([10, 0, 0, 2], 40000)),
+            SocketAddr::from(([10, 0, 0, 1], 4143)),
+            Some(SocketAddr::from(([10, 0, 0, 1], PORT))),
+            tls_peer,
+        )
+    }
+
+    fn policy() -> Policy {
+        Policy::new(vec![PORT].into_iter().collect())
+    }
+
+    /// Serves a request for `source` through the layer.
+    fn serve(policy: Policy, source: Source) -> Result<(), Error> {
+        let mut make = layer(policy).layer(svc::mk(|_: Source| {
+            Ok::<_, Error>(svc::mk(|_: ()| Ok::<_, Error>(())))
+        }));
+        let mut svc = make.call(source).wait().expect("make must succeed");
+        future::lazy(move || svc.call(())).wait()
+    }
+
+    #[test]
+    fn allows_loopback_clients() {
+        let loopback: tls::PeerIdentity = Conditional::None(ReasonForNoPeerName::Loopback.into());
+        assert!(policy().check(&source(loopback.clone())).is_ok());
+        serve(policy(), source(loopback)).expect("loopback requests must be served");
+    }
+
+    #[test]
+    fn rejects_plaintext_remote_clients() {
+        let reasons = [
+            ReasonForNoPeerName::NotProvidedByRemote,
+            ReasonForNoPeerName::NotHttp,
+        ];
+        for reason in &reasons {
+            let plaintext: tls::PeerIdentity = Conditional::None((*reason).into());
+            assert!(policy().check(&source(plaintext.clone())).is_err());
+
+            let err = serve(policy(), source(plaintext)).expect_err("request must fail");
+            assert!(err.is::<IdentityRequired>(), "unexpected error: {}", err);
+        }
+
+        let plaintext = Conditional::None(ReasonForNoPeerName::NotProvidedByRemote.into());
+        serve(Policy::default(), source(plaintext))
+            .expect("requests must be served on ports that do not require identity");
+    }
+
+    #[test]
+    fn accepts_authenticated_clients() {
+        let name = "web.ns.serviceaccount.identity.linkerd.cluster.local";
+        let id = identity::Name::from_hostname(name.as_bytes()).unwrap();
+        assert!(policy().check(&source(Conditional::Some(id.clone()))).is_ok());
+        serve(policy(), source(Conditional::Some(id)))
+            .expect("authenticated requests must be served");
+    }
+}
@@ -1,14 +1,48 @@
 extern crate linkerd2_router as rt;
 extern crate tower_discover;
 
-use futures::{stream::FuturesUnordered, Async, Future, Poll, Stream};
+use futures::{future, stream::FuturesUnordered, sync::mpsc, task, Async, Future, Poll, Stream};
+use futures_watch::{Store, Watch};
 use indexmap::IndexMap;
-use std::{fmt, net::SocketAddr};
+use never::Never;
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet, VecDeque},
+    error, fmt,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
 use tokio::sync::oneshot;
+use tokio_timer::{clock, Delay};
 
 pub use self::tower_discover::Change;
+use dns;
+use metrics::{latency, Counter, FmtMetric, FmtMetrics, Histogram};
+use proxy::http::balance;
 use proxy::Error;
 use svc;
+use NameAddr;
+
+/// The amount of time to wait before re-resolving a name that could not be
+/// resolved.
+const DNS_ERROR_BACKOFF: Duration = Duration::from_secs(5);
+
+metrics! {
+    balancer_endpoint_inserts_total: Counter {
+        "Total count of endpoints inserted into balancers"
+    },
+    balancer_endpoint_removes_total: Counter {
+        "Total count of endpoints removed from balancers"
+    },
+    balancer_endpoint_insert_lag_ms: Histogram<latency::Ms> {
+        "Time from an endpoint being discovered to its insertion into a balancer"
+    },
+    balancer_endpoint_make_failures_total: Counter {
+        "Total count of endpoints skipped because their services could not be built"
+    }
+}
 
 /// Resolves `T`-typed names/addresses as a `Resolution`.
 pub trait Resolve<T> {
@@ -39,28 +73,377 @@ pub enum Update<T> {
     Remove(SocketAddr),
 }
 
+/// An infinite stream of updates to endpoints that are identified by name,
+/// i.e. whose addresses must be resolved via DNS (see `DnsResolving`).
+pub trait NameResolution {
+    type Endpoint;
+    type Error;
+
+    fn poll(&mut self) -> Poll<NameUpdate<Self::Endpoint>, Self::Error>;
+}
+
+/// Implemented by endpoints that may be recognized once they have moved to a
+/// new address, e.g. by the name of the pod that serves them.
+pub trait HasMoveKey {
+    /// Returns a key that the endpoint shares with the endpoint that replaces
+    /// it at a new address, if it has one.
+    fn move_key(&self) -> Option<&str>;
+}
+
+#[derive(Clone, Debug)]
+pub enum NameUpdate<T> {
+    Add(NameAddr, T),
+    Remove(NameAddr),
+}
+
+/// Resolves a name to all of its addresses.
+///
+/// This is abstracted so that `DnsResolving` may be tested without a real DNS
+/// service.
+pub trait LookupIps {
+    type Future: Future<Item = dns::IpAddrs, Error = dns::ResolveError>;
+
+    fn lookup_ips(&self, name: &dns::Name) -> Self::Future;
+}
+
+/// The IP address families of the endpoints that may be added to a balancer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddrFamilies {
+    Both,
+    V4Only,
+    V6Only,
+}
+
+/// Determines how a balancer handles an endpoint whose service cannot be
+/// built.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MakeFailures {
+    /// The balancer fails, so that it is rebuilt.
+    Fail,
+    /// The endpoint is not added to the balancer, and the failure is
+    /// recorded. If the endpoint was already balanced, its previous service
+    /// is removed.
+    Skip,
+}
+
+/// Determines which name's endpoint is used for an address to which more
+/// than one name resolves.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddrCollisions {
+    /// The endpoint of the name that first resolved to the address is used.
+    PreferFirst,
+    /// The endpoint of the name that most recently resolved to the address is
+    /// used.
+    PreferLast,
+    /// The resolution fails with an `AddrCollision`.
+    Error,
+}
+
+/// Indicates that more than one name resolved to the same address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddrCollision {
+    addr: SocketAddr,
+    first: NameAddr,
+    second: NameAddr,
+}
+
+/// Records the changes that a `Discover` applies to its balancer.
+pub trait Record: Clone {
+    /// Records that an endpoint was inserted into the balancer, `lag` after
+    /// it was added (i.e. including the time taken to build its service).
+    fn record_insert(&self, lag: Duration);
+
+    fn record_remove(&self);
+
+    /// Records that an endpoint was skipped because its service could not be
+    /// built.
+    fn record_make_failure(&self);
+}
+
+/// Records balancer endpoint changes as metrics.
+///
+/// Clones share the same metrics, so a single `UpdateMetrics` may be shared by
+/// all balancers.
+#[derive(Clone, Debug, Default)]
+pub struct UpdateMetrics(Arc<Mutex<UpdateMetricsInner>>);
+
+#[derive(Debug, Default)]
+struct UpdateMetricsInner {
+    inserts: Counter,
+    removes: Counter,
+    insert_lag: Histogram<latency::Ms>,
+    make_failures: Counter,
+}
+
+/// A `Resolve` that defers resolving a target until its resolution is first
+/// polled.
+///
+/// Discovery subscriptions are not opened for resolutions that are never
+/// polled (e.g. for balancers that never serve a request). Because the
+/// underlying resolution is not obtained until it is polled, failures to
+/// resolve a target are surfaced as errors of the `Resolution` rather than of
+/// the `Resolve::Future`.
+#[derive(Clone, Debug)]
+pub struct Lazy<R>(R);
+
+/// A `Resolution` that resolves its target when it is first polled.
+pub struct LazyResolution<T, R: Resolve<T>> {
+    state: LazyState<T, R>,
+}
+
+enum LazyState<T, R: Resolve<T>> {
+    Init(T, R),
+    Resolving(R::Future),
+    Resolved(R::Resolution),
+}
+
+/// A `Resolve` that shares each target's `Resolution` among all of the
+/// balancers that resolve the target, so that each target has a single
+/// discovery subscription.
+///
+/// Each subscriber observes an `Add` for each endpoint that was already
+/// resolved when it subscribed, followed by every subsequent update,
+/// independently of the other subscribers. The underlying resolution is
+/// dropped once all of its subscribers are dropped. Targets that are resolved
+/// again before their first resolution completes are not coalesced.
+pub struct Shared<T, R: Resolve<T>> {
+    resolve: R,
+    subscriptions: Subscriptions<T, R::Resolution>,
+}
+
+pub struct Subscriptions<T, R: Resolution>(Arc<Mutex<HashMap<T, Weak<Mutex<Subscription<R>>>>>>);
+
+pub enum SharedFuture<T, F>
+where
+    F: Future,
+    F::Item: Resolution,
+{
+    Subscribed(Option<SharedResolution<F::Item>>),
+    Resolving {
+        future: F,
+        target: Option<T>,
+        subscriptions: Subscriptions<T, F::Item>,
+    },
+}
+
+/// A subscription to a `Resolution` that is shared by a `Shared` resolve.
+pub struct SharedResolution<R: Resolution> {
+    id: usize,
+    subscription: Arc<Mutex<Subscription<R>>>,
+}
+
+struct Subscription<R: Resolution> {
+    resolution: R,
+    /// The resolution's current endpoints, which are replayed to new
+    /// subscribers.
+    endpoints: IndexMap<SocketAddr, R::Endpoint>,
+    subscribers: HashMap<usize, Subscriber<R::Endpoint>>,
+    next_id: usize,
+    failed: bool,
+}
+
+/// The updates that a subscriber has not yet observed.
+struct Subscriber<T> {
+    updates: VecDeque<Update<T>>,
+    /// Set while the subscriber is waiting for an update.
+    task: Option<task::Task>,
+}
+
+/// Indicates that a shared resolution failed while another subscriber was
+/// polling it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SubscriptionFailed;
+
+/// A `Resolution` that only admits endpoints whose targets match a predicate.
+///
+/// Removals of endpoints that were never admitted are suppressed, so that
+/// consumers never observe filtered endpoints at all.
+pub struct Filter<R, P> {
+    resolution: R,
+    predicate: P,
+    admitted: HashSet<SocketAddr>,
+}
+
+/// Limits the resolutions of an `R`-typed resolver to subsets of their
+/// endpoints (see `Subset`), if configured.
+#[derive(Clone, Debug)]
+pub struct Subsetting<R> {
+    resolve: R,
+    params: Option<SubsetParams>,
+}
+
+pub struct SubsetFuture<F> {
+    future: F,
+    params: Option<SubsetParams>,
+}
+
+/// A `Resolution` that admits a stable subset of its endpoints, selected for a
+/// client by rendezvous hashing.
+///
+/// Each endpoint is ranked by a hash of the client's identifier and the
+/// endpoint's address, and the highest-ranked endpoints are admitted. Each
+/// client ranks endpoints differently, so subsets overlap evenly across
+/// clients. When an endpoint is added or removed, at most one other endpoint
+/// is removed or admitted in its place.
+pub struct Subset<R: Resolution> {
+    resolution: R,
+    params: Option<SubsetParams>,
+    /// Every resolved endpoint, whether or not it is admitted.
+    resolved: HashMap<SocketAddr, R::Endpoint>,
+    /// The admitted endpoints, highest-ranked first.
+    admitted: BTreeSet<Ranked>,
+    /// The resolved endpoints that are not admitted, highest-ranked first.
+    standby: BTreeSet<Ranked>,
+    /// Updates to the subset that have not yet been returned.
+    pending: VecDeque<Update<R::Endpoint>>,
+}
+
+/// An endpoint's address, ordered by its rank for a client (highest first).
+type Ranked = (Reverse<u64>, SocketAddr);
+
+#[derive(Copy, Clone, Debug)]
+struct SubsetParams {
+    /// A hash of the client's identifier.
+    client: u64,
+    size: usize,
+}
+
+/// A `Resolution` that resolves the names of an `R`-typed `NameResolution`
+/// via DNS, adding an endpoint for each address to which a name resolves.
+///
+/// Each name is re-resolved when its records expire, and addresses to which
+/// it no longer resolves are removed. If a name cannot be resolved, its prior
+/// addresses are retained and it is re-resolved after a backoff.
+///
+/// If more than one name resolves to the same address, the address is added
+/// once, as configured by `AddrCollisions`, and it is only removed once no
+/// name resolves to it.
+pub struct DnsResolving<R: NameResolution, L: LookupIps> {
+    resolution: R,
+    lookup: L,
+    names: HashMap<NameAddr, Named<R::Endpoint, L::Future>>,
+    claims: Claims<R::Endpoint>,
+    updates: VecDeque<Update<R::Endpoint>>,
+}
+
+/// Resolves a name, e.g. one that has been canonicalized, to an endpoint for
+/// each address to which it resolves via DNS, so that a balancer may be built
+/// for the name.
+///
+/// Each endpoint is identified by its address. The name's records are
+/// resolved as a `DnsResolving`, so their TTLs are honored as addresses are
+/// added and removed.
+#[derive(Clone, Debug)]
+pub struct DnsResolve<L>(L);
+
+/// A `Resolution` of the addresses of a single name.
+pub struct DnsResolution<L: LookupIps>(DnsResolving<SingleName, L>);
+
+/// A `NameResolution` of a single name that never changes.
+struct SingleName(Option<NameAddr>);
+
+/// The names that resolve to each address, in the order in which they were
+/// resolved, with their endpoints.
+struct Claims<T> {
+    collisions: AddrCollisions,
+    addrs: HashMap<SocketAddr, Vec<(NameAddr, T)>>,
+}
+
+struct Named<T, F> {
+    endpoint: T,
+    addrs: HashSet<SocketAddr>,
+    state: NamedState<F>,
+}
+
+enum NamedState<F> {
+    Resolving(F),
+    /// Waiting for the name's records to expire or, if it could not be
+    /// resolved, to retry.
+    Waiting(Delay),
+}
+
 #[derive(Clone, Debug)]
-pub struct Layer<R> {
+pub struct Layer<R, G = ()> {
     resolve: R,
+    recorder: G,
+    subset: Option<SubsetParams>,
+    max_endpoints: Option<usize>,
+    families: AddrFamilies,
+    move_window: Option<Duration>,
+    make_failures: MakeFailures,
 }
 
 #[derive(Clone, Debug)]
-pub struct MakeSvc<R, M> {
+pub struct MakeSvc<R, M, G = ()> {
     resolve: R,
     inner: M,
+    recorder: G,
+    max_endpoints: Option<usize>,
+    families: AddrFamilies,
+    move_window: Option<Duration>,
+    make_failures: MakeFailures,
 }
 
 /// Observes an `R`-typed resolution stream, using an `M`-typed endpoint stack to
 /// build a service for each endpoint.
-pub struct Discover<R: Resolution, M: svc::Service<R::Endpoint>> {
+///
+/// Changes to the balancer are recorded by the `G`-typed recorder.
+pub struct Discover<R: Resolution, M: svc::Service<R::Endpoint>, G = ()> {
     resolution: R,
     make: M,
     make_futures: MakeFutures<M::Future>,
+    endpoints: HashSet<SocketAddr>,
+    endpoints_store: Option<Store<HashSet<SocketAddr>>>,
+    /// The most recent target resolved for each address.
+    targets: HashMap<SocketAddr, R::Endpoint>,
+    evicted: HashSet<SocketAddr>,
+    evictions: Option<mpsc::UnboundedReceiver<Eviction>>,
+    recorder: G,
+    max_endpoints: Option<usize>,
+    /// Endpoints that were resolved while `max_endpoints` were already
+    /// resolved, with the order in which they were resolved.
+    overflow: HashMap<SocketAddr, (u64, R::Endpoint)>,
+    next_overflow_seq: u64,
+    families: AddrFamilies,
+    move_window: Option<Duration>,
+    make_failures: MakeFailures,
+    /// Endpoints whose removals are deferred, in the order in which they were
+    /// unresolved.
+    ///
+    /// These endpoints are still balanced, so they count against
+    /// `max_endpoints` until they are removed.
+    moving: Vec<Moving>,
+}
+
+/// A balanced endpoint that is removed once an endpoint that replaces it has
+/// been inserted, or once its move window elapses.
+struct Moving {
+    addr: SocketAddr,
+    /// The endpoint's move key, which its replacement must share.
+    key: String,
+    replacement: Option<SocketAddr>,
+    replaced: bool,
+    expiry: Delay,
+}
+
+/// Evicts endpoints from a `Discover`, independently of its resolution.
+#[derive(Clone, Debug)]
+pub struct Evict(mpsc::UnboundedSender<Eviction>);
+
+#[derive(Debug)]
+enum Eviction {
+    Evict(SocketAddr),
+    Restore(SocketAddr),
 }
 
-pub struct DiscoverFuture<F, M> {
+pub struct DiscoverFuture<F, M, G = ()> {
     future: F,
     make: M,
+    recorder: G,
+    max_endpoints: Option<usize>,
+    families: AddrFamilies,
+    move_window: Option<Duration>,
+    make_failures: MakeFailures,
 }
 
 struct MakeFutures<F> {
@@ -72,387 +455,1860 @@ struct MakeFuture<F> {
     inner: F,
     canceled: oneshot::Receiver<()>,
     addr: SocketAddr,
+    /// The time at which the service was requested.
+    added_at: Instant,
 }
 
 enum MakeError<E> {
-    Inner(E),
+    Inner(SocketAddr, E),
     Canceled,
 }
 
-// === impl Layer ===
+// === impl Lazy ===
 
-pub fn layer<T, R>(resolve: R) -> Layer<R>
+impl<R> Lazy<R> {
+    pub fn new(resolve: R) -> Self {
+        Lazy(resolve)
+    }
+}
+
+impl<T, R> Resolve<T> for Lazy<R>
 where
+    T: Clone,
     R: Resolve<T> + Clone,
-    R::Endpoint: fmt::Debug,
+    <R::Future as Future>::Error: Into<Error>,
+    <R::Resolution as Resolution>::Error: Into<Error>,
 {
-    Layer { resolve }
+    type Endpoint = R::Endpoint;
+    type Resolution = LazyResolution<T, R>;
+    type Future = future::FutureResult<Self::Resolution, Never>;
+
+    fn resolve(&self, target: &T) -> Self::Future {
+        future::ok(LazyResolution {
+            state: LazyState::Init(target.clone(), self.0.clone()),
+        })
+    }
 }
 
-impl<R, M> svc::Layer<M> for Layer<R>
+// === impl LazyResolution ===
+
+impl<T, R> Resolution for LazyResolution<T, R>
 where
-    R: Clone,
+    R: Resolve<T>,
+    <R::Future as Future>::Error: Into<Error>,
+    <R::Resolution as Resolution>::Error: Into<Error>,
 {
-    type Service = MakeSvc<R, M>;
+    type Endpoint = R::Endpoint;
+    type Error = Error;
 
-    fn layer(&self, inner: M) -> Self::Service {
-        MakeSvc {
-            resolve: self.resolve.clone(),
-            inner,
+    fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error> {
+        loop {
+            self.state = match self.state {
+                LazyState::Init(ref target, ref resolve) => {
+                    trace!("resolving lazily");
+                    LazyState::Resolving(resolve.resolve(target))
+                }
+                LazyState::Resolving(ref mut future) => {
+                    let resolution = try_ready!(future.poll().map_err(Into::into));
+                    LazyState::Resolved(resolution)
+                }
+                LazyState::Resolved(ref mut resolution) => {
+                    return resolution.poll().map_err(Into::into);
+                }
+            };
         }
     }
 }
 
-// === impl MakeSvc ===
+// === impl Shared ===
 
-impl<T, R, M> svc::Service<T> for MakeSvc<R, M>
+impl<T, R> Shared<T, R>
 where
+    T: Eq + Hash,
     R: Resolve<T>,
-    R::Endpoint: fmt::Debug,
-    M: svc::Service<R::Endpoint> + Clone,
 {
-    type Response = Discover<R::Resolution, M>;
-    type Error = <R::Future as Future>::Error;
-    type Future = DiscoverFuture<R::Future, Option<M>>;
+    pub fn new(resolve: R) -> Self {
+        Self {
+            resolve,
+            subscriptions: Subscriptions(Arc::new(Mutex::new(HashMap::new()))),
+        }
+    }
+}
 
-    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        Ok(().into()) // always ready to make a Discover
+impl<T, R> Resolve<T> for Shared<T, R>
+where
+    T: Clone + Eq + Hash,
+    R: Resolve<T>,
+    R::Endpoint: Clone,
+{
+    type Endpoint = R::Endpoint;
+    type Resolution = SharedResolution<R::Resolution>;
+    type Future = SharedFuture<T, R::Future>;
+
+    fn resolve(&self, target: &T) -> Self::Future {
+        if let Some(subscription) = self.subscriptions.get(target) {
+            trace!("sharing an existing resolution");
+            return SharedFuture::Subscribed(Some(SharedResolution::new(subscription)));
+        }
+
+        SharedFuture::Resolving {
+            future: self.resolve.resolve(target),
+            target: Some(target.clone()),
+            subscriptions: self.subscriptions.clone(),
+        }
+    }
+}
+
+impl<T, R: Resolve<T> + Clone> Clone for Shared<T, R> {
+    fn clone(&self) -> Self {
+        Self {
+            resolve: self.resolve.clone(),
+            subscriptions: self.subscriptions.clone(),
+        }
     }
+}
 
-    fn call(&mut self, target: T) -> Self::Future {
-        let future = self.resolve.resolve(&target);
-        DiscoverFuture {
-            future,
-            make: Some(self.inner.clone()),
+impl<T, R: Resolve<T> + fmt::Debug> fmt::Debug for Shared<T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shared")
+            .field("resolve", &self.resolve)
+            .finish()
+    }
+}
+
+// === impl Subscriptions ===
+
+impl<T: Eq + Hash, R: Resolution> Subscriptions<T, R> {
+    /// Returns the live subscription for `target`, if there is one.
+    fn get(&self, target: &T) -> Option<Arc<Mutex<Subscription<R>>>> {
+        let mut subscriptions = self.0.lock().expect("subscriptions lock poisoned");
+        // Subscriptions whose subscribers have all been dropped are
+        // forgotten.
+        subscriptions.retain(|_, s| s.upgrade().is_some());
+        let subscription = subscriptions.get(target)?.upgrade()?;
+        let failed = subscription.lock().expect("subscription lock poisoned").failed;
+        if failed {
+            return None;
+        }
+        Some(subscription)
+    }
+
+    /// Shares `resolution` as the subscription for `target`, unless another
+    /// live subscription was added while it was being resolved.
+    fn insert(&self, target: T, resolution: R) -> Arc<Mutex<Subscription<R>>> {
+        if let Some(subscription) = self.get(&target) {
+            return subscription;
         }
+
+        let subscription = Arc::new(Mutex::new(Subscription {
+            resolution,
+            endpoints: IndexMap::new(),
+            subscribers: HashMap::new(),
+            next_id: 0,
+            failed: false,
+        }));
+        let mut subscriptions = self.0.lock().expect("subscriptions lock poisoned");
+        subscriptions.insert(target, Arc::downgrade(&subscription));
+        subscription
     }
 }
 
-// === impl DiscoverFuture ===
+impl<T, R: Resolution> Clone for Subscriptions<T, R> {
+    fn clone(&self) -> Self {
+        Subscriptions(self.0.clone())
+    }
+}
 
-impl<F, M> Future for DiscoverFuture<F, Option<M>>
+// === impl SharedFuture ===
+
+impl<T, F> Future for SharedFuture<T, F>
 where
+    T: Eq + Hash,
     F: Future,
     F::Item: Resolution,
-    M: svc::Service<<F::Item as Resolution>::Endpoint>,
+    <F::Item as Resolution>::Endpoint: Clone,
 {
-    type Item = Discover<F::Item, M>;
+    type Item = SharedResolution<F::Item>;
     type Error = F::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let resolution = try_ready!(self.future.poll());
-        let make = self.make.take().expect("polled after ready");
-        Ok(Async::Ready(Discover::new(resolution, make)))
+        match *self {
+            SharedFuture::Subscribed(ref mut resolution) => {
+                let resolution = resolution.take().expect("polled after ready");
+                Ok(Async::Ready(resolution))
+            }
+            SharedFuture::Resolving {
+                ref mut future,
+                ref mut target,
+                ref subscriptions,
+            } => {
+                let resolution = try_ready!(future.poll());
+                let target = target.take().expect("polled after ready");
+                let subscription = subscriptions.insert(target, resolution);
+                Ok(Async::Ready(SharedResolution::new(subscription)))
+            }
+        }
     }
 }
 
-// === impl Discover ===
+// === impl SharedResolution ===
 
-impl<R, M> Discover<R, M>
+impl<R: Resolution> SharedResolution<R>
 where
-    R: Resolution,
-    M: svc::Service<R::Endpoint>,
+    R::Endpoint: Clone,
 {
-    fn new(resolution: R, make: M) -> Self {
-        Self {
-            resolution,
-            make,
-            make_futures: MakeFutures::new(),
-        }
+    fn new(subscription: Arc<Mutex<Subscription<R>>>) -> Self {
+        let id = subscription
+            .lock()
+            .expect("subscription lock poisoned")
+            .subscribe();
+        Self { id, subscription }
     }
 }
 
-impl<R, M> Discover<R, M>
+impl<R> Resolution for SharedResolution<R>
 where
     R: Resolution,
-    R::Endpoint: fmt::Debug,
+    R::Endpoint: Clone,
     R::Error: Into<Error>,
-    M: svc::Service<R::Endpoint>,
-    M::Error: Into<Error>,
 {
-    fn poll_resolution(&mut self) -> Poll<Change<SocketAddr, M::Response>, Error> {
+    type Endpoint = R::Endpoint;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error> {
+        let mut subscription = self.subscription.lock().expect("subscription lock poisoned");
+        subscription.poll_update(self.id)
+    }
+}
+
+impl<R: Resolution> Drop for SharedResolution<R> {
+    fn drop(&mut self) {
+        if let Ok(mut subscription) = self.subscription.lock() {
+            subscription.subscribers.remove(&self.id);
+            // The underlying resolution may only notify this subscriber's
+            // task, so the others must poll it again.
+            subscription.notify_all();
+        }
+    }
+}
+
+// === impl Subscription ===
+
+impl<R: Resolution> Subscription<R> {
+    fn notify_all(&mut self) {
+        for subscriber in self.subscribers.values_mut() {
+            if let Some(task) = subscriber.task.take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+impl<R: Resolution> Subscription<R>
+where
+    R::Endpoint: Clone,
+{
+    /// Adds a subscriber that first observes the current endpoints.
+    fn subscribe(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let updates = self
+            .endpoints
+            .iter()
+            .map(|(addr, ep)| Update::Add(*addr, ep.clone()))
+            .collect();
+        self.subscribers.insert(id, Subscriber { updates, task: None });
+        id
+    }
+
+    fn poll_update(&mut self, id: usize) -> Poll<Update<R::Endpoint>, Error>
+    where
+        R::Error: Into<Error>,
+    {
         loop {
-            // Before polling the resolution, where we could potentially receive
-            // an `Add`, poll_ready to ensure that `make` is ready to build new
-            // services. Don't process any updates until we can do so.
-            try_ready!(self.make.poll_ready().map_err(Into::into));
+            let next = self
+                .subscribers
+                .get_mut(&id)
+                .and_then(|s| s.updates.pop_front());
+            if let Some(update) = next {
+                return Ok(Async::Ready(update));
+            }
+
+            if self.failed {
+                return Err(SubscriptionFailed.into());
+            }
+
+            let update = match self.resolution.poll() {
+                Ok(Async::Ready(update)) => update,
+                Ok(Async::NotReady) => {
+                    if let Some(subscriber) = self.subscribers.get_mut(&id) {
+                        subscriber.task = Some(task::current());
+                    }
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => {
+                    self.failed = true;
+                    self.notify_all();
+                    return Err(e.into());
+                }
+            };
 
-            let update = try_ready!(self.resolution.poll().map_err(Into::into));
-            trace!("watch: {:?}", update);
             match update {
-                Update::Add(addr, target) => {
-                    // Start building the service and continue. If a pending
-                    // service exists for this addr, it will be canceled.
-                    let fut = self.make.call(target);
-                    self.make_futures.push(addr, fut);
+                Update::Add(addr, ref ep) => {
+                    self.endpoints.insert(addr, ep.clone());
                 }
                 Update::Remove(addr) => {
-                    self.make_futures.remove(&addr);
-                    return Ok(Async::Ready(Change::Remove(addr)));
+                    self.endpoints.remove(&addr);
                 }
             }
+            for subscriber in self.subscribers.values_mut() {
+                subscriber.updates.push_back(update.clone());
+            }
+            self.notify_all();
         }
     }
 }
 
-impl<R, M> tower_discover::Discover for Discover<R, M>
+// === impl SubscriptionFailed ===
+
+impl fmt::Display for SubscriptionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the shared resolution failed")
+    }
+}
+
+impl error::Error for SubscriptionFailed {}
+
+// === impl Filter ===
+
+impl<R, P> Filter<R, P>
 where
     R: Resolution,
-    R::Endpoint: fmt::Debug,
-    R::Error: Into<Error>,
-    M: svc::Service<R::Endpoint>,
-    M::Error: Into<Error>,
+    P: Fn(&R::Endpoint) -> bool,
 {
-    type Key = SocketAddr;
-    type Service = M::Response;
-    type Error = Error;
-
-    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
-        if let Async::Ready(change) = self.poll_resolution()? {
-            return Ok(Async::Ready(change));
+    /// Admits the endpoints of `resolution` for which `predicate` returns
+    /// true.
+    pub fn new(resolution: R, predicate: P) -> Self {
+        Self {
+            resolution,
+            predicate,
+            admitted: HashSet::new(),
         }
+    }
+}
 
-        if let Async::Ready(Some((addr, svc))) = self.make_futures.poll().map_err(Into::into)? {
-            return Ok(Async::Ready(Change::Insert(addr, svc)));
-        }
+impl<R, P> Resolution for Filter<R, P>
+where
+    R: Resolution,
+    P: Fn(&R::Endpoint) -> bool,
+{
+    type Endpoint = R::Endpoint;
+    type Error = R::Error;
 
-        Ok(Async::NotReady)
+    fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error> {
+        loop {
+            match try_ready!(self.resolution.poll()) {
+                Update::Add(addr, target) => {
+                    if (self.predicate)(&target) {
+                        self.admitted.insert(addr);
+                        return Ok(Async::Ready(Update::Add(addr, target)));
+                    }
+
+                    trace!("filtered {}", addr);
+                    // An admitted endpoint whose target no longer matches is
+                    // removed.
+                    if self.admitted.remove(&addr) {
+                        return Ok(Async::Ready(Update::Remove(addr)));
+                    }
+                }
+                Update::Remove(addr) => {
+                    if self.admitted.remove(&addr) {
+                        return Ok(Async::Ready(Update::Remove(addr)));
+                    }
+                }
+            }
+        }
     }
 }
 
-// === impl MakeFutures ===
+// === impl Subsetting ===
 
-impl<F: Future> MakeFutures<F> {
-    fn new() -> Self {
+impl<R> Subsetting<R> {
+    /// Wraps `resolve` without limiting its resolutions' endpoints.
+    pub fn new(resolve: R) -> Self {
         Self {
-            futures: FuturesUnordered::new(),
-            cancelations: IndexMap::new(),
+            resolve,
+            params: None,
         }
     }
 
-    fn push(&mut self, addr: SocketAddr, inner: F) {
-        let (cancel, canceled) = oneshot::channel();
-        if let Some(prior) = self.cancelations.insert(addr, cancel) {
-            let _ = prior.send(());
+    /// Limits each resolution to at most `size` endpoints, selected for the
+    /// client identified by `client`.
+    pub fn with_subset(self, client: &str, size: usize) -> Self {
+        Self {
+            params: Some(SubsetParams::new(client, size)),
+            ..self
         }
-        self.futures.push(MakeFuture {
-            addr,
-            inner,
-            canceled,
-        });
     }
+}
 
-    fn remove(&mut self, addr: &SocketAddr) {
-        if let Some(cancel) = self.cancelations.remove(addr) {
-            let _ = cancel.send(());
+impl<T, R> Resolve<T> for Subsetting<R>
+where
+    R: Resolve<T>,
+    R::Endpoint: Clone,
+{
+    type Endpoint = R::Endpoint;
+    type Resolution = Subset<R::Resolution>;
+    type Future = SubsetFuture<R::Future>;
+
+    fn resolve(&self, target: &T) -> Self::Future {
+        SubsetFuture {
+            future: self.resolve.resolve(target),
+            params: self.params,
         }
     }
 }
 
-impl<F: Future> Stream for MakeFutures<F> {
-    type Item = (SocketAddr, F::Item);
+// === impl SubsetFuture ===
+
+impl<F> Future for SubsetFuture<F>
+where
+    F: Future,
+    F::Item: Resolution,
+    <F::Item as Resolution>::Endpoint: Clone,
+{
+    type Item = Subset<F::Item>;
     type Error = F::Error;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let resolution = try_ready!(self.future.poll());
+        Ok(Async::Ready(Subset::with_params(resolution, self.params)))
+    }
+}
+
+// === impl Subset ===
+
+impl<R> Subset<R>
+where
+    R: Resolution,
+    R::Endpoint: Clone,
+{
+    /// Admits at most `size` of the endpoints of `resolution`, selected for
+    /// the client identified by `client`.
+    pub fn new(resolution: R, client: &str, size: usize) -> Self {
+        Self::with_params(resolution, Some(SubsetParams::new(client, size)))
+    }
+
+    fn with_params(resolution: R, params: Option<SubsetParams>) -> Self {
+        Self {
+            resolution,
+            params,
+            resolved: HashMap::new(),
+            admitted: BTreeSet::new(),
+            standby: BTreeSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Admits a newly-resolved endpoint if it is among the `size`
+    /// highest-ranked endpoints, in place of the lowest-ranked admitted
+    /// endpoint if the subset is full.
+    ///
+    /// Endpoints are admitted before others are removed, so that the subset
+    /// is never emptied while endpoints are resolved.
+    fn insert(&mut self, ranked: Ranked, size: usize) {
+        if self.admitted.len() < size {
+            self.admit(ranked);
+            return;
+        }
+
+        let lowest = match self.admitted.iter().next_back() {
+            Some(&lowest) if ranked < lowest => lowest,
+            _ => {
+                self.standby.insert(ranked);
+                return;
+            }
+        };
+        self.admit(ranked);
+        self.admitted.remove(&lowest);
+        self.standby.insert(lowest);
+        trace!("removing {} from the subset", lowest.1);
+        self.pending.push_back(Update::Remove(lowest.1));
+    }
+
+    /// Forgets an unresolved endpoint, admitting the highest-ranked endpoint
+    /// on standby in its place if it was admitted.
+    fn remove(&mut self, ranked: Ranked) {
+        if !self.admitted.remove(&ranked) {
+            self.standby.remove(&ranked);
+            return;
+        }
+
+        let next = self.standby.iter().next().cloned();
+        if let Some(next) = next {
+            self.standby.remove(&next);
+            self.admit(next);
+        }
+        trace!("removing {} from the subset", ranked.1);
+        self.pending.push_back(Update::Remove(ranked.1));
+    }
+
+    fn admit(&mut self, ranked: Ranked) {
+        trace!("admitting {} to the subset", ranked.1);
+        let target = self.resolved[&ranked.1].clone();
+        self.pending.push_back(Update::Add(ranked.1, target));
+        self.admitted.insert(ranked);
+    }
+}
+
+impl<R> Resolution for Subset<R>
+where
+    R: Resolution,
+    R::Endpoint: Clone,
+{
+    type Endpoint = R::Endpoint;
+    type Error = R::Error;
+
+    fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error> {
         loop {
-            return match self.futures.poll() {
-                Err(MakeError::Canceled) => continue,
-                Err(MakeError::Inner(err)) => Err(err),
-                Ok(Async::Ready(Some((addr, svc)))) => {
-                    let _rm = self.cancelations.remove(&addr);
-                    debug_assert!(_rm.is_some(), "cancelation missing for {}", addr);
-                    Ok(Async::Ready(Some((addr, svc))))
-                }
-                Ok(r) => Ok(r),
+            if let Some(update) = self.pending.pop_front() {
+                return Ok(Async::Ready(update));
+            }
+
+            let update = try_ready!(self.resolution.poll());
+            let params = match self.params {
+                Some(params) => params,
+                None => return Ok(Async::Ready(update)),
             };
+            match update {
+                Update::Add(addr, target) => {
+                    let ranked = (Reverse(params.rank(&addr)), addr);
+                    if self.resolved.insert(addr, target.clone()).is_none() {
+                        self.insert(ranked, params.size);
+                    } else if self.admitted.contains(&ranked) {
+                        // Updates to admitted endpoints' targets are passed on.
+                        self.pending.push_back(Update::Add(addr, target));
+                    }
+                }
+                Update::Remove(addr) => {
+                    if self.resolved.remove(&addr).is_some() {
+                        self.remove((Reverse(params.rank(&addr)), addr));
+                    }
+                }
+            }
         }
     }
 }
 
-// === impl MakeFuture ===
+// === impl SubsetParams ===
 
-impl<F: Future> Future for MakeFuture<F> {
-    type Item = (SocketAddr, F::Item);
-    type Error = MakeError<F::Error>;
+impl SubsetParams {
+    /// Seeds the hash of each client's identifier.
+    ///
+    /// Clients with the same identifier must rank endpoints identically, even
+    /// if they run different versions of the proxy, so endpoints are ranked
+    /// by a fixed hash (FNV-1a, with a finalizer to mix its bits) rather than
+    /// by `DefaultHasher`, whose algorithm is unspecified.
+    const SEED: u64 = 0xcbf2_9ce4_8422_2325;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(Async::Ready(())) = self.canceled.poll() {
-            trace!("canceled making service for {:?}", self.addr);
-            return Err(MakeError::Canceled);
+    fn new(client: &str, size: usize) -> Self {
+        Self {
+            client: Self::finish(Self::hash(Self::SEED, client.as_bytes())),
+            size,
         }
-        let svc = try_ready!(self.inner.poll());
-        Ok((self.addr, svc).into())
     }
-}
 
-// === impl MakeError ===
+    /// Ranks `addr` for this client.
+    fn rank(&self, addr: &SocketAddr) -> u64 {
+        let hash = match addr.ip() {
+            IpAddr::V4(ip) => Self::hash(self.client, &ip.octets()),
+            IpAddr::V6(ip) => Self::hash(self.client, &ip.octets()),
+        };
+        Self::finish(Self::hash(hash, &addr.port().to_be_bytes()))
+    }
+
+    /// Folds `bytes` into `hash` with FNV-1a.
+    fn hash(hash: u64, bytes: &[u8]) -> u64 {
+        bytes.iter().fold(hash, |hash, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
 
-impl<E> From<E> for MakeError<E> {
-    fn from(inner: E) -> Self {
-        MakeError::Inner(inner)
+    /// Mixes the bits of `hash` (as in MurmurHash3's 64-bit finalizer), so
+    /// that every bit of a rank depends on every byte of the address.
+    fn finish(mut hash: u64) -> u64 {
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use self::tower_discover::{Change, Discover as _Discover};
-    use super::*;
-    use futures::future;
-    use svc::Service;
-    use tokio::sync::mpsc;
-    use tower_util::service_fn;
+// === impl LookupIps ===
 
-    impl<E> Resolution for mpsc::Receiver<Update<E>> {
-        type Endpoint = E;
-        type Error = mpsc::error::RecvError;
+impl LookupIps for dns::Resolver {
+    type Future = dns::IpAddrsFuture;
 
-        fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error> {
-            let ep = try_ready!(Stream::poll(self)).expect("stream must not terminate");
-            Ok(Async::Ready(ep))
-        }
+    fn lookup_ips(&self, name: &dns::Name) -> Self::Future {
+        self.resolve_all_ips(name)
     }
+}
 
-    #[derive(Debug)]
-    struct Svc<T>(Vec<oneshot::Receiver<T>>);
-    impl<T> Service<()> for Svc<T> {
-        type Response = T;
-        type Error = oneshot::error::RecvError;
-        type Future = oneshot::Receiver<T>;
+// === impl DnsResolving ===
 
-        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-            Ok(().into())
+impl<R, L> DnsResolving<R, L>
+where
+    R: NameResolution,
+    R::Endpoint: Clone,
+    L: LookupIps,
+{
+    pub fn new(resolution: R, lookup: L) -> Self {
+        Self {
+            resolution,
+            lookup,
+            names: HashMap::new(),
+            claims: Claims {
+                collisions: AddrCollisions::default(),
+                addrs: HashMap::new(),
+            },
+            updates: VecDeque::new(),
         }
+    }
 
-        fn call(&mut self, _: ()) -> Self::Future {
-            self.0.pop().expect("exhausted")
+    /// Configures how addresses to which more than one name resolves are
+    /// handled.
+    ///
+    /// By default, the first name to resolve to an address is preferred.
+    pub fn with_addr_collisions(mut self, collisions: AddrCollisions) -> Self {
+        self.claims.collisions = collisions;
+        self
+    }
+
+    fn add(&mut self, name: NameAddr, endpoint: R::Endpoint) {
+        match self.names.entry(name) {
+            Entry::Occupied(mut e) => {
+                // The name's addresses are re-added with the new endpoint.
+                for addr in &e.get().addrs {
+                    if let Some(update) = self.claims.update(*addr, e.key(), &endpoint) {
+                        self.updates.push_back(update);
+                    }
+                }
+                e.get_mut().endpoint = endpoint;
+            }
+            Entry::Vacant(e) => {
+                trace!("resolving {}", e.key());
+                let future = self.lookup.lookup_ips(e.key().name());
+                e.insert(Named {
+                    endpoint,
+                    addrs: HashSet::new(),
+                    state: NamedState::Resolving(future),
+                });
+            }
         }
     }
 
-    #[test]
-    fn inserts_delivered_out_of_order() {
-        with_task(move || {
-            let (mut reso_tx, resolution) = mpsc::channel(2);
-            let (make0_tx, make0_rx) = oneshot::channel::<Svc<usize>>();
-            let (make1_tx, make1_rx) = oneshot::channel::<Svc<usize>>();
-            let make = Svc(vec![make1_rx, make0_rx]);
+    fn remove(&mut self, name: &NameAddr) {
+        if let Some(named) = self.names.remove(name) {
+            for addr in named.addrs {
+                if let Some(update) = self.claims.release(addr, name) {
+                    self.updates.push_back(update);
+                }
+            }
+        }
+    }
 
-            let mut discover = Discover::new(resolution, make);
-            assert!(
-                discover.poll().expect("discover can't fail").is_not_ready(),
-                "ready without updates"
-            );
+    /// Drives the resolution of each name, queueing updates for the addresses
+    /// that have changed.
+    fn poll_names(&mut self) -> Result<(), Error> {
+        for (name, named) in self.names.iter_mut() {
+            loop {
+                named.state = match named.state {
+                    NamedState::Waiting(ref mut delay) => match delay.poll()? {
+                        Async::NotReady => break,
+                        Async::Ready(()) => {
+                            trace!("re-resolving {}", name);
+                            NamedState::Resolving(self.lookup.lookup_ips(name.name()))
+                        }
+                    },
+                    NamedState::Resolving(ref mut future) => match future.poll() {
+                        Ok(Async::NotReady) => break,
+                        Ok(Async::Ready(ips)) => {
+                            let port = name.port();
+                            let addrs = ips
+                                .addrs
+                                .into_iter()
+                                .map(|ip| SocketAddr::new(ip, port))
+                                .collect::<HashSet<_>>();
+                            for addr in named.addrs.difference(&addrs) {
+                                if let Some(update) = self.claims.release(*addr, name) {
+                                    self.updates.push_back(update);
+                                }
+                            }
+                            for addr in addrs.difference(&named.addrs) {
+                                let claim = self.claims.claim(*addr, name, &named.endpoint)?;
+                                if let Some(update) = claim {
+                                    self.updates.push_back(update);
+                                }
+                            }
+                            named.addrs = addrs;
+                            NamedState::Waiting(Delay::new(ips.valid_until))
+                        }
+                        Err(e) => {
+                            debug!(
+                                "failed to resolve {}: {}; retrying in {:?}",
+                                name, e, DNS_ERROR_BACKOFF
+                            );
+                            NamedState::Waiting(Delay::new(clock::now() + DNS_ERROR_BACKOFF))
+                        }
+                    },
+                };
+            }
+        }
 
-            let addr0 = SocketAddr::from(([127, 0, 0, 1], 80));
-            reso_tx.try_send(Update::Add(addr0, ())).unwrap();
-            assert!(
-                discover.poll().expect("discover can't fail").is_not_ready(),
-                "ready without service being made"
-            );
-            assert_eq!(
-                discover.make_futures.futures.len(),
-                1,
-                "must be only one pending make"
-            );
-            assert_eq!(
-                discover.make_futures.cancelations.len(),
-                1,
-                "no pending cancelation"
-            );
+        Ok(())
+    }
+}
 
-            let addr1 = SocketAddr::from(([127, 0, 0, 2], 80));
-            reso_tx
-                .try_send(Update::Add(addr1, ()))
-                .expect("update must be sent");
-            assert!(
-                discover.poll().expect("discover can't fail").is_not_ready(),
-                "ready without service being made"
-            );
-            assert_eq!(
-                discover.make_futures.futures.len(),
-                2,
-                "must be only one pending make"
-            );
-            assert_eq!(
-                discover.make_futures.cancelations.len(),
-                2,
-                "no pending cancelation"
-            );
+impl<R, L> Resolution for DnsResolving<R, L>
+where
+    R: NameResolution,
+    R::Endpoint: Clone,
+    R::Error: Into<Error>,
+    L: LookupIps,
+{
+    type Endpoint = R::Endpoint;
+    type Error = Error;
 
-            let (rsp1_tx, rsp1_rx) = oneshot::channel();
-            make1_tx
-                .send(Svc(vec![rsp1_rx]))
-                .expect("make must receive service");
-            match discover.poll().expect("discover can't fail") {
-                Async::NotReady => panic!("not processed"),
-                Async::Ready(Change::Remove(..)) => panic!("unexpected remove"),
-                Async::Ready(Change::Insert(a, mut svc)) => {
-                    assert_eq!(a, addr1);
+    fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error> {
+        loop {
+            if let Some(update) = self.updates.pop_front() {
+                return Ok(Async::Ready(update));
+            }
 
-                    assert!(svc.poll_ready().unwrap().is_ready());
-                    let mut fut = svc.call(());
-                    assert!(fut.poll().unwrap().is_not_ready());
-                    rsp1_tx.send(1).unwrap();
-                    assert_eq!(fut.poll().unwrap(), Async::Ready(1));
+            match self.resolution.poll().map_err(Into::into)? {
+                Async::Ready(NameUpdate::Add(name, endpoint)) => self.add(name, endpoint),
+                Async::Ready(NameUpdate::Remove(name)) => self.remove(&name),
+                Async::NotReady => {
+                    self.poll_names()?;
+                    if self.updates.is_empty() {
+                        return Ok(Async::NotReady);
+                    }
                 }
             }
-            assert_eq!(
-                discover.make_futures.futures.len(),
-                1,
-                "must be only one pending make"
-            );
-            assert_eq!(
-                discover.make_futures.cancelations.len(),
-                1,
-                "no pending cancelation"
+        }
+    }
+}
+
+// === impl Claims ===
+
+impl<T: Clone> Claims<T> {
+    /// Records that `name` resolves to `addr`, returning the update to apply
+    /// if the address's endpoint changes.
+    fn claim(
+        &mut self,
+        addr: SocketAddr,
+        name: &NameAddr,
+        endpoint: &T,
+    ) -> Result<Option<Update<T>>, AddrCollision> {
+        let collisions = self.collisions;
+        let claims = self.addrs.entry(addr).or_insert_with(Vec::new);
+        if let Some(&(ref owner, _)) = collisions.owner(claims) {
+            if collisions == AddrCollisions::Error {
+                return Err(AddrCollision {
+                    addr,
+                    first: owner.clone(),
+                    second: name.clone(),
+                });
+            }
+            warn!(
+                "{} and {} both resolve to {}; using {}",
+                owner,
+                name,
+                addr,
+                if collisions == AddrCollisions::PreferLast {
+                    name
+                } else {
+                    owner
+                }
             );
+        }
 
-            let (rsp0_tx, rsp0_rx) = oneshot::channel();
-            make0_tx
-                .send(Svc(vec![rsp0_rx]))
-                .expect("make must receive service");
-            match discover.poll().expect("discover can't fail") {
-                Async::NotReady => panic!("not processed"),
-                Async::Ready(Change::Remove(..)) => panic!("unexpected remove"),
-                Async::Ready(Change::Insert(a, mut svc)) => {
-                    assert_eq!(a, addr0);
+        claims.push((name.clone(), endpoint.clone()));
+        if claims.len() == 1 || collisions == AddrCollisions::PreferLast {
+            return Ok(Some(Update::Add(addr, endpoint.clone())));
+        }
+        Ok(None)
+    }
 
-                    assert!(svc.poll_ready().unwrap().is_ready());
-                    let mut fut = svc.call(());
-                    assert!(fut.poll().unwrap().is_not_ready());
-                    rsp0_tx.send(0).unwrap();
-                    assert_eq!(fut.poll().unwrap(), Async::Ready(0));
+    /// Updates the endpoint of `name`'s claim to `addr`, returning the update
+    /// to apply if the address uses `name`'s endpoint.
+    fn update(&mut self, addr: SocketAddr, name: &NameAddr, endpoint: &T) -> Option<Update<T>> {
+        let collisions = self.collisions;
+        let claims = self.addrs.get_mut(&addr)?;
+        let owned = collisions.owner(claims).map(|c| c.0 == *name).unwrap_or(false);
+        for claim in claims.iter_mut().filter(|c| c.0 == *name) {
+            claim.1 = endpoint.clone();
+        }
+        if owned {
+            Some(Update::Add(addr, endpoint.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Records that `name` no longer resolves to `addr`, returning the update
+    /// to apply if the address's endpoint changes or it should be removed.
+    fn release(&mut self, addr: SocketAddr, name: &NameAddr) -> Option<Update<T>> {
+        let collisions = self.collisions;
+        let update = {
+            let claims = self.addrs.get_mut(&addr)?;
+            let owned = collisions.owner(claims).map(|c| c.0 == *name).unwrap_or(false);
+            claims.retain(|c| c.0 != *name);
+            if !owned {
+                return None;
+            }
+            match collisions.owner(claims) {
+                Some(&(ref next, ref endpoint)) => {
+                    debug!("{} no longer resolves to {}; using {}", name, addr, next);
+                    Update::Add(addr, endpoint.clone())
                 }
+                None => Update::Remove(addr),
             }
-            assert!(discover.make_futures.futures.is_empty(), "futures remain");
-            assert!(
-                discover.make_futures.cancelations.is_empty(),
-                "cancelation remains"
-            );
-        });
+        };
+
+        if let Update::Remove(_) = update {
+            self.addrs.remove(&addr);
+        }
+        Some(update)
     }
+}
 
-    #[test]
-    fn overwriting_insert_cancels_original() {
-        with_task(move || {
-            let (mut reso_tx, resolution) = mpsc::channel(2);
-            let (make0_tx, make0_rx) = oneshot::channel::<Svc<usize>>();
-            let (make1_tx, make1_rx) = oneshot::channel::<Svc<usize>>();
-            let make = Svc(vec![make1_rx, make0_rx]);
+// === impl AddrCollisions ===
 
-            let mut discover = Discover::new(resolution, make);
-            assert!(
-                discover.poll().expect("discover can't fail").is_not_ready(),
-                "ready without updates"
-            );
+impl AddrCollisions {
+    /// Returns the claim whose endpoint is used for an address.
+    fn owner<'a, T>(&self, claims: &'a [(NameAddr, T)]) -> Option<&'a (NameAddr, T)> {
+        match *self {
+            AddrCollisions::PreferLast => claims.last(),
+            AddrCollisions::PreferFirst | AddrCollisions::Error => claims.first(),
+        }
+    }
+}
 
-            let addr = SocketAddr::from(([127, 0, 0, 1], 80));
-            reso_tx.try_send(Update::Add(addr, ())).unwrap();
-            assert!(
-                discover.poll().expect("discover can't fail").is_not_ready(),
-                "ready without service being made"
-            );
-            assert_eq!(
-                discover.make_futures.futures.len(),
+impl Default for AddrCollisions {
+    fn default() -> Self {
+        AddrCollisions::PreferFirst
+    }
+}
+
+// === impl MakeFailures ===
+
+impl Default for MakeFailures {
+    fn default() -> Self {
+        MakeFailures::Fail
+    }
+}
+
+// === impl AddrCollision ===
+
+impl fmt::Display for AddrCollision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} and {} both resolve to {}",
+            self.first, self.second, self.addr
+        )
+    }
+}
+
+impl error::Error for AddrCollision {}
+
+// === impl DnsResolve ===
+
+impl<L> DnsResolve<L> {
+    pub fn new(lookup: L) -> Self {
+        DnsResolve(lookup)
+    }
+}
+
+impl<L: LookupIps + Clone> Resolve<NameAddr> for DnsResolve<L> {
+    type Endpoint = SocketAddr;
+    type Resolution = DnsResolution<L>;
+    type Future = future::FutureResult<Self::Resolution, Never>;
+
+    fn resolve(&self, name: &NameAddr) -> Self::Future {
+        let name = SingleName(Some(name.clone()));
+        future::ok(DnsResolution(DnsResolving::new(name, self.0.clone())))
+    }
+}
+
+// === impl DnsResolution ===
+
+impl<L: LookupIps> Resolution for DnsResolution<L> {
+    type Endpoint = SocketAddr;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error> {
+        match try_ready!(self.0.poll()) {
+            Update::Add(addr, ()) => Ok(Async::Ready(Update::Add(addr, addr))),
+            Update::Remove(addr) => Ok(Async::Ready(Update::Remove(addr))),
+        }
+    }
+}
+
+// === impl SingleName ===
+
+impl NameResolution for SingleName {
+    type Endpoint = ();
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<NameUpdate<Self::Endpoint>, Self::Error> {
+        match self.0.take() {
+            Some(name) => Ok(Async::Ready(NameUpdate::Add(name, ()))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+// === impl AddrFamilies ===
+
+impl AddrFamilies {
+    fn allows(&self, addr: &SocketAddr) -> bool {
+        match *self {
+            AddrFamilies::Both => true,
+            AddrFamilies::V4Only => addr.is_ipv4(),
+            AddrFamilies::V6Only => addr.is_ipv6(),
+        }
+    }
+}
+
+impl Default for AddrFamilies {
+    fn default() -> Self {
+        AddrFamilies::Both
+    }
+}
+
+// === impl Layer ===
+
+pub fn layer<T, R>(resolve: R) -> Layer<R>
+where
+    R: Resolve<T> + Clone,
+    R::Endpoint: fmt::Debug,
+{
+    Layer {
+        resolve,
+        recorder: (),
+        subset: None,
+        max_endpoints: None,
+        families: AddrFamilies::default(),
+        move_window: None,
+        make_failures: MakeFailures::default(),
+    }
+}
+
+impl<R> Layer<R> {
+    /// Configures how the changes that balancers' discovery streams apply are
+    /// recorded.
+    ///
+    /// By default, changes are not recorded.
+    pub fn with_recorder<G: Record>(self, recorder: G) -> Layer<R, G> {
+        Layer {
+            resolve: self.resolve,
+            recorder,
+            subset: self.subset,
+            max_endpoints: self.max_endpoints,
+            families: self.families,
+            move_window: self.move_window,
+            make_failures: self.make_failures,
+        }
+    }
+}
+
+impl<R, G> Layer<R, G> {
+    /// Limits each balancer to a stable subset of at most `size` of its
+    /// target's endpoints, selected for the client identified by `client`.
+    ///
+    /// Clients with distinct identifiers select distinct subsets, so that each
+    /// endpoint is balanced by a similar number of clients (see `Subset`).
+    ///
+    /// By default, balancers hold all of their targets' endpoints.
+    pub fn with_subset(self, client: &str, size: usize) -> Self {
+        Self {
+            subset: Some(SubsetParams::new(client, size)),
+            ..self
+        }
+    }
+
+    /// Limits the number of endpoints that each balancer holds to `max`.
+    ///
+    /// Endpoints resolved while the balancer is full are held back, and are
+    /// added (oldest first) as endpoints are removed.
+    pub fn with_max_endpoints(self, max: usize) -> Self {
+        Self {
+            max_endpoints: Some(max),
+            ..self
+        }
+    }
+
+    /// Limits the endpoints that balancers hold to addresses of the given IP
+    /// families.
+    ///
+    /// By default, endpoints of both families are added.
+    pub fn with_addr_families(self, families: AddrFamilies) -> Self {
+        Self { families, ..self }
+    }
+
+    /// Defers the removal of balanced endpoints by up to `window`, in case
+    /// they have moved to a new address.
+    ///
+    /// An endpoint that is added with the same `HasMoveKey::move_key` while a
+    /// removal is deferred is assumed to replace the removed endpoint, which
+    /// is only removed once its replacement has been inserted into the
+    /// balancer. Requests are then balanced over the new address, and those
+    /// already dispatched to the old address are not canceled. Endpoints
+    /// without a move key are removed as soon as they are unresolved.
+    ///
+    /// Endpoints whose removals are deferred count against the balancer's
+    /// maximum number of endpoints until they are removed.
+    ///
+    /// By default, endpoints are removed as soon as they are unresolved.
+    pub fn with_move_window(self, window: Duration) -> Self {
+        Self {
+            move_window: Some(window),
+            ..self
+        }
+    }
+
+    /// Configures how balancers handle endpoints whose services cannot be
+    /// built.
+    ///
+    /// By default, such an endpoint fails the balancer.
+    pub fn with_make_failures(self, make_failures: MakeFailures) -> Self {
+        Self {
+            make_failures,
+            ..self
+        }
+    }
+}
+
+impl<R, M, G> svc::Layer<M> for Layer<R, G>
+where
+    R: Clone,
+    G: Clone,
+{
+    type Service = MakeSvc<Subsetting<R>, M, G>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        MakeSvc {
+            resolve: Subsetting {
+                resolve: self.resolve.clone(),
+                params: self.subset,
+            },
+            inner,
+            recorder: self.recorder.clone(),
+            max_endpoints: self.max_endpoints,
+            families: self.families,
+            move_window: self.move_window,
+            make_failures: self.make_failures,
+        }
+    }
+}
+
+// === impl MakeSvc ===
+
+impl<T, R, M, G> svc::Service<T> for MakeSvc<R, M, G>
+where
+    R: Resolve<T>,
+    R::Endpoint: fmt::Debug,
+    M: svc::Service<R::Endpoint> + Clone,
+    G: Record,
+{
+    type Response = Discover<R::Resolution, M, G>;
+    type Error = <R::Future as Future>::Error;
+    type Future = DiscoverFuture<R::Future, Option<M>, G>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        Ok(().into()) // always ready to make a Discover
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let future = self.resolve.resolve(&target);
+        DiscoverFuture {
+            future,
+            make: Some(self.inner.clone()),
+            recorder: self.recorder.clone(),
+            max_endpoints: self.max_endpoints,
+            families: self.families,
+            move_window: self.move_window,
+            make_failures: self.make_failures,
+        }
+    }
+}
+
+// === impl DiscoverFuture ===
+
+impl<F, M, G> Future for DiscoverFuture<F, Option<M>, G>
+where
+    F: Future,
+    F::Item: Resolution,
+    M: svc::Service<<F::Item as Resolution>::Endpoint>,
+    G: Record,
+{
+    type Item = Discover<F::Item, M, G>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let resolution = try_ready!(self.future.poll());
+        let make = self.make.take().expect("polled after ready");
+        let mut discover = Discover::new(resolution, make).with_recorder(self.recorder.clone());
+        discover.max_endpoints = self.max_endpoints;
+        discover.families = self.families;
+        discover.move_window = self.move_window;
+        discover.make_failures = self.make_failures;
+        Ok(Async::Ready(discover))
+    }
+}
+
+// === impl Discover ===
+
+impl<R, M> Discover<R, M>
+where
+    R: Resolution,
+    M: svc::Service<R::Endpoint>,
+{
+    fn new(resolution: R, make: M) -> Self {
+        Self {
+            resolution,
+            make,
+            make_futures: MakeFutures::new(),
+            endpoints: HashSet::new(),
+            endpoints_store: None,
+            targets: HashMap::new(),
+            evicted: HashSet::new(),
+            evictions: None,
+            recorder: (),
+            max_endpoints: None,
+            overflow: HashMap::new(),
+            next_overflow_seq: 0,
+            families: AddrFamilies::default(),
+            move_window: None,
+            make_failures: MakeFailures::default(),
+            moving: Vec::new(),
+        }
+    }
+
+    fn with_recorder<G: Record>(self, recorder: G) -> Discover<R, M, G> {
+        Discover {
+            resolution: self.resolution,
+            make: self.make,
+            make_futures: self.make_futures,
+            endpoints: self.endpoints,
+            endpoints_store: self.endpoints_store,
+            targets: self.targets,
+            evicted: self.evicted,
+            evictions: self.evictions,
+            recorder,
+            max_endpoints: self.max_endpoints,
+            overflow: self.overflow,
+            next_overflow_seq: self.next_overflow_seq,
+            families: self.families,
+            move_window: self.move_window,
+            make_failures: self.make_failures,
+            moving: self.moving,
+        }
+    }
+}
+
+impl<R, M, G> Discover<R, M, G>
+where
+    R: Resolution,
+    M: svc::Service<R::Endpoint>,
+{
+    /// Returns a handle that evicts endpoints from the balancer and restores
+    /// them.
+    ///
+    /// Any previously-returned handle stops evicting endpoints.
+    pub fn evict_handle(&mut self) -> Evict {
+        let (tx, rx) = mpsc::unbounded();
+        self.evictions = Some(rx);
+        Evict(tx)
+    }
+
+    fn is_full(&self) -> bool {
+        match self.max_endpoints {
+            Some(max) => self.targets.len() + self.moving.len() >= max,
+            None => false,
+        }
+    }
+
+    /// Takes the oldest overflowed endpoint, if there is room for it.
+    fn take_overflow(&mut self) -> Option<(SocketAddr, R::Endpoint)> {
+        if self.is_full() {
+            return None;
+        }
+        let addr = self
+            .overflow
+            .iter()
+            .min_by_key(|&(_, &(seq, _))| seq)
+            .map(|(addr, _)| *addr)?;
+        self.overflow.remove(&addr).map(|(_, target)| (addr, target))
+    }
+
+    fn poll_eviction(&mut self) -> Option<Eviction> {
+        let polled = match self.evictions {
+            Some(ref mut rx) => rx.poll(),
+            None => return None,
+        };
+        match polled {
+            Ok(Async::Ready(Some(eviction))) => Some(eviction),
+            Ok(Async::NotReady) => None,
+            _ => {
+                trace!("evict handle dropped");
+                self.evictions = None;
+                None
+            }
+        }
+    }
+
+    /// Returns a `Watch` that is updated with the set of endpoints that have
+    /// been inserted into (and not yet removed from) the balancer.
+    ///
+    /// Any previously-returned watch stops receiving updates.
+    pub fn watch_endpoints(&mut self) -> Watch<HashSet<SocketAddr>> {
+        let (watch, store) = Watch::new(self.endpoints.clone());
+        self.endpoints_store = Some(store);
+        watch
+    }
+
+    fn publish_endpoints<S>(&mut self, change: &Change<SocketAddr, S>) {
+        let changed = match change {
+            Change::Insert(addr, _) => self.endpoints.insert(*addr),
+            Change::Remove(addr) => self.endpoints.remove(addr),
+        };
+        if !changed {
+            return;
+        }
+
+        let lost_watchers = match self.endpoints_store {
+            Some(ref mut store) => store.store(self.endpoints.clone()).is_err(),
+            None => false,
+        };
+        if lost_watchers {
+            trace!("endpoints watch dropped");
+            self.endpoints_store = None;
+        }
+    }
+
+    /// Returns a moving endpoint that should now be removed, i.e. because its
+    /// replacement has been inserted or its move window has elapsed.
+    fn poll_moved(&mut self) -> Result<Option<SocketAddr>, Error> {
+        for i in 0..self.moving.len() {
+            let moved = self.moving[i].replaced || self.moving[i].expiry.poll()?.is_ready();
+            if moved {
+                return Ok(Some(self.moving.remove(i).addr));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<R, M, G> Discover<R, M, G>
+where
+    R: Resolution,
+    R::Endpoint: Clone + fmt::Debug + HasMoveKey,
+    R::Error: Into<Error>,
+    M: svc::Service<R::Endpoint>,
+    M::Error: Into<Error>,
+{
+    fn poll_resolution(&mut self) -> Poll<Change<SocketAddr, M::Response>, Error> {
+        loop {
+            if let Some(addr) = self.poll_moved()? {
+                debug!("removing {}", addr);
+                return Ok(Async::Ready(Change::Remove(addr)));
+            }
+
+            // Before polling the resolution, where we could potentially receive
+            // an `Add`, poll_ready to ensure that `make` is ready to build new
+            // services. Don't process any updates until we can do so.
+            try_ready!(self.make.poll_ready().map_err(Into::into));
+
+            if let Some((addr, target)) = self.take_overflow() {
+                debug!("admitting {} from overflow", addr);
+                self.targets.insert(addr, target.clone());
+                let fut = self.make.call(target);
+                self.make_futures.push(addr, fut);
+                continue;
+            }
+
+            if let Some(eviction) = self.poll_eviction() {
+                match eviction {
+                    Eviction::Evict(addr) => {
+                        if self.targets.contains_key(&addr) && self.evicted.insert(addr) {
+                            debug!("evicting {}", addr);
+                            self.make_futures.remove(&addr);
+                            return Ok(Async::Ready(Change::Remove(addr)));
+                        }
+                    }
+                    Eviction::Restore(addr) => {
+                        if self.evicted.remove(&addr) {
+                            debug!("restoring {}", addr);
+                            let target = self.targets[&addr].clone();
+                            let fut = self.make.call(target);
+                            self.make_futures.push(addr, fut);
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let update = try_ready!(self.resolution.poll().map_err(Into::into));
+            trace!("watch: {:?}", update);
+            match update {
+                Update::Add(addr, _) if !self.families.allows(&addr) => {
+                    debug!("not adding {}; its address family is disabled", addr);
+                }
+                Update::Remove(addr) if !self.families.allows(&addr) => {}
+                Update::Add(addr, target) => {
+                    let balanced = self.targets.contains_key(&addr)
+                        || self.moving.iter().any(|m| m.addr == addr);
+                    if !balanced && self.is_full() {
+                        let seq = match self.overflow.get(&addr) {
+                            Some(&(seq, _)) => seq,
+                            None => {
+                                self.next_overflow_seq += 1;
+                                self.next_overflow_seq
+                            }
+                        };
+                        if self.overflow.is_empty() {
+                            warn!(
+                                "balancer is full; not adding {} until endpoints are removed",
+                                addr
+                            );
+                        } else {
+                            debug!("balancer is full; not adding {}", addr);
+                        }
+                        self.overflow.insert(addr, (seq, target));
+                        continue;
+                    }
+
+                    // An evicted endpoint is restored when it is resolved
+                    // again.
+                    self.evicted.remove(&addr);
+                    let is_new = self.targets.insert(addr, target.clone()).is_none();
+
+                    // A moving endpoint that is resolved again is replaced by
+                    // its new service. Otherwise, a new endpoint is assumed to
+                    // replace the first moving endpoint with the same key.
+                    let len = self.moving.len();
+                    self.moving.retain(|m| m.addr != addr);
+                    if is_new && self.moving.len() == len {
+                        if let Some(key) = target.move_key() {
+                            let moving = self
+                                .moving
+                                .iter_mut()
+                                .find(|m| m.replacement.is_none() && m.key == key);
+                            if let Some(moving) = moving {
+                                debug!("{} may have moved to {}", moving.addr, addr);
+                                moving.replacement = Some(addr);
+                            }
+                        }
+                    }
+
+                    // Start building the service and continue. If a pending
+                    // service exists for this addr, it will be canceled.
+                    let fut = self.make.call(target);
+                    self.make_futures.push(addr, fut);
+                }
+                Update::Remove(addr) => {
+                    if self.overflow.remove(&addr).is_some() {
+                        trace!("removed {} from overflow", addr);
+                        continue;
+                    }
+
+                    self.evicted.remove(&addr);
+                    let target = self.targets.remove(&addr);
+                    self.make_futures.remove(&addr);
+
+                    let key = target.as_ref().and_then(HasMoveKey::move_key);
+                    if let (Some(window), Some(key)) = (self.move_window, key) {
+                        let moving = self.moving.iter().any(|m| m.addr == addr);
+                        if self.endpoints.contains(&addr) && !moving {
+                            debug!("deferring the removal of {} for up to {:?}", addr, window);
+                            self.moving.push(Moving {
+                                addr,
+                                key: key.to_owned(),
+                                replacement: None,
+                                replaced: false,
+                                expiry: Delay::new(clock::now() + window),
+                            });
+                            continue;
+                        }
+                    }
+
+                    return Ok(Async::Ready(Change::Remove(addr)));
+                }
+            }
+        }
+    }
+}
+
+impl<R, M, G> tower_discover::Discover for Discover<R, M, G>
+where
+    R: Resolution,
+    R::Endpoint: Clone + fmt::Debug + HasMoveKey,
+    R::Error: Into<Error>,
+    M: svc::Service<R::Endpoint>,
+    M::Error: Into<Error>,
+    G: Record,
+{
+    type Key = SocketAddr;
+    type Service = M::Response;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let change = loop {
+            if let Async::Ready(change) = self.poll_resolution()? {
+                break change;
+            }
+
+            match self.make_futures.poll() {
+                Ok(Async::Ready(Some((addr, svc, added_at)))) => {
+                    self.recorder.record_insert(clock::now() - added_at);
+                    for moving in self.moving.iter_mut() {
+                        if moving.replacement == Some(addr) {
+                            moving.replaced = true;
+                        }
+                    }
+                    break Change::Insert(addr, svc);
+                }
+                Ok(_) => return Ok(Async::NotReady),
+                Err((addr, e)) => {
+                    let e: Error = e.into();
+                    if self.make_failures == MakeFailures::Fail {
+                        return Err(e);
+                    }
+
+                    // The endpoint is forgotten, so it is built again if it
+                    // is resolved again.
+                    warn!("not adding {}; its service could not be built: {}", addr, e);
+                    self.recorder.record_make_failure();
+                    self.targets.remove(&addr);
+
+                    // A service that was built for the endpoint's previous
+                    // target must not outlive the target.
+                    if self.endpoints.contains(&addr) {
+                        debug!("removing {}", addr);
+                        break Change::Remove(addr);
+                    }
+                }
+            }
+        };
+
+        if let Change::Remove(_) = change {
+            self.recorder.record_remove();
+        }
+        self.publish_endpoints(&change);
+        Ok(Async::Ready(change))
+    }
+}
+
+impl<R, M, G> balance::HasTargets for Discover<R, M, G>
+where
+    R: Resolution,
+    R::Endpoint: Clone + PartialEq,
+    M: svc::Service<R::Endpoint>,
+{
+    type Target = R::Endpoint;
+
+    fn target(&self, addr: &SocketAddr) -> Option<&R::Endpoint> {
+        self.targets.get(addr)
+    }
+}
+
+// === impl Evict ===
+
+impl Evict {
+    /// Removes the endpoint at `addr` from the balancer.
+    ///
+    /// The endpoint is rebuilt when it is restored or when it is resolved
+    /// again. Requests already dispatched to the endpoint are not canceled.
+    ///
+    /// Returns false if the balancer no longer exists.
+    pub fn evict(&self, addr: SocketAddr) -> bool {
+        self.0.unbounded_send(Eviction::Evict(addr)).is_ok()
+    }
+
+    /// Rebuilds the evicted endpoint at `addr`, if it is still resolved.
+    ///
+    /// Returns false if the balancer no longer exists.
+    pub fn restore(&self, addr: SocketAddr) -> bool {
+        self.0.unbounded_send(Eviction::Restore(addr)).is_ok()
+    }
+}
+
+// === impl Record ===
+
+impl Record for () {
+    fn record_insert(&self, _: Duration) {}
+
+    fn record_remove(&self) {}
+
+    fn record_make_failure(&self) {}
+}
+
+// === impl UpdateMetrics ===
+
+impl Record for UpdateMetrics {
+    fn record_insert(&self, lag: Duration) {
+        if let Ok(mut inner) = self.0.lock() {
+            inner.inserts.incr();
+            inner.insert_lag.add(lag);
+        }
+    }
+
+    fn record_remove(&self) {
+        if let Ok(mut inner) = self.0.lock() {
+            inner.removes.incr();
+        }
+    }
+
+    fn record_make_failure(&self) {
+        if let Ok(mut inner) = self.0.lock() {
+            inner.make_failures.incr();
+        }
+    }
+}
+
+impl FmtMetrics for UpdateMetrics {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = match self.0.lock() {
+            Ok(inner) => inner,
+            Err(_) => return Ok(()),
+        };
+
+        balancer_endpoint_inserts_total.fmt_help(f)?;
+        balancer_endpoint_inserts_total.fmt_metric(f, inner.inserts)?;
+
+        balancer_endpoint_removes_total.fmt_help(f)?;
+        balancer_endpoint_removes_total.fmt_metric(f, inner.removes)?;
+
+        balancer_endpoint_make_failures_total.fmt_help(f)?;
+        balancer_endpoint_make_failures_total.fmt_metric(f, inner.make_failures)?;
+
+        balancer_endpoint_insert_lag_ms.fmt_help(f)?;
+        inner
+            .insert_lag
+            .fmt_metric(f, balancer_endpoint_insert_lag_ms.name)
+    }
+}
+
+// === impl MakeFutures ===
+
+impl<F: Future> MakeFutures<F> {
+    fn new() -> Self {
+        Self {
+            futures: FuturesUnordered::new(),
+            cancelations: IndexMap::new(),
+        }
+    }
+
+    fn push(&mut self, addr: SocketAddr, inner: F) {
+        let (cancel, canceled) = oneshot::channel();
+        if let Some(prior) = self.cancelations.insert(addr, cancel) {
+            let _ = prior.send(());
+        }
+        self.futures.push(MakeFuture {
+            addr,
+            inner,
+            canceled,
+            added_at: clock::now(),
+        });
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) {
+        if let Some(cancel) = self.cancelations.remove(addr) {
+            let _ = cancel.send(());
+        }
+    }
+}
+
+impl<F: Future> Stream for MakeFutures<F> {
+    type Item = (SocketAddr, F::Item, Instant);
+    /// The address of the endpoint whose service could not be built.
+    type Error = (SocketAddr, F::Error);
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            return match self.futures.poll() {
+                Err(MakeError::Canceled) => continue,
+                Err(MakeError::Inner(addr, err)) => {
+                    self.cancelations.remove(&addr);
+                    Err((addr, err))
+                }
+                Ok(Async::Ready(Some((addr, svc, added_at)))) => {
+                    let _rm = self.cancelations.remove(&addr);
+                    debug_assert!(_rm.is_some(), "cancelation missing for {}", addr);
+                    Ok(Async::Ready(Some((addr, svc, added_at))))
+                }
+                Ok(r) => Ok(r),
+            };
+        }
+    }
+}
+
+// === impl MakeFuture ===
+
+impl<F: Future> Future for MakeFuture<F> {
+    type Item = (SocketAddr, F::Item, Instant);
+    type Error = MakeError<F::Error>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Ok(Async::Ready(())) = self.canceled.poll() {
+            trace!("canceled making service for {:?}", self.addr);
+            return Err(MakeError::Canceled);
+        }
+        let addr = self.addr;
+        let svc = try_ready!(self.inner.poll().map_err(|e| MakeError::Inner(addr, e)));
+        Ok((self.addr, svc, self.added_at).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::tower_discover::{Change, Discover as _Discover};
+    use super::*;
+    use futures::future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use svc::Service;
+    use tokio::sync::mpsc;
+    use tower_util::service_fn;
+
+    impl<E> Resolution for mpsc::Receiver<Update<E>> {
+        type Endpoint = E;
+        type Error = mpsc::error::RecvError;
+
+        fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error> {
+            let ep = try_ready!(Stream::poll(self)).expect("stream must not terminate");
+            Ok(Async::Ready(ep))
+        }
+    }
+
+    /// Resolves a single target to a channel, counting each resolution.
+    #[derive(Clone)]
+    struct MockResolve {
+        resolutions: Arc<AtomicUsize>,
+        rx: Arc<Mutex<Option<mpsc::Receiver<Update<()>>>>>,
+    }
+
+    impl Resolve<()> for MockResolve {
+        type Endpoint = ();
+        type Resolution = mpsc::Receiver<Update<()>>;
+        type Future = future::FutureResult<Self::Resolution, Error>;
+
+        fn resolve(&self, _: &()) -> Self::Future {
+            self.resolutions.fetch_add(1, Ordering::SeqCst);
+            let rx = self.rx.lock().unwrap().take().expect("resolved twice");
+            future::ok(rx)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Svc<T>(Vec<oneshot::Receiver<T>>);
+    impl<T> Service<()> for Svc<T> {
+        type Response = T;
+        type Error = oneshot::error::RecvError;
+        type Future = oneshot::Receiver<T>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            self.0.pop().expect("exhausted")
+        }
+    }
+
+    impl HasMoveKey for () {
+        fn move_key(&self) -> Option<&str> {
+            None
+        }
+    }
+
+    impl HasMoveKey for bool {
+        fn move_key(&self) -> Option<&str> {
+            None
+        }
+    }
+
+    impl<'a> HasMoveKey for &'a str {
+        fn move_key(&self) -> Option<&str> {
+            None
+        }
+    }
+
+    /// A target that, like `outbound::Endpoint`, includes its address and is
+    /// labeled with the pod that serves it.
+    #[derive(Clone, Debug, PartialEq)]
+    struct PodEndpoint {
+        addr: SocketAddr,
+        pod: &'static str,
+    }
+
+    impl HasMoveKey for PodEndpoint {
+        fn move_key(&self) -> Option<&str> {
+            Some(self.pod)
+        }
+    }
+
+    fn pod(addr: SocketAddr, pod: &'static str) -> Update<PodEndpoint> {
+        Update::Add(addr, PodEndpoint { addr, pod })
+    }
+
+    #[test]
+    fn inserts_delivered_out_of_order() {
+        with_task(move || {
+            let (mut reso_tx, resolution) = mpsc::channel(2);
+            let (make0_tx, make0_rx) = oneshot::channel::<Svc<usize>>();
+            let (make1_tx, make1_rx) = oneshot::channel::<Svc<usize>>();
+            let make = Svc(vec![make1_rx, make0_rx]);
+
+            let mut discover = Discover::new(resolution, make);
+            assert!(
+                discover.poll().expect("discover can't fail").is_not_ready(),
+                "ready without updates"
+            );
+
+            let addr0 = SocketAddr::from(([127, 0, 0, 1], 80));
+            reso_tx.try_send(Update::Add(addr0, ())).unwrap();
+            assert!(
+                discover.poll().expect("discover can't fail").is_not_ready(),
+                "ready without service being made"
+            );
+            assert_eq!(
+                discover.make_futures.futures.len(),
+                1,
+                "must be only one pending make"
+            );
+            assert_eq!(
+                discover.make_futures.cancelations.len(),
+                1,
+                "no pending cancelation"
+            );
+
+            let addr1 = SocketAddr::from(([127, 0, 0, 2], 80));
+            reso_tx
+                .try_send(Update::Add(addr1, ()))
+                .expect("update must be sent");
+            assert!(
+                discover.poll().expect("discover can't fail").is_not_ready(),
+                "ready without service being made"
+            );
+            assert_eq!(
+                discover.make_futures.futures.len(),
+                2,
+                "must be only one pending make"
+            );
+            assert_eq!(
+                discover.make_futures.cancelations.len(),
+                2,
+                "no pending cancelation"
+            );
+
+            let (rsp1_tx, rsp1_rx) = oneshot::channel();
+            make1_tx
+                .send(Svc(vec![rsp1_rx]))
+                .expect("make must receive service");
+            match discover.poll().expect("discover can't fail") {
+                Async::NotReady => panic!("not processed"),
+                Async::Ready(Change::Remove(..)) => panic!("unexpected remove"),
+                Async::Ready(Change::Insert(a, mut svc)) => {
+                    assert_eq!(a, addr1);
+
+                    assert!(svc.poll_ready().unwrap().is_ready());
+                    let mut fut = svc.call(());
+                    assert!(fut.poll().unwrap().is_not_ready());
+                    rsp1_tx.send(1).unwrap();
+                    assert_eq!(fut.poll().unwrap(), Async::Ready(1));
+                }
+            }
+            assert_eq!(
+                discover.make_futures.futures.len(),
+                1,
+                "must be only one pending make"
+            );
+            assert_eq!(
+                discover.make_futures.cancelations.len(),
+                1,
+                "no pending cancelation"
+            );
+
+            let (rsp0_tx, rsp0_rx) = oneshot::channel();
+            make0_tx
+                .send(Svc(vec![rsp0_rx]))
+                .expect("make must receive service");
+            match discover.poll().expect("discover can't fail") {
+                Async::NotReady => panic!("not processed"),
+                Async::Ready(Change::Remove(..)) => panic!("unexpected remove"),
+                Async::Ready(Change::Insert(a, mut svc)) => {
+                    assert_eq!(a, addr0);
+
+                    assert!(svc.poll_ready().unwrap().is_ready());
+                    let mut fut = svc.call(());
+                    assert!(fut.poll().unwrap().is_not_ready());
+                    rsp0_tx.send(0).unwrap();
+                    assert_eq!(fut.poll().unwrap(), Async::Ready(0));
+                }
+            }
+            assert!(discover.make_futures.futures.is_empty(), "futures remain");
+            assert!(
+                discover.make_futures.cancelations.is_empty(),
+                "cancelation remains"
+            );
+        });
+    }
+
+    #[test]
+    fn overwriting_insert_cancels_original() {
+        with_task(move || {
+            let (mut reso_tx, resolution) = mpsc::channel(2);
+            let (make0_tx, make0_rx) = oneshot::channel::<Svc<usize>>();
+            let (make1_tx, make1_rx) = oneshot::channel::<Svc<usize>>();
+            let make = Svc(vec![make1_rx, make0_rx]);
+
+            let mut discover = Discover::new(resolution, make);
+            assert!(
+                discover.poll().expect("discover can't fail").is_not_ready(),
+                "ready without updates"
+            );
+
+            let addr = SocketAddr::from(([127, 0, 0, 1], 80));
+            reso_tx.try_send(Update::Add(addr, ())).unwrap();
+            assert!(
+                discover.poll().expect("discover can't fail").is_not_ready(),
+                "ready without service being made"
+            );
+            assert_eq!(
+                discover.make_futures.futures.len(),
+                1,
+                "must be only one pending make"
+            );
+            assert_eq!(
+                discover.make_futures.cancelations.len(),
+                1,
+                "no pending cancelation"
+            );
+
+            reso_tx
+                .try_send(Update::Add(addr, ()))
+                .expect("update must be sent");
+            assert!(
+                discover.poll().expect("discover can't fail").is_not_ready(),
+                "ready without service being made"
+            );
+            assert_eq!(
+                discover.make_futures.futures.len(),
                 1,
                 "must be only one pending make"
             );
@@ -462,87 +2318,925 @@ mod tests {
                 "no pending cancelation"
             );
 
-            reso_tx
-                .try_send(Update::Add(addr, ()))
-                .expect("update must be sent");
+            make0_tx
+                .send(Svc(vec![]))
+                .expect_err("receiver must have been dropped");
+
+            let (rsp1_tx, rsp1_rx) = oneshot::channel();
+            make1_tx
+                .send(Svc(vec![rsp1_rx]))
+                .expect("make must receive service");
+            match discover.poll().expect("discover can't fail") {
+                Async::NotReady => panic!("not processed"),
+                Async::Ready(Change::Remove(..)) => panic!("unexpected remove"),
+                Async::Ready(Change::Insert(a, mut svc)) => {
+                    assert_eq!(a, addr);
+
+                    assert!(svc.poll_ready().unwrap().is_ready());
+                    let mut fut = svc.call(());
+                    assert!(fut.poll().unwrap().is_not_ready());
+                    rsp1_tx.send(1).unwrap();
+                    assert_eq!(fut.poll().unwrap(), Async::Ready(1));
+                }
+            }
+            assert!(
+                discover.make_futures.cancelations.is_empty(),
+                "cancelation remains"
+            );
+        });
+    }
+
+    #[test]
+    fn cancelation_of_pending_service() {
+        with_task(move || {
+            let (mut tx, resolution) = mpsc::channel(1);
+            let make = service_fn(|()| future::empty::<Svc<()>, Error>());
+
+            let mut discover = Discover::new(resolution, make);
+            assert!(
+                discover.poll().expect("discover can't fail").is_not_ready(),
+                "ready without updates"
+            );
+
+            let addr = SocketAddr::from(([127, 0, 0, 1], 80));
+            tx.try_send(Update::Add(addr, ())).unwrap();
+            assert!(
+                discover.poll().expect("discover can't fail").is_not_ready(),
+                "ready without service being made"
+            );
+            assert_eq!(
+                discover.make_futures.cancelations.len(),
+                1,
+                "no pending cancelation"
+            );
+
+            tx.try_send(Update::Remove(addr)).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::NotReady => panic!("remove not processed"),
+                Async::Ready(Change::Insert(..)) => panic!("unexpected insert"),
+                Async::Ready(Change::Remove(a)) => assert_eq!(a, addr),
+            }
+            assert!(
+                discover.make_futures.cancelations.is_empty(),
+                "cancelation remains"
+            );
+        });
+    }
+
+    #[test]
+    fn watched_endpoints_track_changes() {
+        with_task(move || {
+            let (mut tx, resolution) = mpsc::channel(1);
+            let make = service_fn(|()| future::ok::<_, Error>(Svc::<()>(vec![])));
+
+            let mut discover = Discover::new(resolution, make);
+            let watch = discover.watch_endpoints();
+            assert!(watch.borrow().is_empty(), "no endpoints yet");
+
+            let addr0 = SocketAddr::from(([127, 0, 0, 1], 80));
+            tx.try_send(Update::Add(addr0, ())).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr0),
+                _ => panic!("insert not processed"),
+            }
+            assert!(watch.borrow().contains(&addr0), "insert not watched");
+
+            let addr1 = SocketAddr::from(([127, 0, 0, 2], 80));
+            tx.try_send(Update::Add(addr1, ())).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr1),
+                _ => panic!("insert not processed"),
+            }
+            assert_eq!(watch.borrow().len(), 2);
+
+            tx.try_send(Update::Remove(addr0)).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Remove(a)) => assert_eq!(a, addr0),
+                _ => panic!("remove not processed"),
+            }
+            assert!(!watch.borrow().contains(&addr0), "remove not watched");
+            assert!(watch.borrow().contains(&addr1));
+        });
+    }
+
+    #[test]
+    fn lazy_resolves_on_first_poll() {
+        with_task(move || {
+            let (mut tx, rx) = mpsc::channel(2);
+            let resolutions = Arc::new(AtomicUsize::new(0));
+            let resolve = Lazy::new(MockResolve {
+                resolutions: resolutions.clone(),
+                rx: Arc::new(Mutex::new(Some(rx))),
+            });
+
+            let addr0 = SocketAddr::from(([127, 0, 0, 1], 80));
+            let addr1 = SocketAddr::from(([127, 0, 0, 2], 80));
+            tx.try_send(Update::Add(addr0, ())).unwrap();
+            tx.try_send(Update::Add(addr1, ())).unwrap();
+
+            let make = service_fn(|()| future::ok::<_, Error>(Svc::<()>(vec![])));
+            let mut discover = DiscoverFuture {
+                future: resolve.resolve(&()),
+                make: Some(make),
+                recorder: (),
+                max_endpoints: None,
+                families: AddrFamilies::Both,
+                move_window: None,
+                make_failures: MakeFailures::Fail,
+            }
+            .wait()
+            .expect("lazy resolve can't fail");
+            assert_eq!(
+                resolutions.load(Ordering::SeqCst),
+                0,
+                "must not resolve before the discover is polled"
+            );
+
+            // The initial membership is delivered once the discover is polled.
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr0),
+                _ => panic!("initial insert not processed"),
+            }
+            assert_eq!(resolutions.load(Ordering::SeqCst), 1);
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr1),
+                _ => panic!("initial insert not processed"),
+            }
+
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+            assert_eq!(resolutions.load(Ordering::SeqCst), 1, "must resolve once");
+        });
+    }
+
+    #[test]
+    fn shared_resolutions_are_resolved_once() {
+        with_task(move || {
+            let (mut tx, rx) = mpsc::channel(2);
+            let resolutions = Arc::new(AtomicUsize::new(0));
+            let resolve = Shared::new(MockResolve {
+                resolutions: resolutions.clone(),
+                rx: Arc::new(Mutex::new(Some(rx))),
+            });
+            let discover = || {
+                let make = service_fn(|()| future::ok::<_, Error>(Svc::<()>(vec![])));
+                DiscoverFuture {
+                    future: resolve.resolve(&()),
+                    make: Some(make),
+                    recorder: (),
+                    max_endpoints: None,
+                    families: AddrFamilies::Both,
+                    move_window: None,
+                    make_failures: MakeFailures::Fail,
+                }
+                .wait()
+                .expect("shared resolve can't fail")
+            };
+
+            let addr0 = SocketAddr::from(([127, 0, 0, 1], 80));
+            let addr1 = SocketAddr::from(([127, 0, 0, 2], 80));
+            tx.try_send(Update::Add(addr0, ())).unwrap();
+
+            let mut discover0 = discover();
+            match discover0.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr0),
+                _ => panic!("initial insert not processed"),
+            }
+
+            // The second balancer observes the endpoints that were resolved
+            // before it subscribed.
+            let mut discover1 = discover();
+            match discover1.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr0),
+                _ => panic!("initial insert not replayed"),
+            }
+            assert!(discover1.poll().expect("discover can't fail").is_not_ready());
+
+            tx.try_send(Update::Add(addr1, ())).unwrap();
+            for d in &mut [discover1, discover0] {
+                match d.poll().expect("discover can't fail") {
+                    Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr1),
+                    _ => panic!("insert not processed"),
+                }
+                assert!(d.poll().expect("discover can't fail").is_not_ready());
+            }
+
+            assert_eq!(resolutions.load(Ordering::SeqCst), 1, "must resolve once");
+        });
+    }
+
+    #[test]
+    fn evicted_endpoints_are_removed_until_restored() {
+        with_task(move || {
+            let (mut tx, resolution) = mpsc::channel(1);
+            let make = service_fn(|()| future::ok::<_, Error>(Svc::<()>(vec![])));
+
+            let mut discover = Discover::new(resolution, make);
+            let evict = discover.evict_handle();
+            let watch = discover.watch_endpoints();
+
+            let addr0 = SocketAddr::from(([127, 0, 0, 1], 80));
+            let addr1 = SocketAddr::from(([127, 0, 0, 2], 80));
+            for addr in &[addr0, addr1] {
+                tx.try_send(Update::Add(*addr, ())).unwrap();
+                match discover.poll().expect("discover can't fail") {
+                    Async::Ready(Change::Insert(a, _)) => assert_eq!(a, *addr),
+                    _ => panic!("insert not processed"),
+                }
+            }
+
+            // Unknown addresses are not evicted.
+            assert!(evict.evict(SocketAddr::from(([127, 0, 0, 3], 80))));
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+
+            assert!(evict.evict(addr0));
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Remove(a)) => assert_eq!(a, addr0),
+                _ => panic!("eviction not processed"),
+            }
+            assert!(!watch.borrow().contains(&addr0), "eviction not watched");
+            assert!(watch.borrow().contains(&addr1));
+
+            // Evicting an endpoint twice has no effect.
+            assert!(evict.evict(addr0));
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+
+            assert!(evict.restore(addr0));
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr0),
+                _ => panic!("restoration not processed"),
+            }
+            assert!(watch.borrow().contains(&addr0), "restoration not watched");
+
+            // Restoring an endpoint that isn't evicted has no effect.
+            assert!(evict.restore(addr1));
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+        });
+    }
+
+    #[test]
+    fn records_inserts_and_removes() {
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let (mut tx, resolution) = mpsc::channel(1);
+            let (make_tx, make_rx) = oneshot::channel::<Svc<()>>();
+            let make = Svc(vec![make_rx]);
+
+            let metrics = UpdateMetrics::default();
+            let mut discover = Discover::new(resolution, make).with_recorder(metrics.clone());
+
+            let addr = SocketAddr::from(([127, 0, 0, 1], 80));
+            tx.try_send(Update::Add(addr, ())).unwrap();
             assert!(
                 discover.poll().expect("discover can't fail").is_not_ready(),
                 "ready without service being made"
             );
-            assert_eq!(
-                discover.make_futures.futures.len(),
-                1,
-                "must be only one pending make"
-            );
-            assert_eq!(
-                discover.make_futures.cancelations.len(),
-                1,
-                "no pending cancelation"
-            );
 
-            make0_tx
+            time.advance(Duration::from_millis(10));
+            make_tx
                 .send(Svc(vec![]))
-                .expect_err("receiver must have been dropped");
-
-            let (rsp1_tx, rsp1_rx) = oneshot::channel();
-            make1_tx
-                .send(Svc(vec![rsp1_rx]))
                 .expect("make must receive service");
             match discover.poll().expect("discover can't fail") {
-                Async::NotReady => panic!("not processed"),
-                Async::Ready(Change::Remove(..)) => panic!("unexpected remove"),
-                Async::Ready(Change::Insert(a, mut svc)) => {
-                    assert_eq!(a, addr);
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr),
+                _ => panic!("insert not processed"),
+            }
 
-                    assert!(svc.poll_ready().unwrap().is_ready());
-                    let mut fut = svc.call(());
-                    assert!(fut.poll().unwrap().is_not_ready());
-                    rsp1_tx.send(1).unwrap();
-                    assert_eq!(fut.poll().unwrap(), Async::Ready(1));
+            tx.try_send(Update::Remove(addr)).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Remove(a)) => assert_eq!(a, addr),
+                _ => panic!("remove not processed"),
+            }
+
+            let inner = metrics.0.lock().unwrap();
+            assert_eq!(inner.inserts.value(), 1);
+            assert_eq!(inner.removes.value(), 1);
+            // The insert must include the time spent making the service.
+            inner.insert_lag.assert_lt_exactly(10, 0);
+        });
+    }
+
+    #[test]
+    fn endpoints_over_the_limit_are_held_until_removals() {
+        with_task(move || {
+            let (mut tx, resolution) = mpsc::channel(10);
+            let make = service_fn(|()| future::ok::<_, Error>(Svc::<()>(vec![])));
+
+            let mut discover = Discover::new(resolution, make);
+            discover.max_endpoints = Some(3);
+            let watch = discover.watch_endpoints();
+
+            let addrs = (1..=10)
+                .map(|i| SocketAddr::from(([127, 0, 0, i], 80)))
+                .collect::<Vec<_>>();
+            for addr in &addrs {
+                tx.try_send(Update::Add(*addr, ())).unwrap();
+            }
+            for addr in &addrs[..3] {
+                match discover.poll().expect("discover can't fail") {
+                    Async::Ready(Change::Insert(a, _)) => assert_eq!(a, *addr),
+                    _ => panic!("insert not processed"),
                 }
             }
             assert!(
-                discover.make_futures.cancelations.is_empty(),
-                "cancelation remains"
+                discover.poll().expect("discover can't fail").is_not_ready(),
+                "endpoints must not be inserted over the limit"
             );
+            assert_eq!(watch.borrow().len(), 3);
+
+            // Removing an overflowed endpoint does not change the balancer.
+            tx.try_send(Update::Remove(addrs[3])).unwrap();
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+
+            // Removing an inserted endpoint admits the oldest overflowed
+            // endpoint.
+            tx.try_send(Update::Remove(addrs[0])).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Remove(a)) => assert_eq!(a, addrs[0]),
+                _ => panic!("remove not processed"),
+            }
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addrs[4]),
+                _ => panic!("overflowed endpoint not inserted"),
+            }
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+            assert_eq!(watch.borrow().len(), 3);
         });
     }
 
     #[test]
-    fn cancelation_of_pending_service() {
+    fn endpoints_of_disabled_families_are_not_added() {
+        with_task(move || {
+            let (mut tx, resolution) = mpsc::channel(10);
+            let make = service_fn(|()| future::ok::<_, Error>(Svc::<()>(vec![])));
+
+            let mut discover = Discover::new(resolution, make);
+            discover.families = AddrFamilies::V4Only;
+            let watch = discover.watch_endpoints();
+
+            let v4 = SocketAddr::from(([127, 0, 0, 1], 80));
+            let v6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 80));
+            tx.try_send(Update::Add(v6, ())).unwrap();
+            tx.try_send(Update::Add(v4, ())).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, v4),
+                _ => panic!("insert not processed"),
+            }
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+            assert!(!discover.targets.contains_key(&v6));
+            assert!(!watch.borrow().contains(&v6));
+
+            // Removing an endpoint of a disabled family does not change the
+            // balancer.
+            tx.try_send(Update::Remove(v6)).unwrap();
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+        });
+    }
+
+    #[test]
+    fn endpoints_that_cannot_be_made_are_skipped() {
         with_task(move || {
+            let make = || {
+                service_fn(|fails: bool| {
+                    if fails {
+                        future::err(Error::from("endpoint cannot be built"))
+                    } else {
+                        future::ok(Svc::<()>(vec![]))
+                    }
+                })
+            };
+            let addrs = (1..=3)
+                .map(|i| SocketAddr::from(([127, 0, 0, i], 80)))
+                .collect::<Vec<_>>();
+
+            // By default, the balancer fails.
             let (mut tx, resolution) = mpsc::channel(1);
-            let make = service_fn(|()| future::empty::<Svc<()>, Error>());
+            let mut discover = Discover::new(resolution, make());
+            tx.try_send(Update::Add(addrs[1], true)).unwrap();
+            assert!(discover.poll().is_err(), "discover must fail");
+
+            let (mut tx, resolution) = mpsc::channel(10);
+            let metrics = UpdateMetrics::default();
+            let mut discover = Discover::new(resolution, make()).with_recorder(metrics.clone());
+            discover.make_failures = MakeFailures::Skip;
+            let watch = discover.watch_endpoints();
+
+            for (i, addr) in addrs.iter().enumerate() {
+                tx.try_send(Update::Add(*addr, i == 1)).unwrap();
+            }
+            let mut inserted = Vec::new();
+            while let Async::Ready(change) = discover.poll().expect("discover can't fail") {
+                match change {
+                    Change::Insert(a, _) => inserted.push(a),
+                    Change::Remove(a) => panic!("unexpected remove of {}", a),
+                }
+            }
+            inserted.sort();
+            assert_eq!(inserted, vec![addrs[0], addrs[2]]);
+            assert!(!watch.borrow().contains(&addrs[1]));
+            assert!(!discover.targets.contains_key(&addrs[1]));
+            assert_eq!(metrics.0.lock().unwrap().make_failures.value(), 1);
+
+            // The skipped endpoint is built again if it is resolved again.
+            tx.try_send(Update::Add(addrs[1], false)).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addrs[1]),
+                _ => panic!("insert not processed"),
+            }
+
+            // A balanced endpoint whose service cannot be rebuilt is removed
+            // along with its target.
+            tx.try_send(Update::Add(addrs[1], true)).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Remove(a)) => assert_eq!(a, addrs[1]),
+                _ => panic!("remove not processed"),
+            }
+            assert!(!watch.borrow().contains(&addrs[1]));
+            assert!(!discover.targets.contains_key(&addrs[1]));
+            assert_eq!(metrics.0.lock().unwrap().make_failures.value(), 2);
+        });
+    }
+
+    #[test]
+    fn filtered_endpoints_never_reach_the_balancer() {
+        with_task(move || {
+            let (mut tx, resolution) = mpsc::channel(10);
+            let resolution = Filter::new(resolution, |zone: &&str| *zone != "bad");
+            let make = service_fn(|_: &str| future::ok::<_, Error>(Svc::<()>(vec![])));
 
             let mut discover = Discover::new(resolution, make);
+            let watch = discover.watch_endpoints();
+
+            let good = SocketAddr::from(([127, 0, 0, 1], 80));
+            let bad = SocketAddr::from(([127, 0, 0, 2], 80));
+            tx.try_send(Update::Add(bad, "bad")).unwrap();
+            tx.try_send(Update::Add(good, "good")).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, good),
+                _ => panic!("insert not processed"),
+            }
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+            assert!(!discover.targets.contains_key(&bad));
+            assert!(!watch.borrow().contains(&bad));
+
+            tx.try_send(Update::Remove(bad)).unwrap();
             assert!(
                 discover.poll().expect("discover can't fail").is_not_ready(),
-                "ready without updates"
+                "removals of filtered endpoints must be suppressed"
             );
 
+            // An endpoint that stops matching is removed.
+            tx.try_send(Update::Add(good, "bad")).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Remove(a)) => assert_eq!(a, good),
+                _ => panic!("remove not processed"),
+            }
+            assert!(watch.borrow().is_empty());
+        });
+    }
+
+    /// Applies each of `subset`'s ready updates to `admitted`, returning the
+    /// number of updates.
+    fn apply_subset<R>(subset: &mut R, admitted: &mut HashSet<SocketAddr>) -> usize
+    where
+        R: Resolution,
+        R::Error: fmt::Debug,
+    {
+        let mut updates = 0;
+        while let Async::Ready(update) = subset.poll().expect("subset can't fail") {
+            updates += 1;
+            match update {
+                Update::Add(addr, _) => admitted.insert(addr),
+                Update::Remove(addr) => admitted.remove(&addr),
+            };
+        }
+        updates
+    }
+
+    #[test]
+    fn subsets_are_limited_and_stable() {
+        with_task(move || {
+            let (mut tx, resolution) = mpsc::channel(20);
+            let mut subset = Subset::new(resolution, "web-5d8f7c9b4-x2x7k", 3);
+            let mut admitted = HashSet::new();
+
+            let addrs = (1..=10)
+                .map(|i| SocketAddr::from(([10, 0, 0, i], 80)))
+                .collect::<Vec<_>>();
+            for addr in &addrs {
+                tx.try_send(Update::Add(*addr, ())).unwrap();
+            }
+            apply_subset(&mut subset, &mut admitted);
+            assert_eq!(admitted.len(), 3);
+
+            // An added endpoint displaces at most one admitted endpoint.
+            let before = admitted.clone();
+            let added = SocketAddr::from(([10, 0, 0, 11], 80));
+            tx.try_send(Update::Add(added, ())).unwrap();
+            assert!(apply_subset(&mut subset, &mut admitted) <= 2);
+            assert_eq!(admitted.len(), 3);
+            assert!(before.intersection(&admitted).count() >= 2);
+
+            // Removing an endpoint outside of the subset does not change it.
+            let before = admitted.clone();
+            let outside = *addrs
+                .iter()
+                .find(|a| !admitted.contains(*a))
+                .expect("some endpoints must not be admitted");
+            tx.try_send(Update::Remove(outside)).unwrap();
+            assert_eq!(apply_subset(&mut subset, &mut admitted), 0);
+            assert_eq!(admitted, before);
+
+            // A removed member of the subset is replaced by one endpoint.
+            let member = *admitted.iter().next().unwrap();
+            tx.try_send(Update::Remove(member)).unwrap();
+            assert_eq!(apply_subset(&mut subset, &mut admitted), 2);
+            assert_eq!(admitted.len(), 3);
+            assert!(!admitted.contains(&member));
+            assert_eq!(before.intersection(&admitted).count(), 2);
+
+            // The same client selects the same subset of the same endpoints,
+            // regardless of the order in which they were resolved.
+            let (mut tx, resolution) = mpsc::channel(20);
+            let mut again = Subset::new(resolution, "web-5d8f7c9b4-x2x7k", 3);
+            let mut readmitted = HashSet::new();
+            tx.try_send(Update::Add(added, ())).unwrap();
+            for addr in addrs.iter().rev().filter(|a| **a != outside && **a != member) {
+                tx.try_send(Update::Add(*addr, ())).unwrap();
+            }
+            apply_subset(&mut again, &mut readmitted);
+            assert_eq!(readmitted, admitted);
+        });
+    }
+
+    #[test]
+    fn subsets_admit_the_highest_ranked_endpoints() {
+        with_task(move || {
+            let (mut tx, resolution) = mpsc::channel(2);
+            let mut subset = Subset::new(resolution, "web-5d8f7c9b4-x2x7k", 3);
+            let params = subset.params.expect("subset must be limited");
+            let mut admitted = HashSet::new();
+
+            let addr = |i| SocketAddr::from(([10, 0, 0, i], 80));
+            let mut resolved = Vec::new();
+            for i in 1..=20 {
+                tx.try_send(Update::Add(addr(i), ())).unwrap();
+                resolved.push(addr(i));
+                if i % 3 == 0 {
+                    let middle = resolved.len() / 2;
+                    let removed = resolved.remove(middle);
+                    tx.try_send(Update::Remove(removed)).unwrap();
+                }
+                apply_subset(&mut subset, &mut admitted);
+
+                resolved.sort_by_key(|a| Reverse(params.rank(a)));
+                let highest = resolved.iter().take(3).cloned().collect::<HashSet<_>>();
+                assert_eq!(admitted, highest);
+            }
+        });
+    }
+
+    #[test]
+    fn subset_ranks_are_fixed() {
+        let params = SubsetParams::new("client", 3);
+        assert_eq!(params.client, 0x9793_e1cf_88ef_4af7);
+        let rank = |ip: [u8; 4]| params.rank(&SocketAddr::from((ip, 8080)));
+        assert_eq!(rank([10, 0, 0, 1]), 0xaef3_83cb_6c7b_5229);
+        assert_eq!(rank([10, 0, 0, 2]), 0x6191_d063_9601_93e5);
+    }
+
+    #[test]
+    fn moved_endpoints_are_removed_once_replaced() {
+        use tokio::runtime::current_thread::Runtime;
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(|| {
+            let (mut tx, resolution) = mpsc::channel(2);
+            let (old_tx, old_rx) = oneshot::channel::<Svc<usize>>();
+            let (new_tx, new_rx) = oneshot::channel::<Svc<usize>>();
+            let makes = Mutex::new(vec![new_rx, old_rx]);
+            let make =
+                service_fn(move |_: PodEndpoint| makes.lock().unwrap().pop().expect("exhausted"));
+
+            let mut discover = Discover::new(resolution, make);
+            discover.move_window = Some(Duration::from_secs(60));
+            let watch = discover.watch_endpoints();
+
+            let old = SocketAddr::from(([127, 0, 0, 1], 80));
+            let (rsp_tx, rsp_rx) = oneshot::channel();
+            old_tx
+                .send(Svc(vec![rsp_rx]))
+                .expect("make must receive service");
+            tx.try_send(pod(old, "web-0")).unwrap();
+            let mut in_flight = match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, mut svc)) => {
+                    assert_eq!(a, old);
+                    svc.call(())
+                }
+                _ => panic!("insert not processed"),
+            };
+
+            // The endpoint moves. Its removal is deferred until its
+            // replacement has been inserted.
+            tx.try_send(Update::Remove(old)).unwrap();
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+            assert!(watch.borrow().contains(&old));
+
+            // The pod is resolved at its new address, so its target is not
+            // equal to the old one.
+            let new = SocketAddr::from(([127, 0, 0, 2], 80));
+            tx.try_send(pod(new, "web-0")).unwrap();
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+            assert!(watch.borrow().contains(&old));
+
+            new_tx.send(Svc(vec![])).expect("make must receive service");
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, new),
+                _ => panic!("insert not processed"),
+            }
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Remove(a)) => assert_eq!(a, old),
+                _ => panic!("remove not processed"),
+            }
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+            assert_eq!(*watch.borrow(), vec![new].into_iter().collect::<HashSet<_>>());
+
+            // The request dispatched to the old address still completes.
+            rsp_tx.send(1).unwrap();
+            assert_eq!(in_flight.poll().unwrap(), Async::Ready(1));
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn moving_endpoints_are_only_replaced_by_the_same_pod() {
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let (mut tx, resolution) = mpsc::channel(10);
+            let make = service_fn(|_: PodEndpoint| future::ok::<_, Error>(Svc::<()>(vec![])));
+            let mut discover = Discover::new(resolution, make);
+            discover.move_window = Some(Duration::from_secs(60));
+            discover.max_endpoints = Some(2);
+
+            let old = SocketAddr::from(([127, 0, 0, 1], 80));
+            let other = SocketAddr::from(([127, 0, 0, 2], 80));
+            let new = SocketAddr::from(([127, 0, 0, 3], 80));
+            tx.try_send(pod(old, "web-0")).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, old),
+                _ => panic!("insert not processed"),
+            }
+
+            // An endpoint for another pod does not replace the moving
+            // endpoint.
+            tx.try_send(Update::Remove(old)).unwrap();
+            tx.try_send(pod(other, "db-0")).unwrap();
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, other),
+                _ => panic!("insert not processed"),
+            }
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+
+            // The moving endpoint is still balanced, so the balancer is full.
+            tx.try_send(pod(new, "web-0")).unwrap();
+            assert!(discover.poll().expect("discover can't fail").is_not_ready());
+            assert!(!discover.targets.contains_key(&new));
+
+            time.advance(Duration::from_secs(60));
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Remove(a)) => assert_eq!(a, old),
+                _ => panic!("remove not processed"),
+            }
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, new),
+                _ => panic!("insert not processed"),
+            }
+        });
+    }
+
+    #[test]
+    fn endpoints_without_move_keys_are_removed_immediately() {
+        with_task(|| {
+            let (mut tx, resolution) = mpsc::channel(2);
+            let make = service_fn(|_: ()| future::ok::<_, Error>(Svc::<()>(vec![])));
+            let mut discover = Discover::new(resolution, make);
+            discover.move_window = Some(Duration::from_secs(60));
+
             let addr = SocketAddr::from(([127, 0, 0, 1], 80));
             tx.try_send(Update::Add(addr, ())).unwrap();
-            assert!(
-                discover.poll().expect("discover can't fail").is_not_ready(),
-                "ready without service being made"
-            );
-            assert_eq!(
-                discover.make_futures.cancelations.len(),
-                1,
-                "no pending cancelation"
-            );
+            match discover.poll().expect("discover can't fail") {
+                Async::Ready(Change::Insert(a, _)) => assert_eq!(a, addr),
+                _ => panic!("insert not processed"),
+            }
 
             tx.try_send(Update::Remove(addr)).unwrap();
             match discover.poll().expect("discover can't fail") {
-                Async::NotReady => panic!("remove not processed"),
-                Async::Ready(Change::Insert(..)) => panic!("unexpected insert"),
                 Async::Ready(Change::Remove(a)) => assert_eq!(a, addr),
+                _ => panic!("remove not processed"),
             }
-            assert!(
-                discover.make_futures.cancelations.is_empty(),
-                "cancelation remains"
-            );
-        });
+            assert!(discover.moving.is_empty());
+        })
+    }
+
+    impl<E> NameResolution for mpsc::Receiver<NameUpdate<E>> {
+        type Endpoint = E;
+        type Error = mpsc::error::RecvError;
+
+        fn poll(&mut self) -> Poll<NameUpdate<Self::Endpoint>, Self::Error> {
+            let ep = try_ready!(Stream::poll(self)).expect("stream must not terminate");
+            Ok(Async::Ready(ep))
+        }
+    }
+
+    /// Resolves every name to the same addresses.
+    struct MockLookup(Vec<::std::net::IpAddr>);
+
+    impl LookupIps for MockLookup {
+        type Future = future::FutureResult<dns::IpAddrs, dns::ResolveError>;
+
+        fn lookup_ips(&self, _: &dns::Name) -> Self::Future {
+            future::ok(dns::IpAddrs {
+                addrs: self.0.clone(),
+                valid_until: clock::now() + Duration::from_secs(60),
+            })
+        }
+    }
+
+    #[test]
+    fn dns_resolving_adds_each_resolved_address() {
+        use tokio::runtime::current_thread::Runtime;
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(future::lazy(|| {
+            let ips = vec![[10, 0, 0, 1].into(), [10, 0, 0, 2].into()];
+            let (mut tx, rx) = mpsc::channel(2);
+            let mut resolution = DnsResolving::new(rx, MockLookup(ips));
+            assert!(resolution.poll().expect("must not fail").is_not_ready());
+
+            let name = NameAddr::from_str("web.example.com:8080").unwrap();
+            tx.try_send(NameUpdate::Add(name.clone(), ())).unwrap();
+
+            let mut added = HashSet::new();
+            for _ in 0..2 {
+                match resolution.poll().expect("must not fail") {
+                    Async::Ready(Update::Add(addr, ())) => assert!(added.insert(addr)),
+                    _ => panic!("expected an add"),
+                }
+            }
+            assert!(resolution.poll().expect("must not fail").is_not_ready());
+            let expected = vec![
+                SocketAddr::from(([10, 0, 0, 1], 8080)),
+                SocketAddr::from(([10, 0, 0, 2], 8080)),
+            ];
+            assert_eq!(added, expected.into_iter().collect());
+
+            tx.try_send(NameUpdate::Remove(name)).unwrap();
+            for _ in 0..2 {
+                match resolution.poll().expect("must not fail") {
+                    Async::Ready(Update::Remove(addr)) => assert!(added.remove(&addr)),
+                    _ => panic!("expected a remove"),
+                }
+            }
+            assert!(added.is_empty());
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn dns_resolving_uses_one_name_per_address() {
+        use tokio::runtime::current_thread::Runtime;
+
+        let addr = SocketAddr::from(([10, 0, 0, 1], 8080));
+        let web = NameAddr::from_str("web.example.com:8080").unwrap();
+        let www = NameAddr::from_str("www.example.com:8080").unwrap();
+
+        // Adds `web` and then `www`, both of which resolve to `addr`, and
+        // then removes them in the same order, returning the endpoint (or
+        // removal) with which `addr` is updated after each change.
+        let run = |collisions: AddrCollisions| {
+            let changes = vec![
+                NameUpdate::Add(web.clone(), "web"),
+                NameUpdate::Add(www.clone(), "www"),
+                NameUpdate::Remove(web.clone()),
+                NameUpdate::Remove(www.clone()),
+            ];
+            let mut rt = Runtime::new().expect("runtime");
+            rt.block_on(future::lazy(move || {
+                let (mut tx, rx) = mpsc::channel(1);
+                let lookup = MockLookup(vec![[10, 0, 0, 1].into()]);
+                let mut resolution =
+                    DnsResolving::new(rx, lookup).with_addr_collisions(collisions);
+                let mut updates = vec![];
+                for change in changes {
+                    tx.try_send(change).unwrap();
+                    updates.push(match resolution.poll()? {
+                        Async::Ready(Update::Add(a, endpoint)) => Some((a, Some(endpoint))),
+                        Async::Ready(Update::Remove(a)) => Some((a, None)),
+                        Async::NotReady => None,
+                    });
+                }
+                Ok::<_, Error>(updates)
+            }))
+        };
+
+        let updates = run(AddrCollisions::PreferFirst).expect("must not fail");
+        assert_eq!(
+            updates,
+            vec![
+                Some((addr, Some("web"))),
+                None,
+                Some((addr, Some("www"))),
+                Some((addr, None)),
+            ]
+        );
+
+        let updates = run(AddrCollisions::PreferLast).expect("must not fail");
+        assert_eq!(
+            updates,
+            vec![
+                Some((addr, Some("web"))),
+                Some((addr, Some("www"))),
+                None,
+                Some((addr, None)),
+            ]
+        );
+
+        let err = run(AddrCollisions::Error).expect_err("collisions must fail");
+        assert!(err.is::<AddrCollision>(), "unexpected error: {}", err);
+    }
+
+    /// Resolves every name to each of a sequence of answers in turn. Each
+    /// answer but the last expires immediately.
+    #[derive(Clone)]
+    struct ChangingLookup(Arc<Mutex<VecDeque<Vec<::std::net::IpAddr>>>>);
+
+    impl LookupIps for ChangingLookup {
+        type Future = future::FutureResult<dns::IpAddrs, dns::ResolveError>;
+
+        fn lookup_ips(&self, _: &dns::Name) -> Self::Future {
+            let mut answers = self.0.lock().unwrap();
+            let (addrs, ttl) = if answers.len() > 1 {
+                (answers.pop_front().unwrap(), Duration::from_millis(0))
+            } else {
+                (answers[0].clone(), Duration::from_secs(60))
+            };
+            future::ok(dns::IpAddrs {
+                addrs,
+                valid_until: clock::now() + ttl,
+            })
+        }
+    }
+
+    #[test]
+    fn dns_resolve_balances_each_address_of_a_name() {
+        use svc::Layer as _Layer;
+        use tokio::runtime::current_thread::Runtime;
+
+        let addr = |last: u8| SocketAddr::from(([10, 0, 0, last], 8080));
+        let answers = vec![
+            vec![[10, 0, 0, 1].into(), [10, 0, 0, 2].into()],
+            vec![[10, 0, 0, 2].into(), [10, 0, 0, 3].into()],
+        ];
+        let lookup = ChangingLookup(Arc::new(Mutex::new(answers.into_iter().collect())));
+        let endpoint = |addr: SocketAddr| {
+            future::ok::<_, Never>(service_fn(move |()| future::ok::<_, Never>(addr)))
+        };
+        let mut make = layer(DnsResolve::new(lookup)).layer(service_fn(endpoint));
+
+        let mut rt = Runtime::new().expect("runtime");
+        let name = NameAddr::from_str("web.example.com:8080").unwrap();
+        let mut discover = rt.block_on(make.call(name)).expect("must resolve");
+
+        // Applies changes to a set of endpoints as a balancer would, until
+        // the balancer holds exactly the `expected` endpoints.
+        let mut balanced = HashMap::new();
+        let mut poll_until = |expected: Vec<SocketAddr>| {
+            rt.block_on(future::poll_fn(|| {
+                while !expected.iter().all(|a| balanced.contains_key(a))
+                    || balanced.len() != expected.len()
+                {
+                    match try_ready!(discover.poll()) {
+                        Change::Insert(addr, svc) => balanced.insert(addr, svc),
+                        Change::Remove(addr) => balanced.remove(&addr),
+                    };
+                }
+                Ok::<_, Error>(Async::Ready(()))
+            }))
+            .expect("discovery must not fail");
+            for (addr, svc) in balanced.iter_mut() {
+                let rsp = svc.call(()).wait().expect("endpoint must respond");
+                assert_eq!(rsp, *addr, "each endpoint must serve its own address");
+            }
+        };
+
+        poll_until(vec![addr(1), addr(2)]);
+        // The first answer expires immediately, so the name is re-resolved,
+        // and the address to which it no longer resolves is removed.
+        poll_until(vec![addr(2), addr(3)]);
     }
 
     fn with_task<F: FnOnce() -> U, U>(f: F) -> U {
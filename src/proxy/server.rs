@@ -5,19 +5,21 @@ use std::marker::PhantomData;
 use std::net::SocketAddr;
 use std::{error, fmt};
 
-use futures::{future, Poll};
+use futures::{future, sync::oneshot, Poll};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use super::Accept;
 use app::config::H2Settings;
 use drain;
+use identity;
 use never::Never;
 use proxy::http::{
+    forwarded::ProxyHeader,
     glue::{HttpBody, HyperServerSvc},
     upgrade,
 };
 use proxy::protocol::Protocol;
-use proxy::{tcp, Error};
+use proxy::{require_identity, tcp, Error};
 use svc::{MakeService, Service};
 use transport::{
     tls::{self, HasPeerIdentity},
@@ -45,14 +47,16 @@ use transport::{
 /// 5. If the stream is not determined to be HTTP, then the orignal destination
 ///    address is used to transparently forward the TCP stream. A `C`-typed
 ///    `Connect` `Stack` is used to build a connection to the destination (i.e.,
-///    instrumented with telemetry, etc).
+///    instrumented with telemetry, etc). The target is built from a
+///    `ProxyHeader` describing the client and original destination, e.g. so
+///    that the connection may announce the client to the destination.
 ///
 /// 6. Otherwise, an `R`-typed `Service` `Stack` is used to build a service that
 ///    can route HTTP  requests for the `Source`.
 pub struct Server<A, T, C, R, B>
 where
     // Used when forwarding a TCP stream (e.g. with telemetry, timeouts).
-    T: From<SocketAddr>,
+    T: From<ProxyHeader>,
     // Prepares a route for each accepted HTTP connection.
     R: MakeService<
             Source,
@@ -78,14 +82,17 @@ pub struct Source {
     pub local: SocketAddr,
     pub orig_dst: Option<SocketAddr>,
     pub tls_peer: tls::PeerIdentity,
+    /// The local identity under which TLS was terminated, if it was.
+    pub tls_server_identity: Option<identity::Name>,
     _p: (),
 }
 
 /// Establishes connections for forwarded connections.
 ///
-/// Fails to produce a `Connect` if a `Source`'s `orig_dst` is None.
+/// Fails to produce a `Connect` if a `Source`'s `orig_dst` is None, or if
+/// the `Source`'s client is not authenticated as its port requires.
 #[derive(Debug)]
-struct ForwardConnect<T, C>(C, PhantomData<T>);
+struct ForwardConnect<T, C>(C, require_identity::Policy, PhantomData<T>);
 
 /// An error indicating an accepted socket did not have an SO_ORIGINAL_DST
 /// address and therefore could not be forwarded.
@@ -138,6 +145,7 @@ impl Source {
             local,
             orig_dst,
             tls_peer,
+            tls_server_identity: None,
             _p: (),
         }
     }
@@ -152,7 +160,7 @@ impl fmt::Display for Source {
 
 impl<T, C> Service<Source> for ForwardConnect<T, C>
 where
-    T: From<SocketAddr>,
+    T: From<ProxyHeader>,
     C: Service<T>,
     C::Error: Into<Error>,
 {
@@ -168,8 +176,15 @@ where
     }
 
     fn call(&mut self, s: Source) -> Self::Future {
+        if let Err(e) = self.1.check(&s) {
+            return future::Either::A(future::err(e.into()));
+        }
+
         let target = match s.orig_dst {
-            Some(addr) => T::from(addr),
+            Some(destination) => T::from(ProxyHeader {
+                source: s.remote,
+                destination,
+            }),
             None => return future::Either::A(future::err(NoOriginalDst.into())),
         };
 
@@ -179,7 +194,7 @@ where
 
 impl<T, C: Clone> Clone for ForwardConnect<T, C> {
     fn clone(&self) -> Self {
-        ForwardConnect(self.0.clone(), PhantomData)
+        ForwardConnect(self.0.clone(), self.1.clone(), PhantomData)
     }
 }
 
@@ -196,7 +211,7 @@ where
     A: Accept<Connection>,
     A::Io: fmt::Debug + Send + Peek + 'static,
 
-    T: From<SocketAddr> + Send + 'static,
+    T: From<ProxyHeader> + Send + 'static,
 
     C: Service<T> + Clone + Send + 'static,
     C::Response: AsyncRead + AsyncWrite + fmt::Debug + Send + 'static,
@@ -223,7 +238,7 @@ where
         route: R,
         drain_signal: drain::Watch,
     ) -> Self {
-        let connect = ForwardConnect(connect, PhantomData);
+        let connect = ForwardConnect(connect, require_identity::Policy::default(), PhantomData);
         let log = ::logging::Server::proxy(proxy_name, listen_addr);
         Server {
             drain_signal,
@@ -236,6 +251,16 @@ where
         }
     }
 
+    /// Closes, rather than forwards, non-HTTP connections whose clients are
+    /// not authenticated on ports where `policy` requires it.
+    ///
+    /// HTTP requests are authorized by the `route` stack (i.e. with
+    /// `require_identity::layer`).
+    pub fn with_require_identity(mut self, policy: require_identity::Policy) -> Self {
+        self.connect.1 = policy;
+        self
+    }
+
     pub fn log(&self) -> &::logging::Server {
         &self.log
     }
@@ -248,12 +273,14 @@ where
     /// executor.
     pub fn serve(
         &self,
-        connection: Connection,
+        mut connection: Connection,
         remote_addr: SocketAddr,
         h2_settings: H2Settings,
     ) -> impl Future<Item = (), Error = ()> {
+        let closed = connection.on_close();
         let orig_dst = connection.original_dst_addr();
         let disable_protocol_detection = !connection.should_detect_protocol();
+        let alpn = connection.alpn_protocol().map(String::from);
 
         let log = self.log.clone().with_remote(remote_addr);
 
@@ -262,6 +289,7 @@ where
             local: connection.local_addr().unwrap_or(self.listen_addr),
             orig_dst,
             tls_peer: connection.peer_identity(),
+            tls_server_identity: connection.server_identity().cloned(),
             _p: (),
         };
 
@@ -273,15 +301,23 @@ where
             trace!("protocol detection disabled for {:?}", orig_dst);
             let fwd = tcp::forward(io, connect, source);
             let fut = self.drain_signal.clone().watch(fwd, |_| {});
-            return log.future(Either::B(fut));
+            return log.future(log_access(Either::B(fut), closed));
         }
 
         let detect_protocol = io
             .peek()
             .map_err(|e| debug!("peek error: {}", e))
-            .map(|io| {
-                let p = Protocol::detect(io.peeked());
-                (p, io)
+            .and_then(move |io| {
+                // A peer that speaks a different protocol than it negotiated
+                // would be mis-parsed, so the connection is closed instead.
+                let alpn = alpn.as_ref().map(String::as_str);
+                match Protocol::detect_negotiated(io.peeked(), alpn) {
+                    Ok(p) => Ok((p, io)),
+                    Err(e) => {
+                        warn!("{}", e);
+                        Err(())
+                    }
+                }
             });
 
         let mut http = self.http.clone();
@@ -349,6 +385,28 @@ where
             }),
         });
 
-        log.future(Either::A(serve))
+        log.future(log_access(Either::A(serve), closed))
     }
 }
+
+/// Logs the connection's `TlsAccessRecord` once it has been served and
+/// closed.
+///
+/// Upgraded connections may outlive `serve`, so this waits until the
+/// connection itself is dropped.
+fn log_access<F>(
+    serve: F,
+    closed: oneshot::Receiver<tls::TlsAccessRecord>,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = ()>,
+{
+    serve.then(move |served| {
+        closed.then(move |record| {
+            if let Ok(record) = record {
+                debug!("connection closed: {}", record);
+            }
+            served
+        })
+    })
+}
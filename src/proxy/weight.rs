@@ -0,0 +1,467 @@
+//! Records each balanced endpoint's configured weight alongside the share of
+//! its balancer's requests that it actually served, so that the intended and
+//! observed splits of traffic may be compared.
+//!
+//! Request shares are computed over windows that are rotated as metrics are
+//! read, so that requests are recorded without locking: a window ends at the
+//! first read after it has lasted the configured duration, and the exported
+//! share describes the last complete window. Shares are expressed in the
+//! same units as weights (i.e. 10,000 is the whole), but weights are
+//! relative, so an endpoint's intended share is its weight divided by the sum
+//! of its balancer's weights.
+
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use tokio_timer::clock;
+
+use metrics::{Counter, FmtLabels, FmtMetric, FmtMetrics, Gauge};
+use svc;
+use NameAddr;
+
+/// The share of requests that corresponds to all of a balancer's requests.
+const WHOLE_SHARE: u64 = 10_000;
+
+metrics! {
+    balancer_endpoint_weight: Gauge {
+        "The configured weight of each balanced endpoint, where 10000 is 1.0"
+    },
+    balancer_endpoint_requests_total: Counter {
+        "Total count of requests dispatched to each balanced endpoint"
+    },
+    balancer_endpoint_request_share: Gauge {
+        "Each endpoint's share of its balancer's requests in the last window, where 10000 is all"
+    }
+}
+
+/// Describes an endpoint's relative weight within its balancer.
+pub trait HasWeight {
+    /// The name of the balancer's target, if it has one.
+    fn dst_name(&self) -> Option<&NameAddr>;
+
+    fn addr(&self) -> SocketAddr;
+
+    /// The endpoint's weight, where 10,000 corresponds to 1.0.
+    fn weight(&self) -> u32;
+}
+
+#[derive(Clone, Debug)]
+pub struct Layer {
+    metrics: Option<Metrics>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MakeSvc<M> {
+    metrics: Option<Metrics>,
+    inner: M,
+}
+
+pub struct MakeFuture<F> {
+    recorder: Option<Recorder>,
+    inner: F,
+}
+
+/// An endpoint service that records the requests dispatched to it.
+#[derive(Debug)]
+pub struct Service<S> {
+    recorder: Option<Recorder>,
+    inner: S,
+}
+
+/// Records the weights and request shares of balanced endpoints.
+///
+/// Clones share the same metrics, so a single `Metrics` may be shared by all
+/// balancers.
+#[derive(Clone, Debug)]
+pub struct Metrics(Arc<Mutex<Inner>>);
+
+#[derive(Debug)]
+struct Inner {
+    window: Duration,
+    /// The time at which the current window started.
+    window_start: Instant,
+    endpoints: HashMap<Key, Endpoint>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    dst_name: Option<NameAddr>,
+    addr: SocketAddr,
+}
+
+#[derive(Debug, Default)]
+struct Endpoint {
+    weight: u32,
+    counts: Arc<Counts>,
+    /// Requests dispatched within the last complete window.
+    last: u64,
+    /// The number of services that record requests for the endpoint. The
+    /// endpoint is forgotten once all of them are dropped.
+    services: usize,
+}
+
+/// An endpoint's request counts, which are shared with the endpoint's
+/// services so that requests are recorded without locking the metrics.
+#[derive(Debug, Default)]
+struct Counts {
+    requests: AtomicU64,
+    /// Requests dispatched within the current window.
+    current: AtomicU64,
+}
+
+/// Records requests on behalf of a single endpoint service.
+#[derive(Debug)]
+struct Recorder {
+    key: Key,
+    counts: Arc<Counts>,
+    metrics: Metrics,
+}
+
+struct Labels<'a>(&'a Key);
+
+// === impl Layer ===
+
+/// Records the weights and request shares of endpoints into `metrics`.
+///
+/// If `metrics` is `None`, endpoint services are not instrumented.
+pub fn layer(metrics: Option<Metrics>) -> Layer {
+    Layer { metrics }
+}
+
+impl<M> svc::Layer<M> for Layer {
+    type Service = MakeSvc<M>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        MakeSvc {
+            metrics: self.metrics.clone(),
+            inner,
+        }
+    }
+}
+
+// === impl MakeSvc ===
+
+impl<T, M> svc::Service<T> for MakeSvc<M>
+where
+    T: HasWeight,
+    M: svc::Service<T>,
+{
+    type Response = Service<M::Response>;
+    type Error = M::Error;
+    type Future = MakeFuture<M::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let recorder = self.metrics.as_ref().map(|m| m.register(&target));
+        MakeFuture {
+            recorder,
+            inner: self.inner.call(target),
+        }
+    }
+}
+
+// === impl MakeFuture ===
+
+impl<F: Future> Future for MakeFuture<F> {
+    type Item = Service<F::Item>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        let recorder = self.recorder.take();
+        Ok(Async::Ready(Service { recorder, inner }))
+    }
+}
+
+// === impl Service ===
+
+impl<S, Req> svc::Service<Req> for Service<S>
+where
+    S: svc::Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        if let Some(ref recorder) = self.recorder {
+            recorder.record();
+        }
+        self.inner.call(req)
+    }
+}
+
+// === impl Metrics ===
+
+impl Metrics {
+    /// Computes request shares over windows of `window`.
+    pub fn new(window: Duration) -> Self {
+        Metrics(Arc::new(Mutex::new(Inner {
+            window,
+            window_start: clock::now(),
+            endpoints: HashMap::new(),
+        })))
+    }
+
+    fn register<T: HasWeight>(&self, target: &T) -> Recorder {
+        let key = Key {
+            dst_name: target.dst_name().cloned(),
+            addr: target.addr(),
+        };
+        let counts = match self.0.lock() {
+            Ok(mut inner) => {
+                let endpoint = inner
+                    .endpoints
+                    .entry(key.clone())
+                    .or_insert_with(Endpoint::default);
+                // The most recently built service describes the current weight.
+                endpoint.weight = target.weight();
+                endpoint.services += 1;
+                endpoint.counts.clone()
+            }
+            Err(_) => Arc::new(Counts::default()),
+        };
+        Recorder {
+            key,
+            counts,
+            metrics: self.clone(),
+        }
+    }
+}
+
+impl FmtMetrics for Metrics {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut inner = match self.0.lock() {
+            Ok(inner) => inner,
+            Err(_) => return Ok(()),
+        };
+        inner.rotate(clock::now());
+        let inner = &*inner;
+        if inner.endpoints.is_empty() {
+            return Ok(());
+        }
+
+        balancer_endpoint_weight.fmt_help(f)?;
+        for (key, endpoint) in &inner.endpoints {
+            Gauge::from(u64::from(endpoint.weight)).fmt_metric_labeled(
+                f,
+                balancer_endpoint_weight.name,
+                Labels(key),
+            )?;
+        }
+
+        balancer_endpoint_requests_total.fmt_help(f)?;
+        for (key, endpoint) in &inner.endpoints {
+            let requests = endpoint.counts.requests.load(Ordering::Relaxed);
+            Counter::from(requests).fmt_metric_labeled(
+                f,
+                balancer_endpoint_requests_total.name,
+                Labels(key),
+            )?;
+        }
+
+        // Each endpoint's share is relative to all of the requests that were
+        // dispatched to its balancer's target.
+        let mut totals = HashMap::<&Option<NameAddr>, u64>::new();
+        for (key, endpoint) in &inner.endpoints {
+            *totals.entry(&key.dst_name).or_insert(0) += endpoint.last;
+        }
+        balancer_endpoint_request_share.fmt_help(f)?;
+        for (key, endpoint) in &inner.endpoints {
+            let total = totals.get(&key.dst_name).cloned().unwrap_or(0);
+            let share = if total == 0 {
+                0
+            } else {
+                endpoint.last * WHOLE_SHARE / total
+            };
+            Gauge::from(share).fmt_metric_labeled(
+                f,
+                balancer_endpoint_request_share.name,
+                Labels(key),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// === impl Inner ===
+
+impl Inner {
+    /// Starts a new window if the current window has elapsed.
+    fn rotate(&mut self, now: Instant) {
+        if now < self.window_start + self.window {
+            return;
+        }
+
+        for endpoint in self.endpoints.values_mut() {
+            endpoint.last = endpoint.counts.current.swap(0, Ordering::Relaxed);
+        }
+        self.window_start = now;
+    }
+}
+
+// === impl Recorder ===
+
+impl Recorder {
+    fn record(&self) {
+        self.counts.requests.fetch_add(1, Ordering::Relaxed);
+        self.counts.current.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.metrics.0.lock() {
+            let forget = match inner.endpoints.get_mut(&self.key) {
+                Some(endpoint) => {
+                    endpoint.services -= 1;
+                    endpoint.services == 0
+                }
+                None => false,
+            };
+            if forget {
+                inner.endpoints.remove(&self.key);
+            }
+        }
+    }
+}
+
+// === impl Labels ===
+
+impl<'a> FmtLabels for Labels<'a> {
+    fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref name) = self.0.dst_name {
+            write!(f, "authority=\"{}\",", name)?;
+        }
+        write!(f, "addr=\"{}\"", self.0.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use svc::{Layer as _Layer, Service as _Service};
+
+    struct Target {
+        dst_name: NameAddr,
+        addr: SocketAddr,
+        weight: u32,
+    }
+
+    impl HasWeight for Target {
+        fn dst_name(&self) -> Option<&NameAddr> {
+            Some(&self.dst_name)
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.addr
+        }
+
+        fn weight(&self) -> u32 {
+            self.weight
+        }
+    }
+
+    #[derive(Debug)]
+    struct Ready;
+
+    impl svc::Service<()> for Ready {
+        type Response = ();
+        type Error = ();
+        type Future = future::FutureResult<(), ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            future::ok(())
+        }
+    }
+
+    fn build<M>(make: &mut M, port: u16, weight: u32) -> Service<Ready>
+    where
+        M: svc::Service<Target, Response = Service<Ready>>,
+        M::Error: fmt::Debug,
+    {
+        let target = Target {
+            dst_name: NameAddr::from_str("foo.ns.svc.cluster.local:80").unwrap(),
+            addr: SocketAddr::from(([10, 0, 0, 1], port)),
+            weight,
+        };
+        make.call(target).wait().expect("make must succeed")
+    }
+
+    #[test]
+    fn records_weights_and_request_shares() {
+        let window = Duration::from_secs(10);
+        let metrics = Metrics::new(window);
+        let mut make = layer(Some(metrics.clone()))
+            .layer(svc::mk(|_: Target| Ok::<_, ()>(Ready)));
+
+        let mut heavy = build(&mut make, 8080, 30_000);
+        let mut light = build(&mut make, 8081, 10_000);
+        for _ in 0..3 {
+            heavy.call(()).wait().unwrap();
+        }
+        light.call(()).wait().unwrap();
+
+        // End the current window so that its requests are reported.
+        metrics.0.lock().unwrap().window_start = clock::now() - window;
+        let out = metrics.as_display().to_string();
+
+        let heavy_labels = "authority=\"foo.ns.svc.cluster.local:80\",addr=\"10.0.0.1:8080\"";
+        let light_labels = "authority=\"foo.ns.svc.cluster.local:80\",addr=\"10.0.0.1:8081\"";
+        for line in &[
+            format!("balancer_endpoint_weight{{{}}} 30000", heavy_labels),
+            format!("balancer_endpoint_weight{{{}}} 10000", light_labels),
+            format!("balancer_endpoint_requests_total{{{}}} 3", heavy_labels),
+            format!("balancer_endpoint_requests_total{{{}}} 1", light_labels),
+            format!("balancer_endpoint_request_share{{{}}} 7500", heavy_labels),
+            format!("balancer_endpoint_request_share{{{}}} 2500", light_labels),
+        ] {
+            assert!(out.contains(line.as_str()), "{} not in:\n{}", line, out);
+        }
+
+        drop(heavy);
+        drop(light);
+        assert_eq!(
+            metrics.as_display().to_string(),
+            "",
+            "endpoints must be forgotten once their services are dropped"
+        );
+    }
+
+    #[test]
+    fn requests_are_recorded_without_locking_metrics() {
+        let metrics = Metrics::new(Duration::from_secs(10));
+        let mut make = layer(Some(metrics.clone())).layer(svc::mk(|_: Target| Ok::<_, ()>(Ready)));
+        let mut svc = build(&mut make, 8080, 10_000);
+
+        // Metrics are locked while they are read, which would deadlock if
+        // recording a request took the lock.
+        let locked = metrics.0.lock().unwrap();
+        svc.call(()).wait().unwrap();
+        drop(locked);
+
+        let line = "balancer_endpoint_requests_total{\
+                    authority=\"foo.ns.svc.cluster.local:80\",addr=\"10.0.0.1:8080\"} 1";
+        let out = metrics.as_display().to_string();
+        assert!(out.contains(line), "{} not in:\n{}", line, out);
+    }
+}
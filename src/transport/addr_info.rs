@@ -1,7 +1,7 @@
 use std::env;
 use std::fmt::Debug;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use tokio::net::TcpStream;
 
 #[cfg(target_os = "windows")]
@@ -10,6 +10,7 @@ extern crate ws2_32;
 
 pub trait AddrInfo: Debug {
     fn local_addr(&self) -> Result<SocketAddr, io::Error>;
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error>;
     fn get_original_dst(&self) -> Option<SocketAddr>;
 }
 
@@ -18,6 +19,10 @@ impl<T: AddrInfo + ?Sized> AddrInfo for Box<T> {
         self.as_ref().local_addr()
     }
 
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.as_ref().peer_addr()
+    }
+
     fn get_original_dst(&self) -> Option<SocketAddr> {
         self.as_ref().get_original_dst()
     }
@@ -28,6 +33,10 @@ impl AddrInfo for TcpStream {
         TcpStream::local_addr(&self)
     }
 
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        TcpStream::peer_addr(&self)
+    }
+
     #[cfg(target_os = "linux")]
     fn get_original_dst(&self) -> Option<SocketAddr> {
         use std::os::unix::io::AsRawFd;
@@ -85,13 +94,292 @@ impl GetOriginalDst for SoOriginalDst {
     }
 }
 
+/// Looks up values by key in a map, e.g. an eBPF map.
+pub trait MapLookup {
+    /// Returns the `value_len`-byte value stored for `key`, if there is one.
+    fn lookup(&self, key: &[u8], value_len: usize) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Reads the original destination address of each connection from a map that
+/// is maintained by the eBPF program that redirected the connection to the
+/// proxy (i.e. instead of iptables, so that `SO_ORIGINAL_DST` is not set).
+///
+/// Entries are keyed by each connection's source address.
+#[derive(Clone, Debug)]
+pub struct MapOriginalDst<M> {
+    map: M,
+    layout: MapLayout,
+}
+
+/// Describes how a `MapOriginalDst`'s map encodes its keys and values.
+///
+/// Each address is encoded as its IP address (in network order) followed by
+/// its port (in network order) and two bytes of padding. Keys may be followed
+/// by a network namespace cookie (in host order), aligned to 8 bytes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MapLayout {
+    ipv6: bool,
+    netns_cookie: Option<u64>,
+}
+
+// === impl MapOriginalDst ===
+
+impl<M: MapLookup> MapOriginalDst<M> {
+    pub fn new(map: M, layout: MapLayout) -> Self {
+        Self { map, layout }
+    }
+}
+
+impl<M: MapLookup> GetOriginalDst for MapOriginalDst<M> {
+    fn get_original_dst(&self, sock: &AddrInfo) -> Option<SocketAddr> {
+        let peer = match sock.peer_addr() {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("failed to read peer address: {}", e);
+                return None;
+            }
+        };
+        let key = self.layout.key(peer)?;
+
+        match self.map.lookup(&key, self.layout.addr_len()) {
+            Ok(Some(value)) => {
+                let dst = self.layout.parse_addr(&value);
+                if dst.is_none() {
+                    warn!("invalid original destination for {}: {:?}", peer, value);
+                }
+                dst
+            }
+            Ok(None) => {
+                debug!("no original destination for {}", peer);
+                None
+            }
+            Err(e) => {
+                warn!("failed to read original destination for {}: {}", peer, e);
+                None
+            }
+        }
+    }
+}
+
+// === impl MapLayout ===
+
+impl MapLayout {
+    /// Encodes addresses as 16-byte IPv6 addresses, with IPv4 addresses
+    /// mapped into IPv6.
+    ///
+    /// By default, addresses are encoded as 4-byte IPv4 addresses, and IPv6
+    /// addresses cannot be looked up.
+    pub fn with_ipv6(self) -> Self {
+        Self { ipv6: true, ..self }
+    }
+
+    /// Suffixes each key with the cookie of the proxy's network namespace.
+    pub fn with_netns_cookie(self, cookie: u64) -> Self {
+        Self {
+            netns_cookie: Some(cookie),
+            ..self
+        }
+    }
+
+    fn ip_len(&self) -> usize {
+        if self.ipv6 {
+            16
+        } else {
+            4
+        }
+    }
+
+    fn addr_len(&self) -> usize {
+        self.ip_len() + 4
+    }
+
+    fn key(&self, addr: SocketAddr) -> Option<Vec<u8>> {
+        let mut key = Vec::with_capacity(self.addr_len() + 12);
+        match (addr.ip(), self.ipv6) {
+            (IpAddr::V4(ip), false) => key.extend_from_slice(&ip.octets()),
+            (IpAddr::V4(ip), true) => key.extend_from_slice(&ip.to_ipv6_mapped().octets()),
+            (IpAddr::V6(ip), true) => key.extend_from_slice(&ip.octets()),
+            (IpAddr::V6(_), false) => {
+                debug!("cannot look up IPv6 address {} in an IPv4 map", addr);
+                return None;
+            }
+        }
+        key.extend_from_slice(&addr.port().to_be_bytes());
+        key.extend_from_slice(&[0, 0]);
+
+        if let Some(cookie) = self.netns_cookie {
+            while key.len() % 8 != 0 {
+                key.push(0);
+            }
+            key.extend_from_slice(&cookie.to_ne_bytes());
+        }
+
+        Some(key)
+    }
+
+    fn parse_addr(&self, value: &[u8]) -> Option<SocketAddr> {
+        if value.len() < self.addr_len() {
+            return None;
+        }
+
+        let (ip, rest) = value.split_at(self.ip_len());
+        let port = u16::from(rest[0]) << 8 | u16::from(rest[1]);
+        let ip = if self.ipv6 {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(ip);
+            let ip = Ipv6Addr::from(octets);
+            match ip.to_ipv4() {
+                // Only IPv4-mapped addresses are treated as IPv4, since
+                // `to_ipv4` also converts IPv4-compatible addresses (e.g.
+                // `::1`).
+                Some(v4) if ip.segments()[5] == 0xffff => IpAddr::V4(v4),
+                _ => IpAddr::V6(ip),
+            }
+        } else {
+            IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+        };
+
+        Some(SocketAddr::new(ip, port))
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::linux::PinnedMap;
+
 #[cfg(target_os = "linux")]
 mod linux {
     use libc;
+    use std::ffi::CString;
     use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::ffi::OsStrExt;
     use std::os::unix::io::RawFd;
+    use std::path::Path;
+    use std::sync::Arc;
     use std::{io, mem};
 
+    use super::MapLookup;
+
+    const BPF_MAP_LOOKUP_ELEM: libc::c_long = 1;
+    const BPF_OBJ_GET: libc::c_long = 7;
+    const BPF_OBJ_GET_INFO_BY_FD: libc::c_long = 15;
+
+    /// An eBPF map that has been pinned to the BPF filesystem.
+    #[derive(Clone, Debug)]
+    pub struct PinnedMap(Arc<MapFd>);
+
+    #[derive(Debug)]
+    struct MapFd {
+        fd: RawFd,
+        key_size: usize,
+        value_size: usize,
+    }
+
+    #[repr(C)]
+    struct ObjGetAttr {
+        pathname: u64,
+        bpf_fd: u32,
+        file_flags: u32,
+    }
+
+    #[repr(C)]
+    struct InfoAttr {
+        bpf_fd: u32,
+        info_len: u32,
+        info: u64,
+    }
+
+    #[repr(C)]
+    struct MapElemAttr {
+        map_fd: u32,
+        _pad: u32,
+        key: u64,
+        value: u64,
+        flags: u64,
+    }
+
+    impl PinnedMap {
+        /// Opens the map pinned at `path`, e.g. under `/sys/fs/bpf`.
+        pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+            let path = CString::new(path.as_ref().as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let attr = ObjGetAttr {
+                pathname: path.as_ptr() as u64,
+                bpf_fd: 0,
+                file_flags: 0,
+            };
+            let fd = unsafe { bpf(BPF_OBJ_GET, &attr) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = fd as RawFd;
+
+            // The kernel writes as much of the map's info (i.e. `struct
+            // bpf_map_info`) as fits; only its key and value sizes are used.
+            let mut info = [0u32; 20];
+            let attr = InfoAttr {
+                bpf_fd: fd as u32,
+                info_len: mem::size_of_val(&info) as u32,
+                info: info.as_mut_ptr() as u64,
+            };
+            if unsafe { bpf(BPF_OBJ_GET_INFO_BY_FD, &attr) } < 0 {
+                let e = io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+
+            Ok(PinnedMap(Arc::new(MapFd {
+                fd,
+                key_size: info[2] as usize,
+                value_size: info[3] as usize,
+            })))
+        }
+    }
+
+    impl MapLookup for PinnedMap {
+        fn lookup(&self, key: &[u8], value_len: usize) -> io::Result<Option<Vec<u8>>> {
+            // The kernel reads and writes the map's sizes, regardless of the
+            // buffers' sizes.
+            if key.len() != self.0.key_size || value_len != self.0.value_size {
+                let msg = format!(
+                    "map has {}-byte keys and {}-byte values, not {} and {}",
+                    self.0.key_size,
+                    self.0.value_size,
+                    key.len(),
+                    value_len
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+
+            let mut value = vec![0; value_len];
+            let attr = MapElemAttr {
+                map_fd: self.0.fd as u32,
+                _pad: 0,
+                key: key.as_ptr() as u64,
+                value: value.as_mut_ptr() as u64,
+                flags: 0,
+            };
+            if unsafe { bpf(BPF_MAP_LOOKUP_ELEM, &attr) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() == Some(libc::ENOENT) {
+                    return Ok(None);
+                }
+                return Err(e);
+            }
+
+            Ok(Some(value))
+        }
+    }
+
+    impl Drop for MapFd {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+
+    unsafe fn bpf<T>(cmd: libc::c_long, attr: &T) -> libc::c_long {
+        libc::syscall(libc::SYS_bpf, cmd, attr as *const T, mem::size_of::<T>())
+    }
+
     pub unsafe fn so_original_dst(fd: RawFd) -> io::Result<SocketAddr> {
         let mut sockaddr: libc::sockaddr_storage = mem::zeroed();
         let mut socklen: libc::socklen_t = mem::size_of::<libc::sockaddr_storage>() as u32;
@@ -180,102 +468,230 @@ mod linux {
     }
 }
 
-// #[cfg(target_os = "windows")]
-// mod windows {
-//     // use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-//     use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-//     use std::os::windows::io::RawSocket;
-//     use std::{io, mem};
-//     // use transport::addr_info::winapi;
-//     use transport::addr_info::winapi::shared::ws2def;
-//     use transport::addr_info::ws2_32;
-//     use transport::addr_info::winapi::shared::minwindef::DWORD;
-
-//     pub unsafe fn so_original_dst(fd: RawSocket) -> io::Result<SocketAddr> {
-//         let mut value: DWORD = 0;
-//         let mut value_len: ::std::os::raw::c_int = mem::size_of_val(&value) as _;
-
-//         let ret = ws2_32::getsockopt(
-//             fd,
-//             ws2def::IPPROTO_IP,
-//             0x300f,
-//             // &mut value as *mut DWORD as _,
-//             &mut value as *mut _ as *mut _,
-//             &mut value_len,
-//         );
-//         if ret != 0 {
-//             let e = io::Error::from_raw_os_error(ws2_32::WSAGetLastError());
-//             warn!("failed to read SO_ORIGINAL_DST: {:?}", e);
-//             return Err(e);
-//         }
-
-//         mk_addr(&value, value_len)
-//     }
-
-//     // Borrowed with love from net2-rs
-//     // https://github.com/rust-lang-nursery/net2-rs/blob/1b4cb4fb05fbad750b271f38221eab583b666e5e/src/socket.rs#L103
-//     fn mk_addr(storage: &ws2def::SOCKADDR_STORAGE, len: ::std::os::raw::c_int) -> io::Result<SocketAddr> {
-//         match storage.ss_family as ::std::os::raw::c_int {
-//             ws2def::AF_INET => {
-//                 assert!(len as usize >= mem::size_of::<ws2def::SOCKADDR_IN>());
-
-//                 let sa = {
-//                     let sa = storage as *const _ as *const ws2def::SOCKADDR_IN;
-//                     unsafe { *sa }
-//                 };
-
-//                 let bits = ntoh32(sa.sin_addr.S_un.s_addr);
-//                 let ip = Ipv4Addr::new(
-//                     (bits >> 24) as u8,
-//                     (bits >> 16) as u8,
-//                     (bits >> 8) as u8,
-//                     bits as u8,
-//                 );
-//                 let port = sa.sin_port;
-//                 Ok(SocketAddr::V4(SocketAddrV4::new(ip, ntoh16(port))))
-//             }
-//             // ws2def::AF_INET6 => {
-//             //     assert!(len as usize >= mem::size_of::<winapi::shared::ws2ipdef::sockaddr_in6>());
-
-//             //     let sa = {
-//             //         let sa = storage as *const _ as *const winapi::ws2ipdef::sockaddr_in6;
-//             //         unsafe { *sa }
-//             //     };
-
-//             //     let arr = sa.sin6_addr.s6_addr;
-//             //     let ip = Ipv6Addr::new(
-//             //         (arr[0] as u16) << 8 | (arr[1] as u16),
-//             //         (arr[2] as u16) << 8 | (arr[3] as u16),
-//             //         (arr[4] as u16) << 8 | (arr[5] as u16),
-//             //         (arr[6] as u16) << 8 | (arr[7] as u16),
-//             //         (arr[8] as u16) << 8 | (arr[9] as u16),
-//             //         (arr[10] as u16) << 8 | (arr[11] as u16),
-//             //         (arr[12] as u16) << 8 | (arr[13] as u16),
-//             //         (arr[14] as u16) << 8 | (arr[15] as u16),
-//             //     );
-
-//             //     let port = sa.sin6_port;
-//             //     let flowinfo = sa.sin6_flowinfo;
-//             //     let scope_id = sa.sin6_scope_id;
-//             //     Ok(SocketAddr::V6(SocketAddrV6::new(
-//             //         ip,
-//             //         ntoh16(port),
-//             //         flowinfo,
-//             //         scope_id,
-//             //     )))
-//             // }
-//             _ => Err(io::Error::new(
-//                 io::ErrorKind::InvalidInput,
-//                 "invalid argument",
-//             )),
-//         }
-//     }
-
-//     fn ntoh16(i: u16) -> u16 {
-//         <u16>::from_be(i)
-//     }
-
-//     fn ntoh32(i: u32) -> u32 {
-//         <u32>::from_be(i)
-//     }
-// }
+#[cfg(target_os = "windows")]
+mod windows {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::raw::c_int;
+    use std::{io, mem};
+    use transport::addr_info::winapi::shared::{ws2def, ws2ipdef};
+
+    // use std::os::windows::io::RawSocket;
+    // use transport::addr_info::ws2_32;
+    // use transport::addr_info::winapi::shared::minwindef::DWORD;
+
+    // pub unsafe fn so_original_dst(fd: RawSocket) -> io::Result<SocketAddr> {
+    //     let mut value: DWORD = 0;
+    //     let mut value_len: ::std::os::raw::c_int = mem::size_of_val(&value) as _;
+
+    //     let ret = ws2_32::getsockopt(
+    //         fd,
+    //         ws2def::IPPROTO_IP,
+    //         0x300f,
+    //         // &mut value as *mut DWORD as _,
+    //         &mut value as *mut _ as *mut _,
+    //         &mut value_len,
+    //     );
+    //     if ret != 0 {
+    //         let e = io::Error::from_raw_os_error(ws2_32::WSAGetLastError());
+    //         warn!("failed to read SO_ORIGINAL_DST: {:?}", e);
+    //         return Err(e);
+    //     }
+
+    //     mk_addr(&value, value_len)
+    // }
+
+    // Borrowed with love from net2-rs
+    // https://github.com/rust-lang-nursery/net2-rs/blob/1b4cb4fb05fbad750b271f38221eab583b666e5e/src/socket.rs#L103
+    #[allow(dead_code)] // Unused until `so_original_dst` is implemented.
+    fn mk_addr(storage: &ws2def::SOCKADDR_STORAGE, len: c_int) -> io::Result<SocketAddr> {
+        match storage.ss_family as c_int {
+            ws2def::AF_INET => {
+                assert!(len as usize >= mem::size_of::<ws2def::SOCKADDR_IN>());
+
+                let sa = {
+                    let sa = storage as *const _ as *const ws2def::SOCKADDR_IN;
+                    unsafe { *sa }
+                };
+
+                let bits = ntoh32(unsafe { *sa.sin_addr.S_un.S_addr() });
+                let ip = Ipv4Addr::new(
+                    (bits >> 24) as u8,
+                    (bits >> 16) as u8,
+                    (bits >> 8) as u8,
+                    bits as u8,
+                );
+                let port = sa.sin_port;
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, ntoh16(port))))
+            }
+            ws2def::AF_INET6 => {
+                assert!(len as usize >= mem::size_of::<ws2ipdef::SOCKADDR_IN6>());
+
+                let sa = {
+                    let sa = storage as *const _ as *const ws2ipdef::SOCKADDR_IN6;
+                    unsafe { *sa }
+                };
+
+                let arr = unsafe { *sa.sin6_addr.u.Byte() };
+                let ip = Ipv6Addr::new(
+                    (arr[0] as u16) << 8 | (arr[1] as u16),
+                    (arr[2] as u16) << 8 | (arr[3] as u16),
+                    (arr[4] as u16) << 8 | (arr[5] as u16),
+                    (arr[6] as u16) << 8 | (arr[7] as u16),
+                    (arr[8] as u16) << 8 | (arr[9] as u16),
+                    (arr[10] as u16) << 8 | (arr[11] as u16),
+                    (arr[12] as u16) << 8 | (arr[13] as u16),
+                    (arr[14] as u16) << 8 | (arr[15] as u16),
+                );
+
+                let port = sa.sin6_port;
+                let flowinfo = sa.sin6_flowinfo;
+                let scope_id = unsafe { *sa.u.sin6_scope_id() };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    ntoh16(port),
+                    flowinfo,
+                    scope_id,
+                )))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid argument",
+            )),
+        }
+    }
+
+    fn ntoh16(i: u16) -> u16 {
+        <u16>::from_be(i)
+    }
+
+    fn ntoh32(i: u32) -> u32 {
+        <u32>::from_be(i)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn mk_addr_ipv6() {
+            let ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x17);
+            let expected = SocketAddrV6::new(ip, 4143, 0x12345, 7);
+
+            let mut storage: ws2def::SOCKADDR_STORAGE = unsafe { mem::zeroed() };
+            {
+                let sa = &mut storage as *mut _ as *mut ws2ipdef::SOCKADDR_IN6;
+                let sa = unsafe { &mut *sa };
+                sa.sin6_family = ws2def::AF_INET6 as _;
+                sa.sin6_port = expected.port().to_be();
+                sa.sin6_flowinfo = expected.flowinfo();
+                unsafe {
+                    *sa.sin6_addr.u.Byte_mut() = ip.octets();
+                    *sa.u.sin6_scope_id_mut() = expected.scope_id();
+                }
+            }
+            let len = mem::size_of::<ws2ipdef::SOCKADDR_IN6>() as c_int;
+
+            match mk_addr(&storage, len).expect("address must be parsed") {
+                SocketAddr::V6(addr) => {
+                    assert_eq!(addr.ip(), &ip);
+                    assert_eq!(addr.port(), 4143);
+                    assert_eq!(addr.flowinfo(), 0x12345);
+                    assert_eq!(addr.scope_id(), 7);
+                }
+                addr => panic!("unexpected address: {}", addr),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct MockMap(HashMap<Vec<u8>, Vec<u8>>);
+
+    impl MapLookup for MockMap {
+        fn lookup(&self, key: &[u8], value_len: usize) -> io::Result<Option<Vec<u8>>> {
+            assert!(self.0.keys().all(|k| k.len() == key.len()), "unexpected key: {:?}", key);
+            Ok(self.0.get(key).map(|v| {
+                assert_eq!(v.len(), value_len);
+                v.clone()
+            }))
+        }
+    }
+
+    #[derive(Debug)]
+    struct MockSocket(SocketAddr);
+
+    impl AddrInfo for MockSocket {
+        fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+            unreachable!("not called in test")
+        }
+
+        fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+            Ok(self.0)
+        }
+
+        fn get_original_dst(&self) -> Option<SocketAddr> {
+            unreachable!("not called in test")
+        }
+    }
+
+    #[test]
+    fn map_original_dst_ipv4_with_netns_cookie() {
+        let cookie = 0x0102_0304_0506_0708u64;
+        let mut key = vec![10, 1, 2, 3, 0x9c, 0x40, 0, 0];
+        key.extend_from_slice(&cookie.to_ne_bytes());
+        let value = vec![192, 0, 2, 1, 0x1f, 0x90, 0, 0];
+
+        let mut map = MockMap::default();
+        map.0.insert(key, value);
+        let layout = MapLayout::default().with_netns_cookie(cookie);
+        let dst = MapOriginalDst::new(map, layout);
+
+        let client = MockSocket(SocketAddr::from(([10, 1, 2, 3], 40000)));
+        let expected = SocketAddr::from(([192, 0, 2, 1], 8080));
+        assert_eq!(dst.get_original_dst(&client), Some(expected));
+
+        let unknown = MockSocket(SocketAddr::from(([10, 1, 2, 3], 40001)));
+        assert_eq!(dst.get_original_dst(&unknown), None);
+
+        let ipv6 = MockSocket(SocketAddr::from(([0xfd00, 0, 0, 0, 0, 0, 0, 1], 40000)));
+        assert_eq!(dst.get_original_dst(&ipv6), None);
+    }
+
+    #[test]
+    fn map_original_dst_ipv6() {
+        let client = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let mut key = client.octets().to_vec();
+        key.extend_from_slice(&[0x9c, 0x40, 0, 0]);
+        let orig = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x17);
+        let mut value = orig.octets().to_vec();
+        value.extend_from_slice(&[0x1f, 0x90, 0, 0]);
+
+        // IPv4 clients are mapped into IPv6.
+        let mut mapped_key = Ipv4Addr::new(10, 1, 2, 3).to_ipv6_mapped().octets().to_vec();
+        mapped_key.extend_from_slice(&[0x9c, 0x40, 0, 0]);
+        let mut mapped_value = Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped().octets().to_vec();
+        mapped_value.extend_from_slice(&[0x1f, 0x90, 0, 0]);
+
+        let mut map = MockMap::default();
+        map.0.insert(key, value);
+        map.0.insert(mapped_key, mapped_value);
+        let dst = MapOriginalDst::new(map, MapLayout::default().with_ipv6());
+
+        let v6 = MockSocket(SocketAddr::from((client, 40000)));
+        assert_eq!(
+            dst.get_original_dst(&v6),
+            Some(SocketAddr::from((orig, 8080)))
+        );
+
+        let v4 = MockSocket(SocketAddr::from(([10, 1, 2, 3], 40000)));
+        assert_eq!(
+            dst.get_original_dst(&v4),
+            Some(SocketAddr::from(([192, 0, 2, 1], 8080)))
+        );
+    }
+}
@@ -10,17 +10,39 @@ pub trait HasPeerAddr {
     fn peer_addr(&self) -> SocketAddr;
 }
 
+/// Configures sockets before they are connected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SocketOptions {
+    mark: Option<u32>,
+    nodelay: bool,
+}
+
 pub fn svc<T>(
 ) -> impl svc::Service<T, Response = TcpStream, Error = io::Error, Future = ConnectFuture> + Clone
 where
     T: HasPeerAddr,
 {
-    svc::mk(|target: T| {
+    svc_with_options(SocketOptions::default())
+}
+
+/// Connects to targets with sockets configured by `options`.
+pub fn svc_with_options<T>(
+    options: SocketOptions,
+) -> impl svc::Service<T, Response = TcpStream, Error = io::Error, Future = ConnectFuture> + Clone
+where
+    T: HasPeerAddr,
+{
+    svc::mk(move |target: T| {
         let addr = target.peer_addr();
         debug!("connecting to {}", addr);
+        let state = match options.connect(&addr) {
+            Ok(future) => State::Connecting(future),
+            Err(e) => State::Failed(Some(e)),
+        };
         ConnectFuture {
             addr,
-            future: TcpStream::connect(&addr),
+            nodelay: options.nodelay,
+            state,
         }
     })
 }
@@ -28,7 +50,15 @@ where
 #[derive(Debug)]
 pub struct ConnectFuture {
     addr: SocketAddr,
-    future: tcp::ConnectFuture,
+    nodelay: bool,
+    state: State,
+}
+
+#[derive(Debug)]
+enum State {
+    Connecting(tcp::ConnectFuture),
+    /// The socket could not be configured.
+    Failed(Option<io::Error>),
 }
 
 impl HasPeerAddr for SocketAddr {
@@ -37,6 +67,52 @@ impl HasPeerAddr for SocketAddr {
     }
 }
 
+// === impl SocketOptions ===
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            mark: None,
+            nodelay: true,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Marks sockets (i.e. with `SO_MARK`), e.g. so that their traffic may be
+    /// routed by policy.
+    ///
+    /// Marks are only set on Linux, and are ignored elsewhere. Setting a mark
+    /// requires the `CAP_NET_ADMIN` capability.
+    pub fn with_mark(self, mark: u32) -> Self {
+        Self {
+            mark: Some(mark),
+            ..self
+        }
+    }
+
+    /// Sets whether `TCP_NODELAY` is set on connected sockets, i.e. whether
+    /// Nagle's algorithm is disabled.
+    ///
+    /// By default, `TCP_NODELAY` is set.
+    pub fn with_nodelay(self, nodelay: bool) -> Self {
+        Self { nodelay, ..self }
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<tcp::ConnectFuture> {
+        match self.mark {
+            #[cfg(target_os = "linux")]
+            Some(mark) => {
+                use tokio::reactor::Handle;
+
+                let socket = linux::marked_socket(addr, mark)?;
+                Ok(TcpStream::connect_std(socket, addr, &Handle::default()))
+            }
+            _ => Ok(TcpStream::connect(addr)),
+        }
+    }
+}
+
 // === impl ConnectFuture ===
 
 impl Future for ConnectFuture {
@@ -44,12 +120,119 @@ impl Future for ConnectFuture {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let io = try_ready!(self.future.poll().map_err(|e| {
-            let details = format!("{} (address: {})", e, self.addr);
+        let addr = self.addr;
+        let future = match self.state {
+            State::Connecting(ref mut future) => future,
+            State::Failed(ref mut e) => {
+                let e = e.take().expect("polled after failure");
+                let details = format!("failed to configure socket: {} (address: {})", e, addr);
+                return Err(io::Error::new(e.kind(), details));
+            }
+        };
+        let io = try_ready!(future.poll().map_err(|e| {
+            let details = format!("{} (address: {})", e, addr);
             io::Error::new(e.kind(), details)
         }));
-        debug!("connection established to {}", self.addr);
-        super::set_nodelay_or_warn(&io);
+        debug!("connection established to {}", addr);
+        super::set_nodelay_or_warn(&io, self.nodelay);
         Ok(io.into())
     }
 }
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use libc;
+    use std::net::{SocketAddr, TcpStream};
+    use std::os::unix::io::FromRawFd;
+    use std::{io, mem};
+
+    /// Creates an unconnected socket for `addr`'s address family, marked with
+    /// `mark`.
+    pub fn marked_socket(addr: &SocketAddr, mark: u32) -> io::Result<TcpStream> {
+        let domain = if addr.is_ipv4() {
+            libc::AF_INET
+        } else {
+            libc::AF_INET6
+        };
+        let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The socket is owned from here, so that it is closed on failure.
+        let socket = unsafe { TcpStream::from_raw_fd(fd) };
+
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_MARK,
+                &mark as *const u32 as *const libc::c_void,
+                mem::size_of::<u32>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+
+    #[cfg(test)]
+    pub fn mark<S: ::std::os::unix::io::AsRawFd>(socket: &S) -> io::Result<u32> {
+        let mut mark = 0u32;
+        let mut len = mem::size_of::<u32>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_MARK,
+                &mut mark as *mut u32 as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(mark)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use svc::Service;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn sets_nodelay_on_connected_sockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("must bind");
+        let addr = listener.local_addr().expect("must have an address");
+        let mut rt = Runtime::new().expect("runtime");
+
+        let mut connect = svc::<SocketAddr>();
+        let io = rt.block_on(connect.call(addr)).expect("must connect");
+        assert!(io.nodelay().expect("must get TCP_NODELAY"), "must be set by default");
+
+        let mut connect = svc_with_options(SocketOptions::default().with_nodelay(false));
+        let io = rt.block_on(connect.call(addr)).expect("must connect");
+        assert!(!io.nodelay().expect("must get TCP_NODELAY"), "must not be set");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[ignore] // Marking sockets requires CAP_NET_ADMIN, which CI doesn't have.
+    fn marks_outbound_sockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("must bind");
+        let addr = listener.local_addr().expect("must have an address");
+
+        let mut connect = svc_with_options(SocketOptions::default().with_mark(0x2a));
+        let mut rt = Runtime::new().expect("runtime");
+        let io = rt.block_on(connect.call(addr)).expect("must connect");
+        assert_eq!(linux::mark(&io).expect("must get mark"), 0x2a);
+
+        let mut connect = svc::<SocketAddr>();
+        let io = rt.block_on(connect.call(addr)).expect("must connect");
+        assert_eq!(linux::mark(&io).expect("must get mark"), 0);
+    }
+}
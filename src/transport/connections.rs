@@ -0,0 +1,368 @@
+//! Counts the connections open to each endpoint and, if configured, limits
+//! them, so that a single endpoint cannot exhaust the proxy's file
+//! descriptors.
+//!
+//! Connections to an endpoint that is at its limit wait until another
+//! connection to the endpoint is closed, so they are bounded by any connect
+//! timeout that wraps this layer.
+
+use bytes::Buf;
+use futures::{task, Async, Future, Poll};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use svc;
+use transport::{connect::HasPeerAddr, tls, Peek};
+
+/// Counts the connections open to each endpoint.
+///
+/// Clones share the same counts.
+#[derive(Clone, Debug, Default)]
+pub struct Connections(Arc<Mutex<HashMap<SocketAddr, Endpoint>>>);
+
+#[derive(Debug, Default)]
+struct Endpoint {
+    open: usize,
+    /// Tasks waiting for a connection to the endpoint to close. Each task is
+    /// registered once, however often it is polled.
+    waiting: Vec<task::Task>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Layer {
+    connections: Connections,
+    max_per_endpoint: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Connect<M> {
+    connections: Connections,
+    max_per_endpoint: Option<usize>,
+    inner: M,
+}
+
+pub enum ConnectFuture<T, M: svc::MakeConnection<T>> {
+    /// Waiting for the number of connections to the endpoint to fall below
+    /// the limit.
+    Waiting {
+        addr: SocketAddr,
+        max: usize,
+        connections: Connections,
+        target: Option<T>,
+        inner: M,
+    },
+    Connecting {
+        future: M::Future,
+        guard: Option<Guard>,
+    },
+}
+
+/// Counts a connection as open until it is dropped.
+#[derive(Debug)]
+pub struct Guard {
+    addr: SocketAddr,
+    connections: Connections,
+}
+
+/// A connection that is counted as open until it is dropped.
+#[derive(Debug)]
+pub struct Tracked<I> {
+    io: I,
+    _guard: Guard,
+}
+
+// === impl Connections ===
+
+impl Connections {
+    /// Returns a `Layer` that counts connections with these counts, without
+    /// limiting them.
+    pub fn layer(&self) -> Layer {
+        Layer {
+            connections: self.clone(),
+            max_per_endpoint: None,
+        }
+    }
+
+    /// Returns the number of connections open to `addr`.
+    pub fn get(&self, addr: &SocketAddr) -> usize {
+        let endpoints = self.0.lock().expect("connections lock poisoned");
+        endpoints.get(addr).map(|e| e.open).unwrap_or(0)
+    }
+
+    /// Counts a new connection to `addr`, unless `max` connections are
+    /// already open to it, in which case the current task is notified when
+    /// one of them is closed.
+    fn try_open(&self, addr: SocketAddr, max: Option<usize>) -> Option<Guard> {
+        let mut endpoints = self.0.lock().expect("connections lock poisoned");
+        let endpoint = endpoints.entry(addr).or_insert_with(Endpoint::default);
+        if max.map(|max| endpoint.open >= max).unwrap_or(false) {
+            if !endpoint.waiting.iter().any(|t| t.will_notify_current()) {
+                endpoint.waiting.push(task::current());
+            }
+            return None;
+        }
+
+        endpoint.open += 1;
+        Some(Guard {
+            addr,
+            connections: self.clone(),
+        })
+    }
+}
+
+// === impl Layer ===
+
+pub fn layer() -> Layer {
+    Connections::default().layer()
+}
+
+impl Layer {
+    /// Limits the number of connections that may be open to each endpoint.
+    ///
+    /// By default, connections are counted but not limited.
+    pub fn with_max_per_endpoint(self, max: usize) -> Self {
+        Self {
+            max_per_endpoint: Some(max),
+            ..self
+        }
+    }
+
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+}
+
+impl<M> svc::Layer<M> for Layer {
+    type Service = Connect<M>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        Connect {
+            connections: self.connections.clone(),
+            max_per_endpoint: self.max_per_endpoint,
+            inner,
+        }
+    }
+}
+
+// === impl Connect ===
+
+/// impl MakeConnection
+impl<T, M> svc::Service<T> for Connect<M>
+where
+    T: HasPeerAddr,
+    M: svc::MakeConnection<T> + Clone,
+{
+    type Response = Tracked<M::Connection>;
+    type Error = M::Error;
+    type Future = ConnectFuture<T, M>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let addr = target.peer_addr();
+        match self.max_per_endpoint {
+            Some(max) => ConnectFuture::Waiting {
+                addr,
+                max,
+                connections: self.connections.clone(),
+                target: Some(target),
+                inner: self.inner.clone(),
+            },
+            None => ConnectFuture::Connecting {
+                guard: self.connections.try_open(addr, None),
+                future: self.inner.make_connection(target),
+            },
+        }
+    }
+}
+
+// === impl ConnectFuture ===
+
+impl<T, M> Future for ConnectFuture<T, M>
+where
+    M: svc::MakeConnection<T>,
+{
+    type Item = Tracked<M::Connection>;
+    type Error = M::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            *self = match *self {
+                ConnectFuture::Waiting {
+                    addr,
+                    max,
+                    ref connections,
+                    ref mut target,
+                    ref mut inner,
+                } => {
+                    try_ready!(inner.poll_ready());
+                    let guard = match connections.try_open(addr, Some(max)) {
+                        Some(guard) => guard,
+                        None => {
+                            trace!("waiting for a connection to {} to close", addr);
+                            return Ok(Async::NotReady);
+                        }
+                    };
+                    let target = target.take().expect("polled after ready");
+                    ConnectFuture::Connecting {
+                        future: inner.make_connection(target),
+                        guard: Some(guard),
+                    }
+                }
+                ConnectFuture::Connecting {
+                    ref mut future,
+                    ref mut guard,
+                } => {
+                    let io = try_ready!(future.poll());
+                    let guard = guard.take().expect("polled after ready");
+                    return Ok(Async::Ready(Tracked { io, _guard: guard }));
+                }
+            };
+        }
+    }
+}
+
+// === impl Guard ===
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut endpoints = match self.connections.0.lock() {
+            Ok(endpoints) => endpoints,
+            Err(_) => return,
+        };
+        let closed = match endpoints.get_mut(&self.addr) {
+            Some(endpoint) => {
+                endpoint.open -= 1;
+                for task in endpoint.waiting.drain(..) {
+                    task.notify();
+                }
+                endpoint.open == 0
+            }
+            None => false,
+        };
+        if closed {
+            endpoints.remove(&self.addr);
+        }
+    }
+}
+
+// === impl Tracked ===
+
+impl<I: io::Read> io::Read for Tracked<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl<I: io::Write> io::Write for Tracked<I> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<I: AsyncRead> AsyncRead for Tracked<I> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.io.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<I: AsyncWrite> AsyncWrite for Tracked<I> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        self.io.write_buf(buf)
+    }
+}
+
+impl<I: Peek> Peek for Tracked<I> {
+    fn poll_peek(&mut self) -> Poll<usize, io::Error> {
+        self.io.poll_peek()
+    }
+
+    fn peeked(&self) -> &[u8] {
+        self.io.peeked()
+    }
+}
+
+impl<I: tls::HasPeerIdentity> tls::HasPeerIdentity for Tracked<I> {
+    fn peer_identity(&self) -> tls::PeerIdentity {
+        self.io.peer_identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::net::TcpListener;
+    use svc::Service;
+    use tokio::net::TcpStream;
+    use tokio::runtime::current_thread::Runtime;
+    use transport::connect;
+
+    #[test]
+    fn connections_per_endpoint_do_not_exceed_max() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("must bind");
+        let addr = listener.local_addr().expect("must have an address");
+        let other = TcpListener::bind("127.0.0.1:0").expect("must bind");
+        let other = other.local_addr().expect("must have an address");
+
+        let layer = layer().with_max_per_endpoint(2);
+        let connections = layer.connections().clone();
+        let mut connect = svc::Layer::layer(&layer, connect::svc::<SocketAddr>());
+
+        let mut rt = Runtime::new().expect("runtime");
+        let first = rt.block_on(connect.call(addr)).expect("must connect");
+        let second = rt.block_on(connect.call(addr)).expect("must connect");
+        assert_eq!(connections.get(&addr), 2);
+
+        // Connections to another endpoint are not limited.
+        let _other = rt.block_on(connect.call(other)).expect("must connect");
+        assert_eq!(connections.get(&other), 1);
+
+        // A third connection waits for one of the others to close.
+        let mut third = connect.call(addr);
+        let waiting = rt
+            .block_on(future::lazy(|| {
+                Ok::<_, ()>(third.poll().expect("must not fail").is_not_ready())
+            }))
+            .unwrap();
+        assert!(waiting, "connections must wait for the max");
+        assert_eq!(connections.get(&addr), 2);
+
+        drop(first);
+        assert_eq!(connections.get(&addr), 1);
+        let third: Tracked<TcpStream> = rt.block_on(third).expect("must connect");
+        assert_eq!(connections.get(&addr), 2);
+
+        drop((second, third));
+        assert_eq!(connections.get(&addr), 0);
+    }
+
+    #[test]
+    fn waiting_connections_are_registered_once() {
+        let connections = Connections::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let _open = connections.try_open(addr, Some(1)).expect("must open");
+
+        let waiting = future::lazy(|| {
+            for _ in 0..1000 {
+                assert!(connections.try_open(addr, Some(1)).is_none());
+            }
+            let endpoints = connections.0.lock().unwrap();
+            Ok::<_, ()>(endpoints[&addr].waiting.len())
+        });
+        assert_eq!(waiting.wait().unwrap(), 1);
+    }
+}
@@ -67,6 +67,10 @@ impl AddrInfo for BoxedIo {
         self.0.local_addr()
     }
 
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.0.peer_addr()
+    }
+
     fn get_original_dst(&self) -> Option<SocketAddr> {
         self.0.get_original_dst()
     }
@@ -150,6 +154,10 @@ mod tests {
             unreachable!("not called in test")
         }
 
+        fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+            unreachable!("not called in test")
+        }
+
         fn get_original_dst(&self) -> Option<SocketAddr> {
             unreachable!("not called in test")
         }
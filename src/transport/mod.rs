@@ -1,26 +1,32 @@
 mod addr_info;
 pub mod connect;
+pub mod connections;
 mod io;
 pub mod keepalive;
 pub mod metrics;
 mod peek;
 mod prefixed;
+pub mod proxy_protocol;
 pub mod tls;
 
 pub use self::{
-    addr_info::{AddrInfo, GetOriginalDst, SoOriginalDst},
+    addr_info::{AddrInfo, GetOriginalDst, MapLayout, MapLookup, MapOriginalDst, SoOriginalDst},
     io::BoxedIo,
     keepalive::SetKeepalive,
     peek::Peek,
     tls::{Connection, Listen},
 };
 
+#[cfg(target_os = "linux")]
+pub use self::addr_info::PinnedMap;
+
 // Misc.
 
-fn set_nodelay_or_warn(socket: &::tokio::net::TcpStream) {
-    if let Err(e) = socket.set_nodelay(true) {
+fn set_nodelay_or_warn(socket: &::tokio::net::TcpStream, nodelay: bool) {
+    if let Err(e) = socket.set_nodelay(nodelay) {
         warn!(
-            "could not set TCP_NODELAY on {:?}/{:?}: {}",
+            "could not set TCP_NODELAY={} on {:?}/{:?}: {}",
+            nodelay,
             socket.local_addr(),
             socket.peer_addr(),
             e
@@ -90,6 +90,10 @@ where
         self.io.local_addr()
     }
 
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.io.peer_addr()
+    }
+
     fn get_original_dst(&self) -> Option<SocketAddr> {
         self.io.get_original_dst()
     }
@@ -0,0 +1,281 @@
+//! Writes PROXY protocol (v2) headers on outbound connections, so that an
+//! upstream behind its own L4 load balancer learns the address of the client
+//! whose connection is being forwarded.
+//!
+//! The header is written as soon as the connection is established, before
+//! any application bytes, so this layer must wrap the raw connection (i.e.
+//! beneath the TLS client, for upstreams that terminate TLS behind the load
+//! balancer).
+
+use futures::{Async, Future, Poll};
+use indexmap::IndexSet;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, WriteAll};
+
+pub use proxy::http::forwarded::ProxyHeader;
+use svc;
+
+/// The signature that begins every v2 header.
+const SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// Protocol version 2, `PROXY` command.
+const VERSION_2_PROXY: u8 = 0x21;
+
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// Describes the connection that a target's connection forwards, if any.
+pub trait HasProxyHeader {
+    fn proxy_header(&self) -> Option<ProxyHeader>;
+}
+
+#[derive(Clone, Debug)]
+pub struct Layer {
+    ports: Arc<IndexSet<u16>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Connect<M> {
+    ports: Arc<IndexSet<u16>>,
+    inner: M,
+}
+
+pub enum ConnectFuture<F: Future> {
+    Connecting {
+        future: F,
+        /// The encoded header, if one is written on the connection.
+        header: Option<Vec<u8>>,
+    },
+    Writing(WriteAll<F::Item, Vec<u8>>),
+}
+
+/// Encodes `header` as a v2 `PROXY` header over TCP.
+///
+/// If the source and destination addresses are of different families, both
+/// are described as IPv6 addresses.
+pub fn encode(header: &ProxyHeader) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(SIGNATURE.len() + 4 + 36);
+    buf.extend_from_slice(&SIGNATURE);
+    buf.push(VERSION_2_PROXY);
+    match (header.source.ip(), header.destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            buf.push(TCP_OVER_IPV4);
+            buf.extend_from_slice(&12u16.to_be_bytes());
+            buf.extend_from_slice(&src.octets());
+            buf.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            buf.push(TCP_OVER_IPV6);
+            buf.extend_from_slice(&36u16.to_be_bytes());
+            buf.extend_from_slice(&to_ipv6(src).octets());
+            buf.extend_from_slice(&to_ipv6(dst).octets());
+        }
+    }
+    buf.extend_from_slice(&header.source.port().to_be_bytes());
+    buf.extend_from_slice(&header.destination.port().to_be_bytes());
+    buf
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+// === impl Layer ===
+
+pub fn layer() -> Layer {
+    Layer {
+        ports: Arc::new(IndexSet::new()),
+    }
+}
+
+impl Layer {
+    /// Configures the destination ports for which PROXY headers are written.
+    ///
+    /// By default, headers are never written.
+    pub fn with_ports(self, ports: IndexSet<u16>) -> Self {
+        Self {
+            ports: Arc::new(ports),
+        }
+    }
+}
+
+impl<M> svc::Layer<M> for Layer {
+    type Service = Connect<M>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        Connect {
+            ports: self.ports.clone(),
+            inner,
+        }
+    }
+}
+
+// === impl Connect ===
+
+/// impl MakeConnection
+impl<T, M> svc::Service<T> for Connect<M>
+where
+    T: HasProxyHeader,
+    M: svc::MakeConnection<T>,
+    M::Connection: AsyncWrite,
+    M::Error: From<io::Error>,
+{
+    type Response = M::Connection;
+    type Error = M::Error;
+    type Future = ConnectFuture<M::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let ports = &self.ports;
+        let header = target
+            .proxy_header()
+            .filter(|h| ports.contains(&h.destination.port()))
+            .map(|h| {
+                debug!("writing PROXY header: {:?}", h);
+                encode(&h)
+            });
+        ConnectFuture::Connecting {
+            future: self.inner.make_connection(target),
+            header,
+        }
+    }
+}
+
+// === impl ConnectFuture ===
+
+impl<F> Future for ConnectFuture<F>
+where
+    F: Future,
+    F::Item: AsyncWrite,
+    F::Error: From<io::Error>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            *self = match *self {
+                ConnectFuture::Connecting {
+                    ref mut future,
+                    ref mut header,
+                } => {
+                    let conn = try_ready!(future.poll());
+                    match header.take() {
+                        Some(header) => {
+                            ConnectFuture::Writing(::tokio::io::write_all(conn, header))
+                        }
+                        None => return Ok(Async::Ready(conn)),
+                    }
+                }
+                ConnectFuture::Writing(ref mut write) => {
+                    let (conn, _) = try_ready!(write.poll());
+                    return Ok(Async::Ready(conn));
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpListener};
+    use svc::{Layer as _Layer, Service as _Service};
+    use tokio::runtime::current_thread::Runtime;
+    use transport::connect;
+
+    struct Target {
+        addr: SocketAddr,
+        header: ProxyHeader,
+    }
+
+    impl connect::HasPeerAddr for Target {
+        fn peer_addr(&self) -> SocketAddr {
+            self.addr
+        }
+    }
+
+    impl HasProxyHeader for Target {
+        fn proxy_header(&self) -> Option<ProxyHeader> {
+            Some(self.header)
+        }
+    }
+
+    #[test]
+    fn encodes_ipv4_headers() {
+        let header = ProxyHeader {
+            source: SocketAddr::from(([192, 0, 2, 60], 47011)),
+            destination: SocketAddr::from(([10, 0, 0, 2], 80)),
+        };
+        let mut expected = SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        expected.extend_from_slice(&[192, 0, 2, 60, 10, 0, 0, 2]);
+        expected.extend_from_slice(&[0xB7, 0xA3, 0, 80]);
+        assert_eq!(encode(&header), expected);
+    }
+
+    #[test]
+    fn encodes_mixed_families_as_ipv6() {
+        let header = ProxyHeader {
+            source: SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x17], 4711)),
+            destination: SocketAddr::from(([10, 0, 0, 2], 80)),
+        };
+        let encoded = encode(&header);
+        assert_eq!(&encoded[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(&encoded[16..32], &to_ipv6(header.source.ip()).octets());
+        assert_eq!(
+            &encoded[32..48],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 2]
+        );
+        assert_eq!(&encoded[48..], &[0x12, 0x67, 0, 80]);
+    }
+
+    /// Connects to a listener through the layer, writes `request`, and
+    /// returns the bytes that the listener received.
+    fn received(ports: IndexSet<u16>, header: ProxyHeader, request: &[u8]) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("must bind");
+        let addr = listener.local_addr().expect("must have an address");
+        let mut connect = layer().with_ports(ports).layer(connect::svc());
+
+        let mut rt = Runtime::new().expect("runtime");
+        let conn = rt
+            .block_on(connect.call(Target { addr, header }))
+            .expect("must connect");
+        rt.block_on(::tokio::io::write_all(conn, request.to_vec()))
+            .expect("must write request");
+
+        let (mut accepted, _) = listener.accept().expect("must accept");
+        let mut buf = Vec::new();
+        accepted.read_to_end(&mut buf).expect("must read");
+        buf
+    }
+
+    #[test]
+    fn header_precedes_the_request() {
+        let header = ProxyHeader {
+            source: SocketAddr::from(([192, 0, 2, 60], 47011)),
+            destination: SocketAddr::from(([10, 0, 0, 2], 5432)),
+        };
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        let ports = vec![5432].into_iter().collect::<IndexSet<_>>();
+
+        let buf = received(ports, header, request);
+        let mut expected = encode(&header);
+        expected.extend_from_slice(request);
+        assert_eq!(buf, expected);
+
+        let buf = received(IndexSet::new(), header, request);
+        assert_eq!(buf, request.to_vec(), "headers must only be written for enabled ports");
+    }
+}
@@ -0,0 +1,173 @@
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fmt;
+
+use super::{rustls::ProtocolVersion, Connection, HasPeerIdentity, PeerIdentity};
+use identity;
+use Conditional;
+
+/// Summarizes the TLS state of a finished connection for access logging.
+///
+/// The peer identity is the server's identity on outbound connections and
+/// the client's identity on inbound connections. When the peer has no
+/// identity, the reason is recorded instead.
+///
+/// Whether the connection was shut down cleanly is only known once it has
+/// been closed, so records should be obtained via `Connection::on_close`.
+#[derive(Clone, Debug)]
+pub struct TlsAccessRecord {
+    pub peer_identity: PeerIdentity,
+    pub server_identity: Option<identity::Name>,
+    pub version: Option<ProtocolVersion>,
+    pub alpn_protocol: Option<String>,
+    pub shutdown_clean: bool,
+}
+
+impl<'a> From<&'a Connection> for TlsAccessRecord {
+    fn from(conn: &'a Connection) -> Self {
+        Self {
+            peer_identity: conn.peer_identity(),
+            server_identity: conn.server_identity().cloned(),
+            version: conn.tls_version(),
+            alpn_protocol: conn.alpn_protocol().map(String::from),
+            shutdown_clean: conn.is_shutdown_clean(),
+        }
+    }
+}
+
+/// Formats the record as space-delimited `key=value` pairs, e.g.
+/// `tls=true peer_id=web.ns.serviceaccount.identity.linkerd.cluster.local
+/// server_id=api.ns.serviceaccount.identity.linkerd.cluster.local
+/// version=TLSv1_2 alpn=h2 shutdown=clean`.
+impl fmt::Display for TlsAccessRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peer_identity {
+            Conditional::Some(ref id) => write!(f, "tls=true peer_id={}", id.as_ref())?,
+            Conditional::None(why) => write!(f, "tls=false no_tls_reason={}", why)?,
+        }
+
+        if let Some(ref id) = self.server_identity {
+            write!(f, " server_id={}", id.as_ref())?;
+        }
+
+        if let Some(version) = self.version {
+            write!(f, " version={:?}", version)?;
+        }
+
+        if let Some(ref alpn) = self.alpn_protocol {
+            write!(f, " alpn={}", alpn)?;
+        }
+
+        let shutdown = if self.shutdown_clean { "clean" } else { "unclean" };
+        write!(f, " shutdown={}", shutdown)
+    }
+}
+
+/// Serializes the record as a struct with the same keys as its `Display`
+/// representation. Absent values are serialized as empty options so that
+/// every record has the same shape.
+impl Serialize for TlsAccessRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (peer_id, no_tls_reason): (Option<&str>, _) = match self.peer_identity {
+            Conditional::Some(ref id) => (Some(id.as_ref()), None),
+            Conditional::None(why) => (None, Some(why.to_string())),
+        };
+        let server_id: Option<&str> = self.server_identity.as_ref().map(|id| id.as_ref());
+
+        let mut record = serializer.serialize_struct("TlsAccessRecord", 7)?;
+        record.serialize_field("tls", &peer_id.is_some())?;
+        record.serialize_field("peer_id", &peer_id)?;
+        record.serialize_field("no_tls_reason", &no_tls_reason)?;
+        record.serialize_field("server_id", &server_id)?;
+        record.serialize_field("version", &self.version.map(|v| format!("{:?}", v)))?;
+        record.serialize_field("alpn", &self.alpn_protocol)?;
+        record.serialize_field("shutdown_clean", &self.shutdown_clean)?;
+        record.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use serde_json;
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+    use tokio::{net::TcpStream, reactor::Handle};
+    use transport::tls::{ReasonForNoIdentity, ReasonForNoPeerName};
+    use transport::BoxedIo;
+
+    fn socket() -> (TcpStream, StdTcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("must bind");
+        let addr = listener.local_addr().expect("must have an address");
+        let stream = StdTcpStream::connect(addr).expect("must connect");
+        let (peer, _) = listener.accept().expect("must accept");
+        let stream = TcpStream::from_std(stream, &Handle::default()).expect("must register");
+        (stream, peer)
+    }
+
+    fn name(host: &str) -> identity::Name {
+        identity::Name::from_hostname(host.as_bytes()).expect("name must be valid")
+    }
+
+    #[test]
+    fn records_tls_state() {
+        let client = name("web.ns.serviceaccount.identity.linkerd.cluster.local");
+        let server = name("api.ns.serviceaccount.identity.linkerd.cluster.local");
+        let (io, _peer) = socket();
+        let conn = Connection::tls(
+            BoxedIo::new(io),
+            Conditional::Some(client.clone()),
+            server.clone(),
+            Some(ProtocolVersion::TLSv1_2),
+        )
+        .with_alpn_protocol(Some("h2".to_owned()));
+
+        let record = TlsAccessRecord::from(&conn);
+        assert_eq!(record.peer_identity, Conditional::Some(client));
+        assert_eq!(record.server_identity, Some(server));
+        assert_eq!(record.alpn_protocol, Some("h2".to_owned()));
+        assert!(!record.shutdown_clean);
+
+        assert_eq!(
+            record.to_string(),
+            "tls=true \
+             peer_id=web.ns.serviceaccount.identity.linkerd.cluster.local \
+             server_id=api.ns.serviceaccount.identity.linkerd.cluster.local \
+             version=TLSv1_2 alpn=h2 shutdown=unclean",
+        );
+        assert_eq!(
+            serde_json::to_string(&record).expect("record must serialize"),
+            "{\"tls\":true,\
+             \"peer_id\":\"web.ns.serviceaccount.identity.linkerd.cluster.local\",\
+             \"no_tls_reason\":null,\
+             \"server_id\":\"api.ns.serviceaccount.identity.linkerd.cluster.local\",\
+             \"version\":\"TLSv1_2\",\"alpn\":\"h2\",\"shutdown_clean\":false}",
+        );
+    }
+
+    #[test]
+    fn records_are_captured_when_connections_close() {
+        let (io, _peer) = socket();
+        let why = ReasonForNoIdentity::from(ReasonForNoPeerName::NotProvidedByRemote);
+        let mut conn = Connection::plain(io, why);
+        let closed = conn.on_close();
+
+        let conn = ::tokio::io::shutdown(conn)
+            .wait()
+            .expect("connection must shut down");
+        drop(conn);
+
+        let record = closed.wait().expect("record must be captured");
+        assert_eq!(record.peer_identity, Conditional::None(why));
+        assert!(record.shutdown_clean);
+        assert_eq!(
+            record.to_string(),
+            "tls=false no_tls_reason=not_provided_by_remote shutdown=clean",
+        );
+        assert_eq!(
+            serde_json::to_string(&record).expect("record must serialize"),
+            "{\"tls\":false,\"peer_id\":null,\
+             \"no_tls_reason\":\"not_provided_by_remote\",\"server_id\":null,\
+             \"version\":null,\"alpn\":null,\"shutdown_clean\":true}",
+        );
+    }
+}
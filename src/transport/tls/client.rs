@@ -1,7 +1,11 @@
 use futures::{Async, Future, Poll};
+use futures_watch::Watch;
 use std::sync::Arc;
+use std::time::Instant;
 use std::{fmt, io};
+use tokio_timer::clock;
 
+use super::verify::verify_identity;
 use identity;
 use svc;
 use transport::{io::internal::Io, tls, BoxedIo, Connection};
@@ -9,16 +13,25 @@ use Conditional;
 
 pub use super::rustls::ClientConfig as Config;
 
+/// Provides the configuration used to initiate TLS connections.
+///
+/// The configuration is obtained as each handshake begins, so implementations
+/// may change their configuration (e.g. as certificates are rotated) without
+/// the connector being rebuilt.
 pub trait HasConfig {
     fn tls_client_config(&self) -> Arc<Config>;
 }
 
 #[derive(Clone, Debug)]
-pub struct Layer<L>(tls::Conditional<L>);
+pub struct Layer<L> {
+    local: tls::Conditional<L>,
+    sni: Option<identity::Name>,
+}
 
 #[derive(Clone, Debug)]
 pub struct Connect<L, C> {
     local: tls::Conditional<L>,
+    sni: Option<identity::Name>,
     inner: C,
 }
 
@@ -27,17 +40,45 @@ pub enum ConnectFuture<L, F: Future> {
     Init {
         future: F,
         tls: tls::Conditional<(identity::Name, L)>,
+        sni: Option<identity::Name>,
     },
     Handshake {
         future: tls::tokio_rustls::Connect<F::Item>,
         server_name: identity::Name,
+        started: Instant,
     },
 }
 
+// === impl HasConfig ===
+
+/// Uses the most recently published configuration, so that replacing the
+/// watched value (e.g. with a newly-issued certificate) takes effect for new
+/// connections. Handshakes that have already started complete with the
+/// configuration that they were started with.
+impl<L: HasConfig> HasConfig for Watch<L> {
+    fn tls_client_config(&self) -> Arc<Config> {
+        self.borrow().tls_client_config()
+    }
+}
+
 // === impl Layer ===
 
 pub fn layer<L: HasConfig + Clone>(l: tls::Conditional<L>) -> Layer<L> {
-    Layer(l)
+    Layer { local: l, sni: None }
+}
+
+impl<L> Layer<L> {
+    /// Sends `sni` as the server name of each handshake, e.g. so that
+    /// connections may be routed by a gateway that serves many identities.
+    ///
+    /// Servers' certificates are still verified against the target's
+    /// identity. By default, the target's identity is sent as the SNI.
+    pub fn with_sni(self, sni: identity::Name) -> Self {
+        Self {
+            sni: Some(sni),
+            ..self
+        }
+    }
 }
 
 impl<L, C> svc::Layer<C> for Layer<L>
@@ -48,7 +89,8 @@ where
 
     fn layer(&self, inner: C) -> Self::Service {
         Connect {
-            local: self.0.clone(),
+            local: self.local.clone(),
+            sni: self.sni.clone(),
             inner,
         }
     }
@@ -81,6 +123,7 @@ where
         ConnectFuture::Init {
             future: self.inner.make_connection(target),
             tls,
+            sni: self.sni.clone(),
         }
     }
 }
@@ -100,17 +143,26 @@ where
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
             *self = match self {
-                ConnectFuture::Init { future, tls } => {
+                ConnectFuture::Init { future, tls, sni } => {
                     let io = try_ready!(future.poll());
 
                     match tls {
                         Conditional::Some((server_name, local_tls)) => {
                             trace!("initiating TLS to {}", server_name.as_ref());
-                            let future = tls::Connector::from(local_tls.tls_client_config())
-                                .connect(server_name.as_dns_name_ref(), io);
+                            let config = local_tls.tls_client_config();
+                            let future = match sni {
+                                None => tls::Connector::from(config)
+                                    .connect(server_name.as_dns_name_ref(), io),
+                                Some(sni) => {
+                                    trace!("sending SNI {}", sni.as_ref());
+                                    let config = verify_identity(config, server_name.clone());
+                                    tls::Connector::from(config).connect(sni.as_dns_name_ref(), io)
+                                }
+                            };
                             ConnectFuture::Handshake {
                                 future,
                                 server_name: server_name.clone(),
+                                started: clock::now(),
                             }
                         }
                         Conditional::None(why) => {
@@ -122,14 +174,168 @@ where
                 ConnectFuture::Handshake {
                     future,
                     server_name,
+                    started,
                 } => {
                     let io = try_ready!(future.poll());
+                    let (version, alpn) = {
+                        use super::rustls::Session;
+                        let session = io.get_ref().1;
+                        let alpn = session.get_alpn_protocol().map(String::from);
+                        (session.get_protocol_version(), alpn)
+                    };
                     let io = BoxedIo::new(super::TlsIo::from(io));
                     trace!("established TLS to {}", server_name.as_ref());
-                    let c = Connection::tls(io, Conditional::Some(server_name.clone()));
+                    let c = Connection::tls(
+                        io,
+                        Conditional::Some(server_name.clone()),
+                        server_name.clone(),
+                        version,
+                    )
+                    .with_alpn_protocol(alpn)
+                    .with_handshake_duration(clock::now() - *started);
                     return Ok(Async::Ready(c));
                 }
             };
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::rustls::{self, Session};
+    use super::super::webpki;
+    use super::*;
+    use identity::{test_util::*, CrtKey, Name};
+    use std::fs;
+    use transport::tls::listen::HasConfig as _HasConfig;
+
+    fn crt_der(s: &Strings) -> Vec<u8> {
+        fs::read(format!("src/identity/testdata/{}", s.crt)).expect("certificate must be read")
+    }
+
+    /// Drives a handshake to `server` in memory, returning the certificate
+    /// presented by the client.
+    fn handshake(mut c: rustls::ClientSession, server: &CrtKey) -> Vec<u8> {
+        let mut s = rustls::ServerSession::new(&server.tls_server_config());
+
+        while c.is_handshaking() || s.is_handshaking() {
+            let mut buf = Vec::new();
+            c.write_tls(&mut buf).expect("write to vec");
+            s.read_tls(&mut &buf[..]).expect("read from slice");
+            s.process_new_packets().expect("server handshake must succeed");
+
+            let mut buf = Vec::new();
+            s.write_tls(&mut buf).expect("write to vec");
+            c.read_tls(&mut &buf[..]).expect("read from slice");
+            c.process_new_packets().expect("client handshake must succeed");
+        }
+
+        let crts = s.get_peer_certificates().expect("client must present a certificate");
+        crts[0].as_ref().to_vec()
+    }
+
+    /// Serves `server`'s certificate regardless of the SNI server name.
+    struct IgnoreSni {
+        inner: Arc<dyn rustls::ResolvesServerCert>,
+        name: Name,
+    }
+
+    impl rustls::ResolvesServerCert for IgnoreSni {
+        fn resolve(
+            &self,
+            _: Option<webpki::DNSNameRef>,
+            sigschemes: &[rustls::SignatureScheme],
+        ) -> Option<rustls::sign::CertifiedKey> {
+            self.inner.resolve(Some(self.name.as_dns_name_ref()), sigschemes)
+        }
+    }
+
+    /// Drives a handshake to `server` in memory, returning the SNI server
+    /// name that the server received.
+    fn handshake_sni(
+        mut c: rustls::ClientSession,
+        server: &CrtKey,
+    ) -> Result<Option<String>, rustls::TLSError> {
+        let mut config = server.tls_server_config().as_ref().clone();
+        config.cert_resolver = Arc::new(IgnoreSni {
+            inner: config.cert_resolver.clone(),
+            name: server.tls_server_name(),
+        });
+        let mut s = rustls::ServerSession::new(&Arc::new(config));
+
+        while c.is_handshaking() || s.is_handshaking() {
+            let mut buf = Vec::new();
+            c.write_tls(&mut buf).expect("write to vec");
+            s.read_tls(&mut &buf[..]).expect("read from slice");
+            s.process_new_packets().expect("server handshake must succeed");
+
+            let mut buf = Vec::new();
+            s.write_tls(&mut buf).expect("write to vec");
+            c.read_tls(&mut &buf[..]).expect("read from slice");
+            c.process_new_packets()?;
+        }
+
+        Ok(s.get_sni_hostname().map(String::from))
+    }
+
+    #[test]
+    fn sni_override_does_not_change_verified_identity() {
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let client = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let gateway = Name::from_hostname(b"gateway.example.com").expect("name must be valid");
+        let connect = |identity: &Strings| {
+            let identity = Name::from_hostname(identity.name.as_bytes()).expect("valid name");
+            let config = verify_identity(client.tls_client_config(), identity);
+            rustls::ClientSession::new(&config, gateway.as_dns_name_ref())
+        };
+
+        let sni = handshake_sni(connect(&FOO_NS1), &server).expect("handshake must succeed");
+        assert_eq!(sni.as_ref().map(String::as_str), Some(gateway.as_ref()));
+
+        assert!(
+            handshake_sni(connect(&BAR_NS1), &server).is_err(),
+            "the server's certificate must be verified against the identity"
+        );
+
+        let unverified = rustls::ClientSession::new(
+            &client.tls_client_config(),
+            gateway.as_dns_name_ref(),
+        );
+        assert!(
+            handshake_sni(unverified, &server).is_err(),
+            "the server's certificate must not be valid for the SNI"
+        );
+    }
+
+    #[test]
+    fn watched_config_is_used_for_new_connections() {
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let server_name: Name = server.tls_server_name();
+        let connect = |local: &Watch<CrtKey>| {
+            let config = local.tls_client_config();
+            rustls::ClientSession::new(&config, server_name.as_dns_name_ref())
+        };
+
+        let bar = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let (local, mut store) = Watch::new(bar);
+
+        let before = connect(&local);
+        let in_flight = connect(&local);
+        assert_eq!(handshake(before, &server), crt_der(&BAR_NS1));
+
+        let foo = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        assert!(store.store(foo).is_ok(), "watch must not be dropped");
+
+        let after = connect(&local);
+        assert_eq!(
+            handshake(after, &server),
+            crt_der(&FOO_NS1),
+            "new connections must use the new certificate"
+        );
+        assert_eq!(
+            handshake(in_flight, &server),
+            crt_der(&BAR_NS1),
+            "started handshakes must use the prior certificate"
+        );
+    }
+}
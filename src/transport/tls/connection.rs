@@ -1,11 +1,15 @@
 use bytes::{Buf, BytesMut};
+use futures::sync::oneshot;
 use std::net::SocketAddr;
+use std::time::Duration;
 use std::{cmp, io};
 use tokio::prelude::*;
 
 use identity;
 use transport::io::internal::Io;
-use transport::tls::{ReasonForNoIdentity, ReasonForNoPeerName};
+use transport::tls::io::{BytesTransferred, Counted};
+use transport::tls::rustls::ProtocolVersion;
+use transport::tls::{ReasonForNoIdentity, ReasonForNoPeerName, TlsAccessRecord};
 use transport::{AddrInfo, BoxedIo, Peek, SetKeepalive};
 use Conditional;
 
@@ -18,7 +22,9 @@ use Conditional;
 /// subverted.
 #[derive(Debug)]
 pub struct Connection {
-    io: BoxedIo,
+    /// Counts the bytes transferred over the connection, whether or not it
+    /// is secured with TLS.
+    io: Counted<BoxedIo>,
 
     /// This buffer gets filled up when "peeking" bytes on this Connection.
     ///
@@ -31,12 +37,37 @@ pub struct Connection {
     /// Whether or not the connection is secured with TLS.
     tls_peer_identity: super::PeerIdentity,
 
+    /// The identity of the server side of the TLS session, if TLS was used.
+    ///
+    /// On accepted connections, this is the local identity under which the
+    /// connection was terminated; on established connections, it's the
+    /// remote peer's identity.
+    tls_server_identity: Option<identity::Name>,
+
+    /// The TLS protocol version negotiated during the handshake, if TLS was
+    /// used.
+    tls_version: Option<ProtocolVersion>,
+
+    /// The application protocol negotiated via ALPN during the TLS
+    /// handshake, if one was.
+    alpn_protocol: Option<String>,
+
+    /// The time taken to complete the TLS handshake, if TLS was used.
+    handshake_duration: Option<Duration>,
+
+    /// Set once the write side of the connection has been shut down without
+    /// error.
+    shutdown_clean: bool,
+
     /// If true, the proxy should attempt to detect the protocol for this
     /// connection. If false, protocol detection should be skipped.
     detect_protocol: bool,
 
     /// The connection's original destination address, if there was one.
     orig_dst: Option<SocketAddr>,
+
+    /// Notified with the connection's `TlsAccessRecord` when it is dropped.
+    on_close: Option<oneshot::Sender<TlsAccessRecord>>,
 }
 
 // === impl Connection ===
@@ -48,13 +79,19 @@ impl Connection {
 
     pub(super) fn without_protocol_detection<I: Io + 'static>(io: I) -> Self {
         Connection {
-            io: BoxedIo::new(io),
+            io: Counted::new(BoxedIo::new(io)),
             peek_buf: BytesMut::new(),
             tls_peer_identity: Conditional::None(ReasonForNoIdentity::NoPeerName(
                 ReasonForNoPeerName::NotHttp,
             )),
+            tls_server_identity: None,
+            tls_version: None,
+            alpn_protocol: None,
+            handshake_duration: None,
+            shutdown_clean: false,
             detect_protocol: false,
             orig_dst: None,
+            on_close: None,
         }
     }
 
@@ -64,29 +101,54 @@ impl Connection {
         why_no_tls: ReasonForNoIdentity,
     ) -> Self {
         Connection {
-            io: BoxedIo::new(io),
+            io: Counted::new(BoxedIo::new(io)),
             peek_buf,
             tls_peer_identity: Conditional::None(why_no_tls),
+            tls_server_identity: None,
+            tls_version: None,
+            alpn_protocol: None,
+            handshake_duration: None,
+            shutdown_clean: false,
             detect_protocol: true,
             orig_dst: None,
+            on_close: None,
         }
     }
 
     pub(super) fn tls(
         io: BoxedIo,
         tls_peer_identity: Conditional<identity::Name, super::ReasonForNoPeerName>,
+        tls_server_identity: identity::Name,
+        tls_version: Option<ProtocolVersion>,
     ) -> Self {
         Connection {
-            io: io,
+            io: Counted::new(io),
             peek_buf: BytesMut::new(),
             tls_peer_identity: tls_peer_identity.map_reason(|r| r.into()),
+            tls_server_identity: Some(tls_server_identity),
+            tls_version,
+            alpn_protocol: None,
+            handshake_duration: None,
+            shutdown_clean: false,
             detect_protocol: true,
             orig_dst: None,
+            on_close: None,
         }
     }
 
-    pub(super) fn with_original_dst(self, orig_dst: Option<SocketAddr>) -> Self {
-        Self { orig_dst, ..self }
+    pub(super) fn with_original_dst(mut self, orig_dst: Option<SocketAddr>) -> Self {
+        self.orig_dst = orig_dst;
+        self
+    }
+
+    pub(super) fn with_alpn_protocol(mut self, alpn_protocol: Option<String>) -> Self {
+        self.alpn_protocol = alpn_protocol;
+        self
+    }
+
+    pub(super) fn with_handshake_duration(mut self, handshake_duration: Duration) -> Self {
+        self.handshake_duration = Some(handshake_duration);
+        self
     }
 
     pub fn original_dst_addr(&self) -> Option<SocketAddr> {
@@ -100,6 +162,95 @@ impl Connection {
     pub fn should_detect_protocol(&self) -> bool {
         self.detect_protocol
     }
+
+    /// Returns the identity of the server side of the TLS session, if TLS was
+    /// used.
+    pub fn server_identity(&self) -> Option<&identity::Name> {
+        self.tls_server_identity.as_ref()
+    }
+
+    /// Returns the verified identity of the remote server.
+    ///
+    /// On connections established by the proxy, this is the identity that
+    /// the server's certificate was verified against; otherwise, the reason
+    /// that the server was not identified is returned.
+    pub fn remote_identity(&self) -> super::PeerIdentity {
+        match self.tls_server_identity {
+            Some(ref name) => Conditional::Some(name.clone()),
+            None => self.tls_peer_identity.clone(),
+        }
+    }
+
+    /// Checks that the remote server was identified as `expected`.
+    pub fn verify_matches(&self, expected: &identity::Name) -> Result<(), ReasonForNoIdentity> {
+        match self.remote_identity() {
+            Conditional::Some(ref name) if name == expected => Ok(()),
+            Conditional::Some(_) => Err(ReasonForNoIdentity::Mismatched),
+            Conditional::None(reason) => Err(reason),
+        }
+    }
+
+    /// Returns the negotiated TLS protocol version, if TLS was used.
+    pub fn tls_version(&self) -> Option<ProtocolVersion> {
+        self.tls_version
+    }
+
+    /// Returns the application protocol negotiated via ALPN, if TLS was used
+    /// and the peers agreed on a protocol.
+    pub fn alpn_protocol(&self) -> Option<&str> {
+        self.alpn_protocol.as_ref().map(String::as_str)
+    }
+
+    /// Returns the time taken to complete the TLS handshake, from when it
+    /// was started until the session was established, if TLS was used.
+    pub fn handshake_duration(&self) -> Option<Duration> {
+        self.handshake_duration
+    }
+
+    /// Returns the reason that the peer was not identified via mutual TLS, or
+    /// `None` if it was.
+    ///
+    /// On accepted connections, this explains why a client was accepted
+    /// without an identity, e.g. so that policy may allow or deny it.
+    pub fn inbound_tls_reason(&self) -> Option<ReasonForNoIdentity> {
+        match self.tls_peer_identity {
+            Conditional::Some(_) => None,
+            Conditional::None(reason) => Some(reason),
+        }
+    }
+
+    /// Returns the counts of bytes read from and written to the connection.
+    ///
+    /// The returned counts continue to be updated while the connection is
+    /// open, and may be read after it has been closed.
+    pub fn bytes_transferred(&self) -> BytesTransferred {
+        self.io.bytes_transferred().clone()
+    }
+
+    /// Returns true if the connection's write side was shut down cleanly.
+    pub fn is_shutdown_clean(&self) -> bool {
+        self.shutdown_clean
+    }
+
+    /// Returns a future that is satisfied with the connection's
+    /// `TlsAccessRecord` once the connection has been closed.
+    ///
+    /// The connection is usually owned by a server or client by the time it
+    /// closes, so this must be called beforehand. Only the future returned
+    /// by the most recent call is satisfied.
+    pub fn on_close(&mut self) -> oneshot::Receiver<TlsAccessRecord> {
+        let (tx, rx) = oneshot::channel();
+        self.on_close = Some(tx);
+        rx
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(tx) = self.on_close.take() {
+            let _ = tx.send(TlsAccessRecord::from(&*self));
+        }
+    }
 }
 
 impl super::HasPeerIdentity for Connection {
@@ -160,7 +311,9 @@ impl AsyncWrite for Connection {
         // anymore. So, we should tell the remote about this. This
         // is relied upon in our TCP proxy, to start shutting down
         // the pipe if one side closes.
-        self.io.shutdown_write().map(Async::Ready)
+        self.io.get_mut().shutdown_write()?;
+        self.shutdown_clean = true;
+        Ok(Async::Ready(()))
     }
 
     fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
@@ -192,3 +345,130 @@ impl Peek for Connection {
         self.peek_buf.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+    use tokio::{net::TcpStream, reactor::Handle};
+
+    fn socket() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("must bind");
+        let addr = listener.local_addr().expect("must have an address");
+        let stream = StdTcpStream::connect(addr).expect("must connect");
+        TcpStream::from_std(stream, &Handle::default()).expect("must register")
+    }
+
+    fn socket_pair() -> (TcpStream, StdTcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("must bind");
+        let addr = listener.local_addr().expect("must have an address");
+        let stream = StdTcpStream::connect(addr).expect("must connect");
+        let (peer, _) = listener.accept().expect("must accept");
+        let stream = TcpStream::from_std(stream, &Handle::default()).expect("must register");
+        (stream, peer)
+    }
+
+    #[test]
+    fn tls_connection_has_server_identity() {
+        let name = identity::Name::from_hostname(
+            b"foo.ns1.serviceaccount.identity.linkerd.cluster.local",
+        )
+        .expect("name must be valid");
+        let client = Conditional::None(ReasonForNoPeerName::NotProvidedByRemote);
+        let conn = Connection::tls(BoxedIo::new(socket()), client, name.clone(), None);
+        assert_eq!(conn.server_identity(), Some(&name));
+    }
+
+    #[test]
+    fn tls_connection_verifies_remote_identity() {
+        let name = identity::Name::from_hostname(
+            b"foo.ns1.serviceaccount.identity.linkerd.cluster.local",
+        )
+        .expect("name must be valid");
+        let other = identity::Name::from_hostname(
+            b"bar.ns1.serviceaccount.identity.linkerd.cluster.local",
+        )
+        .expect("name must be valid");
+
+        let conn = Connection::tls(
+            BoxedIo::new(socket()),
+            Conditional::Some(name.clone()),
+            name.clone(),
+            None,
+        );
+        assert_eq!(conn.remote_identity(), Conditional::Some(name.clone()));
+        assert_eq!(conn.verify_matches(&name), Ok(()));
+        assert_eq!(
+            conn.verify_matches(&other),
+            Err(ReasonForNoIdentity::Mismatched)
+        );
+
+        let why = ReasonForNoPeerName::NotProvidedByServiceDiscovery.into();
+        let plain = Connection::plain(socket(), why);
+        assert_eq!(plain.remote_identity(), Conditional::None(why));
+        assert_eq!(plain.verify_matches(&name), Err(why));
+    }
+
+    #[test]
+    fn inbound_tls_reasons() {
+        let name = identity::Name::from_hostname(
+            b"foo.ns1.serviceaccount.identity.linkerd.cluster.local",
+        )
+        .expect("name must be valid");
+
+        let loopback = Connection::plain(socket(), ReasonForNoPeerName::Loopback.into());
+        assert_eq!(
+            loopback.inbound_tls_reason(),
+            Some(ReasonForNoPeerName::Loopback.into())
+        );
+
+        let opaque = Connection::without_protocol_detection(socket());
+        assert_eq!(
+            opaque.inbound_tls_reason(),
+            Some(ReasonForNoPeerName::NotHttp.into())
+        );
+
+        let client = Conditional::None(ReasonForNoPeerName::NotProvidedByRemote);
+        let anonymous = Connection::tls(BoxedIo::new(socket()), client, name.clone(), None);
+        assert_eq!(
+            anonymous.inbound_tls_reason(),
+            Some(ReasonForNoPeerName::NotProvidedByRemote.into())
+        );
+
+        let client = Conditional::Some(name.clone());
+        let mutual = Connection::tls(BoxedIo::new(socket()), client, name, None);
+        assert_eq!(mutual.inbound_tls_reason(), None);
+    }
+
+    #[test]
+    fn counts_bytes_transferred() {
+        use std::io::{Read, Write};
+
+        let (io, mut peer) = socket_pair();
+        let conn = Connection::plain(io, ReasonForNoIdentity::Disabled);
+        let bytes = conn.bytes_transferred();
+
+        peer.write_all(b"hello, world").expect("peer must write");
+        let (conn, buf) = ::tokio::io::read_exact(conn, [0u8; 12])
+            .wait()
+            .expect("connection must read");
+        assert_eq!(&buf, b"hello, world");
+
+        let (conn, _) = ::tokio::io::write_all(conn, b"goodbye")
+            .wait()
+            .expect("connection must write");
+        let mut buf = [0u8; 7];
+        peer.read_exact(&mut buf).expect("peer must read");
+        assert_eq!(&buf, b"goodbye");
+
+        drop(conn);
+        assert_eq!(bytes.read(), 12);
+        assert_eq!(bytes.written(), 7);
+    }
+
+    #[test]
+    fn plaintext_connection_has_no_server_identity() {
+        let conn = Connection::plain(socket(), ReasonForNoIdentity::Disabled);
+        assert_eq!(conn.server_identity(), None);
+    }
+}
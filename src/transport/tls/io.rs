@@ -2,6 +2,10 @@ use bytes::Buf;
 use std::fmt::Debug;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use tokio::prelude::*;
 
 use super::{rustls::Session, tokio_rustls::TlsStream};
@@ -15,6 +19,26 @@ where
     S: Debug,
     C: Debug;
 
+/// Counts the bytes read from and written to a connection.
+///
+/// Clones share the same counts, so they may be read after the connection
+/// has been closed.
+#[derive(Clone, Debug, Default)]
+pub struct BytesTransferred(Arc<Counts>);
+
+#[derive(Debug, Default)]
+struct Counts {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+/// Wraps an `Io`, counting the bytes transferred over it.
+#[derive(Debug)]
+pub(super) struct Counted<I> {
+    inner: I,
+    bytes: BytesTransferred,
+}
+
 // === imp TlsIo ===
 
 impl<S, C> From<TlsStream<S, C>> for TlsIo<S, C>
@@ -84,6 +108,10 @@ where
         self.0.get_ref().0.local_addr()
     }
 
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.0.get_ref().0.peer_addr()
+    }
+
     fn get_original_dst(&self) -> Option<SocketAddr> {
         self.0.get_ref().0.get_original_dst()
     }
@@ -116,3 +144,106 @@ where
         self.0.write_buf(&mut buf)
     }
 }
+
+// === impl BytesTransferred ===
+
+impl BytesTransferred {
+    /// Returns the total number of bytes read from the connection.
+    pub fn read(&self) -> u64 {
+        self.0.read.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of bytes written to the connection.
+    pub fn written(&self) -> u64 {
+        self.0.written.load(Ordering::Relaxed)
+    }
+
+    fn add_read(&self, n: usize) {
+        self.0.read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn add_written(&self, n: usize) {
+        self.0.written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+// === impl Counted ===
+
+impl<I> Counted<I> {
+    pub(super) fn new(inner: I) -> Self {
+        Self {
+            inner,
+            bytes: BytesTransferred::default(),
+        }
+    }
+
+    pub(super) fn get_mut(&mut self) -> &mut I {
+        &mut self.inner
+    }
+
+    pub(super) fn bytes_transferred(&self) -> &BytesTransferred {
+        &self.bytes
+    }
+}
+
+impl<I: io::Read> io::Read for Counted<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.add_read(n);
+        Ok(n)
+    }
+}
+
+impl<I: AsyncRead> AsyncRead for Counted<I> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<I: io::Write> io::Write for Counted<I> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes.add_written(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<I: AsyncWrite> AsyncWrite for Counted<I> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        let n = try_ready!(self.inner.write_buf(buf));
+        self.bytes.add_written(n);
+        Ok(Async::Ready(n))
+    }
+}
+
+impl<I: AddrInfo> AddrInfo for Counted<I> {
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.inner.peer_addr()
+    }
+
+    fn get_original_dst(&self) -> Option<SocketAddr> {
+        self.inner.get_original_dst()
+    }
+}
+
+impl<I: SetKeepalive> SetKeepalive for Counted<I> {
+    fn keepalive(&self) -> io::Result<Option<::std::time::Duration>> {
+        self.inner.keepalive()
+    }
+
+    fn set_keepalive(&mut self, ka: Option<::std::time::Duration>) -> io::Result<()> {
+        self.inner.set_keepalive(ka)
+    }
+}
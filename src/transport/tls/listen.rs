@@ -7,13 +7,19 @@ use indexmap::IndexSet;
 use std::io;
 use std::net::{SocketAddr, TcpListener as StdListener};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::{
     io::AsyncRead,
     net::{TcpListener, TcpStream},
     reactor::Handle,
 };
+use tokio_sync::semaphore::{Permit, Semaphore};
+use tokio_timer::{clock, Delay};
 
+use super::ocsp::{OcspStaple, Stapler};
+use super::protocol_cache::{self, Detected, ProtocolCache};
 use super::{rustls, tokio_rustls, webpki};
+use drain;
 use identity;
 use transport::prefixed::Prefixed;
 use transport::tls::{self, conditional_accept, Acceptor, Connection, ReasonForNoPeerName};
@@ -38,13 +44,47 @@ pub struct Listen<L, G = ()> {
     local_addr: SocketAddr,
     tls: tls::Conditional<L>,
     disable_protocol_detection_ports: IndexSet<u16>,
+    protocol_cache: Option<ProtocolCache>,
+    /// When set, connections that are still being accepted are aborted once
+    /// a drain is signaled.
+    drain: Option<drain::Watch>,
+    /// When set, limits the number of TLS handshakes that are performed
+    /// concurrently.
+    handshakes: Option<Arc<Semaphore>>,
+    /// When set, TLS handshakes that do not complete within this duration
+    /// fail.
+    handshake_timeout: Option<Duration>,
+    /// Whether `TCP_NODELAY` is set on accepted sockets.
+    nodelay: bool,
+    /// When set, staples an OCSP response to the certificate served in TLS
+    /// handshakes.
+    ocsp: Option<Stapler>,
     get_original_dst: G,
 }
 
+/// A connection that is still being accepted, which is aborted (closing its
+/// socket) when the listener drains.
+struct Abortable<F>(Option<F>);
+
 /// A server socket that is in the process of conditionally upgrading to TLS.
 enum Handshake {
     Init(Option<Inner>),
-    Upgrade(super::Accept<Prefixed<TcpStream>>),
+    /// Waiting for one of the limited number of concurrent handshakes.
+    Acquiring(Option<Inner>, Option<HandshakePermit>),
+    Upgrade(
+        super::Accept<Prefixed<TcpStream>>,
+        identity::Name,
+        Instant,
+        Option<HandshakePermit>,
+        Option<Delay>,
+    ),
+}
+
+/// Permits a TLS handshake to be performed while the number of concurrent
+/// handshakes is limited. The permit is released when it is dropped.
+struct HandshakePermit {
+    semaphore: Arc<Semaphore>,
+    permit: Permit,
 }
 
 struct Inner {
@@ -52,6 +92,16 @@ struct Inner {
     config: Arc<Config>,
     server_name: identity::Name,
     peek_buf: BytesMut,
+    /// The cache to update with the detected protocol, and the client and
+    /// original destination address to record it for.
+    protocol_cache: Option<(ProtocolCache, protocol_cache::Key)>,
+    /// A previously-detected protocol that is used instead of peeking.
+    cached: Option<Detected>,
+    /// When the connection was accepted, i.e. when a TLS handshake would
+    /// have started.
+    started: Instant,
+    handshakes: Option<Arc<Semaphore>>,
+    handshake_timeout: Option<Duration>,
 }
 
 // === impl Listen ===
@@ -65,6 +115,12 @@ impl<L: HasConfig> Listen<L> {
             local_addr,
             tls,
             disable_protocol_detection_ports: IndexSet::new(),
+            protocol_cache: None,
+            drain: None,
+            handshakes: None,
+            handshake_timeout: None,
+            nodelay: true,
+            ocsp: None,
             get_original_dst: (),
         })
     }
@@ -78,6 +134,12 @@ impl<L: HasConfig> Listen<L> {
             local_addr: self.local_addr,
             tls: self.tls,
             disable_protocol_detection_ports: self.disable_protocol_detection_ports,
+            protocol_cache: self.protocol_cache,
+            drain: self.drain,
+            handshakes: self.handshakes,
+            handshake_timeout: self.handshake_timeout,
+            nodelay: self.nodelay,
+            ocsp: self.ocsp,
             get_original_dst,
         }
     }
@@ -94,6 +156,77 @@ impl<L: HasConfig, G> Listen<L, G> {
         }
     }
 
+    /// Caches the protocol detected on each connection by its client's IP
+    /// address and its original destination address for `ttl`.
+    ///
+    /// Subsequent connections from the same client to the same original
+    /// destination use the cached protocol rather than peeking for a TLS
+    /// client hello. Each such
+    /// connection instead detects its protocol with probability
+    /// `recheck_probability`, so that a change in the destination's protocol
+    /// is noticed before the cached protocol expires.
+    pub fn with_protocol_cache(self, ttl: Duration, recheck_probability: f64) -> Self {
+        Self {
+            protocol_cache: Some(ProtocolCache::new(ttl, recheck_probability)),
+            ..self
+        }
+    }
+
+    /// Aborts connections that are still being accepted (i.e. whose TLS
+    /// handshakes have not completed) once `drain` is signaled.
+    ///
+    /// Connections that have already been accepted are unaffected.
+    pub fn with_drain(self, drain: drain::Watch) -> Self {
+        Self {
+            drain: Some(drain),
+            ..self
+        }
+    }
+
+    /// Limits the number of TLS handshakes that are performed concurrently to
+    /// `max`, so that a burst of connections does not consume all available
+    /// CPU.
+    ///
+    /// Connections that are accepted while the limit is reached wait to start
+    /// their handshakes until others complete.
+    pub fn with_max_concurrent_handshakes(self, max: usize) -> Self {
+        Self {
+            handshakes: Some(Arc::new(Semaphore::new(max))),
+            ..self
+        }
+    }
+
+    /// Fails TLS handshakes that do not complete within `timeout`, so that
+    /// stalled handshakes do not hold their connections (or, if concurrent
+    /// handshakes are limited, their permits) indefinitely.
+    ///
+    /// By default, handshakes are not timed out.
+    pub fn with_handshake_timeout(self, timeout: Duration) -> Self {
+        Self {
+            handshake_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Sets whether `TCP_NODELAY` is set on accepted sockets, i.e. whether
+    /// Nagle's algorithm is disabled.
+    ///
+    /// By default, `TCP_NODELAY` is set.
+    pub fn with_nodelay(self, nodelay: bool) -> Self {
+        Self { nodelay, ..self }
+    }
+
+    /// Staples the OCSP response held by `staple` to the certificate served in
+    /// TLS handshakes, for clients that request certificate status.
+    ///
+    /// Updates to `staple` apply to subsequent handshakes.
+    pub fn with_ocsp_staple(self, staple: OcspStaple) -> Self {
+        Self {
+            ocsp: Some(Stapler::new(staple)),
+            ..self
+        }
+    }
+
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
@@ -180,9 +313,19 @@ impl<L: HasConfig, G> Listen<L, G> {
                     // doesn't work on all platforms and also the underlying
                     // libraries don't have the necessary API for that, so just
                     // do it here.
-                    set_nodelay_or_warn(&socket);
-
-                    self.new_conn(socket, remote_addr).then(move |r| {
+                    set_nodelay_or_warn(&socket, self.nodelay);
+
+                    let conn = self.new_conn(socket, remote_addr);
+                    let conn = match self.drain {
+                        Some(ref drain) => Either::A(
+                            drain
+                                .clone()
+                                .watch(Abortable(Some(conn)), |conn| conn.0 = None),
+                        ),
+                        None => Either::B(conn),
+                    };
+
+                    conn.then(move |r| {
                         future::ok(match r {
                             Ok(conn) => Some((conn, remote_addr)),
                             Err(err) => {
@@ -229,7 +372,24 @@ impl<L: HasConfig, G> Listen<L, G> {
                     "accepted connection from {} to {:?}; attempting TLS handshake",
                     remote_addr, dst,
                 );
-                let handshake = Handshake::new(socket, tls).map(move |c| c.with_original_dst(dst));
+                let protocol_cache = match (dst, &self.protocol_cache) {
+                    (Some(dst), Some(cache)) => {
+                        let key = protocol_cache::Key::new(remote_addr.ip(), dst);
+                        Some((cache.clone(), key))
+                    }
+                    _ => None,
+                };
+                let handshakes = self.handshakes.clone();
+                let timeout = self.handshake_timeout;
+                let handshake = Handshake::new(
+                    socket,
+                    tls,
+                    self.ocsp.as_ref(),
+                    protocol_cache,
+                    handshakes,
+                    timeout,
+                )
+                .map(move |c| c.with_original_dst(dst));
                 Either::B(Either::A(handshake))
             }
             // TLS is disabled. Return a new plaintext connection.
@@ -257,15 +417,51 @@ impl<L, G: GetOriginalDst> GetOriginalDst for Listen<L, G> {
     }
 }
 
+// === impl Abortable ===
+
+impl<F: Future<Error = io::Error>> Future for Abortable<F> {
+    type Item = F::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.0 {
+            Some(ref mut f) => f.poll(),
+            None => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "listener is draining",
+            )),
+        }
+    }
+}
+
 // === impl Handshake ===
 
 impl Handshake {
-    fn new<T: HasConfig>(socket: TcpStream, tls: &T) -> Self {
+    fn new<T: HasConfig>(
+        socket: TcpStream,
+        tls: &T,
+        ocsp: Option<&Stapler>,
+        protocol_cache: Option<(ProtocolCache, protocol_cache::Key)>,
+        handshakes: Option<Arc<Semaphore>>,
+        handshake_timeout: Option<Duration>,
+    ) -> Self {
+        let cached = protocol_cache
+            .as_ref()
+            .and_then(|&(ref cache, ref key)| cache.get(key));
+        let config = match ocsp {
+            Some(ocsp) => ocsp.config(tls.tls_server_config()),
+            None => tls.tls_server_config(),
+        };
         Handshake::Init(Some(Inner {
             socket,
             server_name: tls.tls_server_name(),
-            config: tls.tls_server_config(),
+            config,
             peek_buf: BytesMut::with_capacity(8192),
+            protocol_cache,
+            cached,
+            started: clock::now(),
+            handshakes,
+            handshake_timeout,
         }))
     }
 
@@ -301,8 +497,17 @@ impl Future for Handshake {
 
                     match try_ready!(poll_match) {
                         conditional_accept::Match::Matched => {
-                            trace!("upgrading accepted connection to TLS");
-                            inner.take().unwrap().into_tls_upgrade()
+                            let inner = inner.take().unwrap();
+                            match inner.handshakes.clone() {
+                                Some(semaphore) => {
+                                    let permit = HandshakePermit::new(semaphore);
+                                    Handshake::Acquiring(Some(inner), Some(permit))
+                                }
+                                None => {
+                                    trace!("upgrading accepted connection to TLS");
+                                    inner.into_tls_upgrade(None)
+                                }
+                            }
                         }
                         conditional_accept::Match::NotMatched => {
                             trace!("passing through accepted connection without TLS");
@@ -314,23 +519,86 @@ impl Future for Handshake {
                         }
                     }
                 }
-                Handshake::Upgrade(future) => {
-                    let io = try_ready!(future.poll());
+                Handshake::Acquiring(inner, permit) => {
+                    try_ready!(permit.as_mut().expect("polled after ready").poll_acquire());
+                    trace!("upgrading accepted connection to TLS");
+                    let inner = inner.take().expect("polled after ready");
+                    inner.into_tls_upgrade(permit.take())
+                }
+                Handshake::Upgrade(future, server_name, started, permit, timeout) => {
+                    let io = match future.poll()? {
+                        Async::Ready(io) => io,
+                        Async::NotReady => {
+                            let expired = match *timeout {
+                                Some(ref mut timeout) => timeout
+                                    .poll()
+                                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                                    .is_ready(),
+                                None => false,
+                            };
+                            if !expired {
+                                return Ok(Async::NotReady);
+                            }
+                            drop(permit.take());
+                            return Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "TLS handshake timed out",
+                            ));
+                        }
+                    };
+                    // Release the permit as soon as the handshake completes.
+                    drop(permit.take());
                     let client_id = Self::client_identity(&io)
                         .map(Conditional::Some)
                         .unwrap_or_else(|| {
                             Conditional::None(super::ReasonForNoPeerName::NotProvidedByRemote)
                         });
-                    trace!("accepted TLS connection; client={:?}", client_id);
-
+                    trace!(
+                        "accepted TLS connection; client={:?}; server={:?}",
+                        client_id,
+                        server_name
+                    );
+
+                    let (version, alpn) = {
+                        use super::rustls::Session;
+                        let session = io.get_ref().1;
+                        let alpn = session.get_alpn_protocol().map(String::from);
+                        (session.get_protocol_version(), alpn)
+                    };
                     let io = BoxedIo::new(super::TlsIo::from(io));
-                    return Ok(Async::Ready(Connection::tls(io, client_id)));
+                    let conn = Connection::tls(io, client_id, server_name.clone(), version)
+                        .with_alpn_protocol(alpn)
+                        .with_handshake_duration(clock::now() - *started);
+                    return Ok(Async::Ready(conn));
                 }
             }
         }
     }
 }
 
+// === impl HandshakePermit ===
+
+impl HandshakePermit {
+    fn new(semaphore: Arc<Semaphore>) -> Self {
+        Self {
+            semaphore,
+            permit: Permit::new(),
+        }
+    }
+
+    fn poll_acquire(&mut self) -> Poll<(), io::Error> {
+        self.permit
+            .poll_acquire(&self.semaphore)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "handshake limit closed"))
+    }
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        self.permit.release(&self.semaphore);
+    }
+}
+
 impl Inner {
     /// Polls the underlying socket for more data and buffers it.
     ///
@@ -338,6 +606,15 @@ impl Inner {
     ///
     /// `NotMatched` is returned if the underlying socket has closed.
     fn poll_match_client_hello(&mut self) -> Poll<conditional_accept::Match, io::Error> {
+        if let Some(detected) = self.cached {
+            trace!("using cached protocol: {:?}", detected);
+            let m = match detected {
+                Detected::Tls => conditional_accept::Match::Matched,
+                Detected::Plaintext => conditional_accept::Match::NotMatched,
+            };
+            return Ok(m.into());
+        }
+
         let sz = try_ready!(self.socket.read_buf(&mut self.peek_buf));
         if sz == 0 {
             // XXX: It is ambiguous whether this is the start of a TLS handshake or not.
@@ -347,13 +624,24 @@ impl Inner {
         }
 
         let buf = self.peek_buf.as_ref();
-        Ok(conditional_accept::match_client_hello(buf, &self.server_name).into())
+        let m = conditional_accept::match_client_hello(buf, &self.server_name);
+        if let Some((ref cache, key)) = self.protocol_cache {
+            match m {
+                conditional_accept::Match::Matched => cache.insert(key, Detected::Tls),
+                conditional_accept::Match::NotMatched => cache.insert(key, Detected::Plaintext),
+                conditional_accept::Match::Incomplete => {}
+            }
+        }
+        Ok(m.into())
     }
 
-    fn into_tls_upgrade(self) -> Handshake {
+    fn into_tls_upgrade(self, permit: Option<HandshakePermit>) -> Handshake {
         let future = Acceptor::from(self.config.clone())
             .accept(Prefixed::new(self.peek_buf.freeze(), self.socket));
-        Handshake::Upgrade(future)
+        let timeout = self
+            .handshake_timeout
+            .map(|timeout| Delay::new(clock::now() + timeout));
+        Handshake::Upgrade(future, self.server_name, self.started, permit, timeout)
     }
 
     fn into_plaintext(self) -> Connection {
@@ -364,3 +652,257 @@ impl Inner {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream as StdTcpStream;
+    use std::time::Instant;
+    use tokio::runtime::Runtime;
+
+    /// A TLS configuration that never completes a handshake.
+    struct Pending;
+
+    impl HasConfig for Pending {
+        fn tls_server_name(&self) -> identity::Name {
+            identity::Name::from_hostname(b"foo.ns1.serviceaccount.identity.linkerd.cluster.local")
+                .expect("name must be valid")
+        }
+
+        fn tls_server_config(&self) -> Arc<Config> {
+            empty_config()
+        }
+    }
+
+    #[test]
+    fn drain_aborts_pending_handshakes() {
+        let (drain_tx, drain_rx) = drain::channel();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listen = Listen::bind(addr, Conditional::Some(Pending))
+            .expect("must bind")
+            .with_drain(drain_rx);
+        let addr = listen.local_addr();
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.spawn(
+            listen
+                .listen_and_fold((), |(), _| -> Result<(), io::Error> {
+                    panic!("handshake must not complete")
+                })
+                .map_err(|e| panic!("listener failed: {}", e)),
+        );
+
+        // Start a handshake without completing its client hello.
+        let mut client = StdTcpStream::connect(addr).expect("must connect");
+        client.write_all(&[22, 3, 1]).expect("must write");
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert!(
+            client.read(&mut buf).is_err(),
+            "the handshake must be pending"
+        );
+
+        let _drained = drain_tx.drain();
+        let start = Instant::now();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        match client.read(&mut buf) {
+            Ok(0) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => {}
+            r => panic!("the handshake must be aborted: {:?}", r),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn tls_connections_record_handshake_duration() {
+        use identity::test_util::{BAR_NS1, FOO_NS1};
+        use std::sync::mpsc;
+        use transport::tls::client::HasConfig as _HasConfig;
+
+        let server = FOO_NS1.validate().expect("valid server credentials");
+        let client = BAR_NS1.validate().expect("valid client credentials");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listen = Listen::bind(addr, Conditional::Some(server.clone())).expect("must bind");
+        let addr = listen.local_addr();
+
+        let (tx, rx) = mpsc::channel();
+        let mut rt = Runtime::new().expect("runtime");
+        rt.spawn(
+            listen
+                .listen_and_fold(tx, |tx, (conn, _)| {
+                    tx.send(conn.handshake_duration()).expect("test must be running");
+                    Ok(tx)
+                })
+                .map_err(|e| panic!("listener failed: {}", e)),
+        );
+
+        let mut session = rustls::ClientSession::new(
+            &client.tls_client_config(),
+            server.tls_server_name().as_dns_name_ref(),
+        );
+        let mut socket = StdTcpStream::connect(addr).expect("must connect");
+        rustls::Stream::new(&mut session, &mut socket)
+            .write_all(b"hello")
+            .expect("handshake must succeed");
+
+        let duration = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("connection must be accepted");
+        assert!(duration.is_some(), "TLS connections must record a handshake duration");
+    }
+
+    /// A client socket that records the bytes it receives from the server.
+    struct Recorded {
+        socket: StdTcpStream,
+        received: Vec<u8>,
+    }
+
+    impl Read for Recorded {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.socket.read(buf)?;
+            self.received.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+    }
+
+    impl Write for Recorded {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.socket.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.socket.flush()
+        }
+    }
+
+    #[test]
+    fn stapled_ocsp_responses_are_served() {
+        use identity::test_util::{BAR_NS1, FOO_NS1};
+        use transport::tls::client::HasConfig as _HasConfig;
+
+        // The staple is not a valid OCSP response, but clients don't verify
+        // stapled responses. Servers only negotiate TLS 1.2 by default, which
+        // sends the staple in the clear, so it can be found in the handshake.
+        const STAPLE: &[u8] = b"linkerd2-proxy test OCSP response";
+
+        let server = FOO_NS1.validate().expect("valid server credentials");
+        let client = BAR_NS1.validate().expect("valid client credentials");
+        let staple = OcspStaple::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listen = Listen::bind(addr, Conditional::Some(server.clone()))
+            .expect("must bind")
+            .with_ocsp_staple(staple.clone());
+        let addr = listen.local_addr();
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.spawn(
+            listen
+                // Hold accepted connections open until the test completes.
+                .listen_and_fold(Vec::new(), |mut conns, (conn, _)| {
+                    conns.push(conn);
+                    Ok(conns)
+                })
+                .map_err(|e| panic!("listener failed: {}", e)),
+        );
+
+        let handshake = || {
+            let mut session = rustls::ClientSession::new(
+                &client.tls_client_config(),
+                server.tls_server_name().as_dns_name_ref(),
+            );
+            let mut socket = Recorded {
+                socket: StdTcpStream::connect(addr).expect("must connect"),
+                received: Vec::new(),
+            };
+            rustls::Stream::new(&mut session, &mut socket)
+                .write_all(b"hello")
+                .expect("handshake must succeed");
+            socket.received
+        };
+        let contains = |haystack: Vec<u8>| haystack.windows(STAPLE.len()).any(|w| w == STAPLE);
+
+        assert!(!contains(handshake()));
+
+        staple.set(Some(STAPLE.to_vec()));
+        assert!(contains(handshake()), "response must be stapled");
+
+        // Updates to the staple apply to subsequent handshakes.
+        staple.set(None);
+        assert!(!contains(handshake()));
+    }
+
+    /// Accepts a connection whose protocol is cached as TLS, so that its
+    /// handshake starts without waiting for a client hello.
+    ///
+    /// The client, which never sends a client hello, is also returned.
+    fn accept_tls(
+        handshakes: &Arc<Semaphore>,
+        timeout: Option<Duration>,
+    ) -> (Handshake, StdTcpStream) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listener = StdListener::bind(addr).expect("must bind");
+        let dst = listener.local_addr().expect("must have an address");
+        let client = StdTcpStream::connect(dst).expect("must connect");
+        let (socket, remote) = listener.accept().expect("must accept");
+        let socket = TcpStream::from_std(socket, &Handle::default()).expect("must register");
+
+        let cache = ProtocolCache::new(Duration::from_secs(60), 0.0);
+        let key = protocol_cache::Key::new(remote.ip(), dst);
+        cache.insert(key, Detected::Tls);
+        let protocol_cache = Some((cache, key));
+        let handshakes = Some(handshakes.clone());
+        let handshake = Handshake::new(socket, &Pending, None, protocol_cache, handshakes, timeout);
+        (handshake, client)
+    }
+
+    #[test]
+    fn concurrent_handshakes_are_limited() {
+        use task::test_util::mock_time;
+
+        mock_time(|_| {
+            let handshakes = Arc::new(Semaphore::new(1));
+            let (mut first, _first) = accept_tls(&handshakes, None);
+            let (mut queued, _queued) = accept_tls(&handshakes, None);
+
+            // The first handshake holds the only permit until it completes.
+            assert!(first.poll().expect("must not fail").is_not_ready());
+            assert!(queued.poll().expect("must not fail").is_not_ready());
+            match queued {
+                Handshake::Acquiring(..) => {}
+                _ => panic!("handshakes over the limit must wait"),
+            }
+
+            drop(first);
+            assert!(queued.poll().expect("must not fail").is_not_ready());
+            match queued {
+                Handshake::Upgrade(..) => {}
+                _ => panic!("the handshake must start once the permit is released"),
+            }
+        })
+    }
+
+    #[test]
+    fn handshake_timeouts_release_permits() {
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let handshakes = Arc::new(Semaphore::new(1));
+            let timeout = Some(Duration::from_secs(10));
+            let (mut handshake, _client) = accept_tls(&handshakes, timeout);
+            assert!(handshake.poll().expect("must not fail").is_not_ready());
+            assert_eq!(handshakes.available_permits(), 0);
+
+            time.advance(Duration::from_secs(10));
+            let err = handshake.poll().err().expect("the handshake must time out");
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(
+                handshakes.available_permits(),
+                1,
+                "the permit must be released"
+            );
+        })
+    }
+}
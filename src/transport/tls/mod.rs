@@ -9,16 +9,23 @@ use std::fmt;
 
 use identity;
 
+mod access_record;
 pub mod client;
 mod conditional_accept;
 mod connection;
 mod io;
 pub mod listen;
+mod ocsp;
+mod protocol_cache;
+mod verify;
 
 use self::io::TlsIo;
 
+pub use self::access_record::TlsAccessRecord;
 pub use self::connection::Connection;
+pub use self::io::BytesTransferred;
 pub use self::listen::Listen;
+pub use self::ocsp::OcspStaple;
 pub use self::rustls::TLSError as Error;
 
 /// Describes whether or not a connection was secured with TLS and, if it was
@@ -28,6 +35,14 @@ pub type Conditional<T> = ::Conditional<T, ReasonForNoIdentity>;
 pub type PeerIdentity = Conditional<identity::Name>;
 pub type Status = Conditional<()>;
 
+/// The TLS states of both hops of a proxied connection: how the client
+/// reached the proxy, and how the proxy reaches the upstream.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DuplexTlsState {
+    inbound: PeerIdentity,
+    outbound: PeerIdentity,
+}
+
 pub trait HasPeerIdentity {
     fn peer_identity(&self) -> PeerIdentity;
 }
@@ -49,6 +64,9 @@ pub enum ReasonForNoIdentity {
 
     /// The remote peer does not have a known identity name.
     NoPeerName(ReasonForNoPeerName),
+
+    /// The remote peer's identity does not match the expected identity.
+    Mismatched,
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -73,6 +91,47 @@ pub enum ReasonForNoPeerName {
     NotProvidedByRemote,
 }
 
+// === impl DuplexTlsState ===
+
+impl DuplexTlsState {
+    pub fn new(inbound: PeerIdentity, outbound: PeerIdentity) -> Self {
+        Self { inbound, outbound }
+    }
+
+    /// The client's identity, if the client reached the proxy over TLS.
+    pub fn inbound(&self) -> &PeerIdentity {
+        &self.inbound
+    }
+
+    /// The upstream's identity, if the proxy reaches the upstream over TLS.
+    pub fn outbound(&self) -> &PeerIdentity {
+        &self.outbound
+    }
+
+    /// Returns true if both hops are secured with TLS.
+    pub fn is_end_to_end(&self) -> bool {
+        !self.inbound.is_none() && !self.outbound.is_none()
+    }
+}
+
+/// Formats each hop's identity or, if the hop is not secured, the reason
+/// why, e.g. `inbound_peer_id=web.ns.serviceaccount.identity.linkerd.cluster.local
+/// outbound_no_tls_reason=loopback`.
+impl fmt::Display for DuplexTlsState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn fmt_hop(f: &mut fmt::Formatter, hop: &str, id: &PeerIdentity) -> fmt::Result {
+            match id {
+                ::Conditional::Some(id) => write!(f, "{}_peer_id={}", hop, id.as_ref()),
+                ::Conditional::None(why) => write!(f, "{}_no_tls_reason={}", hop, why),
+            }
+        }
+
+        fmt_hop(f, "inbound", &self.inbound)?;
+        f.write_str(" ")?;
+        fmt_hop(f, "outbound", &self.outbound)
+    }
+}
+
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -92,6 +151,7 @@ impl fmt::Display for ReasonForNoIdentity {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ReasonForNoIdentity::Disabled => write!(f, "disabled"),
+            ReasonForNoIdentity::Mismatched => write!(f, "mismatched"),
             ReasonForNoIdentity::NoPeerName(n) => write!(f, "{}", n),
         }
     }
@@ -112,3 +172,61 @@ impl fmt::Display for ReasonForNoPeerName {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Conditional;
+
+    fn id(name: &str) -> PeerIdentity {
+        Conditional::Some(identity::Name::from_hostname(name.as_bytes()).unwrap())
+    }
+
+    const WEB: &str = "web.ns.serviceaccount.identity.linkerd.cluster.local";
+    const API: &str = "api.ns.serviceaccount.identity.linkerd.cluster.local";
+
+    #[test]
+    fn displays_both_identities_when_all_tls() {
+        let state = DuplexTlsState::new(id(WEB), id(API));
+        assert!(state.is_end_to_end());
+        assert_eq!(state.inbound(), &id(WEB));
+        assert_eq!(state.outbound(), &id(API));
+        assert_eq!(
+            state.to_string(),
+            format!("inbound_peer_id={} outbound_peer_id={}", WEB, API)
+        );
+    }
+
+    #[test]
+    fn displays_reason_for_the_plaintext_hop_when_half_tls() {
+        let loopback = ReasonForNoIdentity::from(ReasonForNoPeerName::Loopback);
+        let state = DuplexTlsState::new(id(WEB), Conditional::None(loopback));
+        assert!(!state.is_end_to_end());
+        assert_eq!(state.outbound().reason(), Some(loopback));
+        assert_eq!(
+            state.to_string(),
+            format!("inbound_peer_id={} outbound_no_tls_reason=loopback", WEB)
+        );
+
+        let state = DuplexTlsState::new(Conditional::None(ReasonForNoIdentity::Disabled), id(API));
+        assert!(!state.is_end_to_end());
+        assert_eq!(
+            state.to_string(),
+            format!("inbound_no_tls_reason=disabled outbound_peer_id={}", API)
+        );
+    }
+
+    #[test]
+    fn displays_both_reasons_when_no_tls() {
+        let state = DuplexTlsState::new(
+            Conditional::None(ReasonForNoPeerName::NotProvidedByRemote.into()),
+            Conditional::None(ReasonForNoPeerName::NotProvidedByServiceDiscovery.into()),
+        );
+        assert!(!state.is_end_to_end());
+        assert_eq!(
+            state.to_string(),
+            "inbound_no_tls_reason=not_provided_by_remote \
+             outbound_no_tls_reason=not_provided_by_service_discovery"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use futures::{Future, Stream};
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio_timer::Interval;
+
+use super::listen::Config;
+use super::{rustls, webpki};
+
+/// A DER-encoded OCSP response that a listener staples to its certificate.
+///
+/// Clones share the same response, so that it may be refreshed while the
+/// listener is running. A response is only stapled for clients that request
+/// certificate status.
+#[derive(Clone, Debug, Default)]
+pub struct OcspStaple(Arc<RwLock<Option<Vec<u8>>>>);
+
+/// Derives server configs that staple an `OcspStaple` from a listener's
+/// server configs.
+///
+/// A listener's server config is replaced whenever its certificate is
+/// renewed, so the derived config is only rebuilt when the listener's config
+/// changes.
+pub(super) struct Stapler {
+    staple: OcspStaple,
+    /// The last server config that was derived from, and the config derived
+    /// from it.
+    configs: Mutex<Option<(Arc<Config>, Arc<Config>)>>,
+}
+
+/// Resolves certificates with an inner resolver, stapling the current OCSP
+/// response to them.
+struct Stapled {
+    inner: Arc<dyn rustls::ResolvesServerCert>,
+    staple: OcspStaple,
+}
+
+// === impl OcspStaple ===
+
+impl OcspStaple {
+    /// Replaces the stapled response. If `response` is `None`, no response is
+    /// stapled.
+    pub fn set(&self, response: Option<Vec<u8>>) {
+        if let Ok(mut ocsp) = self.0.write() {
+            *ocsp = response;
+        }
+    }
+
+    fn get(&self) -> Option<Vec<u8>> {
+        self.0.read().ok().and_then(|ocsp| ocsp.clone())
+    }
+
+    /// Returns a task that refreshes the stapled response every `interval`
+    /// with the responses obtained from `source`, starting immediately.
+    ///
+    /// If a response can't be obtained, the previous response remains
+    /// stapled, since it may still be valid.
+    pub fn refresh_every<S, F>(
+        self,
+        interval: Duration,
+        mut source: S,
+    ) -> impl Future<Item = (), Error = ()> + Send
+    where
+        S: FnMut() -> F + Send + 'static,
+        F: Future<Item = Vec<u8>> + Send + 'static,
+        F::Error: fmt::Display,
+    {
+        Interval::new_interval(interval)
+            .map_err(|e| error!("OCSP refresh timer failed: {}", e))
+            .for_each(move |_| {
+                let staple = self.clone();
+                source().then(move |rsp| {
+                    match rsp {
+                        Ok(rsp) => {
+                            debug!("refreshed OCSP response");
+                            staple.set(Some(rsp));
+                        }
+                        Err(e) => warn!("failed to refresh OCSP response: {}", e),
+                    }
+                    Ok(())
+                })
+            })
+    }
+}
+
+// === impl Stapler ===
+
+impl Stapler {
+    pub(super) fn new(staple: OcspStaple) -> Self {
+        Self {
+            staple,
+            configs: Mutex::new(None),
+        }
+    }
+
+    /// Returns a config that serves the same certificates as `config`, with
+    /// the OCSP response stapled to them.
+    pub(super) fn config(&self, config: Arc<Config>) -> Arc<Config> {
+        let mut configs = self.configs.lock().expect("OCSP stapler lock poisoned");
+        if let Some((ref orig, ref stapled)) = *configs {
+            if Arc::ptr_eq(orig, &config) {
+                return stapled.clone();
+            }
+        }
+
+        let mut stapled = (*config).clone();
+        stapled.cert_resolver = Arc::new(Stapled {
+            inner: config.cert_resolver.clone(),
+            staple: self.staple.clone(),
+        });
+        let stapled = Arc::new(stapled);
+        *configs = Some((config, stapled.clone()));
+        stapled
+    }
+}
+
+// === impl Stapled ===
+
+impl rustls::ResolvesServerCert for Stapled {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        self.inner.resolve(server_name, sigschemes).map(|mut key| {
+            key.ocsp = self.staple.get();
+            key
+        })
+    }
+}
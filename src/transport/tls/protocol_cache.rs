@@ -0,0 +1,207 @@
+use rand::{self, Rng};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_timer::clock;
+
+/// The protocol detected on an accepted connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum Detected {
+    Tls,
+    Plaintext,
+}
+
+/// The maximum number of protocols that are cached.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Identifies the connections that share a cached protocol, i.e. those from
+/// the same client to the same original destination address.
+///
+/// A destination may be reached by clients that speak different protocols
+/// (e.g. meshed and unmeshed clients), so protocols are not shared across
+/// clients.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(super) struct Key {
+    client: IpAddr,
+    dst: SocketAddr,
+}
+
+/// Caches the protocol last detected for each client and original
+/// destination address, so that further connections may skip detection.
+#[derive(Clone, Debug)]
+pub(super) struct ProtocolCache {
+    ttl: Duration,
+    recheck_probability: f64,
+    /// Once this many protocols are cached, expired protocols are evicted
+    /// and, if none have expired, the protocol that expires soonest is.
+    max_entries: usize,
+    entries: Arc<Mutex<HashMap<Key, (Detected, Instant)>>>,
+}
+
+// === impl Key ===
+
+impl Key {
+    pub(super) fn new(client: IpAddr, dst: SocketAddr) -> Self {
+        Self { client, dst }
+    }
+}
+
+// === impl ProtocolCache ===
+
+impl ProtocolCache {
+    /// Caches each detected protocol for `ttl`.
+    ///
+    /// `recheck_probability` is clamped to `[0.0, 1.0]`.
+    pub(super) fn new(ttl: Duration, recheck_probability: f64) -> Self {
+        let recheck_probability = if recheck_probability.is_nan() {
+            0.0
+        } else {
+            recheck_probability.max(0.0).min(1.0)
+        };
+        Self {
+            ttl,
+            recheck_probability,
+            max_entries: MAX_ENTRIES,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the protocol last detected for connections identified by
+    /// `key`.
+    ///
+    /// `None` is returned if the protocol should be detected, i.e. because no
+    /// protocol is cached, the cached protocol has expired, or the connection
+    /// was randomly chosen to re-check the cached protocol.
+    pub(super) fn get(&self, key: &Key) -> Option<Detected> {
+        let mut entries = self.entries.lock().expect("protocol cache lock poisoned");
+
+        let cached = match entries.get(key) {
+            None => return None,
+            Some(&(detected, valid_until)) if valid_until > clock::now() => Some(detected),
+            Some(_) => None,
+        };
+        let detected = match cached {
+            Some(detected) => detected,
+            None => {
+                entries.remove(key);
+                return None;
+            }
+        };
+
+        if rand::thread_rng().gen_bool(self.recheck_probability) {
+            trace!("re-checking cached protocol for {:?}", key);
+            return None;
+        }
+
+        Some(detected)
+    }
+
+    pub(super) fn insert(&self, key: Key, detected: Detected) {
+        let now = clock::now();
+        let mut entries = self.entries.lock().expect("protocol cache lock poisoned");
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, &mut (_, valid_until)| valid_until > now);
+            if entries.len() >= self.max_entries {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|&(_, &(_, valid_until))| valid_until)
+                    .map(|(key, _)| *key);
+                if let Some(soonest) = soonest {
+                    trace!("protocol cache is full; evicting {:?}", soonest);
+                    entries.remove(&soonest);
+                }
+            }
+        }
+
+        entries.insert(key, (detected, now + self.ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        let dst = SocketAddr::from(([10, 0, 0, 1], 8080));
+        Key::new([10, 1, 0, 1].into(), dst)
+    }
+
+    #[test]
+    fn caches_detected_protocol() {
+        let cache = ProtocolCache::new(Duration::from_secs(60), 0.0);
+        assert_eq!(cache.get(&key()), None);
+
+        cache.insert(key(), Detected::Tls);
+        assert_eq!(cache.get(&key()), Some(Detected::Tls));
+
+        cache.insert(key(), Detected::Plaintext);
+        assert_eq!(cache.get(&key()), Some(Detected::Plaintext));
+
+        let other = Key::new(key().client, SocketAddr::from(([10, 0, 0, 2], 8080)));
+        assert_eq!(cache.get(&other), None);
+    }
+
+    #[test]
+    fn protocols_are_not_shared_across_clients() {
+        let cache = ProtocolCache::new(Duration::from_secs(60), 0.0);
+        cache.insert(key(), Detected::Tls);
+
+        let other = Key::new([10, 1, 0, 2].into(), key().dst);
+        assert_eq!(cache.get(&other), None);
+        assert_eq!(cache.get(&key()), Some(Detected::Tls));
+    }
+
+    #[test]
+    fn full_caches_evict_expired_entries_first() {
+        use task::test_util::mock_time;
+
+        mock_time(|time| {
+            let mut cache = ProtocolCache::new(Duration::from_secs(0), 0.0);
+            cache.max_entries = 2;
+            let dst = |port| Key::new(key().client, SocketAddr::from(([10, 0, 0, 1], port)));
+
+            cache.insert(dst(1), Detected::Tls);
+            cache.insert(dst(2), Detected::Tls);
+            cache.ttl = Duration::from_secs(60);
+            cache.insert(dst(3), Detected::Tls);
+            assert_eq!(
+                cache.entries.lock().unwrap().len(),
+                1,
+                "expired entries must be evicted"
+            );
+
+            time.advance(Duration::from_secs(1));
+            cache.insert(dst(4), Detected::Tls);
+            time.advance(Duration::from_secs(1));
+            cache.insert(dst(5), Detected::Plaintext);
+            let entries = cache.entries.lock().unwrap();
+            assert_eq!(entries.len(), 2, "the cache must be bounded");
+            assert!(
+                !entries.contains_key(&dst(3)),
+                "the soonest-expiring entry must be evicted"
+            );
+            assert!(entries.contains_key(&dst(5)));
+        })
+    }
+
+    #[test]
+    fn expired_entries_are_not_used() {
+        let cache = ProtocolCache::new(Duration::from_secs(0), 0.0);
+        cache.insert(key(), Detected::Tls);
+        assert_eq!(cache.get(&key()), None);
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recheck_skips_cache() {
+        let cache = ProtocolCache::new(Duration::from_secs(60), 1.0);
+        cache.insert(key(), Detected::Tls);
+        assert_eq!(cache.get(&key()), None);
+        assert!(
+            !cache.entries.lock().unwrap().is_empty(),
+            "re-checks must not evict the cached protocol"
+        );
+    }
+}
@@ -0,0 +1,53 @@
+//! Verification of servers' certificates against identities other than the
+//! SNI server name.
+//!
+//! This is the only module that uses rustls's `dangerous_configuration` API,
+//! which the crate enables so that a custom `ServerCertVerifier` may be
+//! installed. Other modules must not call `ClientConfig::dangerous`.
+
+use std::sync::Arc;
+
+use super::{client::Config, rustls, webpki};
+use identity;
+
+/// Verifies a server's certificate against a fixed identity, ignoring the SNI
+/// server name sent in the handshake.
+///
+/// rustls verifies certificates against the name it sends as SNI, so a proxy
+/// that sends a different SNI (e.g. a shared gateway's hostname) needs its
+/// own verifier to verify the target's identity instead. This verifier does
+/// not weaken verification: it delegates to the configuration's own verifier
+/// with the same roots, certificate chain, and OCSP response, and only
+/// substitutes the name. The SNI name is never trusted, so a server must
+/// present a certificate that is valid for the expected identity, exactly as
+/// if no SNI override were configured.
+struct VerifyIdentity {
+    config: Arc<Config>,
+    identity: identity::Name,
+}
+
+/// Returns a copy of `config` that verifies servers' certificates against
+/// `identity` rather than the SNI server name of the handshake.
+pub(super) fn verify_identity(config: Arc<Config>, identity: identity::Name) -> Arc<Config> {
+    let mut c = config.as_ref().clone();
+    c.dangerous()
+        .set_certificate_verifier(Arc::new(VerifyIdentity { config, identity }));
+    Arc::new(c)
+}
+
+impl rustls::ServerCertVerifier for VerifyIdentity {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _sni: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        self.config.get_verifier().verify_server_cert(
+            roots,
+            presented_certs,
+            self.identity.as_dns_name_ref(),
+            ocsp_response,
+        )
+    }
+}
@@ -171,6 +171,12 @@ macro_rules! generate_outbound_tls_accept_not_cert_identity_test {
                 .status()
                 == http::StatusCode::OK
         );
+
+        // The client's TLS must have been forwarded to the server, rather
+        // than terminated by the proxy.
+        let srv = proxy.outbound_server.as_ref().expect("outbound server");
+        assert!(srv.tls_connections() > 0, "server must accept TLS");
+        assert_eq!(srv.plaintext_connections(), 0);
     };
 }
 
@@ -36,7 +36,7 @@ pub use self::bytes::Bytes;
 pub use self::futures::sync::oneshot;
 pub use self::futures::{future::Executor, *};
 pub use self::http::{HeaderMap, Request, Response, StatusCode};
-use self::http_body::Body as HttpBody;
+pub use self::http_body::Body as HttpBody;
 pub use self::linkerd2_proxy::*;
 pub use self::linkerd2_task::LazyExecutor;
 use self::tokio::io::{AsyncRead, AsyncWrite};
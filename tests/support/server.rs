@@ -1,12 +1,14 @@
+use self::tokio::io::{AsyncRead, AsyncWrite};
 use self::tokio::net::TcpStream;
 use self::tokio_rustls::TlsAcceptor;
 use self::RunningIo;
 use rustls::{ServerConfig, ServerSession};
+use std::cmp;
 use std::collections::HashMap;
-use std::io;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, Mutex};
+use std::{error, fmt, io, thread};
+use support::bytes::{Buf, BufMut, BytesMut, IntoBuf};
 use support::futures::future::Either;
 use support::*;
 
@@ -30,6 +32,12 @@ pub fn http2_tls(tls: Arc<ServerConfig>) -> Server {
     Server::http2_tls(tls)
 }
 
+/// Serves HTTP/1.1, switching connections to HTTP/2 when a request carries an
+/// `Upgrade: h2c` header.
+pub fn h2c() -> Server {
+    Server::h2c()
+}
+
 pub fn tcp() -> tcp::TcpServer {
     tcp::server()
 }
@@ -38,32 +46,126 @@ pub struct Server {
     routes: HashMap<String, Route>,
     version: Run,
     tls: Option<Arc<ServerConfig>>,
+    max_connections: Option<usize>,
+    header_limit: Option<HeaderLimit>,
+    capture_raw_heads: bool,
+    h2_settings: H2Settings,
 }
 
 pub struct Listening {
     pub addr: SocketAddr,
     pub(super) shutdown: Shutdown,
     pub(super) conn_count: Arc<AtomicUsize>,
+    pub(super) tls_conn_count: Arc<AtomicUsize>,
+    pub(super) plaintext_conn_count: Arc<AtomicUsize>,
+    pub(super) errors: Errors,
+    pub(super) raw_heads: RawHeads,
+}
+
+/// A failure encountered by a running support server.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The server's socket could not be bound, listened on, or accepted from.
+    Bind(io::Error),
+    /// A TLS handshake with a client failed.
+    TlsAccept(io::Error),
+    /// A connection could not be served.
+    Serve(BoxError),
 }
 
+/// Errors recorded by a server, in the order they occurred.
+pub(super) type Errors = Arc<Mutex<Vec<ServerError>>>;
+
+/// The raw request heads captured by a server, in the order they were read.
+pub(super) type RawHeads = Arc<Mutex<Vec<Bytes>>>;
+
 impl Listening {
     pub fn connections(&self) -> usize {
         self.conn_count.load(Ordering::Acquire)
     }
+
+    /// Returns the number of connections that were served over TLS.
+    pub fn tls_connections(&self) -> usize {
+        self.tls_conn_count.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of connections that were served without TLS.
+    ///
+    /// Tests may assert that this is zero to ensure that a client (e.g. the
+    /// proxy) did not fall back to plaintext.
+    pub fn plaintext_connections(&self) -> usize {
+        self.plaintext_conn_count.load(Ordering::Acquire)
+    }
+
+    /// Returns the errors the server has encountered since this was last
+    /// called, oldest first.
+    pub fn take_errors(&self) -> Vec<ServerError> {
+        self.errors.lock().unwrap().drain(..).collect()
+    }
+
+    /// Returns the most recent error the server has encountered, if any.
+    ///
+    /// Older errors are discarded.
+    pub fn last_error(&self) -> Option<ServerError> {
+        self.take_errors().pop()
+    }
+
+    /// Returns the literal bytes of the first request head (i.e. the request
+    /// line and headers, through the blank line that ends them) read on each
+    /// connection, in the order they were read.
+    ///
+    /// Heads are only captured if the server was configured with
+    /// `capture_raw_heads`.
+    pub fn raw_request_heads(&self) -> Vec<Bytes> {
+        self.raw_heads.lock().unwrap().clone()
+    }
 }
 
 impl Drop for Listening {
     fn drop(&mut self) {
         println!("server Listening dropped; addr={}", self.addr);
+        if let Ok(errors) = self.errors.lock() {
+            for e in errors.iter() {
+                println!("server Listening unhandled error; addr={} err={}", self.addr, e);
+            }
+        }
     }
 }
 
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Bind(e) => write!(f, "bind error: {}", e),
+            ServerError::TlsAccept(e) => write!(f, "TLS accept error: {}", e),
+            ServerError::Serve(e) => write!(f, "serve error: {}", e),
+        }
+    }
+}
+
+impl error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ServerError::Bind(e) => Some(e),
+            ServerError::TlsAccept(e) => Some(e),
+            ServerError::Serve(e) => Some(&**e),
+        }
+    }
+}
+
+fn record(errors: &Errors, e: ServerError) {
+    errors.lock().unwrap().push(e);
+}
+
 impl Server {
     fn new(run: Run, tls: Option<Arc<ServerConfig>>) -> Self {
         Server {
             routes: HashMap::new(),
             version: run,
             tls,
+            max_connections: None,
+            header_limit: None,
+            capture_raw_heads: false,
+            h2_settings: H2Settings::default(),
         }
     }
     fn http1() -> Self {
@@ -82,6 +184,71 @@ impl Server {
         Server::new(Run::Http2, Some(tls))
     }
 
+    fn h2c() -> Self {
+        Server::new(Run::H2c, None)
+    }
+
+    /// Accepts at most `n` connections, resetting all connections accepted
+    /// after them.
+    ///
+    /// Connections that have already been accepted continue to be served.
+    pub fn max_connections(mut self, n: usize) -> Self {
+        self.max_connections = Some(n);
+        self
+    }
+
+    /// Rejects requests whose header fields (including their `: ` and CRLF
+    /// delimiters) total more than `bytes`.
+    ///
+    /// By default, rejected requests are answered with `431 Request Header
+    /// Fields Too Large`.
+    pub fn max_header_size(mut self, bytes: usize) -> Self {
+        let reset = self.header_limit.map_or(false, |l| l.reset);
+        self.header_limit = Some(HeaderLimit { max: bytes, reset });
+        self
+    }
+
+    /// Rejects requests with oversized headers by failing them without a
+    /// response, rather than with a `431`.
+    ///
+    /// HTTP/1 connections are closed and HTTP/2 streams are reset. This has
+    /// no effect unless `max_header_size` is set.
+    pub fn reset_oversized_headers(mut self) -> Self {
+        if let Some(ref mut limit) = self.header_limit {
+            limit.reset = true;
+        }
+        self
+    }
+
+    /// Captures the bytes of the first request head read on each connection,
+    /// before they are parsed, so that tests may assert on the exact wire
+    /// format of requests (e.g. the form of the request target).
+    ///
+    /// This is only meaningful for HTTP/1 servers. Heads are read from the
+    /// decrypted stream of TLS connections.
+    pub fn capture_raw_heads(mut self) -> Self {
+        self.capture_raw_heads = true;
+        self
+    }
+
+    /// Advertises a `SETTINGS_MAX_CONCURRENT_STREAMS` of `n` on HTTP/2
+    /// connections.
+    ///
+    /// By default, hyper's limit is advertised.
+    pub fn http2_max_concurrent_streams(mut self, n: u32) -> Self {
+        self.h2_settings.max_concurrent_streams = Some(n);
+        self
+    }
+
+    /// Advertises a `SETTINGS_INITIAL_WINDOW_SIZE` of `bytes` on HTTP/2
+    /// connections.
+    ///
+    /// By default, hyper's window size is advertised.
+    pub fn http2_initial_window_size(mut self, bytes: u32) -> Self {
+        self.h2_settings.initial_window_size = Some(bytes);
+        self
+    }
+
     /// Return a string body as a 200 OK response, with the string as
     /// the response body.
     pub fn route(mut self, path: &str, resp: &str) -> Self {
@@ -108,13 +275,82 @@ impl Server {
         U::Error: Into<BoxError>,
     {
         let func = move |req| {
-            Box::new(cb(req).into_future().map_err(Into::into))
-                as Box<dyn Future<Item = Response<Bytes>, Error = BoxError> + Send>
+            let rsp = cb(req)
+                .into_future()
+                .map(|rsp| rsp.map(RspBody::new))
+                .map_err(Into::into);
+            Box::new(rsp) as Box<dyn Future<Item = Response<RspBody>, Error = BoxError> + Send>
+        };
+        self.routes.insert(path.into(), Route(Box::new(func)));
+        self
+    }
+
+    /// Call a closure with the gRPC messages of a request that matches,
+    /// returning the messages and status to send back.
+    ///
+    /// Messages are framed with gRPC's 5-byte length prefix, and the status is
+    /// sent in the `grpc-status` and `grpc-message` trailers.
+    pub fn route_grpc<F>(mut self, path: &str, cb: F) -> Self
+    where
+        F: Fn(Vec<Bytes>) -> (Vec<Bytes>, grpc::Status) + Send + Sync + 'static,
+    {
+        let cb = Arc::new(cb);
+        let func = move |req: Request<ReqBody>| {
+            let cb = cb.clone();
+            let rsp = req
+                .into_body()
+                .map_err(|()| BoxError::from("request body error"))
+                .fold(BytesMut::new(), |mut buf, chunk| {
+                    buf.extend_from_slice(&chunk);
+                    Ok::<_, BoxError>(buf)
+                })
+                .and_then(move |buf| {
+                    let (msgs, status) = cb(decode_grpc(buf.freeze())?);
+
+                    let mut trailers = HeaderMap::new();
+                    let code = (status.code() as i32).to_string();
+                    trailers.insert("grpc-status", code.parse()?);
+                    if !status.message().is_empty() {
+                        trailers.insert("grpc-message", status.message().parse()?);
+                    }
+
+                    let rsp = http::Response::builder()
+                        .status(200)
+                        .header("content-type", "application/grpc")
+                        .body(RspBody {
+                            data: Some(encode_grpc(msgs)),
+                            trailers: Some(trailers),
+                        })?;
+                    Ok::<_, BoxError>(rsp)
+                });
+            Box::new(rsp) as Box<dyn Future<Item = Response<RspBody>, Error = BoxError> + Send>
         };
         self.routes.insert(path.into(), Route(Box::new(func)));
         self
     }
 
+    /// Responds to requests that match `path` with header fields that total
+    /// more than `size` bytes (measured as by `max_header_size`), so that
+    /// tests may exercise how the proxy handles upstreams that violate header
+    /// size limits.
+    ///
+    /// The headers are split across many `x-oversized-*` fields, none of which
+    /// is larger than 1KiB.
+    pub fn route_oversized_headers(self, path: &str, size: usize) -> Self {
+        let value = "a".repeat(1024);
+        let mut headers = HeaderMap::new();
+        while header_size(&headers) <= size {
+            let name = format!("x-oversized-{}", headers.len());
+            let name = http::header::HeaderName::from_bytes(name.as_bytes()).unwrap();
+            headers.insert(name, value.parse().unwrap());
+        }
+        self.route_fn(path, move |_| {
+            let mut rsp = Response::new(Bytes::new());
+            *rsp.headers_mut() = headers.clone();
+            rsp
+        })
+    }
+
     pub fn route_with_latency(self, path: &str, resp: &str, latency: Duration) -> Self {
         let resp = Bytes::from(resp);
         self.route_fn(path, move |_| {
@@ -143,15 +379,27 @@ impl Server {
         let mut listening_tx = Some(listening_tx);
         let conn_count = Arc::new(AtomicUsize::from(0));
         let srv_conn_count = Arc::clone(&conn_count);
+        let tls_conn_count = Arc::new(AtomicUsize::from(0));
+        let srv_tls_conn_count = Arc::clone(&tls_conn_count);
+        let plaintext_conn_count = Arc::new(AtomicUsize::from(0));
+        let srv_plaintext_conn_count = Arc::clone(&plaintext_conn_count);
+        let errors = Errors::default();
+        let srv_errors = Arc::clone(&errors);
+        let raw_heads = RawHeads::default();
+        let srv_raw_heads = if self.capture_raw_heads {
+            Some(Arc::clone(&raw_heads))
+        } else {
+            None
+        };
         let version = self.version;
         let tname = format!("support {:?} server (test={})", version, thread_name(),);
 
-        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
-        let listener = net2::TcpBuilder::new_v4().expect("Tcp::new_v4");
-        listener.bind(addr).expect("Tcp::bind");
-        let addr = listener.local_addr().expect("Tcp::local_addr");
+        // The server can't run at all without a bound socket, so this is
+        // treated as a setup bug.
+        let (listener, addr) = bind_local().unwrap_or_else(|e| panic!("support server {}", e));
 
         let tls_config = self.tls.clone();
+        let max_connections = self.max_connections;
 
         ::std::thread::Builder::new()
             .name(tname)
@@ -160,56 +408,96 @@ impl Server {
                     let _ = listening_tx.take().unwrap().send(());
                     delay.wait().expect("support server delay wait");
                 }
-                let listener = listener.listen(1024).expect("Tcp::listen");
 
                 let mut runtime = runtime::current_thread::Runtime::new()
                     .expect("initialize support server runtime");
 
-                let mut new_svc = NewSvc(Arc::new(self.routes));
+                let header_limit = self.header_limit;
+                let mut new_svc = NewSvc(Arc::new(self.routes), self.version, header_limit);
                 let mut http = hyper::server::conn::Http::new();
                 match self.version {
-                    Run::Http1 => http.http1_only(true),
+                    Run::Http1 | Run::H2c => http.http1_only(true),
                     Run::Http2 => http.http2_only(true),
                 };
+                if let Some(limit) = header_limit {
+                    // Ensure that hyper buffers headers up to the limit, so
+                    // that oversized headers are rejected by the limit rather
+                    // than by hyper.
+                    http.max_buf_size(cmp::max(limit.max, DEFAULT_MAX_BUF_SIZE));
+                }
+                if let Some(n) = self.h2_settings.max_concurrent_streams {
+                    http.http2_max_concurrent_streams(n);
+                }
+                if let Some(bytes) = self.h2_settings.initial_window_size {
+                    http.http2_initial_stream_window_size(bytes);
+                }
 
-                let bind =
-                    TcpListener::from_std(listener, &reactor::Handle::default()).expect("from_std");
+                let bind = listener.listen(1024).and_then(|listener| {
+                    TcpListener::from_std(listener, &reactor::Handle::default())
+                });
+
+                let bind = bind.map_err(|e| record(&srv_errors, ServerError::Bind(e)));
 
                 if let Some(listening_tx) = listening_tx {
                     let _ = listening_tx.send(());
                 }
 
+                let bind = match bind {
+                    Ok(bind) => bind,
+                    Err(()) => return,
+                };
+
+                let conn_errors = Arc::clone(&srv_errors);
+                let mut accepted = 0;
                 let serve = bind
                     .incoming()
-                    .and_then(move |s| accept_connection(s, tls_config.clone()))
                     .for_each(move |sock| {
-                        let http_clone = http.clone();
+                        if max_connections.map_or(false, |max| accepted >= max) {
+                            println!("support server refusing connection; accepted={}", accepted);
+                            // Closing without lingering resets the connection.
+                            let _ = sock.set_linger(Some(Duration::from_secs(0)));
+                            return Ok(());
+                        }
+                        accepted += 1;
+
+                        let http = http.clone();
                         let srv_conn_count = Arc::clone(&srv_conn_count);
-                        let fut = new_svc
+                        let srv_tls_conn_count = Arc::clone(&srv_tls_conn_count);
+                        let srv_plaintext_conn_count = Arc::clone(&srv_plaintext_conn_count);
+                        let conn_errors = Arc::clone(&conn_errors);
+                        let raw_heads = srv_raw_heads.clone();
+                        let svc = new_svc
                             .call(())
-                            .inspect(move |_| {
+                            .map_err(|e| ServerError::Serve(e.into()));
+                        let fut = accept_connection(sock, tls_config.clone())
+                            .map_err(ServerError::TlsAccept)
+                            .join(svc)
+                            .and_then(move |(sock, svc)| {
                                 srv_conn_count.fetch_add(1, Ordering::Release);
+                                let count = match sock {
+                                    RunningIo::Tls(..) => &srv_tls_conn_count,
+                                    RunningIo::Plain(..) => &srv_plaintext_conn_count,
+                                };
+                                count.fetch_add(1, Ordering::Release);
+                                let sock = CaptureIo::new(sock, raw_heads);
+                                let conn = http.serve_connection(sock, svc);
+                                let conn = match version {
+                                    Run::H2c => Either::A(conn.with_upgrades()),
+                                    _ => Either::B(conn),
+                                };
+                                conn.map_err(|e| ServerError::Serve(e.into()))
                             })
-                            .map_err(|e| println!("support/server new_service error: {}", e))
-                            .and_then(move |svc| {
-                                http_clone
-                                    .serve_connection(sock, svc)
-                                    .map_err(|e| println!("support/server error: {}", e))
-                            })
-                            .map(|_| ());
+                            .map_err(move |e| record(&conn_errors, e));
                         current_thread::TaskExecutor::current()
                             .execute(fut)
                             .map_err(|e| {
-                                println!("server execute error: {:?}", e);
-                                io::Error::from(io::ErrorKind::Other)
+                                let msg = format!("execute error: {:?}", e);
+                                io::Error::new(io::ErrorKind::Other, msg)
                             })
-                    });
+                    })
+                    .map_err(move |e| record(&srv_errors, ServerError::Bind(e)));
 
-                runtime.spawn(
-                    serve
-                        .map(|_| ())
-                        .map_err(|e| println!("server error: {}", e)),
-                );
+                runtime.spawn(serve);
 
                 runtime.block_on(rx).expect("block on");
             })
@@ -224,21 +512,54 @@ impl Server {
             addr,
             shutdown: tx,
             conn_count,
+            tls_conn_count,
+            plaintext_conn_count,
+            errors,
+            raw_heads,
         }
     }
 }
 
+fn bind_local() -> Result<(net2::TcpBuilder, SocketAddr), ServerError> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let listener = net2::TcpBuilder::new_v4().map_err(ServerError::Bind)?;
+    listener.bind(addr).map_err(ServerError::Bind)?;
+    let addr = listener.local_addr().map_err(ServerError::Bind)?;
+    Ok((listener, addr))
+}
+
+/// hyper's default limit on the size of HTTP/1 read buffers.
+const DEFAULT_MAX_BUF_SIZE: usize = 8192 + 4096 * 100;
+
+#[derive(Clone, Copy, Debug)]
+struct HeaderLimit {
+    max: usize,
+    reset: bool,
+}
+
+/// HTTP/2 settings to advertise instead of hyper's defaults.
+///
+/// hyper does not allow the server's `SETTINGS_MAX_FRAME_SIZE` to be
+/// configured, so it is always the protocol's default.
+#[derive(Clone, Copy, Debug, Default)]
+struct H2Settings {
+    max_concurrent_streams: Option<u32>,
+    initial_window_size: Option<u32>,
+}
+
 #[derive(Clone, Copy, Debug)]
 enum Run {
     Http1,
     Http2,
+    /// HTTP/1.1, with upgrades to HTTP/2.
+    H2c,
 }
 
 struct Route(
     Box<
         dyn Fn(
                 Request<ReqBody>,
-            ) -> Box<dyn Future<Item = Response<Bytes>, Error = BoxError> + Send>
+            ) -> Box<dyn Future<Item = Response<RspBody>, Error = BoxError> + Send>
             + Send,
     >,
 );
@@ -250,13 +571,85 @@ impl Route {
             Box::new(future::ok(
                 http::Response::builder()
                     .status(200)
-                    .body(body.clone())
+                    .body(RspBody::new(body.clone()))
                     .unwrap(),
             ))
         }))
     }
 }
 
+/// A response body, optionally followed by trailers.
+#[derive(Debug, Default)]
+struct RspBody {
+    data: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl RspBody {
+    fn new(data: Bytes) -> Self {
+        Self {
+            data: Some(data).filter(|d| !d.is_empty()),
+            trailers: None,
+        }
+    }
+}
+
+impl hyper::body::Payload for RspBody {
+    type Data = hyper::Chunk;
+    type Error = BoxError;
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
+        Ok(Async::Ready(self.data.take().map(hyper::Chunk::from)))
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, Self::Error> {
+        Ok(Async::Ready(self.trailers.take()))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_none() && self.trailers.is_none()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        if self.trailers.is_some() {
+            return None;
+        }
+        Some(self.data.as_ref().map(|d| d.len() as u64).unwrap_or(0))
+    }
+}
+
+/// Frames each message with gRPC's 5-byte length prefix.
+pub fn encode_grpc(msgs: Vec<Bytes>) -> Bytes {
+    let len = msgs.iter().map(|m| m.len() + 5).sum();
+    let mut buf = BytesMut::with_capacity(len);
+    for msg in msgs {
+        // Messages are never compressed.
+        buf.put_u8(0);
+        buf.put_u32_be(msg.len() as u32);
+        buf.put_slice(&msg);
+    }
+    buf.freeze()
+}
+
+/// Splits a buffer of gRPC length-prefixed messages into its messages.
+pub fn decode_grpc(mut buf: Bytes) -> Result<Vec<Bytes>, BoxError> {
+    let mut msgs = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < 5 {
+            return Err("truncated gRPC message prefix".into());
+        }
+        if buf[0] != 0 {
+            return Err("compressed gRPC messages are not supported".into());
+        }
+        let len = buf.split_to(5).into_buf().get_u32_be() as usize;
+        if buf.len() < len {
+            return Err("truncated gRPC message".into());
+        }
+        msgs.push(buf.split_to(len));
+    }
+    Ok(msgs)
+}
+
 impl ::std::fmt::Debug for Route {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         f.write_str("Route")
@@ -267,13 +660,13 @@ type ReqBody = Box<dyn Stream<Item = Bytes, Error = ()> + Send>;
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(Debug)]
-struct Svc(Arc<HashMap<String, Route>>);
+struct Svc(Arc<HashMap<String, Route>>, Run, Option<HeaderLimit>);
 
 impl Svc {
     fn route(
         &mut self,
         req: Request<ReqBody>,
-    ) -> impl Future<Item = Response<Bytes>, Error = BoxError> {
+    ) -> impl Future<Item = Response<RspBody>, Error = BoxError> {
         match self.0.get(req.uri().path()) {
             Some(Route(ref func)) => func(req),
             None => {
@@ -290,22 +683,44 @@ impl Svc {
 
 impl hyper::service::Service for Svc {
     type ReqBody = hyper::Body;
-    type ResBody = hyper::Body;
+    type ResBody = RspBody;
     type Error = BoxError;
-    type Future = Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = Self::Error> + Send>;
+    type Future = Box<dyn Future<Item = hyper::Response<RspBody>, Error = Self::Error> + Send>;
 
     fn call(&mut self, req: hyper::Request<hyper::Body>) -> Self::Future {
+        if let Some(limit) = self.2 {
+            let size = header_size(req.headers());
+            if size > limit.max {
+                println!("server header size {} exceeds limit {}", size, limit.max);
+                if limit.reset {
+                    let err = BoxError::from("request headers too large");
+                    return Box::new(future::err::<hyper::Response<RspBody>, _>(err));
+                }
+                let res = http::Response::builder()
+                    .status(http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+                    .body(RspBody::default())
+                    .unwrap();
+                return Box::new(future::ok::<_, BoxError>(res));
+            }
+        }
+
+        if let Run::H2c = self.1 {
+            if is_h2c_upgrade(&req) {
+                return Box::new(future::ok(self.upgrade_h2c(req)));
+            }
+        }
+
         let req = req.map(|body| {
             Box::new(body.map(|chunk| chunk.into_bytes()).map_err(|err| {
                 panic!("body error: {}", err);
             })) as ReqBody
         });
-        Box::new(self.route(req).map(|res| res.map(|s| hyper::Body::from(s))))
+        Box::new(self.route(req))
     }
 }
 
 #[derive(Debug)]
-struct NewSvc(Arc<HashMap<String, Route>>);
+struct NewSvc(Arc<HashMap<String, Route>>, Run, Option<HeaderLimit>);
 
 impl Service<()> for NewSvc {
     type Response = Svc;
@@ -317,10 +732,118 @@ impl Service<()> for NewSvc {
     }
 
     fn call(&mut self, _: ()) -> Self::Future {
-        future::ok(Svc(Arc::clone(&self.0)))
+        future::ok(Svc(Arc::clone(&self.0), self.1, self.2))
+    }
+}
+
+impl Svc {
+    /// Switches the connection to HTTP/2 once the `101 Switching Protocols`
+    /// response has been written.
+    ///
+    /// hyper cannot answer the upgrading request over HTTP/2 (i.e. on stream
+    /// 1), so that request is only answered with the `101`; all subsequent
+    /// requests are served over HTTP/2.
+    fn upgrade_h2c(&self, req: hyper::Request<hyper::Body>) -> hyper::Response<RspBody> {
+        println!("support server upgrading to h2c: {:?}", req.uri().path());
+        let svc = Svc(Arc::clone(&self.0), Run::Http2, self.2);
+        let serve = req
+            .into_body()
+            .on_upgrade()
+            .and_then(move |io| {
+                hyper::server::conn::Http::new()
+                    .http2_only(true)
+                    .serve_connection(io, svc)
+            })
+            .map_err(|e| println!("support h2c server error: {}", e));
+        if let Err(e) = current_thread::TaskExecutor::current().execute(serve) {
+            println!("support h2c server execute error: {:?}", e);
+        }
+
+        http::Response::builder()
+            .status(http::StatusCode::SWITCHING_PROTOCOLS)
+            .header("connection", "upgrade")
+            .header("upgrade", "h2c")
+            .body(RspBody::default())
+            .unwrap()
     }
 }
 
+/// Returns the size of `headers` as they would be written in an HTTP/1 message.
+fn header_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+/// Records the bytes of the first request head read from `T`.
+struct CaptureIo<T> {
+    inner: T,
+    /// The bytes read so far, until the end of the head has been read.
+    head: Option<BytesMut>,
+    captured: Option<RawHeads>,
+}
+
+impl<T> CaptureIo<T> {
+    fn new(inner: T, captured: Option<RawHeads>) -> Self {
+        let head = captured.as_ref().map(|_| BytesMut::new());
+        Self {
+            inner,
+            head,
+            captured,
+        }
+    }
+}
+
+impl<T: io::Read> io::Read for CaptureIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        let end = match self.head {
+            Some(ref mut head) => {
+                head.extend_from_slice(&buf[..n]);
+                head.windows(4)
+                    .position(|w| w == b"\r\n\r\n")
+                    .map(|pos| pos + 4)
+            }
+            None => None,
+        };
+        if let Some(end) = end {
+            let mut head = self.head.take().expect("head must be capturing");
+            head.truncate(end);
+            if let Some(ref captured) = self.captured {
+                captured.lock().unwrap().push(head.freeze());
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+impl<T: io::Write> io::Write for CaptureIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for CaptureIo<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for CaptureIo<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+fn is_h2c_upgrade<B>(req: &hyper::Request<B>) -> bool {
+    req.headers()
+        .get("upgrade")
+        .map_or(false, |upgrade| upgrade == "h2c")
+}
+
 fn accept_connection(
     io: TcpStream,
     tls: Option<Arc<ServerConfig>>,
@@ -272,6 +272,11 @@ fn run_server(tcp: TcpServer) -> server::Listening {
     server::Listening {
         addr,
         shutdown: tx,
+        // The TCP server never terminates TLS.
+        plaintext_conn_count: Arc::clone(&conn_count),
         conn_count,
+        tls_conn_count: Default::default(),
+        errors: Default::default(),
+        raw_heads: Default::default(),
     }
 }
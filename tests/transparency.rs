@@ -1248,6 +1248,43 @@ fn http2_request_without_authority() {
     tokio::runtime::current_thread::run(future);
 }
 
+#[test]
+fn http2_requests_are_serialized_by_server_stream_limit() {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    let _ = trace_init();
+
+    // Each request is answered once the test releases it.
+    let (started_tx, started_rx) = mpsc::channel();
+    let srv = server::http2()
+        .http2_max_concurrent_streams(1)
+        .route_async("/", move |_req| {
+            let (release_tx, release_rx) = oneshot::channel::<()>();
+            started_tx.send(release_tx).unwrap();
+            release_rx
+                .map(|()| Response::builder().status(200).body("".into()).unwrap())
+                .map_err(|_| "request was never released")
+        })
+        .run();
+    let proxy = proxy::new().inbound_fuzz_addr(srv).run();
+    let client = client::http2(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    let rsp1 = client.request_async(&mut client.request_builder("/"));
+    let rsp2 = client.request_async(&mut client.request_builder("/"));
+
+    let release1 = started_rx.recv_timeout(TIMEOUT).expect("first request");
+    assert!(
+        started_rx.recv_timeout(Duration::from_millis(100)).is_err(),
+        "second request must wait for the first to complete"
+    );
+    release1.send(()).unwrap();
+    assert_eq!(rsp1.wait().expect("first response").status(), 200);
+
+    let release2 = started_rx.recv_timeout(TIMEOUT).expect("second request");
+    release2.send(()).unwrap();
+    assert_eq!(rsp2.wait().expect("second response").status(), 200);
+}
+
 #[test]
 fn http2_rst_stream_is_propagated() {
     let _ = trace_init();
@@ -1274,6 +1311,217 @@ fn http2_rst_stream_is_propagated() {
     assert_eq!(rst.reason(), Some(reason));
 }
 
+#[test]
+fn http2_grpc_messages_and_trailers() {
+    let _ = trace_init();
+
+    let srv = server::http2()
+        .route_grpc("/test.Echo/Echo", |msgs| {
+            assert_eq!(msgs, vec![Bytes::from("hello"), Bytes::from("world")]);
+            (msgs, grpc::Status::new(grpc::Code::NotFound, "not here"))
+        })
+        .run();
+    let proxy = proxy::new().inbound_fuzz_addr(srv).run();
+    let client = client::http2(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    let req = client
+        .request_builder("/test.Echo/Echo")
+        .method("POST")
+        .header("content-type", "application/grpc")
+        .body(server::encode_grpc(vec![
+            Bytes::from("hello"),
+            Bytes::from("world"),
+        ]))
+        .unwrap();
+    let res = client.request_body(req);
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    let mut body = res.into_body();
+    let data = body.by_ref().concat2().wait().expect("response body");
+    let msgs = server::decode_grpc(data).expect("response must be gRPC-framed");
+    assert_eq!(msgs, vec![Bytes::from("hello"), Bytes::from("world")]);
+
+    let trailers = future::poll_fn(|| body.poll_trailers())
+        .wait()
+        .expect("response trailers")
+        .expect("response must have trailers");
+    assert_eq!(trailers["grpc-status"], "5");
+    assert_eq!(trailers["grpc-message"], "not here");
+}
+
+#[test]
+fn h2c_server_upgrades_http1_connections() {
+    let _ = trace_init();
+
+    let srv = server::h2c().route("/", "hello").run();
+    let conn = client::tcp(srv.addr).connect();
+
+    conn.write(
+        "GET / HTTP/1.1\r\n\
+         host: transparency.test.svc.cluster.local\r\n\
+         connection: upgrade, http2-settings\r\n\
+         upgrade: h2c\r\n\
+         http2-settings: \r\n\
+         \r\n",
+    );
+    let mut rsp = conn.read();
+    assert!(
+        rsp.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"),
+        "expected an upgrade: {:?}",
+        String::from_utf8_lossy(&rsp)
+    );
+
+    // Once upgraded, the server speaks HTTP/2, starting with a SETTINGS frame.
+    conn.write(&b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0"[..]);
+    let head_len = 4 + rsp
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("upgrade response must be complete");
+    while rsp.len() < head_len + 9 {
+        rsp.extend(conn.read());
+    }
+    assert_eq!(rsp[head_len + 3], 0x4, "expected an HTTP/2 SETTINGS frame");
+}
+
+#[test]
+fn server_resets_connections_over_max_connections() {
+    let _ = trace_init();
+
+    let srv = server::http1().route("/", "hello").max_connections(1).run();
+    let client = client::http1(srv.addr, "transparency.test.svc.cluster.local");
+    assert_eq!(client.get("/"), "hello");
+
+    let refused = client::tcp(srv.addr).connect();
+    match refused.try_read() {
+        Ok(ref buf) if buf.is_empty() => {}
+        Err(_) => {}
+        Ok(buf) => panic!("unexpected response: {:?}", String::from_utf8_lossy(&buf)),
+    }
+
+    // The accepted connection continues to be served.
+    assert_eq!(client.get("/"), "hello");
+    assert_eq!(srv.connections(), 1);
+}
+
+#[test]
+fn inbound_http1_preserves_origin_form_request_targets() {
+    let _ = trace_init();
+
+    let srv = server::http1().route("/hi", "hello").capture_raw_heads().run();
+    let proxy = proxy::new().inbound_fuzz_addr(srv).run();
+    let client = client::http1(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    assert_eq!(client.get("/hi"), "hello");
+
+    let heads = proxy.inbound_server.as_ref().unwrap().raw_request_heads();
+    assert_eq!(heads.len(), 1);
+    assert!(
+        heads[0].starts_with(b"GET /hi HTTP/1.1\r\n"),
+        "unexpected request head: {:?}",
+        heads[0]
+    );
+    assert!(heads[0].ends_with(b"\r\n\r\n"));
+}
+
+#[test]
+fn inbound_http1_preserves_absolute_form_request_targets() {
+    let _ = trace_init();
+
+    let auth = "transparency.test.svc.cluster.local";
+    let srv = server::http1().route("/hi", "hello").capture_raw_heads().run();
+    let proxy = proxy::new().inbound_fuzz_addr(srv).run();
+    let client = client::http1_absolute_uris(proxy.inbound, auth);
+
+    assert_eq!(client.get("/hi"), "hello");
+
+    let heads = proxy.inbound_server.as_ref().unwrap().raw_request_heads();
+    assert_eq!(heads.len(), 1);
+    let line = format!("GET http://{}/hi HTTP/1.1\r\n", auth);
+    assert!(
+        heads[0].starts_with(line.as_bytes()),
+        "unexpected request head: {:?}",
+        heads[0]
+    );
+}
+
+#[test]
+fn inbound_forwards_431_for_oversized_headers() {
+    let _ = trace_init();
+
+    let srv = server::http1()
+        .route("/", "hello")
+        .max_header_size(1024)
+        .run();
+    let proxy = proxy::new().inbound_fuzz_addr(srv).run();
+    let client = client::http1(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    assert_eq!(client.get("/"), "hello");
+
+    let big = "a".repeat(2048);
+    let res = client.request(client.request_builder("/").header("x-big", big.as_str()));
+    assert_eq!(res.status(), http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+}
+
+#[test]
+fn inbound_responds_502_when_oversized_headers_are_reset() {
+    let _ = trace_init();
+
+    let srv = server::http1()
+        .route("/", "hello")
+        .max_header_size(1024)
+        .reset_oversized_headers()
+        .run();
+    let proxy = proxy::new().inbound_fuzz_addr(srv).run();
+    let client = client::http1(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    let big = "a".repeat(2048);
+    let res = client.request(client.request_builder("/").header("x-big", big.as_str()));
+    assert_eq!(res.status(), http::StatusCode::BAD_GATEWAY);
+}
+
+#[test]
+fn inbound_responds_502_for_oversized_response_headers() {
+    let _ = trace_init();
+
+    // Larger than the HTTP/1 client's maximum buffer size.
+    let srv = server::http1()
+        .route("/", "hello")
+        .route_oversized_headers("/big", 512 * 1024)
+        .run();
+    let proxy = proxy::new().inbound_fuzz_addr(srv).run();
+    let client = client::http1(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    let res = client.request(&mut client.request_builder("/big"));
+    assert_eq!(res.status(), http::StatusCode::BAD_GATEWAY);
+
+    // The proxy continues to serve requests.
+    assert_eq!(client.get("/"), "hello");
+}
+
+#[test]
+fn h2c_upgrade_is_not_negotiated_through_proxy() {
+    let _ = trace_init();
+
+    let srv = server::h2c()
+        .route_fn("/", |req| {
+            assert_eq!(req.version(), http::Version::HTTP_11);
+            assert!(!req.headers().contains_key("upgrade"));
+            Response::default()
+        })
+        .run();
+    let proxy = proxy::new().inbound(srv).run();
+    let client = client::http1(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    let res = client.request(
+        client
+            .request_builder("/")
+            .header("upgrade", "h2c")
+            .header("http2-settings", "")
+            .header("connection", "upgrade, http2-settings"),
+    );
+    assert_eq!(res.status(), http::StatusCode::OK);
+}
+
 #[test]
 fn http1_orig_proto_does_not_propagate_rst_stream() {
     let _ = trace_init();